@@ -1,9 +1,11 @@
-use crate::query::{parse, Query, OrderOperator};
+use crate::query::{parse, parse_order_by, Query, OrderOperator, SortDirection};
+use crate::value::SqlValue;
 use err_derive::Error;
 use std::any::type_name;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
-use nom::Err;
+use nom::{Err, error::ErrorKind};
+use uuid::Uuid;
 
 #[derive(Debug, Error, PartialEq)]
 pub enum Error {
@@ -12,9 +14,11 @@ pub enum Error {
         field: String,
         accepted_type: String,
     },
-    #[error(display = "syntax error at position {}", pos)]
+    #[error(display = "syntax error at line {}, column {}", line, column)]
     ParseError {
         pos: usize,
+        line: usize,
+        column: usize,
     },
     #[error(display = "unsupported operation \"{}\" on field \"{}\"", required_operation, field)]
     UnsupportedOperation {
@@ -28,34 +32,65 @@ pub enum Error {
     #[error(display = "empty operation \"{}\" on wildcard field", required_operation)]
     EmptyWildcardOperation {
         required_operation: String,
-    }
+    },
+    #[error(display = "empty list of values for field \"{}\"", field)]
+    EmptyList {
+        field: String,
+    },
+    #[error(display = "query too complex, exceeding the limit of {}", limit)]
+    QueryTooComplex {
+        limit: usize,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LikeMode {
+    Contains,
+    Prefix,
+    Suffix,
+}
+
 pub struct FieldConfig {
     pub field: String,
     pub rename: Option<String>,
+    pub columns: Option<Vec<String>>,
     pub type_name: Option<String>,
     pub wildcard: bool,
     pub partial_order: bool,
     pub partial_equal: bool,
     pub use_like: bool,
+    pub like_mode: LikeMode,
+    pub case_insensitive: bool,
+    pub regex: bool,
+    pub jsonb: bool,
+    pub array: bool,
+    pub full_text: Option<String>,
+    pub nullable: bool,
+    pub sortable: bool,
     pub escape_handler: Option<EscapeHandler>,
 }
 
-pub type EscapeHandler = Box<dyn Fn(&str, &FieldConfig) -> Result<String>>;
+// Checks a value of the field and converts it to what is bound to its placeholder, so that the
+// type it is bound as and the literal written inline always agree
+pub type EscapeHandler = Box<dyn Fn(&str, &FieldConfig) -> Result<SqlValue>>;
+
+fn parse_value<T: FromStr>(input: &str, config: &FieldConfig) -> Result<T> {
+    input.parse()
+        .map_err(|_| Error::InvalidValue {
+            field: config.field.clone(),
+            accepted_type: config.type_name.clone().unwrap_or_else(|| type_name::<T>().into()),
+        })
+}
 
+// Checked as T, but bound and written as text
 pub fn escape_quoted_with_converter<T: FromStr>(
     converter: impl Fn(&T) -> String + 'static,
 ) -> EscapeHandler {
     Box::new(move |input: &str, config: &FieldConfig| {
-        let value: T = input.parse()
-            .map_err(|_| Error::InvalidValue {
-                field: config.field.clone(),
-                accepted_type: config.type_name.clone().unwrap_or_else(|| type_name::<T>().into()),
-            })?;
-        Ok(format!("'{}'", converter(&value).replace("'", "''")))
+        let value: T = parse_value(input, config)?;
+        Ok(SqlValue::Text(converter(&value)))
     })
 }
 
@@ -63,21 +98,43 @@ pub fn escape_quoted<T: FromStr + ToString + 'static>() -> EscapeHandler {
     escape_quoted_with_converter(T::to_string)
 }
 
-pub fn escape_unquoted_with_converter<T: FromStr>(
-    converter: impl Fn(&T) -> String + 'static,
+pub fn escape_value_with_converter<T: FromStr>(
+    converter: impl Fn(T) -> SqlValue + 'static,
 ) -> EscapeHandler {
     Box::new(move |input: &str, config: &FieldConfig| {
-        let value: T = input.parse()
+        Ok(converter(parse_value(input, config)?))
+    })
+}
+
+// Bound as the type of T itself, e.g. i32 as an INT4 and DateTime<Utc> as a TIMESTAMPTZ
+pub fn escape_value<T: FromStr + Into<SqlValue> + 'static>() -> EscapeHandler {
+    escape_value_with_converter(T::into)
+}
+
+// For uuid columns, e.g. '936da01f-9abd-4d9d-80c7-02af85c822a8'::uuid
+pub fn escape_uuid() -> EscapeHandler {
+    Box::new(|input: &str, config: &FieldConfig| {
+        let value: Uuid = input.parse()
             .map_err(|_| Error::InvalidValue {
                 field: config.field.clone(),
-                accepted_type: config.type_name.clone().unwrap_or_else(|| type_name::<T>().into()),
+                accepted_type: config.type_name.clone().unwrap_or_else(|| "UUID".into()),
             })?;
-        Ok(converter(&value))
+        Ok(SqlValue::Uuid(value))
     })
 }
 
-pub fn escape_unquoted<T: FromStr + ToString + 'static>() -> EscapeHandler {
-    escape_unquoted_with_converter(T::to_string)
+// For flag fields, accepting true/false, t/f, yes/no, y/n, on/off and 1/0 in any case
+pub fn escape_bool() -> EscapeHandler {
+    Box::new(|input: &str, config: &FieldConfig| {
+        match &input.to_ascii_lowercase()[..] {
+            "true" | "t" | "yes" | "y" | "on" | "1" => Ok(SqlValue::Bool(true)),
+            "false" | "f" | "no" | "n" | "off" | "0" => Ok(SqlValue::Bool(false)),
+            _ => Err(Error::InvalidValue {
+                field: config.field.clone(),
+                accepted_type: config.type_name.clone().unwrap_or_else(|| "bool".into()),
+            }),
+        }
+    })
 }
 
 impl FieldConfig {
@@ -85,11 +142,20 @@ impl FieldConfig {
         Self {
             field: field.into(),
             rename: None,
+            columns: None,
             type_name: None,
             wildcard: false,
             partial_order: false,
             partial_equal: false,
             use_like: false,
+            like_mode: LikeMode::Contains,
+            case_insensitive: false,
+            regex: false,
+            jsonb: false,
+            array: false,
+            full_text: None,
+            nullable: false,
+            sortable: false,
             escape_handler: None,
         }
     }
@@ -97,6 +163,29 @@ impl FieldConfig {
         self.rename = Some(rename.into());
         self
     }
+    // One term matches any of the columns, which replace rename
+    pub fn columns(mut self, columns: Vec<String>) -> Self {
+        self.columns = Some(columns);
+        self
+    }
+    // The clause built by f for each column, joined by joiner. Columns of jsonb fields are
+    // followed by the path, whose last key is taken as text, e.g. data -> 'a' ->> 'b'
+    fn expand(&self, path: &[&str], joiner: &str, f: impl Fn(&str) -> String) -> String {
+        let column = |column: &str| match path.split_last() {
+            Some((last, keys)) => keys.iter()
+                .map(|x| format!(" -> {}", quote(x)))
+                .chain(std::iter::once(format!(" ->> {}", quote(last))))
+                .fold(column.to_string(), |x, y| x + &y),
+            None => column.into(),
+        };
+        match &self.columns {
+            Some(columns) => columns.iter()
+                .map(|x| f(&column(x)))
+                .collect::<Vec<_>>()
+                .join(joiner),
+            None => f(&column(self.rename.as_ref().unwrap_or(&self.field))),
+        }
+    }
     pub fn type_name(mut self, type_name: &str) -> Self {
         self.type_name = Some(type_name.into());
         self
@@ -117,106 +206,417 @@ impl FieldConfig {
         self.use_like = true;
         self
     }
+    // Implies use_like
+    pub fn like_mode(mut self, like_mode: LikeMode) -> Self {
+        self.use_like = true;
+        self.like_mode = like_mode;
+        self
+    }
+    // Equal and IN compare LOWER(rename) with LOWER(value), which a plain index on the column
+    // cannot serve, so an expression index on LOWER(column) is needed instead. LIKE is always
+    // case-insensitive already
+    pub fn case_insensitive(mut self) -> Self {
+        self.case_insensitive = true;
+        self
+    }
+    fn fold_case(&self, expr: &str) -> String {
+        if self.case_insensitive {
+            format!("LOWER({})", expr)
+        } else {
+            expr.into()
+        }
+    }
+    pub fn regex(mut self) -> Self {
+        self.regex = true;
+        self
+    }
+    // Dotted paths below the field, like prefs.theme, are keys into the jsonb column
+    pub fn jsonb(mut self) -> Self {
+        self.jsonb = true;
+        self
+    }
+    // For array columns, equal tests membership and @> containment, with the handler escaping
+    // each element. It takes precedence over use_like
+    pub fn array(mut self) -> Self {
+        self.array = true;
+        self
+    }
+    // Equal matches the words of the value against the column with the given text search
+    // configuration, e.g. english. Implies partial_equal, but joins the wildcard only if
+    // wildcard is also set
+    pub fn full_text(mut self, config_name: &str) -> Self {
+        self.partial_equal = true;
+        self.full_text = Some(config_name.into());
+        self
+    }
+    pub fn nullable(mut self) -> Self {
+        self.nullable = true;
+        self
+    }
+    pub fn sortable(mut self) -> Self {
+        self.sortable = true;
+        self
+    }
     pub fn escape_handler(mut self, func: EscapeHandler) -> Self {
         self.escape_handler = Some(func);
         self
     }
+    // The value as a literal of its type, for inline output
     pub fn escape(&self, input: &str) -> Result<String> {
-        self.escape_handler.as_ref()
-            .unwrap_or(&escape_quoted::<String>())
-            (input, &self)
+        Ok(match self.value(input)? {
+            SqlValue::Bool(value) => if value { "TRUE".into() } else { "FALSE".into() },
+            SqlValue::Int4(value) => value.to_string(),
+            SqlValue::Int8(value) => value.to_string(),
+            SqlValue::Float8(value) if value.is_finite() => value.to_string(),
+            SqlValue::Float8(value) => format!("{}::float8", quote(&value.to_string())),
+            SqlValue::Text(value) => quote(&value),
+            SqlValue::Timestamp(value) => quote(&value.to_rfc3339()),
+            SqlValue::Uuid(value) => format!("{}::uuid", quote(&value.to_hyphenated().to_string())),
+        })
+    }
+    // The value as bound to a placeholder. Without an escape handler, it is text
+    pub fn value(&self, input: &str) -> Result<SqlValue> {
+        match &self.escape_handler {
+            Some(func) => func(input, self),
+            None => Ok(SqlValue::Text(input.into())),
+        }
+    }
+}
+
+// Tells which bound of a range failed, e.g. "created_at (low bound)"
+fn invalid_bound(err: Error, bound: &str) -> Error {
+    match err {
+        Error::InvalidValue { field, accepted_type } => Error::InvalidValue {
+            field: format!("{} ({} bound)", field, bound),
+            accepted_type,
+        },
+        e => e,
+    }
+}
+
+// The byte offset pos is kept next to the line and column, both counted from 1
+fn parse_error(input: &str, err: Err<(&str, ErrorKind)>) -> Error {
+    let pos = match err {
+        Err::Incomplete(..) => input.len(),
+        Err::Error((rest, ..)) | Err::Failure((rest, ..)) => input.len() - rest.len(),
+    };
+    let before = &input[..pos];
+    let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+    Error::ParseError {
+        pos,
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace("'", "''"))
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace("^", "^^")
+        .replace("%", "^%")
+        .replace("_", "^_")
+}
+
+// Where the values of a query go, inline as escaped literals or aside as bound parameters
+enum Output<'a> {
+    Inline,
+    Params(&'a mut Vec<SqlValue>),
+}
+
+impl Output<'_> {
+    fn value(&mut self, config: &FieldConfig, input: &str) -> Result<String> {
+        match self {
+            Output::Inline => config.escape(input),
+            Output::Params(params) => {
+                params.push(config.value(input)?);
+                Ok(format!("${}", params.len()))
+            }
+        }
+    }
+    // The pattern of ILIKE, which is always matched as text
+    fn like_value(&mut self, config: &FieldConfig, input: &str) -> Result<String> {
+        match self {
+            Output::Inline => Ok(escape_like(&config.escape(input)?)),
+            Output::Params(params) => {
+                let value = match config.value(input)? {
+                    SqlValue::Bool(value) => value.to_string(),
+                    SqlValue::Int4(value) => value.to_string(),
+                    SqlValue::Int8(value) => value.to_string(),
+                    SqlValue::Float8(value) => value.to_string(),
+                    SqlValue::Text(value) => value,
+                    SqlValue::Timestamp(value) => value.to_rfc3339(),
+                    SqlValue::Uuid(value) => value.to_hyphenated().to_string(),
+                };
+                params.push(SqlValue::Text(escape_like(&value)));
+                // The type of a placeholder cannot be inferred from ||
+                Ok(format!("${}::TEXT", params.len()))
+            }
+        }
+    }
+    // Parameters bound by f are dropped again if it fails
+    fn attempt<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let len = match self {
+            Output::Inline => 0,
+            Output::Params(params) => params.len(),
+        };
+        let result = f(self);
+        if let (Err(_), Output::Params(params)) = (&result, self) {
+            params.truncate(len);
+        }
+        result
     }
 }
 
 pub struct QueryConfig {
     fields: HashMap<String, FieldConfig>,
+    max_clauses: Option<usize>,
+    max_depth: Option<usize>,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl QueryConfig {
     pub fn new() -> Self {
         Self {
             fields: HashMap::new(),
+            max_clauses: None,
+            max_depth: None,
         }
     }
     pub fn field(mut self, field: FieldConfig) -> Self {
         self.fields.insert(field.field.clone(), field);
         self
     }
+    // Like calling field for each of them, so a field replaces an earlier one of the same name
+    pub fn extend(mut self, fields: impl IntoIterator<Item = FieldConfig>) -> Self {
+        self.fields.extend(fields.into_iter().map(|x| (x.field.clone(), x)));
+        self
+    }
+    // The fields of other replace those of the same name. The limits of other, where set, also
+    // replace those of self
+    pub fn merge(mut self, other: QueryConfig) -> Self {
+        self.fields.extend(other.fields);
+        self.max_clauses = other.max_clauses.or(self.max_clauses);
+        self.max_depth = other.max_depth.or(self.max_depth);
+        self
+    }
+    // Every And, Or, Not and leaf counts as a clause
+    pub fn max_clauses(mut self, max_clauses: usize) -> Self {
+        self.max_clauses = Some(max_clauses);
+        self
+    }
+    // A single leaf is at depth 1, and each And, Or or Not adds a level
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+    // Walks the whole tree before anything is generated
+    fn check_complexity(&self, query: &Query) -> Result<()> {
+        fn walk(
+            config: &QueryConfig, query: &Query, depth: usize, clauses: &mut usize,
+        ) -> Result<()> {
+            *clauses += 1;
+            if let Some(limit) = config.max_clauses.filter(|x| *clauses > *x) {
+                return Err(Error::QueryTooComplex { limit });
+            }
+            if let Some(limit) = config.max_depth.filter(|x| depth > *x) {
+                return Err(Error::QueryTooComplex { limit });
+            }
+            match query {
+                Query::Or { queries } | Query::And { queries } => queries.iter()
+                    .try_for_each(|x| walk(config, x, depth + 1, clauses)),
+                Query::Not { query } => walk(config, query, depth + 1, clauses),
+                _ => Ok(()),
+            }
+        }
+        walk(self, query, 1, &mut 0)
+    }
     pub fn parse_to_postgres(&self, input: &str) -> Result<Option<String>> {
-        Ok(parse(input)
-            .map_err(|err| match err {
-                Err::Incomplete(..) => Error::ParseError { pos: input.len() },
-                Err::Error((rest, ..)) | Err::Failure((rest, ..))
-                    => Error::ParseError { pos: input.len() - rest.len() },
-            })?
+        parse(input)
+            .map_err(|err| parse_error(input, err))?
             .1
             .as_ref()
             .map(|x| self.query_to_postgres(x))
-            .transpose()?)
+            .transpose()
+    }
+    // The config of a field, and the path of keys if it goes into a jsonb field
+    fn lookup<'a>(&self, field: &'a str) -> Result<(&FieldConfig, Vec<&'a str>)> {
+        if let Some(config) = self.fields.get(field) {
+            return Ok((config, Vec::new()));
+        }
+        let mut path = field.split('.');
+        match path.next().and_then(|x| self.fields.get(x)) {
+            Some(config) if config.jsonb => Ok((config, path.collect())),
+            _ => Err(Error::UnknownField { field: field.into() }),
+        }
+    }
+    // Turns e.g. "created_at desc, username" into "created_at DESC, username ASC"
+    pub fn parse_order_by(&self, input: &str) -> Result<Option<String>> {
+        let fields = parse_order_by(input)
+            .map_err(|err| parse_error(input, err))?
+            .1;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        fields.iter()
+            .map(|(field, direction)| {
+                let (config, path) = self.lookup(field)?;
+                if !config.sortable {
+                    return Err(Error::UnsupportedOperation {
+                        field: field.clone(),
+                        required_operation: "sort".into(),
+                    });
+                }
+                let direction = match direction {
+                    SortDirection::Asc => "ASC",
+                    SortDirection::Desc => "DESC",
+                };
+                Ok(config.expand(&path, ", ", |rename| format!("{} {}", rename, direction)))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|x| Some(x.join(", ")))
+    }
+    // The configured fields a query refers to, with * expanded to every wildcard field and jsonb
+    // paths reduced to their top-level field, e.g. to check permissions before running it
+    pub fn referenced_fields(&self, input: &str) -> Result<HashSet<String>> {
+        fn walk(config: &QueryConfig, query: &Query, fields: &mut HashSet<String>) -> Result<()> {
+            match query {
+                Query::Or { queries } | Query::And { queries } => queries.iter()
+                    .try_for_each(|x| walk(config, x, fields)),
+                Query::Not { query } => walk(config, query, fields),
+                Query::Equal { field, .. } | Query::NotEqual { field, .. }
+                | Query::Order { field, .. } | Query::Range { field, .. }
+                | Query::In { field, .. } | Query::Regex { field, .. }
+                | Query::Contains { field, .. } | Query::Null { field, .. } => {
+                    match field {
+                        Some(field) => {
+                            fields.insert(config.lookup(field)?.0.field.clone());
+                        }
+                        None => fields.extend(config.fields.values()
+                            .filter(|x| x.wildcard)
+                            .map(|x| x.field.clone())),
+                    }
+                    Ok(())
+                }
+            }
+        }
+        let mut fields = HashSet::new();
+        if let Some(query) = parse(input).map_err(|err| parse_error(input, err))?.1 {
+            walk(self, &query, &mut fields)?;
+        }
+        Ok(fields)
     }
     pub fn query_to_postgres(&self, query: &Query) -> Result<String> {
+        self.check_complexity(query)?;
+        self.generate(query, &mut Output::Inline)
+    }
+    // Like parse_to_postgres, but values are bound to $1, $2, ... instead of being inlined
+    pub fn parse_to_postgres_params(
+        &self, input: &str,
+    ) -> Result<Option<(String, Vec<SqlValue>)>> {
+        parse(input)
+            .map_err(|err| parse_error(input, err))?
+            .1
+            .as_ref()
+            .map(|x| self.query_to_postgres_params(x))
+            .transpose()
+    }
+    pub fn query_to_postgres_params(&self, query: &Query) -> Result<(String, Vec<SqlValue>)> {
+        self.check_complexity(query)?;
+        let mut params = Vec::new();
+        let result = self.generate(query, &mut Output::Params(&mut params))?;
+        Ok((result, params))
+    }
+    // Equal, or NotEqual when negated. A column that is NULL is neither equal nor not equal to
+    // any value, so NotEqual does not match such rows either. Over the wildcard, NotEqual means
+    // none of the fields is equal, which skips the fields that could never hold the value
+    fn equal(
+        &self, field: &Option<String>, value: &str, negated: bool, out: &mut Output,
+    ) -> Result<String> {
+        let equal = |config: &FieldConfig, path: &[&str], out: &mut Output| -> Result<String> {
+            let joiner = if negated { " AND " } else { " OR " };
+            Ok(if let Some(config_name) = &config.full_text {
+                let value = out.value(config, value)?;
+                let config_name = quote(config_name);
+                config.expand(path, joiner, |rename| {
+                    let search = format!("to_tsvector({}, {}) @@ plainto_tsquery({}, {})",
+                                         config_name, rename, config_name, value);
+                    if negated { format!("NOT ({})", search) } else { search }
+                })
+            } else if config.array {
+                let value = out.value(config, value)?;
+                config.expand(path, joiner, |rename| {
+                    let any = format!("{} = ANY({})", value, rename);
+                    if negated { format!("NOT ({})", any) } else { any }
+                })
+            } else if config.use_like {
+                let value = out.like_value(config, value)?;
+                let pattern = match config.like_mode {
+                    LikeMode::Contains => format!("'%' || {} || '%'", value),
+                    LikeMode::Prefix => format!("{} || '%'", value),
+                    LikeMode::Suffix => format!("'%' || {}", value),
+                };
+                config.expand(path, joiner, |rename| {
+                    let like = format!("{} ILIKE {} ESCAPE '^'", rename, pattern);
+                    if negated { format!("NOT ({})", like) } else { like }
+                })
+            } else {
+                let value = config.fold_case(&out.value(config, value)?);
+                let operator = if negated { "<>" } else { "=" };
+                config.expand(path, joiner, |rename|
+                    format!("{} {} {}", config.fold_case(rename), operator, value))
+            })
+        };
+        Ok(match field {
+            Some(field) => {
+                let (config, path) = self.lookup(field)?;
+                if !config.partial_equal {
+                    return Err(Error::UnsupportedOperation {
+                        field: field.clone(),
+                        required_operation: "equal".into(),
+                    });
+                }
+                equal(config, &path, out)?
+            }
+            None => {
+                let queries = self.fields.values()
+                    .filter(|x| x.wildcard && x.partial_equal)
+                    .map(|config| out.attempt(|out| equal(config, &[], out)))
+                    .flat_map(Result::ok)
+                    .collect::<Vec<_>>();
+                if queries.is_empty() {
+                    return Err(Error::EmptyWildcardOperation {
+                        required_operation: "equal".into(),
+                    });
+                }
+                queries.join(if negated { " AND " } else { " OR " })
+            }
+        })
+    }
+    fn generate(&self, query: &Query, out: &mut Output) -> Result<String> {
         let result = match query {
             Query::Or { queries } => if queries.is_empty() { "TRUE".into() } else {
                 queries.iter()
-                    .map(|x| self.query_to_postgres(x))
+                    .map(|x| self.generate(x, out))
                     .collect::<Result<Vec<_>>>()?
                     .join(" OR ")
             }
             Query::And { queries } => if queries.is_empty() { "FALSE".into() } else {
                 queries.iter()
-                    .map(|x| self.query_to_postgres(x))
+                    .map(|x| self.generate(x, out))
                     .collect::<Result<Vec<_>>>()?
                     .join(" AND ")
             }
-            Query::Not { query } => format!("NOT {}", self.query_to_postgres(query)?),
-            Query::Equal { field, value } => match field {
-                Some(field) => {
-                    let config = self.fields.get(field)
-                        .ok_or_else(|| Error::UnknownField { field: field.clone() })?;
-                    if config.partial_equal == false {
-                        return Err(Error::UnsupportedOperation {
-                            field: field.clone(),
-                            required_operation: "equal".into(),
-                        });
-                    }
-                    let value = config.escape(value)?;
-                    let rename = config.rename.as_ref().unwrap_or(field);
-                    if config.use_like {
-                        let value = value
-                            .replace("^", "^^")
-                            .replace("%", "^%")
-                            .replace("_", "^_");
-                        format!("{} ILIKE '%' || {} || '%' ESCAPE '^'", rename, value)
-                    } else {
-                        format!("{} = {}", rename, value)
-                    }
-                }
-                None => {
-                    let queries = self.fields.values()
-                        .filter(|x| x.wildcard && x.partial_equal)
-                        .map(|config| {
-                            let value = config.escape(value)?;
-                            let rename = config.rename.as_ref().unwrap_or(&config.field);
-                            Ok(if config.use_like {
-                                let value = value
-                                    .replace("^", "^^")
-                                    .replace("%", "^%")
-                                    .replace("_", "^_");
-                                format!("{} ILIKE '%' || {} || '%' ESCAPE '^'", rename, value)
-                            } else {
-                                format!("{} = {}", rename, value)
-                            })
-                        })
-                        .flat_map(Result::ok)
-                        .collect::<Vec<_>>();
-                    if queries.is_empty() {
-                        return Err(Error::EmptyWildcardOperation {
-                            required_operation: "equal".into(),
-                        });
-                    }
-                    queries.join(" OR ")
-                }
-            }
+            Query::Not { query } => format!("NOT {}", self.generate(query, out)?),
+            Query::Equal { field, value } => self.equal(field, value, false, out)?,
+            Query::NotEqual { field, value } => self.equal(field, value, true, out)?,
             Query::Order { field, operator, value } => {
                 let operator = match operator {
                     OrderOperator::Lte => "<=",
@@ -226,26 +626,64 @@ impl QueryConfig {
                 };
                 match field {
                     Some(field) => {
-                        let config = self.fields.get(field)
-                            .ok_or_else(|| Error::UnknownField { field: field.clone() })?;
-                        if config.partial_order == false {
+                        let (config, path) = self.lookup(field)?;
+                        if !config.partial_order {
+                            return Err(Error::UnsupportedOperation {
+                                field: field.clone(),
+                                required_operation: "order".into(),
+                            });
+                        }
+                        let value = out.value(config, value)?;
+                        config.expand(&path, " OR ", |rename|
+                            format!("{} {} {}", rename, operator, value))
+                    }
+                    None => {
+                        let queries = self.fields.values()
+                            .filter(|x| x.wildcard && x.partial_order)
+                            .map(|config| out.attempt(|out| {
+                                let value = out.value(config, value)?;
+                                Ok(config.expand(&[], " OR ", |rename|
+                                    format!("{} {} {}", rename, operator, value)))
+                            }))
+                            .filter_map(Result::ok)
+                            .collect::<Vec<_>>();
+                        if queries.is_empty() {
+                            return Err(Error::EmptyWildcardOperation {
+                                required_operation: "order".into(),
+                            });
+                        }
+                        queries.join(" OR ")
+                    }
+                }
+            }
+            Query::Range { field, low, high } => {
+                let escape = |config: &FieldConfig, out: &mut Output| -> Result<(String, String)> {
+                    Ok((
+                        out.value(config, low).map_err(|e| invalid_bound(e, "low"))?,
+                        out.value(config, high).map_err(|e| invalid_bound(e, "high"))?,
+                    ))
+                };
+                match field {
+                    Some(field) => {
+                        let (config, path) = self.lookup(field)?;
+                        if !config.partial_order {
                             return Err(Error::UnsupportedOperation {
                                 field: field.clone(),
                                 required_operation: "order".into(),
                             });
                         }
-                        let value = config.escape(value)?;
-                        let rename = config.rename.as_ref().unwrap_or(field);
-                        format!("{} {} {}", rename, operator, value)
+                        let (low, high) = escape(config, out)?;
+                        config.expand(&path, " OR ", |rename|
+                            format!("{} BETWEEN {} AND {}", rename, low, high))
                     }
                     None => {
                         let queries = self.fields.values()
                             .filter(|x| x.wildcard && x.partial_order)
-                            .map(|config| {
-                                let value = config.escape(value)?;
-                                let rename = config.rename.as_ref().unwrap_or(&config.field);
-                                Ok(format!("{} {} {}", rename, operator, value))
-                            })
+                            .map(|config| out.attempt(|out| {
+                                let (low, high) = escape(config, out)?;
+                                Ok(config.expand(&[], " OR ", |rename|
+                                    format!("{} BETWEEN {} AND {}", rename, low, high)))
+                            }))
                             .filter_map(Result::ok)
                             .collect::<Vec<_>>();
                         if queries.is_empty() {
@@ -257,6 +695,159 @@ impl QueryConfig {
                     }
                 }
             }
+            // Always an exact match, even for the fields using LIKE
+            Query::In { field, values } => {
+                if values.is_empty() {
+                    return Err(Error::EmptyList {
+                        field: field.clone().unwrap_or_else(|| "*".into()),
+                    });
+                }
+                let escape = |config: &FieldConfig, out: &mut Output| -> Result<String> {
+                    Ok(values.iter()
+                        .map(|x| out.value(config, x).map(|x| config.fold_case(&x)))
+                        .collect::<Result<Vec<_>>>()?
+                        .join(","))
+                };
+                match field {
+                    Some(field) => {
+                        let (config, path) = self.lookup(field)?;
+                        if !config.partial_equal {
+                            return Err(Error::UnsupportedOperation {
+                                field: field.clone(),
+                                required_operation: "equal".into(),
+                            });
+                        }
+                        let values = escape(config, out)?;
+                        config.expand(&path, " OR ", |rename|
+                            format!("{} IN ({})", config.fold_case(rename), values))
+                    }
+                    None => {
+                        let queries = self.fields.values()
+                            .filter(|x| x.wildcard && x.partial_equal)
+                            .map(|config| out.attempt(|out| {
+                                let values = escape(config, out)?;
+                                Ok(config.expand(&[], " OR ", |rename|
+                                    format!("{} IN ({})", config.fold_case(rename), values)))
+                            }))
+                            .filter_map(Result::ok)
+                            .collect::<Vec<_>>();
+                        if queries.is_empty() {
+                            return Err(Error::EmptyWildcardOperation {
+                                required_operation: "equal".into(),
+                            });
+                        }
+                        queries.join(" OR ")
+                    }
+                }
+            }
+            Query::Contains { field, values } => {
+                if values.is_empty() {
+                    return Err(Error::EmptyList {
+                        field: field.clone().unwrap_or_else(|| "*".into()),
+                    });
+                }
+                let escape = |config: &FieldConfig, out: &mut Output| -> Result<String> {
+                    Ok(values.iter()
+                        .map(|x| out.value(config, x))
+                        .collect::<Result<Vec<_>>>()?
+                        .join(","))
+                };
+                match field {
+                    Some(field) => {
+                        let (config, path) = self.lookup(field)?;
+                        if !config.array {
+                            return Err(Error::UnsupportedOperation {
+                                field: field.clone(),
+                                required_operation: "contains".into(),
+                            });
+                        }
+                        let values = escape(config, out)?;
+                        config.expand(&path, " OR ", |rename|
+                            format!("{} @> ARRAY[{}]", rename, values))
+                    }
+                    None => {
+                        let queries = self.fields.values()
+                            .filter(|x| x.wildcard && x.array)
+                            .map(|config| out.attempt(|out| {
+                                let values = escape(config, out)?;
+                                Ok(config.expand(&[], " OR ", |rename|
+                                    format!("{} @> ARRAY[{}]", rename, values)))
+                            }))
+                            .filter_map(Result::ok)
+                            .collect::<Vec<_>>();
+                        if queries.is_empty() {
+                            return Err(Error::EmptyWildcardOperation {
+                                required_operation: "contains".into(),
+                            });
+                        }
+                        queries.join(" OR ")
+                    }
+                }
+            }
+            Query::Regex { field, value, case_insensitive } => {
+                let operator = if *case_insensitive { "~*" } else { "~" };
+                match field {
+                    Some(field) => {
+                        let (config, path) = self.lookup(field)?;
+                        if !config.regex {
+                            return Err(Error::UnsupportedOperation {
+                                field: field.clone(),
+                                required_operation: "regex".into(),
+                            });
+                        }
+                        let value = out.value(config, value)?;
+                        config.expand(&path, " OR ", |rename|
+                            format!("{} {} {}", rename, operator, value))
+                    }
+                    None => {
+                        let queries = self.fields.values()
+                            .filter(|x| x.wildcard && x.regex)
+                            .map(|config| out.attempt(|out| {
+                                let value = out.value(config, value)?;
+                                Ok(config.expand(&[], " OR ", |rename|
+                                    format!("{} {} {}", rename, operator, value)))
+                            }))
+                            .filter_map(Result::ok)
+                            .collect::<Vec<_>>();
+                        if queries.is_empty() {
+                            return Err(Error::EmptyWildcardOperation {
+                                required_operation: "regex".into(),
+                            });
+                        }
+                        queries.join(" OR ")
+                    }
+                }
+            }
+            // There is no value, so the escape handler is not involved
+            Query::Null { field, negated } => {
+                let operator = if *negated { "IS NOT NULL" } else { "IS NULL" };
+                match field {
+                    Some(field) => {
+                        let (config, path) = self.lookup(field)?;
+                        if !config.nullable {
+                            return Err(Error::UnsupportedOperation {
+                                field: field.clone(),
+                                required_operation: "null".into(),
+                            });
+                        }
+                        config.expand(&path, " OR ", |rename| format!("{} {}", rename, operator))
+                    }
+                    None => {
+                        let queries = self.fields.values()
+                            .filter(|x| x.wildcard && x.nullable)
+                            .map(|config|
+                                config.expand(&[], " OR ", |rename|
+                                    format!("{} {}", rename, operator)))
+                            .collect::<Vec<_>>();
+                        if queries.is_empty() {
+                            return Err(Error::EmptyWildcardOperation {
+                                required_operation: "null".into(),
+                            });
+                        }
+                        queries.join(" OR ")
+                    }
+                }
+            }
         };
         Ok(format!("({})", result))
     }
@@ -296,17 +887,52 @@ mod tests {
     pub fn escape_to_number_test() {
         assert_eq!(
             FieldConfig::new("")
-                .escape_handler(escape_unquoted::<i32>())
+                .escape_handler(escape_value::<i32>())
                 .escape("-1"),
             Ok("-1".into()));
         assert_eq!(
             FieldConfig::new("")
-                .escape_handler(escape_unquoted::<u32>())
-                .escape("-1"),
+                .escape_handler(escape_value::<i32>())
+                .escape("1.5"),
             Err(Error::InvalidValue {
                 field: "".into(),
-                accepted_type: "u32".into(),
+                accepted_type: "i32".into(),
+            }));
+    }
+
+    #[test]
+    pub fn escape_to_uuid_test() {
+        assert_eq!(
+            FieldConfig::new("")
+                .escape_handler(escape_uuid())
+                .escape("936DA01F9ABD4D9D80C702AF85C822A8"),
+            Ok("'936da01f-9abd-4d9d-80c7-02af85c822a8'::uuid".into()));
+        assert_eq!(
+            FieldConfig::new("a")
+                .escape_handler(escape_uuid())
+                .escape("936da01f-9abd-4d9d-80c7' OR TRUE"),
+            Err(Error::InvalidValue {
+                field: "a".into(),
+                accepted_type: "UUID".into(),
+            }));
+    }
+
+    #[test]
+    pub fn escape_to_bool_test() {
+        let config = FieldConfig::new("blocked")
+            .escape_handler(escape_bool());
+        for input in &["true", "T", "Yes", "y", "ON", "1"] {
+            assert_eq!(config.escape(input), Ok("TRUE".into()));
+        }
+        for input in &["false", "F", "No", "n", "OFF", "0"] {
+            assert_eq!(config.escape(input), Ok("FALSE".into()));
+        }
+        for input in &["2", "truthy", ""] {
+            assert_eq!(config.escape(input), Err(Error::InvalidValue {
+                field: "blocked".into(),
+                accepted_type: "bool".into(),
             }));
+        }
     }
 
     #[test]
@@ -316,7 +942,7 @@ mod tests {
                 .wildcard()
                 .partial_equal()
                 .partial_order()
-                .escape_handler(escape_unquoted::<i32>())
+                .escape_handler(escape_value::<i32>())
             )
             .field(FieldConfig::new("text")
                 .wildcard()
@@ -346,5 +972,713 @@ mod tests {
             Ok(Some("(\"text\" ILIKE '%' || 'ab^%c' || '%' ESCAPE '^')".into()))
         );
     }
+
+    #[test]
+    pub fn null_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("email")
+                .rename("\"user\".email")
+                .nullable()
+            )
+            .field(FieldConfig::new("avatar")
+                .wildcard()
+                .nullable()
+            )
+            .field(FieldConfig::new("username")
+                .wildcard()
+                .partial_equal()
+                .escape_handler(Box::new(|_, _| panic!("no value to escape")))
+            );
+        assert_eq!(
+            generator.parse_to_postgres("email: null and avatar != null"),
+            Ok(Some("((\"user\".email IS NULL) AND (avatar IS NOT NULL))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("not email: null"),
+            Ok(Some("(NOT (\"user\".email IS NULL))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* : null"),
+            Ok(Some("(avatar IS NULL)".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* != null"),
+            Ok(Some("(avatar IS NOT NULL)".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("username: null"),
+            Err(Error::UnsupportedOperation {
+                field: "username".into(),
+                required_operation: "null".into(),
+            })
+        );
+        assert_eq!(
+            QueryConfig::new()
+                .field(FieldConfig::new("email").wildcard())
+                .parse_to_postgres("* != null"),
+            Err(Error::EmptyWildcardOperation {
+                required_operation: "null".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn range_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("created_at")
+                .wildcard()
+                .rename("\"user\".created_at")
+                .partial_order()
+                .type_name("DateTime")
+                .escape_handler(escape_quoted_with_converter(UtcDateTime::to_rfc3339))
+            )
+            .field(FieldConfig::new("username")
+                .partial_equal()
+            );
+        assert_eq!(
+            generator.parse_to_postgres(
+                "created_at between \"2014-11-28T12:00:09Z\" and \"2014-11-29T12:00:09Z\""),
+            Ok(Some("(\"user\".created_at BETWEEN '2014-11-28T12:00:09+00:00' \
+                AND '2014-11-29T12:00:09+00:00')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres(
+                "* between \"2014-11-28T12:00:09Z\" and \"2014-11-29T12:00:09Z\""),
+            Ok(Some("(\"user\".created_at BETWEEN '2014-11-28T12:00:09+00:00' \
+                AND '2014-11-29T12:00:09+00:00')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres(
+                "created_at between \"2014-11-28T12:00:09Z\" and tomorrow"),
+            Err(Error::InvalidValue {
+                field: "created_at (high bound)".into(),
+                accepted_type: "DateTime".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("created_at between yesterday and tomorrow"),
+            Err(Error::InvalidValue {
+                field: "created_at (low bound)".into(),
+                accepted_type: "DateTime".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("username between a and b"),
+            Err(Error::UnsupportedOperation {
+                field: "username".into(),
+                required_operation: "order".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn in_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("id")
+                .wildcard()
+                .partial_equal()
+                .escape_handler(escape_value::<i32>())
+            )
+            .field(FieldConfig::new("username")
+                .rename("\"user\".username")
+                .partial_equal()
+            )
+            .field(FieldConfig::new("created_at")
+                .partial_order()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("id: [1, 2, 3]"),
+            Ok(Some("(id IN (1,2,3))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("username in (alice, \"o'brien\")"),
+            Ok(Some("(\"user\".username IN ('alice','o''brien'))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* in (1, 2)"),
+            Ok(Some("(id IN (1,2))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* in (1, a)"),
+            Err(Error::EmptyWildcardOperation {
+                required_operation: "equal".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("id: [1, a]"),
+            Err(Error::InvalidValue {
+                field: "id".into(),
+                accepted_type: "i32".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("id: []"),
+            Err(Error::EmptyList {
+                field: "id".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("created_at in (1)"),
+            Err(Error::UnsupportedOperation {
+                field: "created_at".into(),
+                required_operation: "equal".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn order_by_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("created_at")
+                .rename("\"user\".created_at")
+                .sortable()
+            )
+            .field(FieldConfig::new("username")
+                .sortable()
+            )
+            .field(FieldConfig::new("password"));
+        assert_eq!(
+            generator.parse_order_by("created_at desc, username"),
+            Ok(Some("\"user\".created_at DESC, username ASC".into()))
+        );
+        assert_eq!(
+            generator.parse_order_by(" "),
+            Ok(None)
+        );
+        assert_eq!(
+            generator.parse_order_by("username, email desc"),
+            Err(Error::UnknownField {
+                field: "email".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_order_by("password asc"),
+            Err(Error::UnsupportedOperation {
+                field: "password".into(),
+                required_operation: "sort".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_order_by("username up"),
+            Err(Error::ParseError { pos: 9, line: 1, column: 10 })
+        );
+    }
+
+    #[test]
+    pub fn params_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("id")
+                .wildcard()
+                .partial_equal()
+                .partial_order()
+                .escape_handler(escape_value::<i32>())
+            )
+            .field(FieldConfig::new("text")
+                .wildcard()
+                .rename("\"text\"")
+                .use_like()
+                .partial_equal()
+            )
+            .field(FieldConfig::new("created_at")
+                .partial_order()
+                .escape_handler(escape_value::<UtcDateTime>())
+            );
+        assert_eq!(
+            generator.parse_to_postgres_params(
+                "id > 1 and id in (2, 3) and text: \"a'b%\" and \
+                created_at between \"2014-11-28T12:00:09Z\" and \"2014-11-29T12:00:09Z\""),
+            Ok(Some((
+                "((id > $1) AND (id IN ($2,$3)) AND (\"text\" ILIKE '%' || $4::TEXT || '%' ESCAPE '^') \
+                    AND (created_at BETWEEN $5 AND $6))".into(),
+                vec![
+                    SqlValue::Int4(1),
+                    SqlValue::Int4(2),
+                    SqlValue::Int4(3),
+                    SqlValue::Text("a'b^%".into()),
+                    SqlValue::Timestamp("2014-11-28T12:00:09Z".parse().unwrap()),
+                    SqlValue::Timestamp("2014-11-29T12:00:09Z".parse().unwrap()),
+                ],
+            )))
+        );
+        // The id is skipped without leaving its parameter behind
+        assert_eq!(
+            generator.parse_to_postgres_params("abc or * > 5"),
+            Ok(Some((
+                "((\"text\" ILIKE '%' || $1::TEXT || '%' ESCAPE '^') OR (id > $2))".into(),
+                vec![SqlValue::Text("abc".into()), SqlValue::Int4(5)],
+            )))
+        );
+        assert_eq!(
+            generator.parse_to_postgres_params("created_at > yesterday"),
+            Err(Error::InvalidValue {
+                field: "created_at".into(),
+                accepted_type: type_name::<UtcDateTime>().into(),
+            })
+        );
+        assert_eq!(generator.parse_to_postgres_params(" "), Ok(None));
+    }
+
+    #[test]
+    pub fn params_typed_by_escape_handler_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("id")
+                .partial_equal()
+                .escape_handler(escape_value::<i64>())
+            )
+            .field(FieldConfig::new("token")
+                .partial_equal()
+                .escape_handler(escape_uuid())
+            )
+            .field(FieldConfig::new("blocked")
+                .partial_equal()
+                .escape_handler(escape_bool())
+            );
+        let query = "id: 1 and token: 936da01f9abd4d9d80c702af85c822a8 and blocked: yes";
+        let uuid = "936da01f-9abd-4d9d-80c7-02af85c822a8";
+        assert_eq!(
+            generator.parse_to_postgres_params(query),
+            Ok(Some((
+                "((id = $1) AND (token = $2) AND (blocked = $3))".into(),
+                vec![
+                    SqlValue::Int8(1),
+                    SqlValue::Uuid(uuid.parse().unwrap()),
+                    SqlValue::Bool(true),
+                ],
+            )))
+        );
+        assert_eq!(
+            generator.parse_to_postgres(query),
+            Ok(Some(format!("((id = 1) AND (token = '{}'::uuid) AND (blocked = TRUE))", uuid))),
+        );
+    }
+
+    #[test]
+    pub fn like_mode_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("username")
+                .partial_equal()
+                .like_mode(LikeMode::Prefix)
+            )
+            .field(FieldConfig::new("email")
+                .partial_equal()
+                .like_mode(LikeMode::Suffix)
+            )
+            .field(FieldConfig::new("nickname")
+                .partial_equal()
+                .use_like()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("username: ab"),
+            Ok(Some("(username ILIKE 'ab' || '%' ESCAPE '^')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("email: \"_example.com\""),
+            Ok(Some("(email ILIKE '%' || '^_example.com' ESCAPE '^')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("nickname: ab"),
+            Ok(Some("(nickname ILIKE '%' || 'ab' || '%' ESCAPE '^')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres_params("username: ab"),
+            Ok(Some((
+                "(username ILIKE $1::TEXT || '%' ESCAPE '^')".into(),
+                vec![SqlValue::Text("ab".into())],
+            )))
+        );
+    }
+
+    #[test]
+    pub fn case_insensitive_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("username")
+                .wildcard()
+                .rename("\"user\".username")
+                .partial_equal()
+                .case_insensitive()
+            )
+            .field(FieldConfig::new("nickname")
+                .partial_equal()
+                .use_like()
+                .case_insensitive()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("username: Alice"),
+            Ok(Some("(LOWER(\"user\".username) = LOWER('Alice'))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* in (Alice, Bob)"),
+            Ok(Some("(LOWER(\"user\".username) IN (LOWER('Alice'),LOWER('Bob')))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("nickname: Alice"),
+            Ok(Some("(nickname ILIKE '%' || 'Alice' || '%' ESCAPE '^')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres_params("username: Alice"),
+            Ok(Some((
+                "(LOWER(\"user\".username) = LOWER($1))".into(),
+                vec![SqlValue::Text("Alice".into())],
+            )))
+        );
+    }
+
+    #[test]
+    pub fn regex_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("username")
+                .wildcard()
+                .rename("\"user\".username")
+                .regex()
+            )
+            .field(FieldConfig::new("email")
+                .wildcard()
+                .partial_equal()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("username ~ \"^admin'\""),
+            Ok(Some("(\"user\".username ~ '^admin''')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* ~* ^admin"),
+            Ok(Some("(\"user\".username ~* '^admin')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("email ~ ^admin"),
+            Err(Error::UnsupportedOperation {
+                field: "email".into(),
+                required_operation: "regex".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn not_equal_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("id")
+                .wildcard()
+                .partial_equal()
+                .escape_handler(escape_value::<i32>())
+            )
+            .field(FieldConfig::new("username")
+                .rename("\"user\".username")
+                .partial_equal()
+                .case_insensitive()
+            )
+            .field(FieldConfig::new("nickname")
+                .partial_equal()
+                .use_like()
+            )
+            .field(FieldConfig::new("created_at")
+                .partial_order()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("username != Alice and nickname not: al"),
+            Ok(Some("((LOWER(\"user\".username) <> LOWER('Alice')) AND \
+                (NOT (nickname ILIKE '%' || 'al' || '%' ESCAPE '^')))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* != 1"),
+            Ok(Some("(id <> 1)".into()))
+        );
+        let result = QueryConfig::new()
+            .field(FieldConfig::new("id").wildcard().partial_equal())
+            .field(FieldConfig::new("text").wildcard().partial_equal())
+            .parse_to_postgres("* != 1");
+        assert!(
+            result == Ok(Some("(id <> '1' AND text <> '1')".into()))
+            || result == Ok(Some("(text <> '1' AND id <> '1')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("created_at != 1"),
+            Err(Error::UnsupportedOperation {
+                field: "created_at".into(),
+                required_operation: "equal".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn columns_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("name")
+                .columns(vec!["username".into(), "nickname".into()])
+                .partial_equal()
+                .partial_order()
+                .use_like()
+                .sortable()
+            )
+            .field(FieldConfig::new("login")
+                .columns(vec!["username".into(), "email".into()])
+                .wildcard()
+                .partial_equal()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("name: al"),
+            Ok(Some("(username ILIKE '%' || 'al' || '%' ESCAPE '^' OR \
+                nickname ILIKE '%' || 'al' || '%' ESCAPE '^')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("name > al"),
+            Ok(Some("(username > 'al' OR nickname > 'al')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("login: alice"),
+            Ok(Some("(username = 'alice' OR email = 'alice')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("login != alice"),
+            Ok(Some("(username <> 'alice' AND email <> 'alice')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres_params("* : alice"),
+            Ok(Some((
+                "(username = $1 OR email = $1)".into(),
+                vec![SqlValue::Text("alice".into())],
+            )))
+        );
+        assert_eq!(
+            generator.parse_order_by("name desc"),
+            Ok(Some("username DESC, nickname DESC".into()))
+        );
+    }
+
+    #[test]
+    pub fn jsonb_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("prefs")
+                .rename("\"user\".prefs")
+                .jsonb()
+                .partial_equal()
+                .nullable()
+            )
+            .field(FieldConfig::new("username")
+                .partial_equal()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("prefs.theme: dark"),
+            Ok(Some("(\"user\".prefs ->> 'theme' = 'dark')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("prefs.ui.theme: \"it's dark\""),
+            Ok(Some("(\"user\".prefs -> 'ui' ->> 'theme' = 'it''s dark')".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("prefs.ui.theme: null"),
+            Ok(Some("(\"user\".prefs -> 'ui' ->> 'theme' IS NULL)".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("settings.theme: dark"),
+            Err(Error::UnknownField {
+                field: "settings.theme".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("username.theme: dark"),
+            Err(Error::UnknownField {
+                field: "username.theme".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn array_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("roles")
+                .wildcard()
+                .partial_equal()
+                .array()
+            )
+            .field(FieldConfig::new("groups")
+                .partial_equal()
+                .array()
+                .escape_handler(escape_value::<i32>())
+            )
+            .field(FieldConfig::new("username")
+                .partial_equal()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("roles: admin"),
+            Ok(Some("('admin' = ANY(roles))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("roles != admin"),
+            Ok(Some("(NOT ('admin' = ANY(roles)))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("roles @> [admin, \"o'neil\"] and groups @> [1, 2]"),
+            Ok(Some("((roles @> ARRAY['admin','o''neil']) AND (groups @> ARRAY[1,2]))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("* @> [admin]"),
+            Ok(Some("(roles @> ARRAY['admin'])".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("groups @> [a]"),
+            Err(Error::InvalidValue {
+                field: "groups".into(),
+                accepted_type: "i32".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("username @> [alice]"),
+            Err(Error::UnsupportedOperation {
+                field: "username".into(),
+                required_operation: "contains".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn full_text_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("bio")
+                .full_text("english")
+            )
+            .field(FieldConfig::new("title")
+                .wildcard()
+                .full_text("simple")
+            );
+        assert_eq!(
+            generator.parse_to_postgres("bio: \"hello world\""),
+            Ok(Some("(to_tsvector('english', bio) @@ plainto_tsquery('english', 'hello world'))"
+                .into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("bio: \"it's & !me\""),
+            Ok(Some("(to_tsvector('english', bio) @@ plainto_tsquery('english', 'it''s & !me'))"
+                .into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("hello"),
+            Ok(Some("(to_tsvector('simple', title) @@ plainto_tsquery('simple', 'hello'))"
+                .into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres_params("bio != hello"),
+            Ok(Some((
+                "(NOT (to_tsvector('english', bio) @@ plainto_tsquery('english', $1)))".into(),
+                vec![SqlValue::Text("hello".into())],
+            )))
+        );
+    }
+
+    #[test]
+    pub fn complexity_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("id")
+                .partial_equal()
+                .escape_handler(escape_value::<i32>())
+            )
+            .max_clauses(4)
+            .max_depth(3);
+        assert_eq!(
+            generator.parse_to_postgres("id: 1 or id: 2 or id: 3"),
+            Ok(Some("((id = 1) OR (id = 2) OR (id = 3))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("id: 1 or id: 2 or id: 3 or id: 4"),
+            Err(Error::QueryTooComplex { limit: 4 })
+        );
+        assert_eq!(
+            generator.parse_to_postgres_params("id: 1 or id: 2 or id: 3 or id: 4"),
+            Err(Error::QueryTooComplex { limit: 4 })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("not (not (not id: 1))"),
+            Err(Error::QueryTooComplex { limit: 3 })
+        );
+    }
+
+    #[test]
+    pub fn parse_error_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("username")
+                .partial_equal()
+            );
+        assert_eq!(
+            generator.parse_to_postgres("username: a\n  \"abc"),
+            Err(Error::ParseError { pos: 14, line: 2, column: 3 })
+        );
+        assert_eq!(
+            generator.parse_order_by("username,\nusername !"),
+            Err(Error::ParseError { pos: 19, line: 2, column: 10 })
+        );
+    }
+
+    #[test]
+    pub fn referenced_fields_test() {
+        let generator = QueryConfig::new()
+            .field(FieldConfig::new("username")
+                .wildcard()
+                .partial_equal()
+            )
+            .field(FieldConfig::new("nickname")
+                .wildcard()
+                .partial_equal()
+            )
+            .field(FieldConfig::new("email")
+                .partial_equal()
+            )
+            .field(FieldConfig::new("prefs")
+                .jsonb()
+            );
+        let fields = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<HashSet<_>>();
+        assert_eq!(
+            generator.referenced_fields("email: a and not (prefs.theme: dark or b)"),
+            Ok(fields(&["email", "prefs", "username", "nickname"]))
+        );
+        assert_eq!(generator.referenced_fields("email != null"), Ok(fields(&["email"])));
+        assert_eq!(generator.referenced_fields(" "), Ok(fields(&[])));
+        assert_eq!(
+            generator.referenced_fields("username: a and password: b"),
+            Err(Error::UnknownField {
+                field: "password".into(),
+            })
+        );
+    }
+
+    #[test]
+    pub fn merge_test() {
+        let base = QueryConfig::new()
+            .field(FieldConfig::new("id")
+                .partial_equal()
+                .escape_handler(escape_value::<i32>())
+            )
+            .field(FieldConfig::new("username")
+                .partial_equal()
+            )
+            .max_clauses(8);
+        let generator = base
+            .merge(QueryConfig::new()
+                .field(FieldConfig::new("username")
+                    .rename("name")
+                    .partial_equal()
+                )
+            )
+            .extend(vec![
+                FieldConfig::new("email")
+                    .partial_equal(),
+                FieldConfig::new("id")
+                    .partial_order()
+                    .escape_handler(escape_value::<i32>()),
+            ]);
+        assert_eq!(
+            generator.parse_to_postgres("username: alice and email: a and id > 1"),
+            Ok(Some("((name = 'alice') AND (email = 'a') AND (id > 1))".into()))
+        );
+        assert_eq!(
+            generator.parse_to_postgres("id: 1"),
+            Err(Error::UnsupportedOperation {
+                field: "id".into(),
+                required_operation: "equal".into(),
+            })
+        );
+        assert_eq!(
+            generator.parse_to_postgres("id > 1 or id > 2 or id > 3 or id > 4 or id > 5 or \
+                id > 6 or id > 7 or id > 8"),
+            Err(Error::QueryTooComplex { limit: 8 })
+        );
+    }
 }
 
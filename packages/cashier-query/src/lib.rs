@@ -1,2 +1,6 @@
+// err-derive puts its impls in named consts, which newer compilers warn about
+#![allow(non_local_definitions)]
+
 pub mod query;
 pub mod generator;
+pub mod value;
@@ -4,7 +4,7 @@ use nom::sequence::{preceded, terminated, pair, tuple};
 use nom::character::complete::{multispace0, multispace1};
 use nom::bytes::complete::{tag_no_case, tag, take};
 use nom::branch::alt;
-use nom::multi::{separated_nonempty_list, many0, many1};
+use nom::multi::{separated_list, separated_nonempty_list, many0, many1};
 
 // modified from https://github.com/elastic/kibana/blob/master/src/plugins/data/common/es_query/kuery/ast/kuery.peg
 
@@ -16,6 +16,12 @@ pub enum OrderOperator {
     Gt,
 }
 
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Query {
     Or {
@@ -31,11 +37,37 @@ pub enum Query {
         field: Option<String>,
         value: String,
     },
+    NotEqual {
+        field: Option<String>,
+        value: String,
+    },
     Order {
         field: Option<String>,
         operator: OrderOperator,
         value: String,
     },
+    Range {
+        field: Option<String>,
+        low: String,
+        high: String,
+    },
+    In {
+        field: Option<String>,
+        values: Vec<String>,
+    },
+    Regex {
+        field: Option<String>,
+        value: String,
+        case_insensitive: bool,
+    },
+    Contains {
+        field: Option<String>,
+        values: Vec<String>,
+    },
+    Null {
+        field: Option<String>,
+        negated: bool,
+    },
 }
 
 pub fn parse_range_operator(input: &str) -> IResult<&str, OrderOperator> {
@@ -69,6 +101,28 @@ pub fn parse_and(input: &str) -> IResult<&str, &str> {
     )(input)
 }
 
+// Between = Space+ 'between'i Space+
+pub fn parse_between(input: &str) -> IResult<&str, &str> {
+    preceded(
+        multispace1,
+        terminated(
+            tag_no_case("between"),
+            multispace1,
+        )
+    )(input)
+}
+
+// In = Space+ 'in'i Space*
+pub fn parse_in(input: &str) -> IResult<&str, &str> {
+    preceded(
+        multispace1,
+        terminated(
+            tag_no_case("in"),
+            multispace0,
+        )
+    )(input)
+}
+
 // Not = 'not'i Space+
 pub fn parse_not(input: &str) -> IResult<&str, &str> {
     terminated(
@@ -77,6 +131,14 @@ pub fn parse_not(input: &str) -> IResult<&str, &str> {
     )(input)
 }
 
+// Null = 'null'i !UnquotedCharacter
+pub fn parse_null(input: &str) -> IResult<&str, &str> {
+    terminated(
+        tag_no_case("null"),
+        not(parse_unquoted_character),
+    )(input)
+}
+
 // EscapedWhitespace = '\\t'
 //                   | '\\r'
 //                   | '\\n'
@@ -88,7 +150,7 @@ pub fn parse_escaped_whitespace(input: &str) -> IResult<&str, &str> {
     ))(input)
 }
 
-// UnquotedCharacter = [^\\():<>"@* \t\r\n]
+// UnquotedCharacter = [^\\():<>"@*!=~\[\], \t\r\n]
 pub fn parse_unquoted_character(input: &str) -> IResult<&str, &str> {
     preceded(
         not(alt((
@@ -101,6 +163,12 @@ pub fn parse_unquoted_character(input: &str) -> IResult<&str, &str> {
             tag("\""),
             tag("@"),
             tag("*"),
+            tag("!"),
+            tag("="),
+            tag("~"),
+            tag("["),
+            tag("]"),
+            tag(","),
             tag(" "),
             tag("\t"),
             tag("\r"),
@@ -149,6 +217,33 @@ pub fn parse_quoted_string(input: &str) -> IResult<&str, String> {
     ), |chars| chars.join(""))(input)
 }
 
+// UnquotedElement = UnquotedCharacter+
+pub fn parse_unquoted_element(input: &str) -> IResult<&str, String> {
+    map(many1(parse_unquoted_character), |chars| chars.join(""))(input)
+}
+
+// Element = QuotedString | UnquotedElement
+pub fn parse_element(input: &str) -> IResult<&str, String> {
+    alt((
+        parse_quoted_string,
+        parse_unquoted_element,
+    ))(input)
+}
+
+// ListOfElements = Space* (Element (Space* ',' Space* Element)*)? Space*
+pub fn parse_list_of_elements(input: &str) -> IResult<&str, Vec<String>> {
+    preceded(
+        multispace0,
+        terminated(
+            separated_list(
+                tuple((multispace0, tag(","), multispace0)),
+                parse_element,
+            ),
+            multispace0,
+        ),
+    )(input)
+}
+
 // Literal = QuotedString | UnquotedLiteral
 pub fn parse_literal(input: &str) -> IResult<&str, String> {
     alt((
@@ -208,6 +303,7 @@ pub fn parse_or_list_of_values(input: &str) -> IResult<&str, QueryNeedsField> {
 }
 
 // ListOfValues = '(' Space* OrListOfValues Space* ')'
+//              | Null
 //              | Literal
 pub fn parse_list_of_values(input: &str) -> IResult<&str, QueryNeedsField> {
     alt((
@@ -217,7 +313,9 @@ pub fn parse_list_of_values(input: &str) -> IResult<&str, QueryNeedsField> {
                 parse_or_list_of_values,
                 pair(multispace0, tag(")")),
             )
-        ), |func| Box::new(|field| func(field))  as QueryNeedsField),
+        ), |func| Box::new(func)  as QueryNeedsField),
+        map(parse_null,
+            |_| Box::new(|field| Query::Null {field, negated: false}) as QueryNeedsField),
         map(parse_literal,
             |value| Box::new(|field| Query::Equal {field, value}) as QueryNeedsField),
     ))(input)
@@ -256,11 +354,122 @@ pub fn parse_field_range_expression(input: &str) -> IResult<&str, Query> {
     })(input)
 }
 
-// Expression = FieldRangeExpression
+// FieldNotNullExpression = WildcardLiteral Space* '!=' Space* Null
+pub fn parse_field_not_null_expression(input: &str) -> IResult<&str, Query> {
+    map(terminated(
+        parse_wildcard_literal,
+        tuple((multispace0, tag("!="), multispace0, parse_null)),
+    ), |field| Query::Null { field, negated: true })(input)
+}
+
+// FieldBetweenExpression = WildcardLiteral Between Literal And Literal
+pub fn parse_field_between_expression(input: &str) -> IResult<&str, Query> {
+    map(tuple((
+        parse_wildcard_literal,
+        preceded(
+            parse_between,
+            parse_literal,
+        ),
+        preceded(
+            parse_and,
+            parse_literal,
+        ),
+    )), |(field, low, high)| Query::Range {
+        field, low, high,
+    })(input)
+}
+
+// FieldInExpression = WildcardLiteral Space* ':' Space* '[' ListOfElements ']'
+//                   | WildcardLiteral In '(' ListOfElements ')'
+pub fn parse_field_in_expression(input: &str) -> IResult<&str, Query> {
+    map(pair(
+        parse_wildcard_literal,
+        alt((
+            preceded(
+                tuple((multispace0, tag(":"), multispace0, tag("["))),
+                terminated(parse_list_of_elements, tag("]")),
+            ),
+            preceded(
+                pair(parse_in, tag("(")),
+                terminated(parse_list_of_elements, tag(")")),
+            ),
+        )),
+    ), |(field, values)| Query::In { field, values })(input)
+}
+
+// RegexOperator = '~*' | '~'
+pub fn parse_regex_operator(input: &str) -> IResult<&str, bool> {
+    alt((
+        value(true, tag("~*")),
+        value(false, tag("~")),
+    ))(input)
+}
+
+// FieldContainsExpression = WildcardLiteral Space* '@>' Space* '[' ListOfElements ']'
+pub fn parse_field_contains_expression(input: &str) -> IResult<&str, Query> {
+    map(pair(
+        parse_wildcard_literal,
+        preceded(
+            tuple((multispace0, tag("@>"), multispace0, tag("["))),
+            terminated(parse_list_of_elements, tag("]")),
+        ),
+    ), |(field, values)| Query::Contains { field, values })(input)
+}
+
+// FieldRegexExpression = WildcardLiteral Space* RegexOperator Space* Literal
+pub fn parse_field_regex_expression(input: &str) -> IResult<&str, Query> {
+    map(tuple((
+        parse_wildcard_literal,
+        preceded(
+            multispace0,
+            parse_regex_operator,
+        ),
+        preceded(
+            multispace0,
+            parse_literal,
+        )
+    )), |(field, case_insensitive, value)| Query::Regex {
+        field, value, case_insensitive,
+    })(input)
+}
+
+// NotEqualOperator = Space* '!='
+//                  | Space+ 'not'i Space* ':'
+pub fn parse_not_equal_operator(input: &str) -> IResult<&str, ()> {
+    alt((
+        value((), pair(multispace0, tag("!="))),
+        value((), tuple((multispace1, tag_no_case("not"), multispace0, tag(":")))),
+    ))(input)
+}
+
+// FieldNotEqualExpression = WildcardLiteral NotEqualOperator Space* Literal
+pub fn parse_field_not_equal_expression(input: &str) -> IResult<&str, Query> {
+    map(pair(
+        parse_wildcard_literal,
+        preceded(
+            pair(parse_not_equal_operator, multispace0),
+            parse_literal,
+        ),
+    ), |(field, value)| Query::NotEqual { field, value })(input)
+}
+
+// Expression = FieldNotNullExpression
+//            | FieldNotEqualExpression
+//            | FieldBetweenExpression
+//            | FieldInExpression
+//            | FieldContainsExpression
+//            | FieldRegexExpression
+//            | FieldRangeExpression
 //            | FieldValueExpression
 //            | ValueExpression
 pub fn parse_expression(input: &str) -> IResult<&str, Query> {
     alt((
+        parse_field_not_null_expression,
+        parse_field_not_equal_expression,
+        parse_field_between_expression,
+        parse_field_in_expression,
+        parse_field_contains_expression,
+        parse_field_regex_expression,
         parse_field_range_expression,
         parse_field_value_expression,
         parse_value_expression,
@@ -327,6 +536,43 @@ pub fn parse(input: &str) -> IResult<&str, Option<Query>> {
     ))(input)
 }
 
+// SortDirection = 'asc'i !UnquotedElement
+//               | 'desc'i !UnquotedElement
+pub fn parse_sort_direction(input: &str) -> IResult<&str, SortDirection> {
+    terminated(
+        alt((
+            value(SortDirection::Asc, tag_no_case("asc")),
+            value(SortDirection::Desc, tag_no_case("desc")),
+        )),
+        not(parse_unquoted_element),
+    )(input)
+}
+
+// SortField = Element (Space+ SortDirection)?
+pub fn parse_sort_field(input: &str) -> IResult<&str, (String, SortDirection)> {
+    map(pair(
+        parse_element,
+        opt(preceded(
+            multispace1,
+            parse_sort_direction,
+        )),
+    ), |(field, direction)| (field, direction.unwrap_or(SortDirection::Asc)))(input)
+}
+
+// OrderBy = Space* (SortField (Space* ',' Space* SortField)*)? Space*
+pub fn parse_order_by(input: &str) -> IResult<&str, Vec<(String, SortDirection)>> {
+    all_consuming(preceded(
+        multispace0,
+        terminated(
+            separated_list(
+                tuple((multispace0, tag(","), multispace0)),
+                parse_sort_field,
+            ),
+            multispace0,
+        ),
+    ))(input)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -510,4 +756,206 @@ mod tests {
             })))
         );
     }
+
+    #[test]
+    fn parse_field_null_expression() {
+        assert_eq!(
+            parse(" a : null b != NULL"),
+            Ok(("", Some(Query::Or {
+                queries: vec![
+                    Query::Null { field: Some("a".into()), negated: false },
+                    Query::Null { field: Some("b".into()), negated: true },
+                ],
+            }))),
+        );
+        assert_eq!(
+            parse(" * != null"),
+            Ok(("", Some(Query::Null { field: None, negated: true }))),
+        );
+    }
+
+    #[test]
+    fn parse_null_literal() {
+        assert_eq!(
+            parse(" a:\"null\" a:nullable null"),
+            Ok(("", Some(Query::Or {
+                queries: vec![
+                    Query::Equal { field: Some("a".into()), value: "null".into() },
+                    Query::Equal { field: Some("a".into()), value: "nullable".into() },
+                    Query::Equal { field: None, value: "null".into() },
+                ],
+            }))),
+        );
+    }
+
+    #[test]
+    fn parse_field_between_expression() {
+        assert_eq!(
+            parse(" a between 1 and \"2 3\" and b:4"),
+            Ok(("", Some(Query::And {
+                queries: vec![
+                    Query::Range {
+                        field: Some("a".into()),
+                        low: "1".into(),
+                        high: "2 3".into(),
+                    },
+                    Query::Equal { field: Some("b".into()), value: "4".into() },
+                ],
+            }))),
+        );
+        assert_eq!(
+            parse(" * BETWEEN 1 AND 2"),
+            Ok(("", Some(Query::Range {
+                field: None,
+                low: "1".into(),
+                high: "2".into(),
+            }))),
+        );
+    }
+
+    #[test]
+    fn parse_field_in_expression() {
+        assert_eq!(
+            parse(" id: [1, 2,3] or username in ( alice , \"b,ob\" )"),
+            Ok(("", Some(Query::Or {
+                queries: vec![
+                    Query::In {
+                        field: Some("id".into()),
+                        values: vec!["1".into(), "2".into(), "3".into()],
+                    },
+                    Query::In {
+                        field: Some("username".into()),
+                        values: vec!["alice".into(), "b,ob".into()],
+                    },
+                ],
+            }))),
+        );
+        assert_eq!(
+            parse(" * : [ ]"),
+            Ok(("", Some(Query::In { field: None, values: vec![] }))),
+        );
+        assert_eq!(
+            parse(" a in b"),
+            Ok(("", Some(Query::Or {
+                queries: vec![
+                    Query::Equal { field: None, value: "a".into() },
+                    Query::Equal { field: None, value: "in".into() },
+                    Query::Equal { field: None, value: "b".into() },
+                ],
+            }))),
+        );
+    }
+
+    #[test]
+    fn parse_order_by_fields() {
+        assert_eq!(
+            parse_order_by(" created_at DESC,username asc , id "),
+            Ok(("", vec![
+                ("created_at".into(), SortDirection::Desc),
+                ("username".into(), SortDirection::Asc),
+                ("id".into(), SortDirection::Asc),
+            ])),
+        );
+        assert_eq!(parse_order_by("  "), Ok(("", vec![])));
+        assert_eq!(
+            parse_order_by("id ascending"),
+            Err(Err::Error(("ascending", ErrorKind::Eof)))
+        );
+    }
+
+    #[test]
+    fn parse_field_regex_expression() {
+        assert_eq!(
+            parse(" username ~ ^admin or * ~* \"^a(b|c)$\""),
+            Ok(("", Some(Query::Or {
+                queries: vec![
+                    Query::Regex {
+                        field: Some("username".into()),
+                        value: "^admin".into(),
+                        case_insensitive: false,
+                    },
+                    Query::Regex {
+                        field: None,
+                        value: "^a(b|c)$".into(),
+                        case_insensitive: true,
+                    },
+                ],
+            }))),
+        );
+        assert_eq!(
+            parse(" username~^admin"),
+            Ok(("", Some(Query::Regex {
+                field: Some("username".into()),
+                value: "^admin".into(),
+                case_insensitive: false,
+            }))),
+        );
+    }
+
+    #[test]
+    fn parse_field_not_equal_expression() {
+        assert_eq!(
+            parse(" a != 1 b NOT: 2 * != null"),
+            Ok(("", Some(Query::Or {
+                queries: vec![
+                    Query::NotEqual { field: Some("a".into()), value: "1".into() },
+                    Query::NotEqual { field: Some("b".into()), value: "2".into() },
+                    Query::Null { field: None, negated: true },
+                ],
+            }))),
+        );
+        assert_eq!(
+            parse(" not a: 1"),
+            Ok(("", Some(Query::Not {
+                query: Box::new(Query::Equal { field: Some("a".into()), value: "1".into() }),
+            }))),
+        );
+    }
+
+    #[test]
+    fn parse_dotted_field() {
+        assert_eq!(
+            parse(" prefs.ui.theme: dark"),
+            Ok(("", Some(Query::Equal {
+                field: Some("prefs.ui.theme".into()),
+                value: "dark".into(),
+            }))),
+        );
+    }
+
+    #[test]
+    fn parse_field_contains_expression() {
+        assert_eq!(
+            parse(" roles @> [admin, \"user\"]"),
+            Ok(("", Some(Query::Contains {
+                field: Some("roles".into()),
+                values: vec!["admin".into(), "user".into()],
+            }))),
+        );
+    }
+
+    #[test]
+    fn parse_unspaced_operators() {
+        assert_eq!(
+            parse(" a!=1 b!=null id:[1,2] roles@>[admin,user]"),
+            Ok(("", Some(Query::Or {
+                queries: vec![
+                    Query::NotEqual { field: Some("a".into()), value: "1".into() },
+                    Query::Null { field: Some("b".into()), negated: true },
+                    Query::In {
+                        field: Some("id".into()),
+                        values: vec!["1".into(), "2".into()],
+                    },
+                    Query::Contains {
+                        field: Some("roles".into()),
+                        values: vec!["admin".into(), "user".into()],
+                    },
+                ],
+            }))),
+        );
+        assert_eq!(
+            parse(" a=1"),
+            Err(Err::Error(("=1", ErrorKind::Eof)))
+        );
+    }
 }
@@ -0,0 +1,94 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+// A value bound to a placeholder by QueryConfig::parse_to_postgres_params. With the postgres
+// feature, it can be passed to tokio_postgres directly
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Bool(bool),
+    Int4(i32),
+    Int8(i64),
+    Float8(f64),
+    Text(String),
+    Timestamp(DateTime<Utc>),
+    Uuid(Uuid),
+}
+
+impl From<bool> for SqlValue {
+    fn from(value: bool) -> Self {
+        SqlValue::Bool(value)
+    }
+}
+
+impl From<i32> for SqlValue {
+    fn from(value: i32) -> Self {
+        SqlValue::Int4(value)
+    }
+}
+
+impl From<i64> for SqlValue {
+    fn from(value: i64) -> Self {
+        SqlValue::Int8(value)
+    }
+}
+
+impl From<f64> for SqlValue {
+    fn from(value: f64) -> Self {
+        SqlValue::Float8(value)
+    }
+}
+
+impl From<String> for SqlValue {
+    fn from(value: String) -> Self {
+        SqlValue::Text(value)
+    }
+}
+
+impl From<DateTime<Utc>> for SqlValue {
+    fn from(value: DateTime<Utc>) -> Self {
+        SqlValue::Timestamp(value)
+    }
+}
+
+impl From<Uuid> for SqlValue {
+    fn from(value: Uuid) -> Self {
+        SqlValue::Uuid(value)
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use super::SqlValue;
+    use bytes::BytesMut;
+    use chrono::{DateTime, Utc};
+    use postgres_types::{IsNull, ToSql, Type, to_sql_checked};
+    use std::error::Error;
+    use uuid::Uuid;
+
+    // Each variant checks the type of its placeholder on its own, so that e.g. an Int4 is not
+    // written to an INT8 column
+    impl ToSql for SqlValue {
+        fn to_sql(
+            &self, ty: &Type, out: &mut BytesMut,
+        ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            match self {
+                SqlValue::Bool(value) => value.to_sql_checked(ty, out),
+                SqlValue::Int4(value) => value.to_sql_checked(ty, out),
+                SqlValue::Int8(value) => value.to_sql_checked(ty, out),
+                SqlValue::Float8(value) => value.to_sql_checked(ty, out),
+                SqlValue::Text(value) => value.to_sql_checked(ty, out),
+                SqlValue::Timestamp(value) => value.to_sql_checked(ty, out),
+                SqlValue::Uuid(value) => value.to_sql_checked(ty, out),
+            }
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            <bool as ToSql>::accepts(ty) || <i32 as ToSql>::accepts(ty)
+                || <i64 as ToSql>::accepts(ty) || <f64 as ToSql>::accepts(ty)
+                || <String as ToSql>::accepts(ty) || <DateTime<Utc> as ToSql>::accepts(ty)
+                || <Uuid as ToSql>::accepts(ty)
+        }
+
+        to_sql_checked!();
+    }
+}
@@ -1,27 +1,111 @@
 use crate::{
     config::StartConfig,
-    queries::Query,
+    queries::{
+        email::EmailQueue,
+        pool::{Pool, PooledConnection},
+    },
     websocket::{
+        client_subscriber::CloseSession,
         main_subscriber::MainSubscriber,
         push_messages::{InternalMessage, InnerInternalMessage}
     },
-    api::extractors::auth::Auth,
+    api::{
+        avatar_store::AvatarStore, errors::ApiError, extractors::auth::Auth,
+        login_limiter::LoginLimiter, metrics::Metrics, permission_cache::PermissionCache,
+    },
 };
-use actix::{Addr, MailboxError};
+use actix::{Addr, MailboxError, Recipient};
 use chrono::Utc;
-use lettre::SmtpTransport;
-use std::result::Result;
-use tokio::sync::RwLock;
+use log::{error, warn};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    result::Result,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Semaphore;
+
+// Follows SmtpMode of the config
+pub enum Mailer {
+    // Sending through SMTP, or kept by the transport of tests
+    Queue(EmailQueue),
+    Log {
+        // So that tests and local tools can pick up the code without reading the log
+        last_code: Mutex<Option<String>>,
+    },
+    Disabled,
+}
+
+impl Mailer {
+    pub fn log() -> Self {
+        Mailer::Log {
+            last_code: Mutex::new(None),
+        }
+    }
+    pub fn last_code(&self) -> Option<String> {
+        match self {
+            Mailer::Log { last_code } => last_code.lock().unwrap().clone(),
+            _ => None,
+        }
+    }
+}
+
+// Background processing of the latest avatar uploaded for a user, see MediaConfig::avatar_async
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status")]
+pub enum AvatarProcessing {
+    Idle,
+    Processing {
+        provisional: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+// Latest batch resend started by POST /api/v1/admin/resend-pending
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResendProgress {
+    pub total: usize,
+    pub sent: usize,
+    // Sent within SmtpConfig::resend_interval
+    pub skipped: usize,
+    pub failed: usize,
+    pub finished: bool,
+}
 
 pub struct AppState {
     pub config: StartConfig,
-    pub db: RwLock<tokio_postgres::Client>,
-    pub query: Query,
+    pub pool: Pool,
     pub subscriber: Addr<MainSubscriber>,
-    pub smtp: SmtpTransport,
+    pub mailer: Mailer,
+    // The JWT secret, loaded once for signing avatar URLs, see MediaConfig::avatar_url_ttl
+    pub media_secret: Vec<u8>,
+    pub avatar_store: Box<dyn AvatarStore>,
+    // Users without an entry are idle. Only known to the instance which received the upload, so
+    // with more than one instance a status poll may reach another one and read Idle meanwhile.
+    // The avatar of the user, which stays the provisional one until processed, tells either way
+    pub avatar_processing: Mutex<HashMap<i32, AvatarProcessing>>,
+    // A permit for each avatar waiting for or under background processing, see
+    // AVATAR_PROCESSING_CAPACITY
+    pub avatar_jobs: Arc<Semaphore>,
+    pub resend_progress: Mutex<Option<ResendProgress>>,
+    pub login_limiter: LoginLimiter,
+    pub permission_cache: PermissionCache,
+    pub metrics: Metrics,
+    // Every open websocket, so that a shutdown can close them
+    pub websocket_sessions: Mutex<HashSet<Recipient<CloseSession>>>,
 }
 
 impl AppState {
+    // A connection of the pool with its statements, held until dropped
+    pub async fn db(&self) -> Result<PooledConnection<'_>, ApiError> {
+        self.pool.get().await.map_err(|e| {
+            error!("cannot get a database connection: {}", e);
+            ApiError::DatabaseUnavailable
+        })
+    }
     pub async fn send<T: Into<InnerInternalMessage>>(
         &self, message: T, auth: &Auth
     ) -> Result<(), MailboxError> {
@@ -30,6 +114,10 @@ impl AppState {
     pub async fn send_all(
         &self, messages: Vec<InnerInternalMessage>, auth: &Auth
     ) -> Result<(), MailboxError> {
+        // Changes of users, roles and permissions are announced through here
+        for message in &messages {
+            self.permission_cache.apply(message);
+        }
         let (sender_uid, sender_jti) = auth.claims.as_ref()
             .map(|claims| (Some(claims.uid), Some(claims.jti)))
             .unwrap_or_else(|| (None, None));
@@ -43,4 +131,14 @@ impl AppState {
             .await
             .map(|_| ())
     }
+    // Asks every open websocket to send a close frame and stop, returns how many were open
+    pub fn close_websocket_sessions(&self) -> usize {
+        let sessions = std::mem::take(&mut *self.websocket_sessions.lock().unwrap());
+        for session in sessions.iter() {
+            if let Err(e) = session.do_send(CloseSession) {
+                warn!("failed to close websocket session: {}", e);
+            }
+        }
+        sessions.len()
+    }
 }
\ No newline at end of file
@@ -3,8 +3,10 @@ use actix_web::{
     error::ResponseError,
     HttpResponse,
 };
+use chrono::{DateTime, Utc};
 use err_derive::Error;
 use serde::{Serialize};
+use std::iter::FromIterator;
 use validator::{ValidationErrors, ValidationErrorsKind};
 
 #[derive(Debug, Serialize, Clone)]
@@ -14,6 +16,16 @@ pub struct ValidationError {
     message: Option<String>,
 }
 
+impl ValidationError {
+    pub fn new(field: &str, code: &str, message: Option<String>) -> Self {
+        Self {
+            field: field.into(),
+            code: code.into(),
+            message,
+        }
+    }
+}
+
 #[derive(Debug, Error, Serialize, Clone)]
 #[serde(tag = "type")]
 pub enum ApiError {
@@ -55,6 +67,8 @@ pub enum ApiError {
     },
     #[error(display = "missing authorization header")]
     MissingAuthorizationHeader,
+    #[error(display = "not logged in")]
+    NotAuthenticated,
     #[error(display = "attempt to create a user with more roles than creator's")]
     AttemptToElevateRole {
         roles: Vec<String>,
@@ -76,13 +90,88 @@ pub enum ApiError {
     #[error(display = "user registration {}", reason)]
     UserRegistration {
         reason: String,
+        // Set along with the Expired reason, whose registration may be started over right away
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<DateTime<Utc>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hint: Option<String>,
     },
     #[error(display = "user email updating {}", reason)]
     UserEmailUpdating {
         reason: String,
     },
+    #[error(display = "TOTP code required")]
+    TotpRequired,
+    #[error(display = "wrong TOTP code")]
+    WrongTotpCode,
+    #[error(display = "TOTP {}", reason)]
+    Totp {
+        reason: String,
+    },
+    #[error(display = "refresh token {}", reason)]
+    InvalidRefreshToken {
+        reason: String,
+    },
+    #[error(display = "password reset {}", reason)]
+    PasswordReset {
+        reason: String,
+    },
     #[error(display = "cannot find the token")]
     TokenNotFound,
+    #[error(display = "cannot find the role")]
+    RoleNotFound,
+    #[error(display = "duplicated role with same name")]
+    DuplicatedRole,
+    #[error(display = "role would inherit from itself")]
+    RoleCycle,
+    #[error(display = "cannot find the permission {}:{}", subject, action)]
+    PermissionNotFound {
+        subject: String,
+        action: String,
+    },
+    #[error(display = "cannot find the permissions {:?}", ids)]
+    PermissionsNotFound {
+        ids: Vec<i32>,
+    },
+    #[error(display = "seed document references unknown roles or permissions")]
+    InvalidSeedReference {
+        roles: Vec<String>,
+        permissions: Vec<String>,
+    },
+    #[error(display = "invalid or expired media signature")]
+    InvalidMediaSignature,
+    #[error(display = "cannot find the media")]
+    MediaNotFound,
+    #[error(display = "a batch resend is still in progress")]
+    ResendInProgress,
+    #[error(display = "e-mail is not configured on this server")]
+    EmailNotConfigured,
+    #[error(display = "too many e-mails are waiting to be sent, try again later")]
+    EmailQueueFull,
+    #[error(display = "too many avatars are being processed, try again later")]
+    AvatarQueueFull,
+    #[error(display = "the database is unavailable, try again later")]
+    DatabaseUnavailable,
+    #[error(display = "not allowed while impersonating a user")]
+    ForbiddenWhileImpersonating,
+    #[error(display = "too many failed logins, retry after {} seconds", retry_after)]
+    TooManyLoginAttempts {
+        retry_after: u64,
+    },
+    #[error(display = "the e-mail was sent recently, retry after {} seconds", retry_after)]
+    ResendTooSoon {
+        retry_after: u64,
+    },
+    #[error(display = "password found in a breached password list, choose another one")]
+    PasswordCompromised,
+    #[error(display = "nickname contains disallowed chars")]
+    DisallowedNicknameChars {
+        chars: Vec<String>,
+    },
+    #[error(display = "{}", error)]
+    InvalidFilter {
+        error: String,
+    },
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -95,12 +184,12 @@ pub struct PathValidationError {
 fn flatten_validation_errors(errors: &ValidationErrors) -> Vec<PathValidationError> {
     errors.errors()
         .iter()
-        .map(|(field, error_kind)| match error_kind {
+        .flat_map(|(field, error_kind)| match error_kind {
             ValidationErrorsKind::Field(errors) => errors.iter()
                 .map(move |error| PathValidationError {
                     fields: vec![(*field).into()],
-                    code: error.code.to_owned().into(),
-                    message: error.message.as_ref().map(|msg| msg.to_owned().into()),
+                    code: error.code.clone().into(),
+                    message: error.message.as_ref().map(|msg| msg.clone().into()),
                 })
                 .collect::<Vec<_>>(),
             ValidationErrorsKind::Struct(errors) =>
@@ -131,7 +220,6 @@ fn flatten_validation_errors(errors: &ValidationErrors) -> Vec<PathValidationErr
                 )
                 .collect::<Vec<_>>(),
         })
-        .flatten()
         .collect()
 }
 
@@ -157,21 +245,21 @@ impl From<ValidationErrors> for ApiError {
 #[macro_export]
 macro_rules! internal_server_error {
     () => {
-        crate::api::errors::ApiError::InternalServerError {
+        $crate::api::errors::ApiError::InternalServerError {
             file: file!(),
             line: line!(),
             message: None,
         }
     };
     ( &str: tt ) => {
-        crate::api::errors::ApiError::InternalServerError {
+        $crate::api::errors::ApiError::InternalServerError {
             file: file!(),
             line: line!(),
             message: Some($str.into()),
         }
     };
     ( $e: expr ) => {
-        crate::api::errors::ApiError::InternalServerError {
+        $crate::api::errors::ApiError::InternalServerError {
             file: file!(),
             line: line!(),
             message: Some(format!("{:?}", $e)),
@@ -179,8 +267,36 @@ macro_rules! internal_server_error {
     };
 }
 
+// Runs a read on a connection of the pool bound to $db, and once more on a new connection if
+// the first one was lost under it. Only for reads, as a write may have been committed before
+// the connection broke
+#[macro_export]
+macro_rules! retry_db {
+    ( $app_data: expr, | mut $db: ident | $body: expr ) => {
+        $crate::retry_db!(@retry $app_data, (mut $db), $db, $body)
+    };
+    ( $app_data: expr, | $db: ident | $body: expr ) => {
+        $crate::retry_db!(@retry $app_data, ($db), $db, $body)
+    };
+    ( @retry $app_data: expr, ( $($binding: tt)+ ), $db: ident, $body: expr ) => {{
+        let mut retried = false;
+        loop {
+            let $($binding)+ = $app_data.db().await?;
+            let result = $body;
+            match result {
+                Err(ref e) if !retried && (e.is_connection_error() || $db.client.is_closed()) => {
+                    log::warn!("database connection lost during a read, retrying: {}", e);
+                    $db.discard();
+                    retried = true;
+                }
+                result => break result,
+            }
+        }
+    }};
+}
+
 #[derive(Debug, Serialize)]
-struct ApiErrorWrapper {
+pub struct ApiErrorWrapper {
     code: u32,
     message: String,
     data: ApiError,
@@ -194,19 +310,44 @@ impl From<ApiError> for ApiErrorWrapper {
             ApiError::WrongUserOrPassword
             | ApiError::UserBlocked
             | ApiError::InvalidAuthorizationHeader
-            | ApiError::InvalidToken { .. } => 401,
+            | ApiError::InvalidToken { .. }
+            | ApiError::InvalidRefreshToken { .. }
+            | ApiError::TotpRequired
+            | ApiError::WrongTotpCode
+            | ApiError::NotAuthenticated => 401,
             ApiError::PermissionDenied { .. }
-            | ApiError::AttemptToElevateRole { .. } => 403,
+            | ApiError::AttemptToElevateRole { .. }
+            | ApiError::ForbiddenWhileImpersonating
+            | ApiError::InvalidMediaSignature => 403,
             ApiError::JsonPayloadError { .. }
             | ApiError::MultipartPayloadError { .. }
             | ApiError::ValidationError { .. }
             | ApiError::MissingAuthorizationHeader
             | ApiError::AvatarError{ .. }
             | ApiError::UserRegistration { .. }
-            | ApiError:: UserEmailUpdating { .. } => 400,
-            ApiError::DuplicatedUser { .. } => 409,
+            | ApiError:: UserEmailUpdating { .. }
+            | ApiError::PasswordReset { .. }
+            | ApiError::Totp { .. }
+            | ApiError::InvalidSeedReference { .. }
+            | ApiError::PasswordCompromised
+            | ApiError::DisallowedNicknameChars { .. }
+            | ApiError::InvalidFilter { .. } => 400,
+            ApiError::DuplicatedUser { .. }
+            | ApiError::DuplicatedRole
+            | ApiError::RoleCycle
+            | ApiError::ResendInProgress => 409,
             ApiError::UserNotFound
-            | ApiError::TokenNotFound => 404,
+            | ApiError::TokenNotFound
+            | ApiError::RoleNotFound
+            | ApiError::MediaNotFound
+            | ApiError::PermissionNotFound { .. }
+            | ApiError::PermissionsNotFound { .. } => 404,
+            ApiError::TooManyLoginAttempts { .. }
+            | ApiError::ResendTooSoon { .. } => 429,
+            ApiError::EmailNotConfigured
+            | ApiError::EmailQueueFull
+            | ApiError::AvatarQueueFull
+            | ApiError::DatabaseUnavailable => 503,
         };
         ApiErrorWrapper {
             code,
@@ -226,10 +367,16 @@ impl ResponseError for ApiError {
             ApiError::WrongUserOrPassword
             | ApiError::UserBlocked
             | ApiError::InvalidAuthorizationHeader
-            | ApiError::InvalidToken { .. }=>
+            | ApiError::InvalidToken { .. }
+            | ApiError::InvalidRefreshToken { .. }
+            | ApiError::TotpRequired
+            | ApiError::WrongTotpCode
+            | ApiError::NotAuthenticated =>
                 HttpResponse::Unauthorized().json(ApiErrorWrapper::from(self.clone())),
             ApiError::PermissionDenied { .. }
-            | ApiError::AttemptToElevateRole { .. } =>
+            | ApiError::AttemptToElevateRole { .. }
+            | ApiError::ForbiddenWhileImpersonating
+            | ApiError::InvalidMediaSignature =>
                 HttpResponse::Forbidden().json(ApiErrorWrapper::from(self.clone())),
             ApiError::JsonPayloadError { .. }
             | ApiError::MultipartPayloadError { .. }
@@ -237,13 +384,36 @@ impl ResponseError for ApiError {
             | ApiError::MissingAuthorizationHeader
             | ApiError::AvatarError { .. }
             | ApiError::UserRegistration { .. }
-            | ApiError::UserEmailUpdating { .. } =>
+            | ApiError::UserEmailUpdating { .. }
+            | ApiError::PasswordReset { .. }
+            | ApiError::Totp { .. }
+            | ApiError::InvalidSeedReference { .. }
+            | ApiError::PasswordCompromised
+            | ApiError::DisallowedNicknameChars { .. }
+            | ApiError::InvalidFilter { .. } =>
                 HttpResponse::BadRequest().json(ApiErrorWrapper::from(self.clone())),
-            ApiError::DuplicatedUser { .. } =>
+            ApiError::DuplicatedUser { .. }
+            | ApiError::DuplicatedRole
+            | ApiError::RoleCycle
+            | ApiError::ResendInProgress =>
                 HttpResponse::Conflict().json(ApiErrorWrapper::from(self.clone())),
             ApiError::UserNotFound
-            | ApiError::TokenNotFound =>
+            | ApiError::TokenNotFound
+            | ApiError::RoleNotFound
+            | ApiError::MediaNotFound
+            | ApiError::PermissionNotFound { .. }
+            | ApiError::PermissionsNotFound { .. } =>
                 HttpResponse::NotFound().json(ApiErrorWrapper::from(self.clone())),
+            ApiError::TooManyLoginAttempts { retry_after }
+            | ApiError::ResendTooSoon { retry_after } =>
+                HttpResponse::TooManyRequests()
+                    .header("Retry-After", retry_after.to_string())
+                    .json(ApiErrorWrapper::from(self.clone())),
+            ApiError::EmailNotConfigured
+            | ApiError::EmailQueueFull
+            | ApiError::AvatarQueueFull
+            | ApiError::DatabaseUnavailable =>
+                HttpResponse::ServiceUnavailable().json(ApiErrorWrapper::from(self.clone())),
         }
     }
 }
@@ -271,3 +441,101 @@ impl<T: Serialize> From<T> for ApiResultWrapper<T> {
 pub fn respond<T: Serialize>(data: T) -> ApiResult<T> {
     Ok(web::Json(data.into()))
 }
+
+// Bulk operations report each item separately, so one bad item does not fail the whole batch.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status")]
+#[serde(rename_all = "kebab-case")]
+pub enum BulkItemStatus {
+    Ok,
+    Error {
+        error: ApiErrorWrapper,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkItem<K: Serialize> {
+    id: K,
+    #[serde(flatten)]
+    status: BulkItemStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkResults<K: Serialize> {
+    results: Vec<BulkItem<K>>,
+}
+
+impl<K: Serialize> BulkResults<K> {
+    pub fn new() -> Self {
+        Self {
+            results: Vec::new(),
+        }
+    }
+    pub fn push(&mut self, id: K, result: Result<()>) {
+        self.results.push(BulkItem {
+            id,
+            status: match result {
+                Ok(()) => BulkItemStatus::Ok,
+                Err(error) => BulkItemStatus::Error {
+                    error: error.into(),
+                },
+            },
+        });
+    }
+    pub fn ok(&mut self, id: K) {
+        self.push(id, Ok(()));
+    }
+    pub fn error(&mut self, id: K, error: ApiError) {
+        self.push(id, Err(error));
+    }
+    pub fn is_all_ok(&self) -> bool {
+        self.results.iter()
+            .all(|x| match x.status {
+                BulkItemStatus::Ok => true,
+                BulkItemStatus::Error { .. } => false,
+            })
+    }
+}
+
+impl<K: Serialize> Default for BulkResults<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Serialize> FromIterator<(K, Result<()>)> for BulkResults<K> {
+    fn from_iter<I: IntoIterator<Item = (K, Result<()>)>>(iter: I) -> Self {
+        let mut results = Self::new();
+        for (id, result) in iter {
+            results.push(id, result);
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn bulk_results_mixed_test() {
+        let results: BulkResults<i32> = vec![
+            (1, Ok(())),
+            (2, Err(ApiError::UserNotFound)),
+            (3, Ok(())),
+        ].into_iter().collect();
+        assert!(!results.is_all_ok());
+        assert_eq!(serde_json::to_value(&results).unwrap(), json!({
+            "results": [
+                { "id": 1, "status": "ok" },
+                { "id": 2, "status": "error", "error": {
+                    "code": 404,
+                    "message": "cannot find the user",
+                    "data": { "type": "UserNotFound" },
+                } },
+                { "id": 3, "status": "ok" },
+            ],
+        }));
+    }
+}
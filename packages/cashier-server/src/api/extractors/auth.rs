@@ -5,6 +5,7 @@ use crate::{
         app_state::AppState,
     },
     queries::{
+        audit::Actor,
         tokens::JwtClaims,
         users::PermissionSubjectAction,
         errors::Error as QueryError,
@@ -35,6 +36,28 @@ impl Auth {
             })
         }
     }
+
+    // The uid of the admin behind an impersonation token
+    pub fn impersonator(&self) -> Option<i32> {
+        self.claims.as_ref().and_then(|claims| claims.impersonator)
+    }
+
+    // For actions an admin acting as a user must not take, such as changing credentials
+    pub fn try_not_impersonated(&self) -> std::result::Result<(), ApiError> {
+        match self.impersonator() {
+            Some(_) => Err(ApiError::ForbiddenWhileImpersonating),
+            None => Ok(()),
+        }
+    }
+
+    // Whom audited actions of the request are recorded for
+    pub fn actor(&self) -> std::result::Result<Actor, ApiError> {
+        let claims = self.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?;
+        Ok(Actor {
+            uid: claims.uid,
+            impersonator: claims.impersonator,
+        })
+    }
 }
 
 impl FromRequest for Auth {
@@ -51,13 +74,11 @@ impl FromRequest for Auth {
             .map(|x| x.to_str()
                 .ok()
                 .map(|header| header.split_ascii_whitespace().collect::<Vec<_>>())
-                .map(|fragments|
+                .and_then(|fragments|
                     if fragments.len() == 2 && fragments[0] == "Bearer" {
                         Some(String::from(fragments[1]))
-                    } else { None }
-                )
-                .flatten()
-                .ok_or_else(|| ApiError::InvalidAuthorizationHeader)
+                    } else { None })
+                .ok_or(ApiError::InvalidAuthorizationHeader)
             )
             .transpose() {
             Ok(v) => v,
@@ -66,40 +87,56 @@ impl FromRequest for Auth {
         async move {
             let (claims, permissions) = match auth {
                 Some(token) => {
-                    let claims = app_data.query.token
-                        .verify_token(&*app_data.db.read().await, &token)
+                    let db = app_data.db().await?;
+                    let claims = db.query.token
+                        .verify_token(&db.client, &token)
                         .await
                         .map_err(|e| match e {
                             QueryError::InvalidToken { error } => ApiError::InvalidToken { error },
                             e => internal_server_error!(e),
                         })?;
-                    app_data.query.token
-                        .check_token_revoked(&*app_data.db.read().await, claims.jti)
+                    db.query.token
+                        .check_token_revoked(&db.client, claims.jti)
                         .await
                         .map_err(|e| match e {
                             QueryError::TokenNotFound => ApiError::InvalidToken { error: "TokenRevoked".into() },
                             e => internal_server_error!(e),
                         })?;
-                    app_data.query.user
-                        .check_user_valid_by_id(&*app_data.db.read().await, claims.uid)
+                    db.query.user
+                        .check_user_valid_by_id(&db.client, claims.uid)
                         .await
                         .map_err(|e| match e {
                             QueryError::UserNotFound => ApiError::InvalidToken { error: "InvalidUser".into() },
                             QueryError::UserBlocked => ApiError::InvalidToken { error: "UserBlocked".into() },
                             e => internal_server_error!(e),
                         })?;
-                    let permissions = app_data.query.user
-                        .fetch_permission(&*app_data.db.read().await, claims.uid)
-                        .await
-                        .map_err(|e| internal_server_error!(e))?;
+                    let permissions = match app_data.permission_cache.get(Some(claims.uid)) {
+                        Some(permissions) => permissions,
+                        None => {
+                            let permissions = db.query.user
+                                .fetch_permission(&db.client, claims.uid)
+                                .await
+                                .map_err(|e| internal_server_error!(e))?;
+                            app_data.permission_cache.insert(Some(claims.uid), permissions.clone());
+                            permissions
+                        }
+                    };
                     (Some(claims), permissions)
                 }
                 None => (
                     None,
-                    app_data.query.user
-                        .fetch_default_permission(&*app_data.db.read().await)
-                        .await
-                        .map_err(|e| internal_server_error!(e))?,
+                    match app_data.permission_cache.get(None) {
+                        Some(permissions) => permissions,
+                        None => {
+                            let db = app_data.db().await?;
+                            let permissions = db.query.user
+                                .fetch_default_permission(&db.client)
+                                .await
+                                .map_err(|e| internal_server_error!(e))?;
+                            app_data.permission_cache.insert(None, permissions.clone());
+                            permissions
+                        }
+                    },
                 )
             };
             Ok(Auth {
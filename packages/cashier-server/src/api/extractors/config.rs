@@ -1,6 +1,7 @@
 use actix_web_validator::{
     JsonConfig,
     PathConfig,
+    QueryConfig,
     Error,
 };
 use crate::api::errors::ApiError;
@@ -26,14 +27,26 @@ pub fn default_path_config() -> PathConfig {
         }.into())
 }
 
+pub fn default_query_config() -> QueryConfig {
+    QueryConfig::default()
+        .error_handler(|err, _req| match err {
+            Error::Validate(e) => ApiError::from(e),
+            e => ApiError::JsonPayloadError { error: format!("{}", e) },
+        }.into())
+}
+
+// Shared by the handlers of one worker only, as field handlers are not Send
+#[allow(clippy::arc_with_non_send_sync)]
 pub fn avatar_multer_config() -> Arc<MulterConfig> {
     Arc::new(MulterConfig::new()
         .field("avatar", FieldConfig::new()
             .single()
             .accept_file(true)
-            .accept_content_type(vec![mime::IMAGE_PNG, mime::IMAGE_JPEG])
+            // The same formats as check_avatar
+            .accept_content_type(vec![mime::IMAGE_PNG, mime::IMAGE_JPEG, mime::IMAGE_GIF,
+                                      "image/webp".parse().unwrap()])
             .handler(MemoryStorageBuilder::new()
-                .max_size(1024 * 1024)
+                .max_size(crate::constants::AVATAR_MAX_SIZE)
                 .build()
             )
         )
@@ -0,0 +1,22 @@
+use crate::queries::email::Locale;
+use actix_web::{
+    web, Error, FromRequest,
+    http::header::ACCEPT_LANGUAGE,
+};
+use futures::future::{Ready, ok};
+
+// The locale of e-mails sent on behalf of the request, None if Accept-Language has no known one
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptLanguage(pub Option<Locale>);
+
+impl FromRequest for AcceptLanguage {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        ok(AcceptLanguage(req.headers().get(ACCEPT_LANGUAGE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(Locale::from_accept_language)))
+    }
+}
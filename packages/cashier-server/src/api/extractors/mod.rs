@@ -1,3 +1,4 @@
 pub mod auth;
+pub mod locale;
 pub mod multer;
 pub mod config;
@@ -4,10 +4,13 @@ use regex::Regex;
 use serde::{Serialize, Deserialize};
 use validator::Validate;
 use validator_derive::Validate;
-use crate::queries::users::{UserAccessLevel, RoleAccessLevel, PermissionAccessLevel};
+use crate::{
+    api::errors::{ApiError, ValidationError},
+    config::{UsernameConfig, PasswordConfig, NicknameConfig},
+    queries::users::{UserAccessLevel, RoleAccessLevel, PermissionAccessLevel},
+};
 
 lazy_static! {
-    pub static ref USERNAME_REGEX: Regex = Regex::new(r"(?i)^[a-z\d_-]*$").unwrap();
     pub static ref PASSWORD_REGEX: Regex = Regex::new(r"^[^\s]*$").unwrap();
     pub static ref ROLE_REGEX: Regex = Regex::new(r"(?i)^[a-z\d_-]*$").unwrap();
 }
@@ -20,14 +23,72 @@ pub struct Password {
     inner: String,
 }
 
+impl Password {
+    pub fn check(&self, config: &PasswordConfig) -> Result<(), ApiError> {
+        validate_password_strength(&self.inner, config)
+            .map_err(|(code, message)| ApiError::ValidationError {
+                errors: vec![ValidationError::new("password", code, Some(message))],
+            })
+    }
+}
+
+// Returns the code and the message of the first rule the password breaks
+pub fn validate_password_strength(
+    password: &str, config: &PasswordConfig,
+) -> Result<(), (&'static str, String)> {
+    if password.chars().count() < config.min_length {
+        return Err(("min_length", format!("should have at least {} chars", config.min_length)));
+    }
+    if config.require_mixed_case
+        && !(password.chars().any(char::is_lowercase) && password.chars().any(char::is_uppercase)) {
+        return Err(("mixed_case", "should contain both uppercase and lowercase letters".into()));
+    }
+    if config.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+        return Err(("digit", "should contain a digit".into()));
+    }
+    if config.require_symbol && password.chars().all(char::is_alphanumeric) {
+        return Err(("symbol", "should contain a char other than letters and digits".into()));
+    }
+    Ok(())
+}
+
+// The rules are configurable, see Username::check. Usernames are only looked up when logging in,
+// so that changing the rules does not lock out existing users
 #[derive(Debug, Validate, Serialize, Deserialize, Deref, AsRef, From, Into, Clone)]
 #[serde(transparent)]
 pub struct Username {
-    #[validate(regex(path = "USERNAME_REGEX", message = "should contain only alpha numeric and underscore chars"))]
-    #[validate(length(min = 3, max = 24, message = "should have 3 to 24 chars"))]
+    #[validate(length(min = 1, message = "should not be empty"))]
     inner: String,
 }
 
+impl Username {
+    pub fn check(&self, config: &UsernameConfig) -> Result<(), ApiError> {
+        let error = |code: &str, message: String| Err(ApiError::ValidationError {
+            errors: vec![ValidationError::new("username", code, Some(message))],
+        });
+        let length = self.inner.chars().count();
+        if length < config.min_length {
+            return error("min_length", format!("should have at least {} chars", config.min_length));
+        }
+        if length > config.max_length {
+            return error("max_length", format!("should have at most {} chars", config.max_length));
+        }
+        let mut chars = self.inner.chars()
+            .filter(|&c| !c.is_ascii_alphanumeric() && !config.extra_chars.contains(c))
+            .collect::<Vec<_>>();
+        if !chars.is_empty() {
+            chars.sort_unstable();
+            chars.dedup();
+            return error("charset", format!("should not contain {:?}, only alpha numeric and {:?} chars \
+                                             are allowed", chars, config.extra_chars));
+        }
+        if config.lowercase_only && self.inner.chars().any(|c| c.is_ascii_uppercase()) {
+            return error("lowercase", "should not contain uppercase letters".into());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Validate, Serialize, Deserialize, Deref, AsRef, From, Into, Clone)]
 #[serde(transparent)]
 pub struct Email {
@@ -43,13 +104,58 @@ pub struct RoleName {
     pub inner: String,
 }
 
+// The length and emoji are configurable, see Nickname::check
 #[derive(Debug, Validate, Serialize, Deserialize, Deref, AsRef, From, Into, Clone)]
 #[serde(transparent)]
 pub struct Nickname {
-    #[validate(length(min = 3, max = 24, message = "should have 3 to 24 chars"))]
     inner: String,
 }
 
+// Invisible chars let a nickname pass as another one, so they are rejected even with emoji, except
+// for the zero width joiner between two emoji, which makes sequences such as families
+fn is_invisible_char(c: char) -> bool {
+    c.is_control() || matches!(c,
+        '\u{00ad}' | '\u{034f}' | '\u{061c}' | '\u{115f}' | '\u{1160}' | '\u{17b4}' | '\u{17b5}'
+        | '\u{180e}' | '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{206f}'
+        | '\u{3164}' | '\u{feff}' | '\u{ffa0}' | '\u{fff9}'..='\u{fffb}')
+}
+
+fn is_emoji_char(c: char) -> bool {
+    matches!(c,
+        '\u{2600}'..='\u{27bf}' | '\u{2b00}'..='\u{2bff}' | '\u{fe00}'..='\u{fe0f}'
+        | '\u{1f000}'..='\u{1faff}' | '\u{e0020}'..='\u{e007f}')
+}
+
+impl Nickname {
+    pub fn check(&self, config: &NicknameConfig) -> Result<(), ApiError> {
+        let all_chars = self.inner.chars().collect::<Vec<_>>();
+        let emoji_joiner = |i: usize| config.allow_emoji && all_chars[i] == '\u{200d}'
+            && i > 0 && is_emoji_char(all_chars[i - 1])
+            && all_chars.get(i + 1).is_some_and(|&c| is_emoji_char(c));
+        let mut chars = all_chars.iter()
+            .enumerate()
+            .filter(|&(i, &c)| (is_invisible_char(c) && !emoji_joiner(i))
+                || (!config.allow_emoji && is_emoji_char(c)))
+            .map(|(_, &c)| format!("U+{:04X}", c as u32))
+            .collect::<Vec<_>>();
+        if !chars.is_empty() {
+            chars.sort();
+            chars.dedup();
+            return Err(ApiError::DisallowedNicknameChars { chars });
+        }
+        if all_chars.len() < config.min_length || all_chars.len() > config.max_length {
+            return Err(ApiError::ValidationError {
+                errors: vec![ValidationError::new(
+                    "nickname", "length",
+                    Some(format!("should have {} to {} chars", config.min_length,
+                                 config.max_length)),
+                )],
+            });
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Validate, Serialize, Deserialize, Deref, AsRef, From, Into, Clone)]
 #[serde(transparent)]
 pub struct Any24 {
@@ -86,4 +192,106 @@ pub struct PopulateRole {
 #[serde(transparent)]
 pub struct PopulatePermission {
     level: PermissionAccessLevel,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn username_config() -> UsernameConfig {
+        UsernameConfig {
+            min_length: 4,
+            max_length: 8,
+            extra_chars: "_".into(),
+            lowercase_only: true,
+        }
+    }
+
+    fn username_error_code(username: &str) -> Option<String> {
+        match Username::from(String::from(username)).check(&username_config()) {
+            Ok(()) => None,
+            Err(ApiError::ValidationError { errors }) =>
+                serde_json::to_value(&errors).unwrap()[0]["code"].as_str().map(String::from),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn username_rules_test() {
+        assert_eq!(username_error_code("abcd"), None);
+        assert_eq!(username_error_code("a_12345b"), None);
+        assert_eq!(username_error_code("abc"), Some("min_length".into()));
+        assert_eq!(username_error_code("abcdefghi"), Some("max_length".into()));
+        assert_eq!(username_error_code("ab-cd"), Some("charset".into()));
+        assert_eq!(username_error_code("abcd\u{200d}"), Some("charset".into()));
+        assert_eq!(username_error_code("Abcd"), Some("lowercase".into()));
+    }
+
+    fn password_error_code(password: &str) -> Option<&'static str> {
+        let config = PasswordConfig {
+            min_length: 8,
+            require_mixed_case: true,
+            require_digit: true,
+            require_symbol: true,
+        };
+        validate_password_strength(password, &config).err().map(|(code, _)| code)
+    }
+
+    #[test]
+    fn password_strength_test() {
+        assert_eq!(password_error_code("Passw0rd!"), None);
+        assert_eq!(password_error_code("Pa0rd!"), Some("min_length"));
+        assert_eq!(password_error_code("passw0rd!"), Some("mixed_case"));
+        assert_eq!(password_error_code("PASSW0RD!"), Some("mixed_case"));
+        assert_eq!(password_error_code("Password!"), Some("digit"));
+        assert_eq!(password_error_code("Passw0rd"), Some("symbol"));
+    }
+
+    fn config() -> NicknameConfig {
+        NicknameConfig {
+            min_length: 3,
+            max_length: 8,
+            allow_emoji: true,
+        }
+    }
+
+    #[test]
+    fn nickname_zero_width_joiner_test() {
+        let nickname = Nickname::from(String::from("ad\u{200d}min"));
+        match nickname.check(&config()) {
+            Err(ApiError::DisallowedNicknameChars { chars }) =>
+                assert_eq!(chars, vec![String::from("U+200D")]),
+            e => panic!("unexpected result {:?}", e),
+        }
+        assert!(Nickname::from(String::from("admin\u{1f600}")).check(&config()).is_ok());
+        let no_emoji = NicknameConfig { allow_emoji: false, ..config() };
+        assert!(Nickname::from(String::from("admin\u{1f600}")).check(&no_emoji).is_err());
+        // Joining two emoji, such as the family of a man, a woman and a girl
+        let family = String::from("ann\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}");
+        assert!(Nickname::from(family.clone()).check(&config()).is_ok());
+        match Nickname::from(family).check(&no_emoji) {
+            Err(ApiError::DisallowedNicknameChars { chars }) => assert_eq!(chars, vec![
+                String::from("U+1F467"), String::from("U+1F468"), String::from("U+1F469"),
+                String::from("U+200D"),
+            ]),
+            e => panic!("unexpected result {:?}", e),
+        }
+        // Not between two emoji
+        assert!(Nickname::from(String::from("ann\u{1f468}\u{200d}x")).check(&config()).is_err());
+    }
+
+    #[test]
+    fn nickname_over_length_test() {
+        assert!(Nickname::from(String::from("12345678")).check(&config()).is_ok());
+        assert!(Nickname::from(String::from("12")).check(&config()).is_err());
+        match Nickname::from(String::from("123456789")).check(&config()) {
+            Err(ApiError::ValidationError { errors }) => assert_eq!(
+                serde_json::to_value(&errors).unwrap(),
+                serde_json::json!([
+                    { "field": "nickname", "code": "length", "message": "should have 3 to 8 chars" },
+                ]),
+            ),
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use crate::{
+    api::{
+        extractors::{
+            auth::Auth,
+            config::{default_json_config, default_query_config},
+        },
+        errors::{ApiError, ApiResult, BulkResults, respond},
+        app_state::{AppState, Mailer, ResendProgress},
+        fields::RoleName,
+    },
+    queries::{
+        errors::Error as QueryError,
+        roles::{SeedDocument, SeedPermission, SeedRole, SeedLink, SeedResult},
+        users::PendingEmail,
+    },
+    websocket::push_messages::InternalRolePermissionCreated,
+    internal_server_error,
+};
+use actix_rt::time::delay_for;
+use actix_web::web;
+use actix_web_validator::{ValidatedJson, ValidatedQuery};
+use chrono::{Duration as ChronoDuration, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use validator::Validate;
+use validator_derive::Validate;
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeedPermissionRequest {
+    subject: String,
+    action: String,
+    display_name: String,
+    description: String,
+}
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeedRoleRequest {
+    #[validate]
+    name: RoleName,
+    display_name: String,
+    description: String,
+    #[serde(default)]
+    default: bool,
+}
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeedLinkRequest {
+    #[validate]
+    role: RoleName,
+    subject: String,
+    action: String,
+}
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeedRequest {
+    #[validate]
+    #[serde(default)]
+    permissions: Vec<SeedPermissionRequest>,
+    #[validate]
+    #[serde(default)]
+    roles: Vec<SeedRoleRequest>,
+    #[validate]
+    #[serde(default)]
+    links: Vec<SeedLinkRequest>,
+}
+
+impl From<SeedRequest> for SeedDocument {
+    fn from(request: SeedRequest) -> Self {
+        Self {
+            permissions: request.permissions.into_iter()
+                .map(|x| SeedPermission {
+                    subject: x.subject,
+                    action: x.action,
+                    display_name: x.display_name,
+                    description: x.description,
+                })
+                .collect(),
+            roles: request.roles.into_iter()
+                .map(|x| SeedRole {
+                    name: x.name.into(),
+                    display_name: x.display_name,
+                    description: x.description,
+                    default: x.default,
+                })
+                .collect(),
+            links: request.links.into_iter()
+                .map(|x| SeedLink {
+                    role: x.role.into(),
+                    subject: x.subject,
+                    action: x.action,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct SeedQuery {
+    #[serde(default)]
+    partial: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SeedResponse {
+    #[serde(flatten)]
+    result: SeedResult,
+    // Only with partial, one per link keyed by role/subject:action
+    #[serde(flatten)]
+    results: Option<BulkResults<String>>,
+}
+
+// Without partial, a document with links to unknown roles or permissions is rejected as a whole.
+// With it, only those links are left out and reported, and the rest of the document is seeded
+fn split_unknown_links(document: &mut SeedDocument) -> BulkResults<String> {
+    let (roles, permissions) = document.unknown_references();
+    let mut results = BulkResults::new();
+    for link in std::mem::take(&mut document.links) {
+        let permission = format!("{}:{}", link.subject, link.action);
+        let id = format!("{}/{}", link.role, permission);
+        let unknown_roles = Some(link.role.clone())
+            .filter(|x| roles.contains(x))
+            .into_iter()
+            .collect::<Vec<_>>();
+        let unknown_permissions = Some(permission)
+            .filter(|x| permissions.contains(x))
+            .into_iter()
+            .collect::<Vec<_>>();
+        if unknown_roles.is_empty() && unknown_permissions.is_empty() {
+            results.ok(id);
+            document.links.push(link);
+        } else {
+            results.error(id, ApiError::InvalidSeedReference {
+                roles: unknown_roles,
+                permissions: unknown_permissions,
+            });
+        }
+    }
+    results
+}
+
+async fn seed(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<SeedRequest>,
+    query: ValidatedQuery<SeedQuery>,
+    auth: Auth,
+) -> ApiResult<SeedResponse> {
+    auth.try_permission("seed", "create")?;
+    let mut document = SeedDocument::from(request.into_inner());
+    let results = if query.partial {
+        Some(split_unknown_links(&mut document))
+    } else {
+        None
+    };
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let result = db.query.role
+        .seed(&mut db.client, &document)
+        .await
+        .map_err(|err| match err {
+            QueryError::InvalidSeedReference { roles, permissions } =>
+                ApiError::InvalidSeedReference { roles, permissions },
+            e => internal_server_error!(e),
+        })?;
+    let messages = result.links.created.iter()
+        .map(|link| InternalRolePermissionCreated {
+            role: link.role,
+            permission: link.permission,
+            subject: link.subject.clone(),
+            action: link.action.clone(),
+        }.into())
+        .collect::<Vec<_>>();
+    if !messages.is_empty() {
+        app_data.send_all(messages, &auth)
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
+    respond(SeedResponse {
+        result,
+        results,
+    })
+}
+
+fn update_resend_progress(
+    app_data: &web::Data<AppState>,
+    update: impl FnOnce(&mut ResendProgress),
+) {
+    if let Some(progress) = app_data.resend_progress.lock().unwrap().as_mut() {
+        update(progress);
+    }
+}
+
+async fn resend_in_background(
+    app_data: web::Data<AppState>,
+    pending: Vec<PendingEmail>,
+) {
+    // Spreads the e-mails evenly over time so that the SMTP server is not flooded
+    let pace = Duration::from_millis(60_000 / app_data.config.smtp.batch_rate);
+    for (i, item) in pending.iter().enumerate() {
+        if i != 0 {
+            delay_for(pace).await;
+        }
+        let result = match app_data.pool.get().await {
+            Ok(db) => db.query.user
+                .resend_pending_email(
+                    &db.client, app_data.clone(),
+                    &app_data.config.smtp.sender, &app_data.config.site, item,
+                )
+                .await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => update_resend_progress(&app_data, |progress| progress.sent += 1),
+            Err(e) => {
+                error!("failed to resend e-mail of {:?} {}: {}", item.kind, item.id, e);
+                update_resend_progress(&app_data, |progress| progress.failed += 1);
+            }
+        }
+    }
+    update_resend_progress(&app_data, |progress| progress.finished = true);
+}
+
+async fn resend_pending(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<ResendProgress> {
+    auth.try_permission("pending-email", "resend")?;
+    if let Mailer::Disabled = app_data.mailer {
+        return Err(ApiError::EmailNotConfigured);
+    }
+    let db = app_data.db().await?;
+    let pending = db.query.user
+        .find_pending_emails(&db.client)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    let total = pending.len();
+    let recent = Utc::now() - ChronoDuration::seconds(app_data.config.smtp.resend_interval as i64);
+    let pending = pending.into_iter()
+        .filter(|x| x.sent_at <= recent)
+        .collect::<Vec<_>>();
+    let progress = ResendProgress {
+        total,
+        sent: 0,
+        skipped: total - pending.len(),
+        failed: 0,
+        finished: pending.is_empty(),
+    };
+    {
+        let mut current = app_data.resend_progress.lock().unwrap();
+        if let Some(ResendProgress { finished: false, .. }) = *current {
+            return Err(ApiError::ResendInProgress);
+        }
+        *current = Some(progress.clone());
+    }
+    if !pending.is_empty() {
+        actix_rt::spawn(resend_in_background(app_data.clone(), pending));
+    }
+    respond(progress)
+}
+
+async fn read_resend_progress(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<Option<ResendProgress>> {
+    auth.try_permission("pending-email", "read")?;
+    let progress = app_data.resend_progress.lock().unwrap().clone();
+    respond(progress)
+}
+
+pub fn admin_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
+    let state = state.clone();
+    Box::new(move |cfg| {
+        cfg.service(
+            web::scope("/admin")
+                .app_data(state)
+                .app_data(default_json_config())
+                .app_data(default_query_config())
+                .route("/seed", web::post().to(seed))
+                .route("/resend-pending", web::post().to(resend_pending))
+                .route("/resend-pending", web::get().to(read_resend_progress))
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{api_v1, testing::TestApp};
+    use actix_web::{App, http::StatusCode, test};
+
+    #[actix_rt::test]
+    async fn seed_unknown_reference_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, authorization) = app.user("admin", &["permission-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let seed = |links: serde_json::Value| test::TestRequest::post()
+            .uri("/api/v1/admin/seed")
+            .header("Authorization", authorization.clone())
+            .set_json(&serde_json::json!({
+                "permissions": [
+                    { "subject": "post", "action": "read", "displayName": "Read Post", "description": "" },
+                ],
+                "roles": [
+                    { "name": "editor", "displayName": "Editor", "description": "" },
+                ],
+                "links": links,
+            }))
+            .to_request();
+        let response = test::call_service(&mut service, seed(serde_json::json!([
+            { "role": "editor", "subject": "post", "action": "read" },
+            { "role": "ghost", "subject": "post", "action": "write" },
+        ]))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["type"], "InvalidSeedReference");
+        assert_eq!(response["data"]["roles"], serde_json::json!(["ghost"]));
+        assert_eq!(response["data"]["permissions"], serde_json::json!(["post:write"]));
+        // Nothing of the rejected document is kept, not even its valid parts
+        let client = &app.db.client;
+        let count = |sql: &'static str| async move {
+            client.query_one(sql, &[]).await.unwrap().get::<_, i64>(0)
+        };
+        assert_eq!(count("SELECT COUNT(*) FROM permission WHERE subject = 'post'").await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM role WHERE name = 'editor'").await, 0);
+        let response = test::call_service(&mut service, seed(serde_json::json!([
+            { "role": "editor", "subject": "post", "action": "read" },
+        ]))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(count("SELECT COUNT(*) FROM permission WHERE subject = 'post'").await, 1);
+        assert_eq!(count("SELECT COUNT(*) FROM role WHERE name = 'editor'").await, 1);
+    }
+
+    #[actix_rt::test]
+    async fn seed_partial_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, authorization) = app.user("admin", &["permission-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/admin/seed?partial=true")
+            .header("Authorization", authorization.clone())
+            .set_json(&serde_json::json!({
+                "permissions": [
+                    { "subject": "post", "action": "read", "displayName": "Read Post", "description": "" },
+                ],
+                "roles": [
+                    { "name": "editor", "displayName": "Editor", "description": "" },
+                ],
+                "links": [
+                    { "role": "editor", "subject": "post", "action": "read" },
+                    { "role": "ghost", "subject": "post", "action": "read" },
+                    { "role": "editor", "subject": "post", "action": "write" },
+                ],
+            }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let results = &response["data"]["results"];
+        assert_eq!(results[0], serde_json::json!({ "id": "editor/post:read", "status": "ok" }));
+        assert_eq!(results[1]["id"], "ghost/post:read");
+        assert_eq!(results[1]["status"], "error");
+        assert_eq!(results[1]["error"]["data"]["roles"], serde_json::json!(["ghost"]));
+        assert_eq!(results[1]["error"]["data"]["permissions"], serde_json::json!([]));
+        assert_eq!(results[2]["id"], "editor/post:write");
+        assert_eq!(results[2]["error"]["data"]["permissions"], serde_json::json!(["post:write"]));
+        assert_eq!(response["data"]["links"]["created"].as_array().unwrap().len(), 1);
+        let count = app.db.client
+            .query_one("SELECT COUNT(*) FROM role_permission rp JOIN role r ON r.id = rp.role \
+                        WHERE r.name = 'editor'", &[])
+            .await
+            .unwrap()
+            .get::<_, i64>(0);
+        assert_eq!(count, 1);
+    }
+
+    #[actix_rt::test]
+    async fn resend_pending_test() {
+        // One e-mail every 200 milliseconds
+        let app = match TestApp::new(&["--smtp-mode", "log", "--smtp-batch-rate", "300",
+            "--smtp-resend-interval", "3600"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, authorization) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        for (id, code, sent) in &[("old1", "111111", "2 hours"), ("old2", "222222", "3 hours"),
+            ("recent", "333333", "1 minute")] {
+            // As long as generated ids, since CHAR pads shorter ones
+            let id = format!("{:0>24}", id);
+            app.db.client
+                .execute(format!("INSERT INTO user_registration (id, code, username, password, \
+                                  email, created_at, expires_at, sent_at, attempts) \
+                                  VALUES ($1::TEXT, $2, $1, '', $1 || '@example.com', NOW(), \
+                                  NOW() + INTERVAL '1 day', NOW() - INTERVAL '{}', 0)", sent)
+                             .as_str(), &[&id, code])
+                .await
+                .unwrap();
+        }
+        let resend = || test::TestRequest::post()
+            .uri("/api/v1/admin/resend-pending")
+            .header("Authorization", authorization.clone())
+            .to_request();
+        let started = std::time::Instant::now();
+        let response = test::call_service(&mut service, resend()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["total"], 3);
+        assert_eq!(response["data"]["skipped"], 1);
+        let response = test::call_service(&mut service, resend()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let progress = loop {
+            let response = test::call_service(&mut service, test::TestRequest::get()
+                .uri("/api/v1/admin/resend-pending")
+                .header("Authorization", authorization.clone())
+                .to_request()).await;
+            let response: serde_json::Value =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            if response["data"]["finished"] == true {
+                break response["data"].clone();
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(20)).await;
+        };
+        assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+        assert_eq!(progress["sent"], 2);
+        assert_eq!(progress["failed"], 0);
+        // Oldest first, so the last one sent is old1
+        assert_eq!(app.app_data.mailer.last_code().as_deref(), Some("111111"));
+        // Everything was sent just now
+        let response = test::call_service(&mut service, resend()).await;
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["skipped"], 3);
+        assert_eq!(response["data"]["finished"], true);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    api::{
+        extractors::{
+            auth::Auth,
+            config::default_query_config,
+        },
+        errors::{ApiError, ApiResult, respond},
+        app_state::AppState,
+    },
+    queries::{
+        audit::AuditEntry,
+        errors::Error as QueryError,
+    },
+    constants::AUDIT_LOG_MAX_LIMIT,
+    internal_server_error,
+    retry_db,
+};
+use actix_web::web;
+use actix_web_validator::ValidatedQuery;
+use serde::{Serialize, Deserialize};
+use validator::Validate;
+use validator_derive::Validate;
+
+#[derive(Debug, Validate, Deserialize)]
+struct ListAuditLogQuery {
+    filter: Option<String>,
+    sort: Option<String>,
+    limit: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct ListAuditLogResponse {
+    items: Vec<AuditEntry>,
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+// filter and sort are in the syntax of cashier-query like GET /api/users, over the fields id,
+// actor, impersonator, action, target and created_at, e.g. filter=action: user-role and target: 3.
+// The newest entries come first by default
+async fn list_audit_log(
+    app_data: web::Data<AppState>,
+    request: ValidatedQuery<ListAuditLogQuery>,
+    auth: Auth,
+) -> ApiResult<ListAuditLogResponse> {
+    auth.try_permission("audit", "read")?;
+    let limit = request.limit.unwrap_or(AUDIT_LOG_MAX_LIMIT).clamp(1, AUDIT_LOG_MAX_LIMIT);
+    let offset = request.offset.max(0);
+    let (items, total) = retry_db!(app_data, |db| db.query.audit
+        .list(&db.client, request.filter.as_deref(), request.sort.as_deref(), limit, offset)
+        .await)
+        .map_err(|err| match err {
+            QueryError::InvalidFilter(e) => ApiError::InvalidFilter { error: e.to_string() },
+            e => internal_server_error!(e),
+        })?;
+    respond(ListAuditLogResponse {
+        items,
+        total,
+        limit,
+        offset,
+    })
+}
+
+pub fn audit_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
+    let state = state.clone();
+    Box::new(move |cfg| {
+        cfg.service(
+            web::scope("audit-log")
+                .app_data(state)
+                .app_data(default_query_config())
+                .route("", web::get().to(list_audit_log))
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{api_v1, testing::TestApp};
+    use actix_web::{App, http::StatusCode, test};
+
+    #[actix_rt::test]
+    async fn audit_log_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        let (admin_id, admin) = app.user("admin", &["user-admin"]).await;
+        let (_, auditor) = app.user("auditor", &["permission-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let block = |blocked: bool| test::TestRequest::patch()
+            .uri(&format!("/api/v1/users/{}", uid))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "blocked": blocked }))
+            .to_request();
+        let response = test::call_service(&mut service, block(true)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Already blocked, so nothing changes
+        let response = test::call_service(&mut service, block(true)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, block(false)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Rejected, so not recorded either
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/users/{}/password", uid))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "password": "123" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let list = |filter: &str, authorization: &str| test::TestRequest::get()
+            .uri(&format!("/api/v1/audit-log?filter={}&sort=id", filter))
+            .header("Authorization", authorization)
+            .to_request();
+        let filter = format!("target:{}", uid);
+        let response = test::call_service(&mut service, list(&filter, &admin)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, list(&filter, &auditor)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let items = response["data"]["items"].as_array().unwrap();
+        assert_eq!(items.iter().map(|x| x["action"].as_str().unwrap()).collect::<Vec<_>>(),
+                   vec!["user.block", "user.unblock"]);
+        assert_eq!(items[0]["actor"], admin_id);
+        assert_eq!(response["data"]["total"], 2);
+        let response = test::call_service(&mut service, list("username:alice", &auditor)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{
+    api::app_state::AppState,
+    config::SmtpMode,
+    constants::HEALTH_CHECK_TIMEOUT,
+    queries::errors::Error as QueryError,
+};
+use actix_web::{web, HttpResponse};
+use log::warn;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DependencyStatus {
+    Up,
+    Down,
+    // Not configured to be checked
+    Unchecked,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessResponse {
+    pub database: DependencyStatus,
+    pub smtp: DependencyStatus,
+}
+
+// Liveness, only tells that the process answers requests
+async fn read_health() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "up" }))
+}
+
+async fn check_database(app_data: &AppState) -> DependencyStatus {
+    // Getting a connection from the pool reconnects if needed
+    let check = async {
+        let db = app_data.pool.get().await?;
+        db.client.query_one("SELECT 1", &[]).await?;
+        Ok::<_, QueryError>(())
+    };
+    match actix_rt::time::timeout(Duration::from_secs(HEALTH_CHECK_TIMEOUT), check).await {
+        Ok(Ok(_)) => DependencyStatus::Up,
+        Ok(Err(e)) => {
+            warn!("readiness check of the database failed: {}", e);
+            DependencyStatus::Down
+        }
+        Err(_) => {
+            warn!("readiness check of the database timed out");
+            DependencyStatus::Down
+        }
+    }
+}
+
+// Only connects to the server, logging in would take an SMTP session per probe
+async fn check_smtp(app_data: &AppState) -> DependencyStatus {
+    let smtp = &app_data.config.smtp;
+    let server = match (smtp.readiness_check, smtp.mode, &smtp.server) {
+        (true, SmtpMode::Send, Some(server)) => server,
+        _ => return DependencyStatus::Unchecked,
+    };
+    let connect = TcpStream::connect((server.as_str(), smtp.port));
+    match actix_rt::time::timeout(Duration::from_secs(HEALTH_CHECK_TIMEOUT), connect).await {
+        Ok(Ok(_)) => DependencyStatus::Up,
+        Ok(Err(e)) => {
+            warn!("readiness check of the SMTP server failed: {}", e);
+            DependencyStatus::Down
+        }
+        Err(_) => {
+            warn!("readiness check of the SMTP server timed out");
+            DependencyStatus::Down
+        }
+    }
+}
+
+// Responds 503 when a checked dependency is down, so the instance is taken out of rotation
+async fn read_readiness(
+    app_data: web::Data<AppState>,
+) -> HttpResponse {
+    let (database, smtp) = futures::join!(check_database(&app_data), check_smtp(&app_data));
+    let response = ReadinessResponse { database, smtp };
+    if database == DependencyStatus::Down || smtp == DependencyStatus::Down {
+        HttpResponse::ServiceUnavailable().json(response)
+    } else {
+        HttpResponse::Ok().json(response)
+    }
+}
+
+// Outside of /api/v1 and without authentication, for load balancers and orchestrators
+pub fn health_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
+    let state = state.clone();
+    Box::new(move |cfg| {
+        cfg.service(web::resource("/healthz").route(web::get().to(read_health)));
+        cfg.service(
+            web::resource("/readyz")
+                .app_data(state)
+                .route(web::get().to(read_readiness))
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::health_api;
+    use crate::api::testing::TestApp;
+    use actix_web::{App, http::StatusCode, test};
+
+    #[actix_rt::test]
+    async fn health_api_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new()
+            .configure(health_api(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/healthz")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/readyz")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(body["database"], "up");
+        // Not opted in with --smtp-readiness-check
+        assert_eq!(body["smtp"], "unchecked");
+    }
+
+    #[actix_rt::test]
+    async fn smtp_readiness_test() {
+        // The SMTP server of the tests is localhost, at the port sent to
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let app = match TestApp::new(&["--smtp-port", &port, "--smtp-readiness-check"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new()
+            .configure(health_api(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/readyz")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(body["smtp"], "up");
+        drop(listener);
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/readyz")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{
+    api::{
+        app_state::AppState,
+        avatar_store::join_avatar_file,
+        errors::ApiError,
+    },
+    internal_server_error,
+};
+use actix_files::NamedFile;
+use actix_web::{
+    web,
+    http::{header, HeaderValue},
+    HttpRequest,
+    HttpResponse,
+};
+use chrono::Utc;
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey,
+    crypto::{sign, verify},
+};
+use serde::Deserialize;
+
+// The signature covers both the file and the expiry, so neither can be changed
+fn signed_message(filename: &str, exp: i64) -> String {
+    format!("{}:{}:{}", crate::constants::AVATAR_FOLDER, filename, exp)
+}
+
+pub fn sign_avatar(secret: &[u8], filename: &str, exp: i64) -> String {
+    let query = format!("exp={}&sig={}", exp,
+                        sign(&signed_message(filename, exp), &EncodingKey::from_secret(secret),
+                             Algorithm::HS256).unwrap());
+    format!("/api/v1/media/avatars/{}?{}", filename, query)
+}
+
+// Public URL from the avatar store unless avatars are only served through signed URLs
+pub fn avatar_url(app_data: &AppState, filename: &str) -> String {
+    match app_data.config.media.avatar_url_ttl {
+        Some(ttl) => sign_avatar(&app_data.media_secret, filename,
+                                 Utc::now().timestamp() + ttl as i64),
+        None => app_data.avatar_store.url_for(filename),
+    }
+}
+
+// Whether If-None-Match of req lists etag, weak or not, or is *
+pub fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers().get_all("If-None-Match")
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x.trim_start_matches("W/") == etag)
+}
+
+#[derive(Debug, Deserialize)]
+struct SignedQuery {
+    exp: Option<i64>,
+    sig: Option<String>,
+}
+
+// A new avatar is written to a new random filename, so the filename alone identifies the content.
+// The response is cached until the signature expires
+async fn read_avatar(
+    app_data: web::Data<AppState>,
+    filename: web::Path<String>,
+    query: web::Query<SignedQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let (exp, sig) = match (query.exp, &query.sig) {
+        (Some(exp), Some(sig)) => (exp, sig),
+        _ => return Err(ApiError::InvalidMediaSignature),
+    };
+    if exp < Utc::now().timestamp() ||
+        !verify(sig, &signed_message(&filename, exp),
+                &DecodingKey::from_secret(&app_data.media_secret), Algorithm::HS256)
+            .unwrap_or(false) {
+        return Err(ApiError::InvalidMediaSignature);
+    }
+    let file = NamedFile::open(join_avatar_file(&app_data.config.media.root, &filename[..]))
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => ApiError::MediaNotFound,
+            _ => internal_server_error!(e),
+        })?;
+    let etag = format!("\"{}\"", filename);
+    let cache_control = format!("private, max-age={}", exp - Utc::now().timestamp());
+    let mut response = if etag_matches(&req, &etag) {
+        HttpResponse::NotModified().finish()
+    } else {
+        file.use_etag(false)
+            .into_response(&req)
+            .map_err(|e| internal_server_error!(e))?
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag)
+        .map_err(|e| internal_server_error!(e))?);
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_str(&cache_control)
+        .map_err(|e| internal_server_error!(e))?);
+    Ok(response)
+}
+
+pub fn media_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
+    let state = state.clone();
+    Box::new(move |cfg| {
+        cfg.service(
+            web::scope("/media")
+                .app_data(state)
+                .route("/avatars/{filename}", web::get().to(read_avatar))
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{avatar_url, sign_avatar};
+    use crate::api::{api_v1, testing::TestApp};
+    use actix_web::{App, http::StatusCode, test};
+    use chrono::Utc;
+    use std::path::Path;
+
+    #[actix_rt::test]
+    async fn signed_avatar_test() {
+        let app = match TestApp::new(&["--media-avatar-url-ttl", "60"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let folder = Path::new(&app.app_data.config.media.root)
+            .join(crate::constants::AVATAR_FOLDER);
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("signed-avatar-test.png"), b"avatar").unwrap();
+        let get = |url: &str| test::TestRequest::get()
+            .uri(url)
+            .to_request();
+        let url = avatar_url(&app.app_data, "signed-avatar-test.png");
+        let response = test::call_service(&mut service, get(&url)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(&test::read_body(response).await[..], b"avatar");
+        // Another file, a longer expiry or a changed signature
+        for url in &[
+            url.replace("signed-avatar-test.png", "other.png"),
+            url.replace("exp=", "exp=1"),
+            url.replace("sig=", "sig=x"),
+            url.split('?').next().unwrap().to_string(),
+        ] {
+            let response = test::call_service(&mut service, get(url)).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let expired = sign_avatar(&app.app_data.media_secret, "signed-avatar-test.png",
+                                  Utc::now().timestamp() - 1);
+        let response = test::call_service(&mut service, get(&expired)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        std::fs::remove_file(folder.join("signed-avatar-test.png")).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn avatar_cache_test() {
+        let app = match TestApp::new(&["--media-avatar-url-ttl", "60"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let folder = Path::new(&app.app_data.config.media.root)
+            .join(crate::constants::AVATAR_FOLDER);
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("cached-avatar-test.png"), b"avatar").unwrap();
+        let url = avatar_url(&app.app_data, "cached-avatar-test.png");
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri(&url)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = |name: &str| response.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!(header("ETag"), "\"cached-avatar-test.png\"");
+        assert!(header("Cache-Control").starts_with("private, max-age="));
+        assert!(response.headers().contains_key("Last-Modified"));
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri(&url)
+            .header("If-None-Match", "\"cached-avatar-test.png\"")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(test::read_body(response).await.is_empty());
+        std::fs::remove_file(folder.join("cached-avatar-test.png")).unwrap();
+    }
+}
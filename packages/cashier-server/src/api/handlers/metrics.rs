@@ -0,0 +1,80 @@
+use crate::{
+    api::{
+        app_state::AppState,
+        errors::ApiError,
+    },
+    websocket::main_subscriber::CountClients,
+    internal_server_error,
+};
+use actix_web::{web, HttpResponse};
+
+// Served on StartConfig::metrics_bind only, so no permission is checked
+async fn read_metrics(
+    app_data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let websocket_clients = match app_data.subscriber.send(CountClients).await
+        .map_err(|e| internal_server_error!(e))? {
+        Ok(count) => count,
+        Err(e) => match e {},
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(app_data.metrics.render(websocket_clients, &app_data.pool.status())))
+}
+
+pub fn metrics_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
+    let state = state.clone();
+    Box::new(move |cfg| {
+        cfg.service(
+            web::scope("/metrics")
+                .app_data(state)
+                .route("", web::get().to(read_metrics))
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::metrics_api;
+    use crate::api::{api_v1, testing::TestApp};
+    use actix_web::{App, http::StatusCode, test};
+
+    #[actix_rt::test]
+    async fn metrics_api_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, alice) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new()
+            .configure(api_v1(&app.app_data))
+            .configure(metrics_api(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/me")
+            .header("Authorization", alice.clone())
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri(&format!("/api/v1/users/{}", uid))
+            .header("Authorization", alice)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/metrics")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        let total = |route: &str, status: u16| format!(
+            "cashier_http_requests_total{{route=\"{}\",method=\"GET\",status=\"{}\"}} 1\n",
+            route, status);
+        assert!(text.contains(&total("/users/me", 200)));
+        assert!(text.contains(&total("/users/{uid}", 403)));
+        assert!(text.contains("cashier_websocket_clients 0\n"));
+        assert!(text.contains("cashier_db_pool_idle "));
+        // Not part of the API
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/metrics")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
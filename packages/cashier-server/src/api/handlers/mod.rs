@@ -1,2 +1,8 @@
+pub mod admin;
+pub mod audit;
+pub mod health;
+pub mod media;
+pub mod metrics;
+pub mod roles;
 pub mod tokens;
 pub mod users;
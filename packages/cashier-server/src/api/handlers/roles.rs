@@ -0,0 +1,512 @@
+use crate::{
+    api::{
+        extractors::{
+            auth::Auth,
+            config::{default_json_config, default_path_config},
+        },
+        errors::{ApiError, ApiResult, respond},
+        app_state::AppState,
+        fields::{RoleName, Id},
+    },
+    queries::{
+        errors::Error as QueryError,
+        pool::Connection,
+        roles::RoleIdCreatedAt,
+    },
+    websocket::push_messages::{
+        InnerInternalMessage, UserRoleDeleted, RolePermissionDeleted,
+        InternalRolePermissionCreated, RoleUpdated, RoleDeleted, InternalRoleParentUpdated,
+    },
+    internal_server_error,
+};
+use actix_web::web;
+use actix_web_validator::{ValidatedJson, ValidatedPath};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use std::iter;
+use validator::Validate;
+use validator_derive::Validate;
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateRoleRequest {
+    #[validate]
+    name: RoleName,
+    #[validate(length(min = 1, max = 64, message = "should have 1 to 64 chars"))]
+    display_name: String,
+    #[validate(length(max = 1024, message = "should have at most 1024 chars"))]
+    description: String,
+}
+
+async fn create_role(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<CreateRoleRequest>,
+    auth: Auth,
+) -> ApiResult<RoleIdCreatedAt> {
+    auth.try_permission("role", "create")?;
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let role = db.query.role
+        .create_role(&mut db.client, &auth.actor()?, &request.name,
+                     &request.display_name, &request.description)
+        .await
+        .map_err(|err| match err {
+            QueryError::DuplicatedRole => ApiError::DuplicatedRole,
+            e => internal_server_error!(e),
+        })?;
+    respond(role)
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct RidPath {
+    #[validate]
+    rid: Id,
+}
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateRoleRequest {
+    #[validate]
+    name: Option<RoleName>,
+    #[validate(length(min = 1, max = 64, message = "should have 1 to 64 chars"))]
+    display_name: Option<String>,
+    #[validate(length(max = 1024, message = "should have at most 1024 chars"))]
+    description: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateRoleResponse {
+    updated_at: DateTime<Utc>,
+}
+
+async fn update_role(
+    app_data: web::Data<AppState>,
+    rid_path: ValidatedPath<RidPath>,
+    request: ValidatedJson<UpdateRoleRequest>,
+    auth: Auth,
+) -> ApiResult<UpdateRoleResponse> {
+    auth.try_permission("role", "update")?;
+    let rid: i32 = rid_path.rid.clone().into();
+    let request = request.into_inner();
+    let name: Option<String> = request.name.map(|x| x.into());
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let updated_at = db.query.role
+        .update_role(&mut db.client, &auth.actor()?, rid, &name,
+                     &request.display_name, &request.description)
+        .await
+        .map_err(|err| match err {
+            QueryError::RoleNotFound => ApiError::RoleNotFound,
+            QueryError::DuplicatedRole => ApiError::DuplicatedRole,
+            e => internal_server_error!(e),
+        })?;
+    app_data.send(RoleUpdated {
+        id: rid,
+        name,
+        display_name: request.display_name,
+        description: request.description,
+        updated_at,
+    }, &auth)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(UpdateRoleResponse {
+        updated_at,
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeleteRoleResponse {
+    deleted_at: DateTime<Utc>,
+}
+
+// Connected users holding the role are told it is gone, and subscribers of the links are told
+// about each removed one, so that their permission trees are updated
+async fn delete_role(
+    app_data: web::Data<AppState>,
+    rid_path: ValidatedPath<RidPath>,
+    auth: Auth,
+) -> ApiResult<DeleteRoleResponse> {
+    auth.try_permission("role", "delete")?;
+    let rid: i32 = rid_path.rid.clone().into();
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let deleted = db.query.role
+        .soft_delete_role(&mut db.client, &auth.actor()?, rid)
+        .await
+        .map_err(|err| match err {
+            QueryError::RoleNotFound => ApiError::RoleNotFound,
+            e => internal_server_error!(e),
+        })?;
+    let messages = iter::once(InnerInternalMessage::from(RoleDeleted { id: rid }))
+        .chain(deleted.users.iter()
+            .map(|&user| UserRoleDeleted {
+                user,
+                role: rid,
+            }.into()))
+        .chain(deleted.permissions.iter()
+            .map(|&permission| RolePermissionDeleted {
+                role: rid,
+                permission,
+            }.into()))
+        .collect::<Vec<_>>();
+    app_data.send_all(messages, &auth)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(DeleteRoleResponse {
+        deleted_at: deleted.deleted_at,
+    })
+}
+
+// A permission by its id, or by its subject and action like {"subject": "user", "action": "read"}
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PermissionRef {
+    Id(i32),
+    Pair {
+        subject: String,
+        action: String,
+    },
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct UpdateRolePermissionsRequest {
+    permissions: Vec<PermissionRef>,
+}
+
+async fn resolve_permissions(
+    db: &Connection, permissions: &[PermissionRef],
+) -> Result<Vec<i32>, ApiError> {
+    let mut ids = Vec::with_capacity(permissions.len());
+    for permission in permissions {
+        ids.push(match permission {
+            PermissionRef::Id(id) => *id,
+            PermissionRef::Pair { subject, action } => db.query.role
+                .resolve_permission(&db.client, subject, action)
+                .await
+                .map_err(|err| match err {
+                    QueryError::PermissionNotFound { subject, action } =>
+                        ApiError::PermissionNotFound { subject, action },
+                    e => internal_server_error!(e),
+                })?,
+        });
+    }
+    Ok(ids)
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateRolePermissionsResponse {
+    changed: Vec<i32>,
+}
+
+// Connected users with the role reload their permission trees, and with them their
+// subscriptions, from the messages
+async fn add_role_permissions(
+    app_data: web::Data<AppState>,
+    rid_path: ValidatedPath<RidPath>,
+    request: ValidatedJson<UpdateRolePermissionsRequest>,
+    auth: Auth,
+) -> ApiResult<UpdateRolePermissionsResponse> {
+    auth.try_permission("role", "update")?;
+    let rid: i32 = rid_path.rid.clone().into();
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let permissions = resolve_permissions(db, &request.permissions).await?;
+    let added = db.query.role
+        .add_permissions_to_role(&mut db.client, &auth.actor()?, rid, &permissions)
+        .await
+        .map_err(|err| match err {
+            QueryError::RoleNotFound => ApiError::RoleNotFound,
+            QueryError::PermissionsNotFound { ids } => ApiError::PermissionsNotFound { ids },
+            e => internal_server_error!(e),
+        })?;
+    let changed = added.iter().map(|x| x.id).collect();
+    let messages = added.into_iter()
+        .map(|x| InternalRolePermissionCreated {
+            role: rid,
+            permission: x.id,
+            subject: x.subject,
+            action: x.action,
+        }.into())
+        .collect::<Vec<_>>();
+    if !messages.is_empty() {
+        app_data.send_all(messages, &auth)
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
+    respond(UpdateRolePermissionsResponse {
+        changed,
+    })
+}
+
+async fn remove_role_permissions(
+    app_data: web::Data<AppState>,
+    rid_path: ValidatedPath<RidPath>,
+    request: ValidatedJson<UpdateRolePermissionsRequest>,
+    auth: Auth,
+) -> ApiResult<UpdateRolePermissionsResponse> {
+    auth.try_permission("role", "update")?;
+    let rid: i32 = rid_path.rid.clone().into();
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let permissions = resolve_permissions(db, &request.permissions).await?;
+    let removed = db.query.role
+        .remove_permissions_from_role(&mut db.client, &auth.actor()?, rid, &permissions)
+        .await
+        .map_err(|err| match err {
+            QueryError::RoleNotFound => ApiError::RoleNotFound,
+            QueryError::PermissionsNotFound { ids } => ApiError::PermissionsNotFound { ids },
+            e => internal_server_error!(e),
+        })?;
+    let changed = removed.iter().map(|x| x.id).collect::<Vec<_>>();
+    if !changed.is_empty() {
+        app_data.send_all(
+            changed.iter()
+                .map(|&permission| RolePermissionDeleted {
+                    role: rid,
+                    permission,
+                }.into())
+                .collect(),
+            &auth
+        )
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
+    respond(UpdateRolePermissionsResponse {
+        changed,
+    })
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct RidParentPath {
+    #[validate]
+    rid: Id,
+    #[validate]
+    parent: Id,
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateRoleParentResponse {
+    changed: bool,
+}
+
+// Connected users holding the role fetch their permissions again, and the cached ones are dropped
+async fn add_role_parent(
+    app_data: web::Data<AppState>,
+    path: ValidatedPath<RidParentPath>,
+    auth: Auth,
+) -> ApiResult<UpdateRoleParentResponse> {
+    auth.try_permission("role", "update")?;
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let changed = db.query.role
+        .add_parent_to_role(&mut db.client, &auth.actor()?,
+                            path.rid.clone().into(), path.parent.clone().into())
+        .await
+        .map_err(|err| match err {
+            QueryError::RoleNotFound => ApiError::RoleNotFound,
+            QueryError::RoleCycle => ApiError::RoleCycle,
+            e => internal_server_error!(e),
+        })?;
+    if changed {
+        app_data.send(InternalRoleParentUpdated {
+            role: path.rid.clone().into(),
+            parent: path.parent.clone().into(),
+        }, &auth)
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
+    respond(UpdateRoleParentResponse {
+        changed,
+    })
+}
+
+async fn remove_role_parent(
+    app_data: web::Data<AppState>,
+    path: ValidatedPath<RidParentPath>,
+    auth: Auth,
+) -> ApiResult<UpdateRoleParentResponse> {
+    auth.try_permission("role", "update")?;
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let changed = db.query.role
+        .remove_parent_from_role(&mut db.client, &auth.actor()?,
+                                 path.rid.clone().into(), path.parent.clone().into())
+        .await
+        .map_err(|err| match err {
+            QueryError::RoleNotFound => ApiError::RoleNotFound,
+            e => internal_server_error!(e),
+        })?;
+    if changed {
+        app_data.send(InternalRoleParentUpdated {
+            role: path.rid.clone().into(),
+            parent: path.parent.clone().into(),
+        }, &auth)
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
+    respond(UpdateRoleParentResponse {
+        changed,
+    })
+}
+
+pub fn roles_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
+    let state = state.clone();
+    Box::new(move |cfg| {
+        cfg.service(
+            web::scope("roles")
+                .app_data(state)
+                .app_data(default_json_config())
+                .app_data(default_path_config())
+                .route("/{rid}/permissions", web::post().to(add_role_permissions))
+                .route("/{rid}/permissions", web::delete().to(remove_role_permissions))
+                .route("/{rid}/parents/{parent}", web::put().to(add_role_parent))
+                .route("/{rid}/parents/{parent}", web::delete().to(remove_role_parent))
+                .route("/{rid}", web::patch().to(update_role))
+                .route("/{rid}", web::delete().to(delete_role))
+                .route("", web::post().to(create_role))
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{api_v1, testing::TestApp};
+    use actix_web::{App, http::StatusCode, test};
+
+    #[actix_rt::test]
+    async fn role_crud_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, admin) = app.user("admin", &["permission-admin"]).await;
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let create = |authorization: &str| test::TestRequest::post()
+            .uri("/api/v1/roles")
+            .header("Authorization", authorization)
+            .set_json(&serde_json::json!({
+                "name": "editor",
+                "displayName": "Editor",
+                "description": "Edits posts",
+            }))
+            .to_request();
+        let response = test::call_service(&mut service, create(&alice)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, create(&admin)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let rid = response["data"]["id"].as_i64().unwrap();
+        let response = test::call_service(&mut service, create(&admin)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = test::call_service(&mut service, test::TestRequest::patch()
+            .uri(&format!("/api/v1/roles/{}", rid))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "displayName": "" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&mut service, test::TestRequest::patch()
+            .uri(&format!("/api/v1/roles/{}", rid))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "displayName": "Post Editor" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let delete = || test::TestRequest::delete()
+            .uri(&format!("/api/v1/roles/{}", rid))
+            .header("Authorization", admin.clone())
+            .to_request();
+        let response = test::call_service(&mut service, delete()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, delete()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn update_role_permissions_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, admin) = app.user("admin", &["permission-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let rid: i32 = app.db.client
+            .query_one("SELECT id FROM role WHERE name = 'normal-user'", &[])
+            .await
+            .unwrap()
+            .get(0);
+        let pid: i32 = app.db.client
+            .query_one("SELECT id FROM permission WHERE subject = 'user' AND action = 'list'", &[])
+            .await
+            .unwrap()
+            .get(0);
+        let request = |method: test::TestRequest, permissions: serde_json::Value| method
+            .uri(&format!("/api/v1/roles/{}/permissions", rid))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "permissions": permissions }))
+            .to_request();
+        let response = test::call_service(&mut service, request(test::TestRequest::post(),
+                                                                serde_json::json!([pid, -1])))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["ids"], serde_json::json!([-1]));
+        let unknown = serde_json::json!([{ "subject": "user", "action": "fly" }]);
+        let response = test::call_service(&mut service,
+                                          request(test::TestRequest::post(), unknown)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let by_id = serde_json::json!([pid]);
+        let by_pair = serde_json::json!([{ "subject": "user", "action": "list" }]);
+        for (method, permissions) in [(test::TestRequest::post(), by_pair.clone()),
+                                          (test::TestRequest::delete(), by_id.clone()),
+                                          (test::TestRequest::post(), by_id),
+                                          (test::TestRequest::delete(), by_pair)] {
+            let response = test::call_service(&mut service, request(method, permissions)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response: serde_json::Value =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            assert_eq!(response["data"]["changed"], serde_json::json!([pid]));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn update_role_parent_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, admin) = app.user("admin", &["permission-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let client = &app.db.client;
+        let role_id = |name: &'static str| async move {
+            client
+                .query_one("SELECT id FROM role WHERE name = $1", &[&name])
+                .await
+                .unwrap()
+                .get::<_, i32>(0)
+        };
+        let (normal, admin_role) = (role_id("normal-user").await, role_id("user-admin").await);
+        let request = |method: test::TestRequest, rid: i32, parent: i32| method
+            .uri(&format!("/api/v1/roles/{}/parents/{}", rid, parent))
+            .header("Authorization", admin.clone())
+            .to_request();
+        let response = test::call_service(&mut service,
+                                          request(test::TestRequest::put(), admin_role, normal))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["changed"], true);
+        let response = test::call_service(&mut service,
+                                          request(test::TestRequest::put(), normal, admin_role))
+            .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = test::call_service(&mut service,
+                                          request(test::TestRequest::delete(), admin_role, normal))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
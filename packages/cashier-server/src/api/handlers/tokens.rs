@@ -2,22 +2,25 @@ use crate::{
     api::{
         extractors::{
             auth::Auth,
-            config::default_json_config,
+            config::{default_json_config, default_path_config},
         },
         errors::{ApiError, ApiResult, respond},
         app_state::AppState,
+        login_limiter::login_keys,
         fields::{
             Username,
             Password,
             Email,
+            Any6,
         },
         fields::{Id}
     },
     queries::{
         tokens::Token,
         errors::Error as QueryError,
-        users::EitherUsernameOrEmail,
+        users::{EitherUsernameOrEmail, PasswordUpgrade},
     },
+    config::{UsernameConfig, PasswordConfig, NicknameConfig},
     websocket::push_messages::{TokenAcquired, TokenRevoked},
     internal_server_error,
 };
@@ -30,10 +33,14 @@ use serde::{Serialize, Deserialize};
 use validator::Validate;
 use validator_derive::Validate;
 use chrono::{DateTime, NaiveDateTime, Utc};
+use log::warn;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Serialize)]
 struct AcquireTokenResponse {
     jwt: String,
+    // Trades for a new pair of tokens via POST /tokens/refresh, once only
+    refresh_token: String,
 }
 
 #[derive(Debug, Validate, Deserialize)]
@@ -42,6 +49,9 @@ pub struct AcquireTokenByUsernameRequest {
     username: Username,
     #[validate]
     password: Password,
+    // Required by users with TOTP enabled
+    #[validate]
+    totp_code: Option<Any6>,
 }
 
 async fn acquire_token_impl_impl(
@@ -50,19 +60,24 @@ async fn acquire_token_impl_impl(
     uid: i32,
     method: &str,
 ) -> std::result::Result<(AcquireTokenResponse, TokenAcquired), ApiError> {
-    let connection_info = req.connection_info();
+    let connection_info = req.connection_info().clone();
     let user_agent = req.headers().get("User-Agent")
         .map(HeaderValue::to_str)
-        .map(std::result::Result::ok)
-        .flatten();
-    let (jwt, claims) = app_data.query.token
-        .create_token(&*app_data.db.read().await, uid, method,
+        .and_then(std::result::Result::ok);
+    let db = app_data.db().await?;
+    let (jwt, claims) = db.query.token
+        .create_token(&db.client, uid, method,
                       connection_info.host(), connection_info.remote(),
                       user_agent)
         .await
         .map_err(|e| internal_server_error!(e))?;
+    let refresh_token = db.query.token
+        .create_refresh_token(&db.client, claims.jti, uid)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
     Ok((AcquireTokenResponse {
         jwt,
+        refresh_token,
     }, TokenAcquired(Token {
         id: claims.jti,
         user: uid,
@@ -75,6 +90,68 @@ async fn acquire_token_impl_impl(
     })))
 }
 
+// Failed logins are limited per client IP and per identifier, see LoginLimiter
+async fn check_user_valid(
+    app_data: &web::Data<AppState>,
+    req: &web::HttpRequest,
+    identifier: &str,
+    credit: &EitherUsernameOrEmail,
+    password: &str,
+    totp_code: Option<&str>,
+) -> std::result::Result<i32, ApiError> {
+    let keys = login_keys(app_data.login_limiter.client_ip(req), identifier);
+    app_data.login_limiter
+        .acquire(&keys)
+        .map_err(|retry_after| ApiError::TooManyLoginAttempts { retry_after })?;
+    let result = verify_credentials(app_data, credit, password, totp_code).await;
+    if !matches!(result, Err(ApiError::WrongUserOrPassword) | Err(ApiError::WrongTotpCode)) {
+        app_data.login_limiter.release(&keys);
+    }
+    result
+}
+
+async fn verify_credentials(
+    app_data: &web::Data<AppState>,
+    credit: &EitherUsernameOrEmail,
+    password: &str,
+    totp_code: Option<&str>,
+) -> std::result::Result<i32, ApiError> {
+    let db = app_data.db().await?;
+    let (uid, upgrade) = db.query.user
+        .check_user_valid(&db.client, credit, password, totp_code)
+        .await
+        .map_err(|e| match e {
+            QueryError::UserNotFound | QueryError::WrongPassword =>
+                ApiError::WrongUserOrPassword,
+            QueryError::UserBlocked => ApiError::UserBlocked,
+            QueryError::TotpRequired => ApiError::TotpRequired,
+            QueryError::WrongTotpCode => ApiError::WrongTotpCode,
+            _ => { internal_server_error!(e) }
+        })?;
+    if let Some(upgrade) = upgrade {
+        actix_rt::spawn(upgrade_password_in_background(app_data.clone(), upgrade));
+    }
+    Ok(uid)
+}
+
+// Rehashes a weak password hash with a connection of its own, after the login has returned.
+// Failures are only logged, the old hash still verifies
+async fn upgrade_password_in_background(
+    app_data: web::Data<AppState>,
+    upgrade: PasswordUpgrade,
+) {
+    let uid = upgrade.id;
+    let result = match app_data.pool.get().await {
+        Ok(db) => db.query.user
+            .upgrade_password(&db.client, upgrade)
+            .await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("failed to upgrade password hash of user {}: {}", uid, e);
+    }
+}
+
 async fn acquire_token_impl(
     app_data: &web::Data<AppState>,
     req: &web::HttpRequest,
@@ -97,16 +174,11 @@ async fn acquire_token_by_username(
     req: web::HttpRequest,
 ) -> ApiResult<AcquireTokenResponse> {
     auth.try_permission("token", "acquire-by-username")?;
-    let uid = app_data.query.user
-        .check_user_valid(&*app_data.db.read().await,
-                          &EitherUsernameOrEmail::Username(data.username.clone().into()),
-                          &data.password)
-        .await
-        .map_err(|e| match e {
-            QueryError::UserNotFound | QueryError::WrongPassword => ApiError::WrongUserOrPassword,
-            QueryError::UserBlocked => ApiError::UserBlocked,
-            _ => { internal_server_error!(e) }
-        })?;
+    let uid = check_user_valid(
+        &app_data, &req, &data.username,
+        &EitherUsernameOrEmail::Username(data.username.clone().into()),
+        &data.password, data.totp_code.as_ref().map(|x| &x[..]),
+    ).await?;
     acquire_token_impl(&app_data, &req, &auth, uid, "username")
         .await
 }
@@ -117,6 +189,8 @@ pub struct AcquireTokenByEmailRequest {
     email: Email,
     #[validate]
     password: Password,
+    #[validate]
+    totp_code: Option<Any6>,
 }
 
 async fn acquire_token_by_email(
@@ -126,30 +200,59 @@ async fn acquire_token_by_email(
     req: web::HttpRequest,
 ) -> ApiResult<AcquireTokenResponse> {
     auth.try_permission("token", "acquire-by-email")?;
-    let uid = app_data.query.user
-        .check_user_valid(&*app_data.db.read().await,
-                          &EitherUsernameOrEmail::Email(data.email.clone().into()),
-                          &data.password)
-        .await
-        .map_err(|e| match e {
-            QueryError::UserNotFound | QueryError::WrongPassword => ApiError::WrongUserOrPassword,
-            QueryError::UserBlocked => ApiError::UserBlocked,
-            _ => { internal_server_error!(e) }
-        })?;
+    let uid = check_user_valid(
+        &app_data, &req, &data.email,
+        &EitherUsernameOrEmail::Email(data.email.clone().into()),
+        &data.password, data.totp_code.as_ref().map(|x| &x[..]),
+    ).await?;
     acquire_token_impl(&app_data, &req, &auth, uid, "email").await
 }
 
+#[derive(Debug, Validate, Deserialize)]
+pub struct AcquireTokenRequest {
+    // Either a username or an email, told apart by EitherUsernameOrEmail::detect
+    #[validate(length(min = 1, message = "should not be empty"))]
+    identifier: String,
+    #[validate]
+    password: Password,
+    #[validate]
+    totp_code: Option<Any6>,
+}
+
+async fn acquire_token(
+    app_data: web::Data<AppState>,
+    data: ValidatedJson<AcquireTokenRequest>,
+    auth: Auth,
+    req: web::HttpRequest,
+) -> ApiResult<AcquireTokenResponse> {
+    let credit = EitherUsernameOrEmail::detect(&data.identifier);
+    let method = match credit {
+        EitherUsernameOrEmail::Username(_) => "username",
+        EitherUsernameOrEmail::Email(_) => "email",
+    };
+    auth.try_permission("token", &format!("acquire-by-{}", method))?;
+    let uid = check_user_valid(&app_data, &req, &data.identifier, &credit,
+                               &data.password, data.totp_code.as_ref().map(|x| &x[..]))
+        .await?;
+    acquire_token_impl(&app_data, &req, &auth, uid, method).await
+}
+
 async fn resume_token(
     app_data: web::Data<AppState>,
     auth: Auth,
     req: web::HttpRequest,
 ) -> ApiResult<AcquireTokenResponse> {
     auth.try_permission("token", "resume")?;
-    let claims = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?;
-    app_data.query.token
-        .revoke_token(&*app_data.db.read().await, claims.jti, None)
-        .await
-        .map_err(|e| internal_server_error!(e))?;
+    auth.try_not_impersonated()?;
+    let claims = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?;
+    {
+        // Handed back before acquire_token_impl_impl takes one
+        let db = app_data.db().await?;
+        db.query.token
+            .revoke_token(&db.client, claims.jti, None)
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
     let (response, msg) = acquire_token_impl_impl(
         &app_data, &req, claims.uid, "resume").await?;
     app_data.send_all(vec![
@@ -164,6 +267,78 @@ async fn resume_token(
     respond(response)
 }
 
+#[derive(Debug, Validate, Deserialize)]
+struct RefreshTokenRequest {
+    #[validate(length(equal = 48, message = "should have 48 chars"))]
+    refresh_token: String,
+}
+
+// Takes no access token, which has usually expired by then
+async fn refresh_token(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<RefreshTokenRequest>,
+    auth: Auth,
+    req: web::HttpRequest,
+) -> ApiResult<AcquireTokenResponse> {
+    auth.try_permission("token", "refresh")?;
+    let connection_info = req.connection_info().clone();
+    let user_agent = req.headers().get("User-Agent")
+        .map(HeaderValue::to_str)
+        .and_then(std::result::Result::ok);
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let result = db.query.token
+        .rotate_refresh_token(&mut db.client, &request.refresh_token,
+                              connection_info.host(), connection_info.remote(), user_agent)
+        .await;
+    let result = match result {
+        Ok(result) => result,
+        Err(QueryError::RefreshTokenReused { revoked }) => {
+            app_data.send_all(
+                revoked.into_iter()
+                    .map(|result| TokenRevoked {
+                        jti: result.id,
+                        uid: result.user,
+                    }.into())
+                    .collect(),
+                &auth
+            )
+                .await
+                .map_err(|e| internal_server_error!(e))?;
+            return Err(ApiError::InvalidRefreshToken { reason: "Reused".into() });
+        }
+        Err(err) => return Err(match err {
+            QueryError::RefreshTokenNotFound => ApiError::InvalidRefreshToken { reason: "NotFound".into() },
+            QueryError::RefreshTokenExpired => ApiError::InvalidRefreshToken { reason: "Expired".into() },
+            QueryError::UserBlocked => ApiError::UserBlocked,
+            e => internal_server_error!(e),
+        }),
+    };
+    let claims = result.claims;
+    app_data.send_all(vec![
+        TokenRevoked {
+            jti: result.superseded.id,
+            uid: result.superseded.user,
+        }.into(),
+        TokenAcquired(Token {
+            id: claims.jti,
+            user: claims.uid,
+            issued_at: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(claims.iat, 0), Utc),
+            expires_at: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(claims.exp, 0), Utc),
+            acquire_method: "refresh".into(),
+            acquire_host: connection_info.host().into(),
+            acquire_remote: connection_info.remote().map(String::from),
+            acquire_user_agent: user_agent.map(String::from),
+        }).into(),
+    ], &auth)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(AcquireTokenResponse {
+        jwt: result.jwt,
+        refresh_token: result.refresh_token,
+    })
+}
+
 // #[derive(Debug, Serialize)]
 // struct ListTokenResponse {
 //     tokens: Vec<Token>,
@@ -174,9 +349,10 @@ async fn resume_token(
 //     auth: Auth,
 // ) -> ApiResult<ListTokenResponse> {
 //     auth.try_permission("token", "list-self")?;
-//     let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
-//     let tokens = app_data.query.token
-//         .find_tokens_from_user(&*app_data.db.read().await, uid)
+//     let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+//     let db = app_data.db().await?;
+//     let tokens = db.query.token
+//         .find_tokens_from_user(&db.client, uid)
 //         .await
 //         .map_err(|e| internal_server_error!(e))?;
 //     respond(ListTokenResponse {
@@ -194,8 +370,9 @@ async fn revoke_token_by_user_impl(
     auth: Auth,
     uid: i32,
 ) -> ApiResult<RevokeTokenResponse> {
-    let results = app_data.query.token
-        .revoke_tokens_from_user(&*app_data.db.read().await, uid)
+    let db = app_data.db().await?;
+    let results = db.query.token
+        .revoke_tokens_from_user(&db.client, uid)
         .await
         .map_err(|e| internal_server_error!(e))?;
     let count = results.len();
@@ -220,7 +397,7 @@ async fn revoke_token_for_me(
     auth: Auth,
 ) -> ApiResult<RevokeTokenResponse> {
     auth.try_permission("token", "revoke-self")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
     revoke_token_by_user_impl(app_data, auth, uid).await
 }
 
@@ -252,8 +429,9 @@ async fn revoke_single_token_impl(
     jti: i32,
     uid: Option<i32>,
 ) -> ApiResult<()> {
-    let result = app_data.query.token
-        .revoke_token(&*app_data.db.read().await, jti, uid)
+    let db = app_data.db().await?;
+    let result = db.query.token
+        .revoke_token(&db.client, jti, uid)
         .await
         .map_err(|err| match err {
             QueryError::TokenNotFound => ApiError::TokenNotFound,
@@ -283,7 +461,7 @@ async fn revoke_single_token_for_me(
     auth: Auth,
 ) -> ApiResult<()> {
     auth.try_permission("token", "revoke-single")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
     revoke_single_token_impl(app_data, auth, jti_path.jti.clone().into(), Some(uid)).await
 }
 
@@ -292,22 +470,82 @@ async fn revoke_this_token_for_me(
     auth: Auth,
 ) -> ApiResult<()> {
     auth.try_permission("token", "revoke-single")?;
-    let claims = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?;
+    let claims = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?;
     let jti = claims.jti;
     let uid = claims.uid;
     revoke_single_token_impl(app_data, auth, jti, Some(uid)).await
 }
 
+#[derive(Debug, Serialize)]
+struct WhoamiResponse {
+    uid: i32,
+    jti: i32,
+    expires_at: DateTime<Utc>,
+    roles: Vec<String>,
+    // subject -> actions
+    permissions: BTreeMap<String, Vec<String>>,
+}
+
+// Needs no permission, as it tells the caller only about its own token
+async fn whoami(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<WhoamiResponse> {
+    let claims = auth.claims.as_ref().ok_or(ApiError::NotAuthenticated)?;
+    let db = app_data.db().await?;
+    let roles = db.query.user
+        .fetch_role_name(&db.client, claims.uid)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    let mut permissions = BTreeMap::new();
+    for permission in auth.permissions.iter() {
+        permissions.entry(permission.subject.clone())
+            .or_insert_with(Vec::new)
+            .push(permission.action.clone());
+    }
+    for actions in permissions.values_mut() {
+        actions.sort();
+    }
+    respond(WhoamiResponse {
+        uid: claims.uid,
+        jti: claims.jti,
+        expires_at: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(claims.exp, 0), Utc),
+        roles,
+        permissions,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct AuthConfigResponse {
+    username: UsernameConfig,
+    password: PasswordConfig,
+    nickname: NicknameConfig,
+}
+
+// The rules enforced on user fields, so that clients can validate with the same rules
+async fn read_auth_config(
+    app_data: web::Data<AppState>,
+) -> ApiResult<AuthConfigResponse> {
+    respond(AuthConfigResponse {
+        username: app_data.config.username.clone(),
+        password: app_data.config.password.clone(),
+        nickname: app_data.config.nickname.clone(),
+    })
+}
+
 pub fn tokens_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
     let state = state.clone();
     Box::new(move |cfg| {
         cfg.service(
             web::scope("/tokens")
-                .app_data(state)
+                .app_data(state.clone())
                 .app_data(default_json_config())
+                .app_data(default_path_config())
                 .route("/acquire-by-username", web::post().to(acquire_token_by_username))
                 .route("/acquire-by-email", web::post().to(acquire_token_by_email))
+                .route("/acquire", web::post().to(acquire_token))
                 .route("/resume", web::post().to(resume_token))
+                .route("/refresh", web::post().to(refresh_token))
                 // .route("/users/me", web::get().to(list_token_for_me))
                 .route("/users/me", web::delete().to(revoke_token_for_me))
                 // .route("/users/{uid}", web::get().to(list_token_by_uid))
@@ -318,6 +556,212 @@ pub fn tokens_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::Servi
                 .route("/my-jwt/this", web::delete().to(revoke_this_token_for_me))
                 // .route("/my-jwt/{jti}", web::get().to(read_token_for_me_by_jti))
                 .route("/my-jwt/{jti}", web::delete().to(revoke_single_token_for_me))
+        ).service(
+            web::scope("/auth")
+                .app_data(state)
+                .route("/whoami", web::get().to(whoami))
+                .route("/config", web::get().to(read_auth_config))
         );
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{api_v1, testing::TestApp};
+    use actix_web::{App, http::StatusCode, test};
+
+    #[actix_rt::test]
+    async fn whoami_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, authorization) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/auth/whoami")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/auth/whoami")
+            .header("Authorization", authorization)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let token = app.db.client
+            .query_one("SELECT id, expires_at FROM token WHERE \"user\" = $1", &[&uid])
+            .await
+            .unwrap();
+        assert_eq!(response["data"]["uid"], uid);
+        assert_eq!(response["data"]["jti"], token.get::<_, i32>("id"));
+        assert_eq!(response["data"]["expires_at"], serde_json::to_value(
+            token.get::<_, chrono::DateTime<chrono::Utc>>("expires_at")).unwrap());
+        assert_eq!(response["data"]["roles"], serde_json::json!(["default", "normal-user"]));
+        let actions = response["data"]["permissions"]["user-email-updating"].as_array().unwrap();
+        assert!(actions.contains(&serde_json::json!("confirm-self")));
+        assert!(actions.contains(&serde_json::json!("confirm-link")));
+        assert!(response["data"]["permissions"].get("role").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn acquire_token_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        app.user("alice", &["normal-user"]).await;
+        // Usernames are only checked against the rules by the handlers
+        {
+            let mut db = app.app_data.db().await.unwrap();
+            let db = &mut *db;
+            db.query.user
+                .insert_one(&mut db.client, "bob@example.org", "password", &[], None, &None,
+                            &None)
+                .await
+                .unwrap();
+        }
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let acquire = |identifier: &str, password: &str| test::TestRequest::post()
+            .uri("/api/v1/tokens/acquire")
+            .set_json(&serde_json::json!({ "identifier": identifier, "password": password }))
+            .to_request();
+        for &identifier in &["alice", "alice@example.com", "bob@example.org"] {
+            let response = test::call_service(&mut service, acquire(identifier, "password")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = test::call_service(&mut service, acquire("alice", "wrong-password")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(&mut service, acquire("carol@example.com", "password"))
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn upgrade_password_test() {
+        let app = match TestApp::new(&["--bcrypt-cost", "5"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        let weak = bcrypt::hash("password", 4).unwrap();
+        app.db.client
+            .execute("UPDATE \"user\" SET password = $1 WHERE id = $2", &[&weak, &uid])
+            .await
+            .unwrap();
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/tokens/acquire")
+            .set_json(&serde_json::json!({ "identifier": "alice", "password": "password" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Rehashed after the login has returned
+        let mut hash = weak.clone();
+        for _ in 0..100 {
+            hash = app.db.client
+                .query_one("SELECT password FROM \"user\" WHERE id = $1", &[&uid])
+                .await
+                .unwrap()
+                .get(0);
+            if hash != weak {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(hash.split('$').nth(2), Some("05"));
+    }
+
+    #[actix_rt::test]
+    async fn access_token_ttl_test() {
+        let app = match TestApp::new(&["--access-token-ttl", "60"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        let token = app.db.client
+            .query_one("SELECT issued_at, expires_at FROM token WHERE \"user\" = $1", &[&uid])
+            .await
+            .unwrap();
+        let issued_at: chrono::DateTime<chrono::Utc> = token.get("issued_at");
+        let expires_at: chrono::DateTime<chrono::Utc> = token.get("expires_at");
+        assert_eq!(expires_at - issued_at, chrono::Duration::seconds(60));
+    }
+
+    #[actix_rt::test]
+    async fn login_limit_test() {
+        let app = match TestApp::new(&["--login-limit-attempts", "2"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let acquire = |password: &str| test::TestRequest::post()
+            .uri("/api/v1/tokens/acquire")
+            .set_json(&serde_json::json!({ "identifier": "alice", "password": password }))
+            .to_request();
+        // Successful logins do not count
+        for _ in 0..3 {
+            let response = test::call_service(&mut service, acquire("password")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        for _ in 0..2 {
+            let response = test::call_service(&mut service, acquire("wrong-password")).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = test::call_service(&mut service, acquire("password")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers().get("Retry-After").unwrap()
+            .to_str().unwrap().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 150);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["type"], "TooManyLoginAttempts");
+    }
+
+    #[actix_rt::test]
+    async fn refresh_token_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/tokens/acquire-by-username")
+            .set_json(&serde_json::json!({ "username": "alice", "password": "password" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let first = response["data"]["refresh_token"].as_str().unwrap().to_string();
+        let refresh = |refresh_token: &str| test::TestRequest::post()
+            .uri("/api/v1/tokens/refresh")
+            .set_json(&serde_json::json!({ "refresh_token": refresh_token }))
+            .to_request();
+        let response = test::call_service(&mut service, refresh(&first)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let second = response["data"]["refresh_token"].as_str().unwrap().to_string();
+        let client = &app.db.client;
+        let live_tokens = || async move {
+            client
+                .query_one("SELECT COUNT(*) FROM token \
+                            WHERE \"user\" = $1 AND NOT revoked AND acquire_method <> 'test'",
+                           &[&uid])
+                .await
+                .unwrap()
+                .get::<_, i64>(0)
+        };
+        assert_eq!(live_tokens().await, 1);
+        // Replaying the first token revokes the chain, the second token included
+        let response = test::call_service(&mut service, refresh(&first)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["reason"], "Reused");
+        assert_eq!(live_tokens().await, 0);
+        let response = test::call_service(&mut service, refresh(&second)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -2,15 +2,20 @@ use crate::{
     api::{
         extractors::{
             auth::Auth,
+            locale::AcceptLanguage,
             multer::Multer,
             config::{
                 default_json_config,
                 default_path_config,
+                default_query_config,
                 avatar_multer_config,
             },
         },
-        errors::{ApiError, ApiResult, respond},
-        app_state::AppState,
+        errors::{ApiError, ApiResult, ApiResultWrapper, BulkResults, respond},
+        handlers::media::{avatar_url, etag_matches},
+        metrics::RequestMetrics,
+        app_state::{AppState, AvatarProcessing},
+        avatar_store::AvatarStoreError,
         fields::{
             Username,
             Password,
@@ -26,34 +31,57 @@ use crate::{
         },
     },
     queries::{
+        email::{Locale, escape_html},
         errors::Error as QueryError,
+        tokens::{Token, TokenInfo},
         users::{
             UserAccessLevel, RoleAccessLevel, PermissionAccessLevel,
-            User, Role, Permission, UserRegistrationPublic,
+            User, Role, Permission, UserRegistrationPublic, PermissionSubjectAction, UserChange,
+            UserWithoutRoles, UserPublic, RoleShort, RoleAssignment, UserAvatars, AvatarThumbnails,
+            mask_email,
         },
     },
-    websocket::push_messages::{UserCreated, UserUpdated, TokenRevoked, double_option},
+    websocket::push_messages::{
+        UserCreated, UserUpdated, UserDeleted, TokenAcquired, TokenRevoked, InternalUserRoleCreated,
+        UserRoleDeleted, PermissionIdSubjectAction, InnerInternalMessage, double_option,
+    },
+    config::{AvatarFormat, AvatarStoreKind},
     internal_server_error,
+    retry_db,
 };
 use actix_web::{
     web::{self, block},
-    error::BlockingError,
+    client::Client,
+    error::{BlockingError, ResponseError},
+    http::{HeaderValue, StatusCode, Uri},
+    HttpRequest,
+    HttpResponse,
 };
 use actix_web_validator::{ValidatedJson, ValidatedPath, ValidatedQuery};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use image::{
+    DynamicImage,
     GenericImageView,
+    ImageFormat,
+    ImageOutputFormat,
     error::ImageError,
+    io::Reader,
 };
 use log::error;
 use rand::{Rng, thread_rng, distributions::Alphanumeric};
+use ring::digest;
 use serde::{Serialize, Deserialize};
 use std::{
-    convert::Infallible,
     iter,
-    path::{Path, PathBuf},
+    io::{self, Cursor},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::Path,
     cmp::Ordering,
+    collections::{HashMap, HashSet},
+    time::Duration,
 };
+use tokio::{net::lookup_host, sync::OwnedSemaphorePermit};
 use validator::Validate;
 use validator_derive::Validate;
 use crate::queries::users::UserEmailUpdatingPublic;
@@ -77,6 +105,8 @@ struct CreateUserRequest {
 struct CreateUserResponse {
     id: i32,
     created_at: DateTime<Utc>,
+    // The roles actually granted, without the names that match no role
+    roles: Vec<String>,
 }
 
 async fn create_user(
@@ -85,13 +115,21 @@ async fn create_user(
     auth: Auth,
 ) -> ApiResult<CreateUserResponse> {
     auth.try_permission("user", "create")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    data.username.check(&app_data.config.username)?;
+    data.password.check(&app_data.config.password)?;
+    if let Some(nickname) = &data.nickname {
+        nickname.check(&app_data.config.nickname)?;
+    }
     let mut roles = data.roles.iter()
         .map(|x| x.clone().into())
-        .collect::<Vec<_>>();
+        .collect::<Vec<String>>();
+    roles.sort();
     roles.dedup();
-    let extra_roles = app_data.query.user
-        .check_extra_roles(&*app_data.db.read().await, uid, &roles[..])
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let extra_roles = db.query.user
+        .check_extra_roles(&db.client, uid, &roles[..])
         .await
         .map_err(|e| internal_server_error!(e))?;
     if !extra_roles.is_empty() {
@@ -99,19 +137,20 @@ async fn create_user(
     }
     let email = data.email.as_ref().map(|x| x.clone().into());
     let nickname = data.nickname.as_ref().map(|x| x.clone().into());
-    let user = app_data.query.user
-        .insert_one(&mut *app_data.db.write().await,
-                    &data.username[..], &data.password[..], &roles[..],
+    let user = db.query.user
+        .insert_one(&mut db.client,
+                    &data.username[..], &data.password[..], &roles[..], Some(uid),
                     &email, &nickname)
         .await
         .map_err(|err| match err {
             QueryError::DuplicatedUser { field } => ApiError::DuplicatedUser { field },
+            QueryError::PasswordCompromised => ApiError::PasswordCompromised,
             e => internal_server_error!(e),
         })?;
     app_data.send(UserCreated {
         id: user.id,
         username: String::from(data.username.clone()),
-        roles,
+        roles: user.roles.clone(),
         email,
         created_at: user.created_at,
     }, &auth)
@@ -120,6 +159,7 @@ async fn create_user(
     respond(CreateUserResponse {
         id: user.id,
         created_at: user.created_at,
+        roles: user.roles,
     })
 }
 
@@ -132,129 +172,492 @@ struct UidPath {
 #[derive(Debug, Serialize)]
 struct UploadAvatarResponse {
     avatar: String,
-    avatar128: Option<String>,
+    avatar_thumbnails: AvatarThumbnails,
+    avatar_webp: Option<String>,
+    avatar_thumbnails_webp: AvatarThumbnails,
+    // The avatar is the uploaded file as is until the processing finishes, which is announced by
+    // UserUpdated
+    processing: bool,
+}
+
+// Encoded avatar files by their filenames, not stored yet
+type AvatarFiles = Vec<(String, Vec<u8>)>;
+
+async fn remove_avatar_file(app_data: &AppState, filename: &str) {
+    if let Err(e) = app_data.avatar_store.delete(filename).await {
+        error!("failed to remove avatar {}: {}", filename, e);
+    }
 }
 
-fn join_avatar_file<P1: AsRef<Path>, P2: AsRef<Path>>(root: P1, path: P2) -> PathBuf {
-    Path::new(root.as_ref())
-        .join(crate::constants::AVATAR_FOLDER)
-        .join(path.as_ref())
+// Removes the files stored so far if any of them fails
+async fn put_avatar_files(
+    app_data: &AppState, files: AvatarFiles,
+) -> Result<(), AvatarStoreError> {
+    let mut stored: Vec<String> = Vec::new();
+    for (filename, content) in files {
+        if let Err(e) = app_data.avatar_store.put(&filename, content).await {
+            for filename in &stored {
+                remove_avatar_file(app_data, &filename[..]).await;
+            }
+            return Err(e);
+        }
+        stored.push(filename);
+    }
+    Ok(())
 }
 
-fn join_avatar_url(url: &str, path: &str) -> String {
-    String::from(url) + "/" + crate::constants::AVATAR_FOLDER + "/" + path
+fn random_avatar_filename() -> String {
+    let mut rng = thread_rng();
+    iter::repeat(())
+        .map(|_| rng.sample(Alphanumeric))
+        .take(crate::constants::AVATAR_FILENAME_LENGTH)
+        .collect::<String>()
 }
 
-fn remove_avatar_file<P1: AsRef<Path>, P2: AsRef<Path>>(root: P1, path: P2) {
-    if let Err(e) = std::fs::remove_file(join_avatar_file(root, path)) {
-        error!("failed to remove file {}", e);
+fn avatar_extension(format: AvatarFormat) -> &'static str {
+    match format {
+        AvatarFormat::Png => "png",
+        AvatarFormat::Jpeg { .. } => "jpg",
     }
 }
 
-async fn upload_avatar_impl(
-    app_data: web::Data<AppState>,
-    uid: i32,
-    data: Multer,
-    auth: Auth,
-) -> ApiResult<UploadAvatarResponse> {
-    // Fetch old avatars
-    let old_avatars = app_data.query.user
-        .fetch_avatars(&*app_data.db.read().await, uid)
-        .await
-        .map_err(|err| match err {
-            QueryError::UserNotFound => ApiError::UserNotFound,
-            e => internal_server_error!(e),
-        })?;
-    // Crop and resize new avatars
-    let root = app_data.config.media.root.clone();
-    let (avatar, avatar128) = block(move || {
-        let content = data.get_single("avatar").extra().content().unwrap();
-        let avatar = image::load_from_memory(content)?;
-        let (width, height) = avatar.dimensions();
-        let size = std::cmp::min(width, height);
-        let cropped_avatar = match width.cmp(&height) {
-            Ordering::Less => avatar.crop_imm(0, (height - size) / 2, size, size),
-            Ordering::Greater => avatar.crop_imm((width - size) / 2, 0, size, size),
-            Ordering::Equal => avatar,
-        };
-        let mut rng = thread_rng();
-        let filename = iter::repeat(())
-            .map(|_| rng.sample(Alphanumeric))
-            .take(crate::constants::AVATAR_FILENAME_LENGTH)
-            .collect::<String>();
-        let origin_filename = format!("{}.{}x{}.png", filename, size, size);
-        cropped_avatar.save_with_format(join_avatar_file(&root, &origin_filename),
-                                        image::ImageFormat::Png)?;
-        let thumbnail_filename = if size <= 128 { None } else {
-            let thumbnail = cropped_avatar.resize(128, 128,image::imageops::FilterType::Triangle);
-            let thumbnail_filename = filename + ".thumb.128x128.png";
-            thumbnail.save_with_format(join_avatar_file(&root, &thumbnail_filename),
-                                       image::ImageFormat::Png)
-                .map_err(|e| {
-                    remove_avatar_file(&root, &origin_filename);
-                    e
-                })?;
-            Some(thumbnail_filename)
-        };
-        Ok((origin_filename, thumbnail_filename))
-    })
-        .await
-        .map_err(|err| match err {
-            BlockingError::Error(ImageError::Decoding(_))
-            => ApiError::AvatarError { error: "cannot decode the uploaded avatar".into(), },
-            e => internal_server_error!(e),
+fn encode_avatar(avatar: &DynamicImage, format: AvatarFormat) -> Result<Vec<u8>, ImageError> {
+    let mut content = Vec::new();
+    match format {
+        AvatarFormat::Png => avatar.write_to(&mut content, ImageOutputFormat::Png)?,
+        // JPEG has no alpha channel
+        AvatarFormat::Jpeg { quality } => DynamicImage::ImageRgb8(avatar.to_rgb8())
+            .write_to(&mut content, ImageOutputFormat::Jpeg(quality))?,
+    }
+    Ok(content)
+}
+
+fn encode_webp_avatar(avatar: &DynamicImage) -> Vec<u8> {
+    webp::Encoder::from_image(&DynamicImage::ImageRgba8(avatar.to_rgba8()))
+        .encode(crate::constants::AVATAR_WEBP_QUALITY)
+        .to_vec()
+}
+
+// Encodes the avatar and its thumbnails, each in the configured format and in WebP
+fn encode_avatar_files(
+    avatar: &DynamicImage, format: AvatarFormat, sizes: &[u32],
+) -> Result<(UserAvatars, AvatarFiles), ImageError> {
+    let filename = random_avatar_filename();
+    let extension = avatar_extension(format);
+    let size = avatar.width();
+    let origin_filename = format!("{}.{}x{}.{}", filename, size, size, extension);
+    let origin_webp_filename = format!("{}.{}x{}.webp", filename, size, size);
+    let mut files = vec![
+        (origin_filename.clone(), encode_avatar(avatar, format)?),
+        (origin_webp_filename.clone(), encode_webp_avatar(avatar)),
+    ];
+    let mut avatars = UserAvatars {
+        avatar: Some(origin_filename),
+        avatar_webp: Some(origin_webp_filename),
+        ..UserAvatars::default()
+    };
+    for &thumbnail_size in sizes.iter().filter(|&&x| x < size) {
+        let thumbnail = avatar.resize(thumbnail_size, thumbnail_size,
+                                      image::imageops::FilterType::Triangle);
+        let thumbnail_filename = format!("{}.thumb.{}x{}.{}", filename,
+                                         thumbnail_size, thumbnail_size, extension);
+        files.push((thumbnail_filename.clone(), encode_avatar(&thumbnail, format)?));
+        avatars.avatar_thumbnails.insert(thumbnail_size, thumbnail_filename);
+        let thumbnail_webp_filename = format!("{}.thumb.{}x{}.webp", filename,
+                                              thumbnail_size, thumbnail_size);
+        files.push((thumbnail_webp_filename.clone(), encode_webp_avatar(&thumbnail)));
+        avatars.avatar_thumbnails_webp.insert(thumbnail_size, thumbnail_webp_filename);
+    }
+    Ok((avatars, files))
+}
+
+// Turns the avatar upright as its EXIF orientation tells. The saved avatars are encoded again
+// without EXIF, so they would come out sideways otherwise
+fn apply_exif_orientation(content: &[u8], avatar: DynamicImage) -> DynamicImage {
+    let orientation = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(content))
+        .ok()
+        .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0)));
+    match orientation {
+        Some(2) => avatar.fliph(),
+        Some(3) => avatar.rotate180(),
+        Some(4) => avatar.flipv(),
+        Some(5) => avatar.rotate90().fliph(),
+        Some(6) => avatar.rotate90(),
+        Some(7) => avatar.rotate270().fliph(),
+        Some(8) => avatar.rotate270(),
+        _ => avatar,
+    }
+}
+
+// Crops and resizes the uploaded avatar, returns the filenames of the avatar and its thumbnails
+// along with their content
+fn process_avatar(
+    content: &[u8], format: AvatarFormat, sizes: &[u32],
+) -> Result<(UserAvatars, AvatarFiles), ImageError> {
+    let avatar = apply_exif_orientation(content, image::load_from_memory(content)?);
+    let (width, height) = avatar.dimensions();
+    let size = std::cmp::min(width, height);
+    let cropped_avatar = match width.cmp(&height) {
+        Ordering::Less => avatar.crop_imm(0, (height - size) / 2, size, size),
+        Ordering::Greater => avatar.crop_imm((width - size) / 2, 0, size, size),
+        Ordering::Equal => avatar,
+    };
+    encode_avatar_files(&cropped_avatar, format, sizes)
+}
+
+// Only looks at the header, so that files which are not images or are too large to decode safely
+// are rejected before being decoded
+fn check_avatar(content: &[u8], max_pixels: u64) -> Result<ImageFormat, ApiError> {
+    let format = match image::guess_format(content) {
+        Ok(format @ ImageFormat::Png) | Ok(format @ ImageFormat::Jpeg)
+        | Ok(format @ ImageFormat::Gif) | Ok(format @ ImageFormat::WebP) => format,
+        _ => return Err(ApiError::AvatarError {
+            error: "the uploaded avatar is not a PNG, JPEG, GIF or WebP image".into(),
+        }),
+    };
+    let (width, height) = Reader::with_format(Cursor::new(content), format)
+        .into_dimensions()
+        .map_err(|_| ApiError::AvatarError {
+            error: "the uploaded avatar has a truncated or malformed header".into(),
         })?;
-    // Save new avatars to database
-    let updated_at = match app_data.query.user
-        .update_avatars(&*app_data.db.read().await, uid, &Some(avatar.clone()), &avatar128)
-        .await {
-        Ok(v) => v,
-        Err(e) => {
-            let avatar = avatar.clone();
-            let avatar128 = avatar128.clone();
-            let root = app_data.config.media.root.clone();
-            block(move || {
-                remove_avatar_file(&root, &avatar);
-                if let Some(avatar128) = avatar128 {
-                    remove_avatar_file(&root, &avatar128);
-                }
-                Ok::<(), Infallible>(())
-            })
-                .await
-                .map_err(|e| internal_server_error!(e))?;
-            return Err(match e {
-                QueryError::UserNotFound => ApiError::UserNotFound,
-                e => internal_server_error!(e),
-            });
+    if width as u64 * height as u64 > max_pixels {
+        return Err(ApiError::AvatarError {
+            error: format!("the uploaded avatar of {}x{} has more than {} pixels",
+                           width, height, max_pixels),
+        });
+    }
+    Ok(format)
+}
+
+fn upload_extension(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Jpeg => "jpg",
+        ImageFormat::Gif => "gif",
+        ImageFormat::WebP => "webp",
+        _ => "png",
+    }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast() || a == 0
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                // Unique local, fc00::/7, and link-local, fe80::/10
+                || (segment & 0xfe00) == 0xfc00 || (segment & 0xffc0) == 0xfe80)
+                // IPv4-mapped and compatible ones are checked as IPv4
+                && ip.to_ipv4().is_none_or(|ip| is_public_ip(IpAddr::V4(ip)))
         }
+    }
+}
+
+async fn resolve_host(host: String, port: u16) -> io::Result<Vec<SocketAddr>> {
+    Ok(lookup_host((host.as_str(), port)).await?.collect())
+}
+
+async fn fetch_avatar(url: &str) -> Result<Vec<u8>, ApiError> {
+    fetch_avatar_with(url, resolve_host).await
+}
+
+// Downloads an avatar from an http(s) URL of a public address. Redirects are not followed, as
+// their targets would skip the address check. The host is resolved once, and the checked address
+// is the one connected to, so a second answer of its DNS cannot point elsewhere
+async fn fetch_avatar_with<R, F>(url: &str, resolve: R) -> Result<Vec<u8>, ApiError>
+    where
+        R: Fn(String, u16) -> F,
+        F: Future<Output = io::Result<Vec<SocketAddr>>>,
+{
+    let error = |error: String| ApiError::AvatarError { error };
+    let uri = url.parse::<Uri>()
+        .map_err(|_| error("the avatar URL is malformed".into()))?;
+    let port = match uri.scheme_str() {
+        Some("http") => uri.port_u16().unwrap_or(80),
+        Some("https") => uri.port_u16().unwrap_or(443),
+        _ => return Err(error("the avatar URL should be http or https".into())),
     };
-    let root = &app_data.config.media.root;
-    // Remove old avatars
-    if let Some(old_avatar) = old_avatars.avatar.as_ref() {
-        remove_avatar_file(root, old_avatar);
+    let host = uri.host()
+        .ok_or_else(|| error("the avatar URL has no host".into()))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let addresses = resolve(host.into(), port)
+        .await
+        .map_err(|_| error(format!("cannot resolve the host {}", host)))?;
+    if addresses.is_empty() || !addresses.iter().all(|x| is_public_ip(x.ip())) {
+        return Err(error(format!("the host {} is not a public address", host)));
     }
-    if let Some(old_avatar128) = old_avatars.avatar128.as_ref() {
-        remove_avatar_file(root, old_avatar128);
+    let download = async {
+        // The URI still gives the Host header and the name the certificate is verified against
+        let mut response = Client::new().get(uri.clone()).address(addresses[0]).send()
+            .await
+            .map_err(|e| error(format!("cannot fetch the avatar: {}", e)))?;
+        if !response.status().is_success() {
+            return Err(error(format!("fetching the avatar responded {}", response.status())));
+        }
+        response.body()
+            .limit(crate::constants::AVATAR_MAX_SIZE)
+            .await
+            .map(|content| content.to_vec())
+            .map_err(|e| error(format!("cannot fetch the avatar: {}", e)))
+    };
+    actix_rt::time::timeout(
+        Duration::from_secs(crate::constants::AVATAR_FETCH_TIMEOUT), download)
+        .await
+        .map_err(|_| error("fetching the avatar timed out".into()))?
+}
+
+fn map_avatar_error(err: BlockingError<ImageError>) -> ApiError {
+    match err {
+        BlockingError::Error(ImageError::Decoding(_))
+        => ApiError::AvatarError { error: "cannot decode the uploaded avatar".into(), },
+        e => internal_server_error!(e),
     }
-    let url = &app_data.config.media.url;
-    let avatar = join_avatar_url(url, &avatar);
-    let avatar128 = avatar128.map(|x| join_avatar_url(url, &x));
-    app_data.send(UserUpdated {
+}
+
+// Pushes the avatars now saved for the user, whose URLs are returned
+async fn push_avatars(
+    app_data: &web::Data<AppState>,
+    uid: i32,
+    avatars: UserAvatars,
+    updated_at: DateTime<Utc>,
+    auth: &Auth,
+) -> Result<UserUpdated, ApiError> {
+    let urls = |x: AvatarThumbnails| x.into_iter()
+        .map(|(size, x)| (size, avatar_url(app_data, &x)))
+        .collect::<AvatarThumbnails>();
+    let message = UserUpdated {
         id: uid,
         username: None,
         email: None,
         password: None,
         nickname: None,
-        avatar: Some(Some(avatar.clone())),
-        avatar128: Some(avatar128.clone()),
+        avatar: Some(avatars.avatar.map(|x| avatar_url(app_data, &x))),
+        avatar_thumbnails: Some(urls(avatars.avatar_thumbnails)),
+        avatar_webp: Some(avatars.avatar_webp.map(|x| avatar_url(app_data, &x))),
+        avatar_thumbnails_webp: Some(urls(avatars.avatar_thumbnails_webp)),
         blocked: None,
         updated_at,
-    }, &auth)
+    };
+    app_data.send(message.clone(), auth)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    Ok(message)
+}
+
+// Stores processed avatars and saves them to database, removes the old ones and pushes the update
+async fn store_avatar(
+    app_data: &web::Data<AppState>,
+    uid: i32,
+    (avatars, files): (UserAvatars, AvatarFiles),
+    auth: &Auth,
+) -> Result<UploadAvatarResponse, ApiError> {
+    put_avatar_files(app_data, files)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    // Fetch old avatars, then save new avatars to database. The connection is handed back before
+    // the files are removed
+    let result = match app_data.pool.get().await {
+        Ok(db) => {
+            let old_avatars = db.query.user
+                .fetch_avatars(&db.client, uid)
+                .await;
+            db.query.user
+                .update_avatars(&db.client, uid, &avatars)
+                .await
+                .and_then(|updated_at| old_avatars.map(|old_avatars| (updated_at, old_avatars)))
+        }
+        Err(e) => Err(e),
+    };
+    let updated_at = match result {
+        Ok((updated_at, old_avatars)) => {
+            // Remove old avatars
+            for old_avatar in old_avatars.files() {
+                remove_avatar_file(app_data, old_avatar).await;
+            }
+            updated_at
+        }
+        Err(e) => {
+            for avatar in avatars.files() {
+                remove_avatar_file(app_data, avatar).await;
+            }
+            return Err(match e {
+                QueryError::UserNotFound => ApiError::UserNotFound,
+                e => internal_server_error!(e),
+            });
+        }
+    };
+    let message = push_avatars(app_data, uid, avatars, updated_at, auth).await?;
+    Ok(UploadAvatarResponse {
+        avatar: message.avatar.flatten().unwrap(),
+        avatar_thumbnails: message.avatar_thumbnails.unwrap(),
+        avatar_webp: message.avatar_webp.flatten(),
+        avatar_thumbnails_webp: message.avatar_thumbnails_webp.unwrap(),
+        processing: false,
+    })
+}
+
+// Replaces the provisional avatar of an upload by the processed ones, or by none if the
+// processing failed. Skipped if the avatar of the user is no longer the provisional one, which a
+// later upload replaced, checked before the files are stored and again by the update itself
+async fn replace_provisional_avatar(
+    app_data: &web::Data<AppState>,
+    uid: i32,
+    provisional: &str,
+    (avatars, files): (UserAvatars, AvatarFiles),
+    auth: &Auth,
+) -> Result<(), ApiError> {
+    let current = match app_data.pool.get().await {
+        Ok(db) => db.query.user
+            .fetch_avatars(&db.client, uid)
+            .await,
+        Err(e) => Err(e),
+    };
+    match current {
+        Ok(current) if current.avatar.as_deref() == Some(provisional) => (),
+        Ok(_) | Err(QueryError::UserNotFound) => return Ok(()),
+        Err(e) => return Err(internal_server_error!(e)),
+    }
+    put_avatar_files(app_data, files)
         .await
         .map_err(|e| internal_server_error!(e))?;
+    let result = match app_data.pool.get().await {
+        Ok(db) => db.query.user
+            .replace_avatars(&db.client, uid, provisional, &avatars)
+            .await,
+        Err(e) => Err(e),
+    };
+    let updated_at = match result {
+        Ok(Some(updated_at)) => updated_at,
+        result => {
+            for avatar in avatars.files() {
+                remove_avatar_file(app_data, avatar).await;
+            }
+            return result.map(|_| ()).map_err(|e| internal_server_error!(e));
+        }
+    };
+    // Only now that no user points to it
+    remove_avatar_file(app_data, provisional).await;
+    push_avatars(app_data, uid, avatars, updated_at, auth).await?;
+    Ok(())
+}
+
+// Clears the status of the user unless a later upload owns it now
+fn finish_avatar_processing(
+    app_data: &web::Data<AppState>,
+    uid: i32,
+    provisional: &str,
+    result: Result<(), ApiError>,
+) {
+    let mut avatar_processing = app_data.avatar_processing.lock().unwrap();
+    if let Some(AvatarProcessing::Processing { provisional: current }) =
+        avatar_processing.get(&uid) {
+        if current != provisional {
+            return;
+        }
+    }
+    match result {
+        Ok(()) => {
+            avatar_processing.remove(&uid);
+        }
+        Err(e) => {
+            error!("failed to process avatar of user {}: {}", uid, e);
+            avatar_processing.insert(uid, AvatarProcessing::Failed {
+                error: format!("{}", e),
+            });
+        }
+    }
+}
+
+// Runs after the response of the upload, on the worker which received it. The content is the
+// same as the provisional avatar, which stays the avatar of the user until replaced here. An
+// avatar that cannot be processed is removed instead of kept as uploaded
+async fn process_avatar_in_background(
+    app_data: web::Data<AppState>,
+    uid: i32,
+    provisional: String,
+    content: Vec<u8>,
+    auth: Auth,
+    // Held until the processing is done
+    _permit: OwnedSemaphorePermit,
+) {
+    let format = app_data.config.media.avatar_format;
+    let sizes = app_data.config.media.avatar_sizes.clone();
+    let result = match block(move || process_avatar(&content, format, &sizes)).await {
+        Ok(avatars) =>
+            replace_provisional_avatar(&app_data, uid, &provisional, avatars, &auth).await,
+        Err(e) => {
+            let e = map_avatar_error(e);
+            replace_provisional_avatar(&app_data, uid, &provisional,
+                                       (UserAvatars::default(), AvatarFiles::new()), &auth)
+                .await
+                .and(Err(e))
+        }
+    };
+    finish_avatar_processing(&app_data, uid, &provisional, result);
+}
+
+// Shared by uploaded and fetched avatars
+async fn upload_avatar_impl(
+    app_data: web::Data<AppState>,
+    uid: i32,
+    content: Vec<u8>,
+    auth: Auth,
+) -> ApiResult<UploadAvatarResponse> {
+    // Check the user exists before processing
+    let db = app_data.db().await?;
+    db.query.user
+        .fetch_avatars(&db.client, uid)
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            e => internal_server_error!(e),
+        })?;
+    drop(db);
+    let image_format = check_avatar(&content, app_data.config.media.avatar_max_pixels)?;
+    if !app_data.config.media.avatar_async {
+        let format = app_data.config.media.avatar_format;
+        let sizes = app_data.config.media.avatar_sizes.clone();
+        // Crop and resize new avatars
+        let avatars = block(move || process_avatar(&content, format, &sizes))
+            .await
+            .map_err(map_avatar_error)?;
+        return respond(store_avatar(&app_data, uid, avatars, &auth).await?);
+    }
+    let permit = app_data.avatar_jobs.clone()
+        .try_acquire_owned()
+        .map_err(|_| ApiError::AvatarQueueFull)?;
+    // Keep the upload as is for the provisional avatar, saved as the avatar of the user so that
+    // its URL works until the processed ones replace it
+    let provisional = format!("{}.upload.{}", random_avatar_filename(),
+                              upload_extension(image_format));
+    app_data.avatar_processing.lock().unwrap().insert(uid, AvatarProcessing::Processing {
+        provisional: provisional.clone(),
+    });
+    let avatars = UserAvatars {
+        avatar: Some(provisional.clone()),
+        ..UserAvatars::default()
+    };
+    let files = vec![(provisional.clone(), content.clone())];
+    let response = match store_avatar(&app_data, uid, (avatars, files), &auth).await {
+        Ok(response) => response,
+        Err(e) => {
+            finish_avatar_processing(&app_data, uid, &provisional, Ok(()));
+            return Err(e);
+        }
+    };
+    actix_rt::spawn(process_avatar_in_background(app_data.clone(), uid, provisional, content,
+                                                 auth, permit));
     respond(UploadAvatarResponse {
-        avatar,
-        avatar128,
+        processing: true,
+        ..response
     })
 }
 
@@ -264,8 +667,9 @@ async fn upload_avatar_for_me(
     auth: Auth,
 ) -> ApiResult<UploadAvatarResponse> {
     auth.try_permission("user-avatar", "update-self")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
-    upload_avatar_impl(app_data, uid, data, auth).await
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let content = data.get_single("avatar").extra().content().unwrap().to_vec();
+    upload_avatar_impl(app_data, uid, content, auth).await
 }
 
 async fn upload_avatar(
@@ -276,7 +680,64 @@ async fn upload_avatar(
 ) -> ApiResult<UploadAvatarResponse> {
     auth.try_permission("user-avatar", "update")?;
     let uid = uid_path.uid.clone().into();
-    upload_avatar_impl(app_data, uid, data, auth).await
+    let content = data.get_single("avatar").extra().content().unwrap().to_vec();
+    upload_avatar_impl(app_data, uid, content, auth).await
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct UploadAvatarFromUrlRequest {
+    #[validate(url(message = "should be a URL"))]
+    url: String,
+}
+
+async fn upload_avatar_from_url(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    request: ValidatedJson<UploadAvatarFromUrlRequest>,
+    auth: Auth,
+) -> ApiResult<UploadAvatarResponse> {
+    auth.try_permission("user-avatar", "update")?;
+    let uid = uid_path.uid.clone().into();
+    // Check the user exists before fetching
+    let db = app_data.db().await?;
+    db.query.user
+        .fetch_avatars(&db.client, uid)
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            e => internal_server_error!(e),
+        })?;
+    drop(db);
+    let content = fetch_avatar(&request.url).await?;
+    upload_avatar_impl(app_data, uid, content, auth).await
+}
+
+fn read_avatar_status_impl(
+    app_data: web::Data<AppState>,
+    uid: i32,
+) -> ApiResult<AvatarProcessing> {
+    respond(app_data.avatar_processing.lock().unwrap()
+        .get(&uid)
+        .cloned()
+        .unwrap_or(AvatarProcessing::Idle))
+}
+
+async fn read_avatar_status_for_me(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<AvatarProcessing> {
+    auth.try_permission("user-avatar", "update-self")?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    read_avatar_status_impl(app_data, uid)
+}
+
+async fn read_avatar_status(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    auth: Auth,
+) -> ApiResult<AvatarProcessing> {
+    auth.try_permission("user-avatar", "update")?;
+    read_avatar_status_impl(app_data, uid_path.uid.clone().into())
 }
 
 async fn delete_avatar_impl(
@@ -285,28 +746,26 @@ async fn delete_avatar_impl(
     auth: Auth,
 ) -> ApiResult<()> {
     // Fetch old avatars
-    let old_avatars = app_data.query.user
-        .fetch_avatars(&*app_data.db.read().await, uid)
+    let db = app_data.db().await?;
+    let old_avatars = db.query.user
+        .fetch_avatars(&db.client, uid)
         .await
         .map_err(|err| match err {
             QueryError::UserNotFound => ApiError::UserNotFound,
             e => internal_server_error!(e),
         })?;
     // Save new avatars to database
-    let updated_at = app_data.query.user
-        .update_avatars(&*app_data.db.read().await, uid, &None, &None)
+    let updated_at = db.query.user
+        .update_avatars(&db.client, uid, &UserAvatars::default())
         .await
         .map_err(|err| match err {
             QueryError::UserNotFound => ApiError::UserNotFound,
             e => internal_server_error!(e),
         })?;
-    let root = &app_data.config.media.root;
+    drop(db);
     // Remove old avatars
-    if let Some(old_avatar) = old_avatars.avatar.as_ref() {
-        remove_avatar_file(root, old_avatar);
-    }
-    if let Some(old_avatar128) = old_avatars.avatar128.as_ref() {
-        remove_avatar_file(root, old_avatar128);
+    for old_avatar in old_avatars.files() {
+        remove_avatar_file(&app_data, old_avatar).await;
     }
     app_data.send(UserUpdated {
         id: uid,
@@ -315,7 +774,9 @@ async fn delete_avatar_impl(
         password: None,
         nickname: None,
         avatar: Some(None),
-        avatar128: Some(None),
+        avatar_thumbnails: Some(AvatarThumbnails::new()),
+        avatar_webp: Some(None),
+        avatar_thumbnails_webp: Some(AvatarThumbnails::new()),
         blocked: None,
         updated_at,
     }, &auth)
@@ -329,7 +790,7 @@ async fn delete_avatar_for_me(
     auth: Auth,
 ) -> ApiResult<()> {
     auth.try_permission("user-avatar", "delete-self")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
     delete_avatar_impl(app_data, uid, auth).await
 }
 
@@ -380,35 +841,60 @@ struct ReadUserResponse {
     permissions: Vec<Permission>,
 }
 
+// The ETag is a digest of the body along with the access levels asked for, rather than of
+// updated_at, which changes neither with the roles of the user nor with the roles themselves
 async fn read_user_impl(
     app_data: web::Data<AppState>,
     request: ReadUserQueryDecoded,
     uid: i32,
-) -> ApiResult<ReadUserResponse> {
-    let (mut user, roles, permissions) = app_data.query.user
-        .find_one_with_permissions_and_roles(
-            &mut *app_data.db.write().await, uid, request.populate_user,
-            request.populate_role, request.populate_permission,
-        )
-        .await
+    redact_email: bool,
+    req: &HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let levels = format!("{:?} {:?} {:?}\n", request.populate_user, request.populate_role,
+                         request.populate_permission);
+    let (mut user, roles, permissions) = retry_db!(app_data, |mut db| {
+        let db = &mut *db;
+        db.query.user
+            .find_one_with_permissions_and_roles(
+                &mut db.client, uid, request.populate_user.clone(),
+                request.populate_role.clone(), request.populate_permission.clone(),
+            )
+            .await
+    })
         .map_err(|err| match err {
             QueryError::UserNotFound => ApiError::UserNotFound,
             e => internal_server_error!(e),
         })?;
-    let media_url = &app_data.config.media.url;
-    user.map_avatars(|x| join_avatar_url(media_url, x));
-    respond(ReadUserResponse {
+    user.map_avatars(|x| avatar_url(&app_data, x));
+    if redact_email {
+        user.redact_email();
+    }
+    let body = serde_json::to_vec(&ApiResultWrapper::from(ReadUserResponse {
         user,
         roles,
         permissions,
-    })
+    })).map_err(|e| internal_server_error!(e))?;
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(levels.as_bytes());
+    context.update(&body);
+    let etag = format!("\"{}\"", context.finish().as_ref()[..16].iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>());
+    if etag_matches(req, &etag) {
+        return Ok(HttpResponse::NotModified().header("ETag", etag).finish());
+    }
+    Ok(HttpResponse::Ok()
+        .header("ETag", etag)
+        .content_type("application/json")
+        .body(body))
 }
 
 async fn read_user_for_me(
     app_data: web::Data<AppState>,
     request: ValidatedQuery<ReadUserQuery>,
     auth: Auth,
-) -> ApiResult<ReadUserResponse> {
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
     let request: ReadUserQueryDecoded = request.into_inner().into();
     match request.populate_user {
         UserAccessLevel::All => auth.try_permission("user", "read")?,
@@ -423,8 +909,8 @@ async fn read_user_for_me(
     if request.populate_permission.is_some() {
         auth.try_permission("permission", "read")?;
     }
-    let uid = auth.claims.ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
-    read_user_impl(app_data, request, uid).await
+    let uid = auth.claims.ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    read_user_impl(app_data, request, uid, false, &req).await
 }
 
 async fn read_user(
@@ -432,7 +918,8 @@ async fn read_user(
     request: ValidatedQuery<ReadUserQuery>,
     uid_path: ValidatedPath<UidPath>,
     auth: Auth,
-) -> ApiResult<ReadUserResponse> {
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
     let request: ReadUserQueryDecoded = request.into_inner().into();
     match request.populate_user {
         UserAccessLevel::All | UserAccessLevel::WithoutRoles =>
@@ -448,8 +935,10 @@ async fn read_user(
     if request.populate_permission.is_some() {
         auth.try_permission("permission", "read")?;
     }
-    let uid = uid_path.uid.clone().into();
-    read_user_impl(app_data, request, uid).await
+    let uid: i32 = uid_path.uid.clone().into();
+    let redact_email = app_data.config.redact_email && !auth.has_permission("user", "read-email")
+        && auth.claims.as_ref().map(|claims| claims.uid) != Some(uid);
+    read_user_impl(app_data, request, uid, redact_email, &req).await
 }
 
 #[derive(Debug, Validate, Deserialize)]
@@ -473,17 +962,24 @@ async fn register_user(
     app_data: web::Data<AppState>,
     request: ValidatedJson<RegisterUserRequest>,
     auth: Auth,
+    language: AcceptLanguage,
 ) -> ApiResult<RegisterUserResponse> {
     auth.try_permission("registration", "create")?;
-    let result = app_data.query.user
+    request.username.check(&app_data.config.username)?;
+    request.password.check(&app_data.config.password)?;
+    let db = app_data.db().await?;
+    let result = db.query.user
         .register_user(
-            &*app_data.db.read().await, app_data.clone(),
+            &db.client, app_data.clone(),
             &app_data.config.smtp.sender, &app_data.config.site,
-            &request.username[..], &request.email[..], &request.password[..],
+            &request.username[..], &request.email[..], &request.password[..], language.0,
         )
         .await
         .map_err(|err| match err {
             QueryError::DuplicatedUser { field } => ApiError::DuplicatedUser { field },
+            QueryError::EmailNotConfigured => ApiError::EmailNotConfigured,
+            QueryError::EmailQueueFull => ApiError::EmailQueueFull,
+            QueryError::PasswordCompromised => ApiError::PasswordCompromised,
             e => internal_server_error!(e),
         })?;
     respond(RegisterUserResponse {
@@ -505,6 +1001,23 @@ struct ConfirmRegistrationRequest {
     code: Any6,
 }
 
+fn user_registration_error(reason: &str) -> ApiError {
+    ApiError::UserRegistration {
+        reason: reason.into(),
+        expires_at: None,
+        hint: None,
+    }
+}
+
+// Tells the client when the registration expired and that registering again is the way out
+fn user_registration_expired(expires_at: DateTime<Utc>) -> ApiError {
+    ApiError::UserRegistration {
+        reason: "Expired".into(),
+        expires_at: Some(expires_at),
+        hint: Some("RegisterAgain".into()),
+    }
+}
+
 async fn confirm_registration(
     app_data: web::Data<AppState>,
     request: ValidatedJson<ConfirmRegistrationRequest>,
@@ -512,14 +1025,17 @@ async fn confirm_registration(
     auth: Auth,
 ) -> ApiResult<()> {
     auth.try_permission("registration", "confirm")?;
-    let result = app_data.query.user
-        .confirm_registration(&mut *app_data.db.write().await,
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let result = db.query.user
+        .confirm_registration(&mut db.client,
                               &path.reg_id[..], &request.code[..])
         .await
         .map_err(|err| match err {
-            QueryError::UserRegistrationNotFound => ApiError::UserRegistration { reason: "NotFound".into() },
-            QueryError::UserRegistrationExpired => ApiError::UserRegistration { reason: "Expired".into() },
-            QueryError::UserRegistrationWrongCode => ApiError::UserRegistration { reason: "WrongCode".into() },
+            QueryError::UserRegistrationNotFound => user_registration_error("NotFound"),
+            QueryError::UserRegistrationExpired { expires_at } =>
+                user_registration_expired(expires_at),
+            QueryError::UserRegistrationWrongCode => user_registration_error("WrongCode"),
             QueryError::DuplicatedUser { field } => ApiError::DuplicatedUser { field },
             e => internal_server_error!(e),
         })?;
@@ -558,85 +1074,402 @@ async fn check_username_existence(
     auth: Auth,
 ) -> ApiResult<CheckExistenceResponse> {
     auth.try_permission("user-username", "check-existence")?;
-    let exists = app_data.query.user
-        .check_username_existence(&*app_data.db.read().await, &request.username[..])
-        .await
+    let exists = retry_db!(app_data, |db| db.query.user
+        .check_username_existence(&db.client, &request.username[..])
+        .await)
         .map_err(|e| internal_server_error!(e))?;
     respond(CheckExistenceResponse {
         exists
     })
 }
 
+// The usernames are checked one by one, so that an invalid one does not fail the others
+#[derive(Debug, Validate, Deserialize)]
+struct CheckUsernamesExistenceRequest {
+    usernames: Vec<Username>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckUsernamesExistenceResponse {
+    // One per distinct username in the order of the request
+    #[serde(flatten)]
+    results: BulkResults<String>,
+    // Only the valid usernames are looked up
+    exists: HashMap<String, bool>,
+}
+
+async fn check_usernames_existence(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<CheckUsernamesExistenceRequest>,
+    auth: Auth,
+) -> ApiResult<CheckUsernamesExistenceResponse> {
+    auth.try_permission("user-username", "check-existence")?;
+    let mut seen = HashSet::new();
+    let checked = request.into_inner().usernames.into_iter()
+        .filter(|x| seen.insert(String::from(x.clone())))
+        .map(|x| {
+            let result = x.check(&app_data.config.username);
+            (String::from(x), result)
+        })
+        .collect::<Vec<_>>();
+    let usernames = checked.iter()
+        .filter(|(_, result)| result.is_ok())
+        .map(|(username, _)| username.clone())
+        .collect::<Vec<_>>();
+    let exists = retry_db!(app_data, |db| db.query.user
+        .check_usernames_existence(&db.client, &usernames)
+        .await)
+        .map_err(|e| internal_server_error!(e))?;
+    respond(CheckUsernamesExistenceResponse {
+        results: checked.into_iter().collect(),
+        exists,
+    })
+}
+
 async fn check_email_existence(
     app_data: web::Data<AppState>,
     request: ValidatedQuery<CheckEmailExistenceRequest>,
     auth: Auth,
 ) -> ApiResult<CheckExistenceResponse> {
     auth.try_permission("user-username", "check-existence")?;
-    let exists = app_data.query.user
-        .check_email_existence(&*app_data.db.read().await, &request.email[..])
-        .await
+    let exists = retry_db!(app_data, |db| db.query.user
+        .check_email_existence(&db.client, &request.email[..])
+        .await)
         .map_err(|e| internal_server_error!(e))?;
     respond(CheckExistenceResponse {
         exists
     })
 }
 
+#[derive(Debug, Validate, Deserialize)]
+struct ReadUserChangesQuery {
+    #[serde(default)]
+    since_xid: i64,
+    #[serde(default)]
+    since_id: i32,
+    limit: Option<i64>,
+}
+
 #[derive(Debug, Serialize)]
-#[serde(tag = "status")]
-enum QueryRegistrationResponse {
-    NotFound,
-    Expired,
-    Processing(UserRegistrationPublic),
-    Passed(UserRegistrationPublic),
-    Rejected(UserRegistrationPublic),
+struct ReadUserChangesResponse {
+    changes: Vec<UserChange>,
+    next_xid: i64,
+    next_id: i32,
 }
 
-async fn query_registration(
+// A client passes next_xid and next_id as since_xid and since_id until no changes are left
+async fn read_user_changes(
     app_data: web::Data<AppState>,
-    path: ValidatedPath<RegIdPath>,
+    request: ValidatedQuery<ReadUserChangesQuery>,
     auth: Auth,
-) -> ApiResult<QueryRegistrationResponse> {
-    auth.try_permission("registration", "read")?;
-    let result = match app_data.query.user
-        .query_registration(&*app_data.db.read().await, &path.reg_id[..])
-        .await {
-        Ok(value) => match value.completed {
-            Some(true) => QueryRegistrationResponse::Passed(value),
-            Some(false) => QueryRegistrationResponse::Rejected(value),
-            None => QueryRegistrationResponse::Processing(value),
-        },
-        Err(QueryError::UserRegistrationExpired) => QueryRegistrationResponse::Expired,
-        Err(QueryError::UserRegistrationNotFound) => QueryRegistrationResponse::NotFound,
-        Err(e) => return Err(internal_server_error!(e)),
-    };
-    respond(result)
+) -> ApiResult<ReadUserChangesResponse> {
+    if !auth.has_permission("user", "list") {
+        auth.try_permission("user-public", "list")?;
+    }
+    let limit = request.limit
+        .unwrap_or(crate::constants::USER_CHANGES_PAGE_SIZE)
+        .clamp(1, crate::constants::USER_CHANGES_PAGE_SIZE);
+    let mut changes = retry_db!(app_data, |db| db.query.user
+        .find_changes(&db.client, request.since_xid, request.since_id, limit)
+        .await)
+        .map_err(|e| internal_server_error!(e))?;
+    for change in changes.iter_mut() {
+        if let Some(user) = &mut change.user {
+            user.avatar = user.avatar.as_ref().map(|x| avatar_url(&app_data, x));
+            user.avatar_thumbnails.values_mut().for_each(|x| *x = avatar_url(&app_data, x));
+            user.avatar_webp = user.avatar_webp.as_ref().map(|x| avatar_url(&app_data, x));
+            user.avatar_thumbnails_webp.values_mut().for_each(|x| *x = avatar_url(&app_data, x));
+        }
+    }
+    let (next_xid, next_id) = changes.last()
+        .map(|x| (x.xid, x.id))
+        .unwrap_or((request.since_xid, request.since_id));
+    respond(ReadUserChangesResponse {
+        changes,
+        next_xid,
+        next_id,
+    })
 }
 
-async fn resend_registration_email(
+#[derive(Debug, Validate, Deserialize)]
+struct ListUsersQuery {
+    filter: Option<String>,
+    sort: Option<String>,
+    limit: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct ListUsersResponse {
+    items: Vec<UserWithoutRoles>,
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+// filter and sort are in the syntax of cashier-query, e.g. filter=nickname: alice or blocked: true
+// and sort=username desc. Where emails are redacted, they cannot be filtered or sorted by
+async fn list_users(
     app_data: web::Data<AppState>,
-    path: ValidatedPath<RegIdPath>,
+    request: ValidatedQuery<ListUsersQuery>,
     auth: Auth,
-) -> ApiResult<()> {
-    auth.try_permission("registration", "resend")?;
-    app_data.query.user
-        .resend_registration_email(
-            &*app_data.db.read().await, app_data.clone(),
-            &app_data.config.smtp.sender, &app_data.config.site,
-            &path.reg_id[..])
-        .await
+) -> ApiResult<ListUsersResponse> {
+    auth.try_permission("user", "list")?;
+    auth.try_permission("user", "read")?;
+    let max_limit = app_data.config.user_list_max_limit as i64;
+    let limit = request.limit.unwrap_or(max_limit).max(1).min(max_limit);
+    let offset = request.offset.max(0);
+    let redact_email = app_data.config.redact_email && !auth.has_permission("user", "read-email");
+    let (mut items, total) = retry_db!(app_data, |db| db.query.user
+        .list_users(&db.client, request.filter.as_deref(),
+                    request.sort.as_deref(), !redact_email, limit, offset)
+        .await)
         .map_err(|err| match err {
-            QueryError::UserRegistrationNotFound => ApiError::UserRegistration { reason: "NotFound".into() },
-            QueryError::UserRegistrationExpired => ApiError::UserRegistration { reason: "Expired".into() },
+            QueryError::InvalidFilter(e) => ApiError::InvalidFilter { error: e.to_string() },
             e => internal_server_error!(e),
         })?;
-    respond(())
+    let uid = auth.claims.as_ref().map(|claims| claims.uid);
+    for user in items.iter_mut() {
+        user.avatar = user.avatar.as_ref().map(|x| avatar_url(&app_data, x));
+        user.avatar_thumbnails.values_mut().for_each(|x| *x = avatar_url(&app_data, x));
+        user.avatar_webp = user.avatar_webp.as_ref().map(|x| avatar_url(&app_data, x));
+        user.avatar_thumbnails_webp.values_mut().for_each(|x| *x = avatar_url(&app_data, x));
+        if redact_email && uid != Some(user.id) {
+            user.email = user.email.as_deref().map(mask_email);
+        }
+    }
+    respond(ListUsersResponse {
+        items,
+        total,
+        limit,
+        offset,
+    })
 }
 
 #[derive(Debug, Validate, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct UpdateUserRequest {
-    #[validate]
+struct CountUsersQuery {
+    filter: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CountUsersResponse {
+    count: i64,
+}
+
+// filter is the same as the one of list_users, and an empty one counts all the users
+async fn count_users(
+    app_data: web::Data<AppState>,
+    request: ValidatedQuery<CountUsersQuery>,
+    auth: Auth,
+) -> ApiResult<CountUsersResponse> {
+    auth.try_permission("user", "read")?;
+    let redact_email = app_data.config.redact_email && !auth.has_permission("user", "read-email");
+    let count = retry_db!(app_data, |db| db.query.user
+        .count_users(&db.client, request.filter.as_deref(), !redact_email)
+        .await)
+        .map_err(|err| match err {
+            QueryError::InvalidFilter(e) => ApiError::InvalidFilter { error: e.to_string() },
+            e => internal_server_error!(e),
+        })?;
+    respond(CountUsersResponse {
+        count,
+    })
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct SearchUsersQuery {
+    #[validate(length(min = 1, max = 64, message = "should have 1 to 64 chars"))]
+    q: String,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchUsersResponse {
+    items: Vec<UserPublic>,
+}
+
+// q is plain text, not a filter, and is looked up in usernames and nicknames case-insensitively
+async fn search_users(
+    app_data: web::Data<AppState>,
+    request: ValidatedQuery<SearchUsersQuery>,
+    auth: Auth,
+) -> ApiResult<SearchUsersResponse> {
+    auth.try_permission("user-public", "read")?;
+    let limit = request.limit
+        .unwrap_or(crate::constants::USER_SEARCH_MAX_LIMIT)
+        .clamp(1, crate::constants::USER_SEARCH_MAX_LIMIT);
+    let mut items = retry_db!(app_data, |db| db.query.user
+        .search_users(&db.client, &request.q, limit)
+        .await)
+        .map_err(|e| internal_server_error!(e))?;
+    for user in items.iter_mut() {
+        user.avatar = user.avatar.as_ref().map(|x| avatar_url(&app_data, x));
+        user.avatar_thumbnails.values_mut().for_each(|x| *x = avatar_url(&app_data, x));
+        user.avatar_webp = user.avatar_webp.as_ref().map(|x| avatar_url(&app_data, x));
+        user.avatar_thumbnails_webp.values_mut().for_each(|x| *x = avatar_url(&app_data, x));
+    }
+    respond(SearchUsersResponse {
+        items,
+    })
+}
+
+// Without http-status, every status is reported with 200 for the existing clients. With it,
+// NotFound and Expired are also reflected by 404 and 410 respectively. The body is the same
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct QueryStatusQuery {
+    #[serde(default)]
+    http_status: bool,
+}
+
+fn respond_with_status<T: Serialize>(
+    data: T, status: StatusCode, request: &QueryStatusQuery,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::build(if request.http_status { status } else { StatusCode::OK })
+        .json(ApiResultWrapper::from(data)))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status")]
+enum QueryRegistrationResponse {
+    NotFound,
+    #[serde(rename_all = "camelCase")]
+    Expired {
+        expires_at: DateTime<Utc>,
+    },
+    Processing(UserRegistrationPublic),
+    Passed(UserRegistrationPublic),
+    Rejected(UserRegistrationPublic),
+}
+
+impl QueryRegistrationResponse {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            QueryRegistrationResponse::NotFound => StatusCode::NOT_FOUND,
+            QueryRegistrationResponse::Expired { .. } => StatusCode::GONE,
+            _ => StatusCode::OK,
+        }
+    }
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct ListPendingQuery {
+    filter: Option<String>,
+    sort: Option<String>,
+    limit: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct ListPendingResponse<T> {
+    items: Vec<T>,
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+// filter and sort are in the syntax of cashier-query, e.g. filter=completed: null for the pending
+// ones. The newest come first by default
+async fn list_registrations(
+    app_data: web::Data<AppState>,
+    request: ValidatedQuery<ListPendingQuery>,
+    auth: Auth,
+) -> ApiResult<ListPendingResponse<UserRegistrationPublic>> {
+    auth.try_permission("registration", "list")?;
+    let max_limit = crate::constants::PENDING_LIST_MAX_LIMIT;
+    let limit = request.limit.unwrap_or(max_limit).max(1).min(max_limit);
+    let offset = request.offset.max(0);
+    let db = app_data.db().await?;
+    let (items, total) = db.query.user
+        .list_registrations(&db.client, request.filter.as_deref(),
+                            request.sort.as_deref(), limit, offset)
+        .await
+        .map_err(|err| match err {
+            QueryError::InvalidFilter(e) => ApiError::InvalidFilter { error: e.to_string() },
+            e => internal_server_error!(e),
+        })?;
+    respond(ListPendingResponse {
+        items,
+        total,
+        limit,
+        offset,
+    })
+}
+
+async fn query_registration(
+    app_data: web::Data<AppState>,
+    path: ValidatedPath<RegIdPath>,
+    request: ValidatedQuery<QueryStatusQuery>,
+    auth: Auth,
+) -> Result<HttpResponse, ApiError> {
+    auth.try_permission("registration", "read")?;
+    let db = app_data.db().await?;
+    let result = match db.query.user
+        .query_registration(&db.client, &path.reg_id[..])
+        .await {
+        Ok(value) => match value.completed {
+            Some(true) => QueryRegistrationResponse::Passed(value),
+            Some(false) => QueryRegistrationResponse::Rejected(value),
+            None => QueryRegistrationResponse::Processing(value),
+        },
+        Err(QueryError::UserRegistrationExpired { expires_at }) =>
+            QueryRegistrationResponse::Expired { expires_at },
+        Err(QueryError::UserRegistrationNotFound) => QueryRegistrationResponse::NotFound,
+        Err(e) => return Err(internal_server_error!(e)),
+    };
+    respond_with_status(&result, result.status_code(), &request)
+}
+
+// For a mistyped e-mail, so that the registration can be started over right away
+async fn cancel_registration(
+    app_data: web::Data<AppState>,
+    path: ValidatedPath<RegIdPath>,
+    auth: Auth,
+) -> ApiResult<()> {
+    auth.try_permission("registration", "cancel")?;
+    let db = app_data.db().await?;
+    db.query.user
+        .cancel_registration(&db.client, &path.reg_id[..])
+        .await
+        .map_err(|err| match err {
+            QueryError::UserRegistrationNotFound => user_registration_error("NotFound"),
+            e => internal_server_error!(e),
+        })?;
+    respond(())
+}
+
+async fn resend_registration_email(
+    app_data: web::Data<AppState>,
+    path: ValidatedPath<RegIdPath>,
+    auth: Auth,
+    language: AcceptLanguage,
+) -> ApiResult<()> {
+    auth.try_permission("registration", "resend")?;
+    let db = app_data.db().await?;
+    db.query.user
+        .resend_registration_email(
+            &db.client, app_data.clone(),
+            &app_data.config.smtp.sender, &app_data.config.site,
+            &path.reg_id[..], app_data.config.smtp.resend_interval, language.0)
+        .await
+        .map_err(|err| match err {
+            QueryError::UserRegistrationNotFound => user_registration_error("NotFound"),
+            QueryError::UserRegistrationExpired { expires_at } =>
+                user_registration_expired(expires_at),
+            QueryError::ResendTooSoon { retry_after } => ApiError::ResendTooSoon { retry_after },
+            QueryError::EmailNotConfigured => ApiError::EmailNotConfigured,
+            QueryError::EmailQueueFull => ApiError::EmailQueueFull,
+            e => internal_server_error!(e),
+        })?;
+    respond(())
+}
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateUserRequest {
+    #[validate]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub username: Option<Username>,
     #[validate]
@@ -664,6 +1497,13 @@ struct UpdateSelfRequest {
     pub nickname: Option<Option<Nickname>>,
 }
 
+#[derive(Debug, Serialize)]
+struct UpdateUserResponse {
+    updated_at: DateTime<Utc>,
+    // False if the update is saved but could not be pushed to subscribers
+    notified: bool,
+}
+
 async fn update_user_impl(
     app_data: web::Data<AppState>,
     auth: Auth,
@@ -672,12 +1512,20 @@ async fn update_user_impl(
     email: Option<Option<Email>>,
     nickname: Option<Option<Nickname>>,
     blocked: Option<Option<bool>>,
-) -> ApiResult<()> {
+) -> ApiResult<UpdateUserResponse> {
+    if let Some(username) = &username {
+        username.check(&app_data.config.username)?;
+    }
+    if let Some(Some(nickname)) = &nickname {
+        nickname.check(&app_data.config.nickname)?;
+    }
     let username = username.map(|x| x.into());
     let email = email.map(|x| x.map(|x| x.into()));
     let nickname = nickname.map(|x| x.map(|x| x.into()));
-    let updated_at = app_data.query.user
-        .update_user(&mut *app_data.db.write().await, uid, &username,
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let updated_at = db.query.user
+        .update_user(&mut db.client, &auth.actor()?, uid, &username,
                      &email, &nickname, &blocked)
         .await
         .map_err(|err| match err {
@@ -685,29 +1533,39 @@ async fn update_user_impl(
             QueryError::DuplicatedUser { field } => ApiError::DuplicatedUser { field },
             e => internal_server_error!(e),
         })?;
-    app_data.send(UserUpdated {
+    // The update is committed by now, so failing to push it must not turn into an error
+    let notified = match app_data.send(UserUpdated {
         id: uid,
         username,
         email,
         password: None,
         nickname,
         avatar: None,
-        avatar128: None,
+        avatar_thumbnails: None,
+        avatar_webp: None,
+        avatar_thumbnails_webp: None,
         blocked,
         updated_at,
-    }, &auth)
-        .await
-        .map_err(|e| internal_server_error!(e))?;
-    respond(())
+    }, &auth).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("failed to push update of user {}: {}", uid, e);
+            false
+        }
+    };
+    respond(UpdateUserResponse {
+        updated_at,
+        notified,
+    })
 }
 
 async fn update_user_for_me(
     app_data: web::Data<AppState>,
     request: ValidatedJson<UpdateSelfRequest>,
     auth: Auth,
-) -> ApiResult<()> {
+) -> ApiResult<UpdateUserResponse> {
     auth.try_permission("user", "update-self")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
     update_user_impl(app_data, auth, uid, request.username.clone(), None,
                      request.nickname.clone(), None).await
 }
@@ -717,11 +1575,109 @@ async fn update_user(
     uid_path: ValidatedPath<UidPath>,
     request: ValidatedJson<UpdateUserRequest>,
     auth: Auth,
-) -> ApiResult<()> {
+) -> ApiResult<UpdateUserResponse> {
     auth.try_permission("user", "update")?;
     let uid = uid_path.uid.clone().into();
     update_user_impl(app_data, auth, uid, request.username.clone(), request.email.clone(),
-                     request.nickname.clone(), request.blocked.clone()).await
+                     request.nickname.clone(), request.blocked).await
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct BulkBlockRequest {
+    #[validate(length(min = 1, max = 1000, message = "should have 1 to 1000 ids"))]
+    uids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkBlockResponse {
+    // One per distinct uid in the order of the request. Users already in the requested state
+    // are ok as well
+    #[serde(flatten)]
+    results: BulkResults<i32>,
+    // False if the updates are saved but could not be pushed to subscribers
+    notified: bool,
+}
+
+// Newly blocked users lose all of their tokens, the same as a deleted user
+async fn set_blocked_bulk(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+    uids: &[i32],
+    blocked: bool,
+) -> ApiResult<BulkBlockResponse> {
+    auth.try_permission("user", "update")?;
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let mut result = db.query.user
+        .set_blocked_bulk(&mut db.client, &auth.actor()?, uids, blocked)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    result.updated.sort_unstable();
+    let updated = result.updated.iter()
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    let revoked = if blocked && !updated.is_empty() {
+        db.query.token
+            .revoke_tokens_from_users(&db.client, &updated)
+            .await
+            .map_err(|e| internal_server_error!(e))?
+    } else {
+        Vec::new()
+    };
+    let messages = result.updated.iter()
+        .map(|(id, updated_at)| UserUpdated {
+            id: *id,
+            username: None,
+            email: None,
+            password: None,
+            nickname: None,
+            avatar: None,
+            avatar_thumbnails: None,
+            avatar_webp: None,
+            avatar_thumbnails_webp: None,
+            blocked: Some(Some(blocked)),
+            updated_at: *updated_at,
+        }.into())
+        .chain(revoked.into_iter()
+            .map(|result| TokenRevoked {
+                jti: result.id,
+                uid: result.user,
+            }.into()))
+        .collect::<Vec<InnerInternalMessage>>();
+    // The updates are committed by now, so failing to push them must not turn into an error
+    let notified = messages.is_empty() || match app_data.send_all(messages, &auth).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("failed to push bulk update of users: {}", e);
+            false
+        }
+    };
+    let not_found = result.not_found.into_iter().collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    let results = uids.iter()
+        .filter(|id| seen.insert(**id))
+        .map(|id| (*id, if not_found.contains(id) { Err(ApiError::UserNotFound) } else { Ok(()) }))
+        .collect();
+    respond(BulkBlockResponse {
+        results,
+        notified,
+    })
+}
+
+async fn bulk_block_users(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<BulkBlockRequest>,
+    auth: Auth,
+) -> ApiResult<BulkBlockResponse> {
+    set_blocked_bulk(app_data, auth, &request.uids, true).await
+}
+
+async fn bulk_unblock_users(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<BulkBlockRequest>,
+    auth: Auth,
+) -> ApiResult<BulkBlockResponse> {
+    set_blocked_bulk(app_data, auth, &request.uids, false).await
 }
 
 #[derive(Debug, Validate, Deserialize)]
@@ -742,19 +1698,25 @@ async fn update_user_email(
     app_data: web::Data<AppState>,
     request: ValidatedJson<UpdateEmailRequest>,
     auth: Auth,
+    language: AcceptLanguage,
 ) -> ApiResult<UpdateEmailResponse> {
     auth.try_permission("user-email-updating", "create-self")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
-    let result = app_data.query.user
+    auth.try_not_impersonated()?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let result = db.query.user
         .update_email(
-            &*app_data.db.read().await, app_data.clone(),
+            &mut db.client, app_data.clone(),
             &app_data.config.smtp.sender, &app_data.config.site,
-            uid, &request.email[..],
+            uid, &request.email[..], language.0,
         )
         .await
         .map_err(|err| match err {
             QueryError::UserNotFound => ApiError::UserNotFound,
             QueryError::DuplicatedUser { field } => ApiError::DuplicatedUser { field },
+            QueryError::EmailNotConfigured => ApiError::EmailNotConfigured,
+            QueryError::EmailQueueFull => ApiError::EmailQueueFull,
             e => internal_server_error!(e),
         })?;
     respond(UpdateEmailResponse {
@@ -780,13 +1742,52 @@ enum QueryEmailUpdatingResponse {
     Rejected(UserEmailUpdatingPublic),
 }
 
+impl QueryEmailUpdatingResponse {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            QueryEmailUpdatingResponse::NotFound => StatusCode::NOT_FOUND,
+            QueryEmailUpdatingResponse::Expired => StatusCode::GONE,
+            _ => StatusCode::OK,
+        }
+    }
+}
+
+// The same as GET /api/registrations, over the e-mail updating of all users
+async fn list_email_updating(
+    app_data: web::Data<AppState>,
+    request: ValidatedQuery<ListPendingQuery>,
+    auth: Auth,
+) -> ApiResult<ListPendingResponse<UserEmailUpdatingPublic>> {
+    auth.try_permission("user-email-updating", "list")?;
+    let max_limit = crate::constants::PENDING_LIST_MAX_LIMIT;
+    let limit = request.limit.unwrap_or(max_limit).max(1).min(max_limit);
+    let offset = request.offset.max(0);
+    let db = app_data.db().await?;
+    let (items, total) = db.query.user
+        .list_email_updating(&db.client, request.filter.as_deref(),
+                             request.sort.as_deref(), limit, offset)
+        .await
+        .map_err(|err| match err {
+            QueryError::InvalidFilter(e) => ApiError::InvalidFilter { error: e.to_string() },
+            e => internal_server_error!(e),
+        })?;
+    respond(ListPendingResponse {
+        items,
+        total,
+        limit,
+        offset,
+    })
+}
+
 async fn query_email_updating(
     app_data: web::Data<AppState>,
     path: ValidatedPath<UpdateIdPath>,
+    request: ValidatedQuery<QueryStatusQuery>,
     auth: Auth,
-) -> ApiResult<QueryEmailUpdatingResponse> {
-    let result = match app_data.query.user
-        .query_email_updating(&*app_data.db.read().await, &auth, &path.update_id[..])
+) -> Result<HttpResponse, ApiError> {
+    let db = app_data.db().await?;
+    let result = match db.query.user
+        .query_email_updating(&db.client, &auth, &path.update_id[..])
         .await {
         Ok(value) => {
             match value.completed {
@@ -803,7 +1804,7 @@ async fn query_email_updating(
         Err(QueryError::UserEmailUpdatingNotFound) => QueryEmailUpdatingResponse::NotFound,
         Err(e) => return Err(internal_server_error!(e)),
     };
-    respond(result)
+    respond_with_status(&result, result.status_code(), &request)
 }
 
 #[derive(Debug, Validate, Deserialize)]
@@ -813,15 +1814,16 @@ struct ConfirmEmailUpdatingRequest {
     code: Any6,
 }
 
-async fn confirm_email_updating(
-    app_data: web::Data<AppState>,
-    request: ValidatedJson<ConfirmEmailUpdatingRequest>,
-    path: ValidatedPath<UpdateIdPath>,
-    auth: Auth,
-) -> ApiResult<()> {
-    let result = app_data.query.user
-        .confirm_email_updating(&mut *app_data.db.write().await, &auth,
-                                &path.update_id[..], &request.code[..])
+async fn confirm_email_updating_impl(
+    app_data: &web::Data<AppState>,
+    auth: &Auth,
+    update_id: &str,
+    code: &str,
+) -> Result<(), ApiError> {
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let result = db.query.user
+        .confirm_email_updating(&mut db.client, auth, update_id, code)
         .await
         .map_err(|err| match err {
             QueryError::UserEmailUpdatingNotFound => ApiError::UserEmailUpdating { reason: "NotFound".into() },
@@ -842,38 +1844,220 @@ async fn confirm_email_updating(
         password: None,
         nickname: None,
         avatar: None,
-        avatar128: None,
+        avatar_thumbnails: None,
+        avatar_webp: None,
+        avatar_thumbnails_webp: None,
         blocked: None,
         updated_at: result.updated_at,
-    }, &auth)
+    }, auth)
         .await
         .map_err(|e| internal_server_error!(e))?;
+    Ok(())
+}
+
+async fn confirm_email_updating(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<ConfirmEmailUpdatingRequest>,
+    path: ValidatedPath<UpdateIdPath>,
+    auth: Auth,
+) -> ApiResult<()> {
+    confirm_email_updating_impl(&app_data, &auth, &path.update_id[..], &request.code[..]).await?;
     respond(())
 }
 
+// The message may carry what the request sent, so it is escaped
+fn email_updating_link_page(locale: Locale, title: &str, message: &str) -> String {
+    format!("<!DOCTYPE html>\
+        <html lang=\"{}\">\
+            <head>\
+                <meta charset=\"utf-8\">\
+                <title>{}</title>\
+            </head>\
+            <body>\
+                <h1>{}</h1>\
+                <p>{}</p>\
+            </body>\
+        </html>", locale.lang(), title, title, escape_html(message))
+}
+
+// Emailed links are opened without an Authorization header. Knowing both the 24-char id and the
+// code proves control of the new mailbox, so the link itself stands for the confirm permission.
+async fn confirm_email_updating_by_link(
+    app_data: web::Data<AppState>,
+    request: ValidatedQuery<ConfirmEmailUpdatingRequest>,
+    path: ValidatedPath<UpdateIdPath>,
+    auth: Auth,
+    language: AcceptLanguage,
+) -> HttpResponse {
+    let result = match auth.try_permission("user-email-updating", "confirm-link") {
+        Ok(()) => {
+            let link_auth = Auth {
+                claims: None,
+                permissions: vec![PermissionSubjectAction {
+                    subject: "user-email-updating".into(),
+                    action: "confirm".into(),
+                }],
+            };
+            confirm_email_updating_impl(&app_data, &link_auth,
+                                        &path.update_id[..], &request.code[..]).await
+        }
+        Err(e) => Err(e),
+    };
+    let locale = language.0.unwrap_or_default();
+    let texts = locale.email_updating_link_texts();
+    let (status, body) = match result {
+        Ok(()) => (StatusCode::OK, email_updating_link_page(
+            locale, texts.confirmed_title, texts.confirmed)),
+        Err(e) => (e.error_response().status(), email_updating_link_page(
+            locale, texts.failed_title, &format!("{}", e))),
+    };
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
 async fn resend_email_updating_email(
     app_data: web::Data<AppState>,
     path: ValidatedPath<UpdateIdPath>,
     auth: Auth,
+    language: AcceptLanguage,
 ) -> ApiResult<()> {
-    app_data.query.user
+    let db = app_data.db().await?;
+    db.query.user
         .resend_email_updating_email(
-            &*app_data.db.read().await, app_data.clone(), &auth,
+            &db.client, app_data.clone(), &auth,
             &app_data.config.smtp.sender, &app_data.config.site,
-            &path.update_id[..])
+            &path.update_id[..], app_data.config.smtp.resend_interval, language.0)
         .await
         .map_err(|err| match err {
             QueryError::UserEmailUpdatingNotFound => ApiError::UserEmailUpdating { reason: "NotFound".into() },
             QueryError::UserEmailUpdatingExpired => ApiError::UserEmailUpdating { reason: "Expired".into() },
+            QueryError::ResendTooSoon { retry_after } => ApiError::ResendTooSoon { retry_after },
             QueryError::PermissionDenied { subject, action } => ApiError::PermissionDenied {
                 subject,
                 action,
             },
+            QueryError::EmailNotConfigured => ApiError::EmailNotConfigured,
+            QueryError::EmailQueueFull => ApiError::EmailQueueFull,
             e => internal_server_error!(e),
         })?;
     respond(())
 }
 
+async fn cancel_email_updating(
+    app_data: web::Data<AppState>,
+    path: ValidatedPath<UpdateIdPath>,
+    auth: Auth,
+) -> ApiResult<()> {
+    let db = app_data.db().await?;
+    db.query.user
+        .cancel_email_updating(&db.client, &auth, &path.update_id[..])
+        .await
+        .map_err(|err| match err {
+            QueryError::UserEmailUpdatingNotFound => ApiError::UserEmailUpdating { reason: "NotFound".into() },
+            QueryError::PermissionDenied { subject, action } => ApiError::PermissionDenied {
+                subject,
+                action,
+            },
+            e => internal_server_error!(e),
+        })?;
+    respond(())
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct RequestPasswordResetRequest {
+    #[validate]
+    email: Email,
+}
+
+// Succeeds whether or not a user has the email, which is only told to the owner of the email
+async fn request_password_reset(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<RequestPasswordResetRequest>,
+    auth: Auth,
+) -> ApiResult<()> {
+    auth.try_permission("password-reset", "create")?;
+    let db = app_data.db().await?;
+    db.query.user
+        .request_password_reset(
+            &db.client, app_data.clone(),
+            &app_data.config.smtp.sender, &app_data.config.site,
+            &request.email[..],
+        )
+        .await
+        .map_err(|err| match err {
+            QueryError::EmailNotConfigured => ApiError::EmailNotConfigured,
+            QueryError::EmailQueueFull => ApiError::EmailQueueFull,
+            e => internal_server_error!(e),
+        })?;
+    respond(())
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct ResetIdPath {
+    #[validate]
+    reset_id: Any24,
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct ConfirmPasswordResetRequest {
+    #[validate]
+    code: Any6,
+    #[validate]
+    password: Password,
+}
+
+async fn confirm_password_reset(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<ConfirmPasswordResetRequest>,
+    path: ValidatedPath<ResetIdPath>,
+    auth: Auth,
+) -> ApiResult<()> {
+    auth.try_permission("password-reset", "confirm")?;
+    request.password.check(&app_data.config.password)?;
+    let request = request.into_inner();
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let result = db.query.user
+        .confirm_password_reset(&mut db.client,
+                                &path.reset_id[..], &request.code[..], request.password.into())
+        .await
+        .map_err(|err| match err {
+            QueryError::PasswordResetNotFound
+            | QueryError::UserNotFound => ApiError::PasswordReset { reason: "NotFound".into() },
+            QueryError::PasswordResetExpired => ApiError::PasswordReset { reason: "Expired".into() },
+            QueryError::PasswordResetWrongCode => ApiError::PasswordReset { reason: "WrongCode".into() },
+            QueryError::PasswordCompromised => ApiError::PasswordCompromised,
+            e => internal_server_error!(e),
+        })?;
+    let uid = result.id;
+    app_data.send_all(
+        iter::once(UserUpdated {
+            id: uid,
+            username: None,
+            email: None,
+            password: Some(()),
+            nickname: None,
+            avatar: None,
+            avatar_thumbnails: None,
+            avatar_webp: None,
+            avatar_thumbnails_webp: None,
+            blocked: None,
+            updated_at: result.updated_at,
+        }.into())
+            .chain(result.revoked_tokens.into_iter()
+                .map(|jti| TokenRevoked {
+                    jti,
+                    uid,
+                }.into()))
+            .collect(),
+        &auth
+    )
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(())
+}
+
 async fn update_password_impl(
     app_data: web::Data<AppState>,
     auth: Auth,
@@ -881,13 +2065,16 @@ async fn update_password_impl(
     password: String,
     old_password: Option<String>,
 ) -> ApiResult<()> {
-    let updated_at = app_data.query.user
-        .update_password(&mut *app_data.db.write().await, uid,
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let updated_at = db.query.user
+        .update_password(&mut db.client, &auth.actor()?, uid,
                          password, old_password)
         .await
         .map_err(|err| match err {
             QueryError::UserNotFound => ApiError::UserNotFound,
             QueryError::WrongPassword => ApiError::WrongUserOrPassword,
+            QueryError::PasswordCompromised => ApiError::PasswordCompromised,
             e => internal_server_error!(e),
         })?;
     app_data.send(UserUpdated {
@@ -897,14 +2084,16 @@ async fn update_password_impl(
         password: Some(()),
         nickname: None,
         avatar: None,
-        avatar128: None,
+        avatar_thumbnails: None,
+        avatar_webp: None,
+        avatar_thumbnails_webp: None,
         blocked: None,
         updated_at,
     }, &auth)
         .await
         .map_err(|e| internal_server_error!(e))?;
-    let results = app_data.query.token
-        .revoke_tokens_from_user(&*app_data.db.read().await, uid)
+    let results = db.query.token
+        .revoke_tokens_from_user(&db.client, uid)
         .await
         .map_err(|e| internal_server_error!(e))?;
     app_data.send_all(
@@ -921,10 +2110,226 @@ async fn update_password_impl(
     respond(())
 }
 
-#[derive(Debug, Validate, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct UpdatePasswordForMeRequest {
-    #[validate]
+#[derive(Debug, Serialize)]
+struct DeleteUserResponse {
+    deleted_at: DateTime<Utc>,
+}
+
+// The user row is kept with the deleted flag, and all of its tokens are revoked
+async fn delete_user(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    auth: Auth,
+) -> ApiResult<DeleteUserResponse> {
+    auth.try_permission("user", "delete")?;
+    let uid: i32 = uid_path.uid.clone().into();
+    let db = app_data.db().await?;
+    let deleted_at = db.query.user
+        .soft_delete_user(&db.client, uid)
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            e => internal_server_error!(e),
+        })?;
+    let results = db.query.token
+        .revoke_tokens_from_user(&db.client, uid)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    app_data.send_all(
+        iter::once(UserDeleted { id: uid }.into())
+            .chain(results.into_iter()
+                .map(|result| TokenRevoked {
+                    jti: result.id,
+                    uid: result.user,
+                }.into()))
+            .collect(),
+        &auth
+    )
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(DeleteUserResponse {
+        deleted_at,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct EnrollTotpResponse {
+    // otpauth URI holding the secret, usually shown as a QR code
+    uri: String,
+}
+
+async fn enroll_totp_for_me(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<EnrollTotpResponse> {
+    auth.try_permission("user-totp", "update-self")?;
+    auth.try_not_impersonated()?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let db = app_data.db().await?;
+    let uri = db.query.user
+        .enroll_totp(&db.client, uid)
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            QueryError::TotpAlreadyEnabled => ApiError::Totp { reason: "AlreadyEnabled".into() },
+            e => internal_server_error!(e),
+        })?;
+    respond(EnrollTotpResponse {
+        uri,
+    })
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct ConfirmTotpRequest {
+    #[validate]
+    code: Any6,
+}
+
+async fn confirm_totp_for_me(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<ConfirmTotpRequest>,
+    auth: Auth,
+) -> ApiResult<()> {
+    auth.try_permission("user-totp", "update-self")?;
+    auth.try_not_impersonated()?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let db = app_data.db().await?;
+    db.query.user
+        .confirm_totp(&db.client, uid, &request.code[..])
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            QueryError::TotpAlreadyEnabled => ApiError::Totp { reason: "AlreadyEnabled".into() },
+            QueryError::TotpNotEnrolled => ApiError::Totp { reason: "NotEnrolled".into() },
+            QueryError::WrongTotpCode => ApiError::Totp { reason: "WrongCode".into() },
+            e => internal_server_error!(e),
+        })?;
+    respond(())
+}
+
+// One of them is required, the password being the way out for a lost authenticator
+#[derive(Debug, Validate, Deserialize)]
+struct DisableTotpRequest {
+    #[validate]
+    code: Option<Any6>,
+    #[validate]
+    password: Option<Password>,
+}
+
+async fn disable_totp_for_me(
+    app_data: web::Data<AppState>,
+    request: ValidatedJson<DisableTotpRequest>,
+    auth: Auth,
+) -> ApiResult<()> {
+    auth.try_permission("user-totp", "update-self")?;
+    auth.try_not_impersonated()?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let db = app_data.db().await?;
+    db.query.user
+        .disable_totp(&db.client, uid, request.code.as_ref().map(|x| &x[..]),
+                      request.password.clone().map(Into::into))
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            QueryError::TotpRequired => ApiError::TotpRequired,
+            QueryError::TotpNotEnrolled => ApiError::Totp { reason: "NotEnrolled".into() },
+            QueryError::WrongTotpCode => ApiError::Totp { reason: "WrongCode".into() },
+            QueryError::WrongPassword => ApiError::WrongUserOrPassword,
+            e => internal_server_error!(e),
+        })?;
+    respond(())
+}
+
+#[derive(Debug, Serialize)]
+struct ListTokensResponse {
+    tokens: Vec<TokenInfo>,
+}
+
+async fn list_tokens_impl(
+    app_data: web::Data<AppState>,
+    uid: i32,
+) -> ApiResult<ListTokensResponse> {
+    let db = app_data.db().await?;
+    let tokens = db.query.token
+        .list_active_tokens(&db.client, uid)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(ListTokensResponse {
+        tokens,
+    })
+}
+
+async fn list_tokens_for_me(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<ListTokensResponse> {
+    auth.try_permission("token", "list-self")?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    list_tokens_impl(app_data, uid).await
+}
+
+async fn list_tokens(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    auth: Auth,
+) -> ApiResult<ListTokensResponse> {
+    auth.try_permission("token", "read")?;
+    list_tokens_impl(app_data, uid_path.uid.clone().into()).await
+}
+
+#[derive(Debug, Serialize)]
+struct ListPermissionsResponse {
+    permissions: Vec<PermissionSubjectAction>,
+}
+
+// Permissions of all the user's roles, the default role included, merged into one list
+async fn list_permissions_for_me(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<ListPermissionsResponse> {
+    auth.try_permission("permission", "read-self")?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let db = app_data.db().await?;
+    let permissions = db.query.user
+        .fetch_effective_permission(&db.client, uid)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(ListPermissionsResponse {
+        permissions,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ListSubscriptionsResponse {
+    subjects: Vec<String>,
+}
+
+// Subjects the user may subscribe to over the websocket, so that clients can check before
+// connecting
+async fn list_subscriptions_for_me(
+    app_data: web::Data<AppState>,
+    auth: Auth,
+) -> ApiResult<ListSubscriptionsResponse> {
+    auth.try_permission("permission", "read-self")?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let db = app_data.db().await?;
+    let mut subjects = db.query.user
+        .fetch_permission_tree(&db.client, Some(uid))
+        .await
+        .map_err(|e| internal_server_error!(e))?
+        .get_subscribe()
+        .into_iter()
+        .collect::<Vec<_>>();
+    subjects.sort();
+    respond(ListSubscriptionsResponse {
+        subjects,
+    })
+}
+
+#[derive(Debug, Validate, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdatePasswordForMeRequest {
+    #[validate]
     password: Password,
     #[validate]
     old_password: Password
@@ -936,7 +2341,9 @@ async fn update_password_for_me(
     auth: Auth,
 ) -> ApiResult<()> {
     auth.try_permission("user-password", "update-self")?;
-    let uid = auth.claims.as_ref().ok_or_else(|| ApiError::MissingAuthorizationHeader)?.uid;
+    auth.try_not_impersonated()?;
+    let uid = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    request.password.check(&app_data.config.password)?;
     update_password_impl(app_data, auth, uid,
                          request.password.clone().into(),
                          Some(request.old_password.clone().into())).await
@@ -957,13 +2364,331 @@ async fn update_password(
 ) -> ApiResult<()> {
     auth.try_permission("user-password", "update")?;
     let uid = uid_path.uid.clone().into();
+    request.password.check(&app_data.config.password)?;
     update_password_impl(app_data, auth, uid, request.password.clone().into(), None).await
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImpersonateUserResponse {
+    jwt: String,
+    expires_at: DateTime<Utc>,
+}
+
+// Acts as the user until the token expires. The target must have no role the caller lacks, and
+// the token can neither be resumed nor used to change the user's credentials
+async fn impersonate_user(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    auth: Auth,
+    req: HttpRequest,
+) -> ApiResult<ImpersonateUserResponse> {
+    auth.try_permission("user", "impersonate")?;
+    auth.try_not_impersonated()?;
+    let caller = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let uid: i32 = uid_path.uid.clone().into();
+    {
+        let db = app_data.db().await?;
+        let client = &db.client;
+        db.query.user
+            .find_one_to_username(client, uid)
+            .await
+            .map_err(|err| match err {
+                QueryError::UserNotFound => ApiError::UserNotFound,
+                e => internal_server_error!(e),
+            })?;
+        let roles = db.query.user
+            .fetch_role_name(client, uid)
+            .await
+            .map_err(|e| internal_server_error!(e))?
+            .into_iter()
+            .filter(|role| role != "default")
+            .collect::<Vec<_>>();
+        let extra_roles = db.query.user
+            .check_extra_roles(client, caller, &roles[..])
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+        if !extra_roles.is_empty() {
+            return Err(ApiError::AttemptToElevateRole { roles: extra_roles });
+        }
+    }
+    let connection_info = req.connection_info().clone();
+    let user_agent = req.headers().get("User-Agent")
+        .map(HeaderValue::to_str)
+        .and_then(std::result::Result::ok);
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let (jwt, claims) = db.query.token
+        .create_impersonation_token(&mut db.client, uid, caller,
+                                    connection_info.host(), connection_info.remote(), user_agent)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    let expires_at = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(claims.exp, 0), Utc);
+    app_data.send(TokenAcquired(Token {
+        id: claims.jti,
+        user: uid,
+        issued_at: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(claims.iat, 0), Utc),
+        expires_at,
+        acquire_method: "impersonation".into(),
+        acquire_host: connection_info.host().into(),
+        acquire_remote: connection_info.remote().map(String::from),
+        acquire_user_agent: user_agent.map(String::from),
+    }), &auth)
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    respond(ImpersonateUserResponse {
+        jwt,
+        expires_at,
+    })
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct PreviewRolePath {
+    #[validate]
+    uid: Id,
+    #[validate]
+    rid: Id,
+}
+
+#[derive(Debug, Serialize)]
+struct PreviewedPermission {
+    id: i32,
+    subject: String,
+    action: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PreviewRoleResponse {
+    // Permissions the user would have after adding the role but has not yet, sorted by id
+    would_gain: Vec<PreviewedPermission>,
+}
+
+// Changes nothing, the role may even be one the user already has
+async fn preview_role(
+    app_data: web::Data<AppState>,
+    path: ValidatedPath<PreviewRolePath>,
+    auth: Auth,
+) -> ApiResult<PreviewRoleResponse> {
+    auth.try_permission("role", "read")?;
+    auth.try_permission("permission", "read")?;
+    let uid = path.uid.clone().into();
+    let rid = path.rid.clone().into();
+    let db = app_data.db().await?;
+    let client = &db.client;
+    db.query.user
+        .find_one_to_username(client, uid)
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            e => internal_server_error!(e),
+        })?;
+    let current = db.query.user
+        .fetch_permission_tree(client, Some(uid))
+        .await
+        .map_err(|e| internal_server_error!(e))?
+        .get();
+    let role_permissions = db.query.user
+        .fetch_role_permissions(client, rid)
+        .await
+        .map_err(|err| match err {
+            QueryError::RoleNotFound => ApiError::RoleNotFound,
+            e => internal_server_error!(e),
+        })?;
+    let mut would_gain = role_permissions.into_iter()
+        .filter(|(id, _)| !current.contains_key(id))
+        .map(|(id, permission)| PreviewedPermission {
+            id,
+            subject: permission.subject,
+            action: permission.action,
+        })
+        .collect::<Vec<_>>();
+    would_gain.sort_by_key(|x| x.id);
+    respond(PreviewRoleResponse {
+        would_gain,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ListRoleAssignmentsResponse {
+    roles: Vec<RoleAssignment>,
+}
+
+// The same roles as GET /api/users/:id with populate, along with who granted them and when
+async fn list_role_assignments(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    auth: Auth,
+) -> ApiResult<ListRoleAssignmentsResponse> {
+    auth.try_permission("user", "read")?;
+    auth.try_permission("role", "read")?;
+    let uid: i32 = uid_path.uid.clone().into();
+    let roles = retry_db!(app_data, |db| {
+        match db.query.user.find_one_to_username(&db.client, uid).await {
+            Ok(_) => db.query.user.find_role_assignments(&db.client, uid).await,
+            Err(e) => Err(e),
+        }
+    })
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            e => internal_server_error!(e),
+        })?;
+    respond(ListRoleAssignmentsResponse {
+        roles,
+    })
+}
+
+#[derive(Debug, Validate, Deserialize)]
+struct UpdateUserRolesRequest {
+    #[validate]
+    roles: Vec<RoleName>,
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateUserRolesResponse {
+    // One per distinct role in the order of the request. Roles the user already has, or does
+    // not have when removing, are ok as well
+    #[serde(flatten)]
+    results: BulkResults<String>,
+    // All the roles of the user afterwards
+    roles: Vec<RoleShort>,
+}
+
+// Like creating a user, the roles must be a subset of the caller's roles, for removing as well.
+// Returns the distinct roles in the order of the request along with the ones outside the subset,
+// which are reported one by one instead of failing the others
+async fn check_user_roles(
+    app_data: &web::Data<AppState>, auth: &Auth, request: &UpdateUserRolesRequest,
+) -> Result<(Vec<String>, HashSet<String>), ApiError> {
+    auth.try_permission("user-role", "update")?;
+    let caller = auth.claims.as_ref().ok_or(ApiError::MissingAuthorizationHeader)?.uid;
+    let mut seen = HashSet::new();
+    let roles = request.roles.iter()
+        .map(|x| x.clone().into())
+        .filter(|x: &String| seen.insert(x.clone()))
+        .collect::<Vec<String>>();
+    let db = app_data.db().await?;
+    let extra_roles = db.query.user
+        .check_extra_roles(&db.client, caller, &roles[..])
+        .await
+        .map_err(|e| internal_server_error!(e))?;
+    Ok((roles, extra_roles.into_iter().collect()))
+}
+
+// Roles the user does not end up with after adding them match no role
+fn user_roles_results(
+    roles: Vec<String>, extra_roles: &HashSet<String>, user_roles: Option<&[RoleShort]>,
+) -> BulkResults<String> {
+    roles.into_iter()
+        .map(|role| {
+            let result = if extra_roles.contains(&role) {
+                Err(ApiError::AttemptToElevateRole { roles: vec![role.clone()] })
+            } else if user_roles.is_some_and(|x| x.iter().all(|x| x.name != role)) {
+                Err(ApiError::RoleNotFound)
+            } else {
+                Ok(())
+            };
+            (role, result)
+        })
+        .collect()
+}
+
+async fn add_user_roles(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    request: ValidatedJson<UpdateUserRolesRequest>,
+    auth: Auth,
+) -> ApiResult<UpdateUserRolesResponse> {
+    let (roles, extra_roles) = check_user_roles(&app_data, &auth, &request).await?;
+    let allowed = roles.iter()
+        .filter(|x| !extra_roles.contains(*x))
+        .cloned()
+        .collect::<Vec<_>>();
+    let uid: i32 = uid_path.uid.clone().into();
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let (added, user_roles) = db.query.user
+        .add_roles_to_user(&mut db.client, &auth.actor()?, uid, &allowed[..])
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            e => internal_server_error!(e),
+        })?;
+    let mut messages: Vec<InnerInternalMessage> = Vec::new();
+    for role in added {
+        let role_permissions = db.query.user
+            .fetch_role_permissions(&db.client, role)
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+        messages.push(InternalUserRoleCreated {
+            user: uid,
+            role,
+            role_permissions: role_permissions.into_iter()
+                .map(|(id, permission)| PermissionIdSubjectAction {
+                    id,
+                    subject: permission.subject,
+                    action: permission.action,
+                })
+                .collect(),
+        }.into());
+    }
+    if !messages.is_empty() {
+        app_data.send_all(messages, &auth)
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
+    respond(UpdateUserRolesResponse {
+        results: user_roles_results(roles, &extra_roles, Some(&user_roles)),
+        roles: user_roles,
+    })
+}
+
+async fn remove_user_roles(
+    app_data: web::Data<AppState>,
+    uid_path: ValidatedPath<UidPath>,
+    request: ValidatedJson<UpdateUserRolesRequest>,
+    auth: Auth,
+) -> ApiResult<UpdateUserRolesResponse> {
+    let (roles, extra_roles) = check_user_roles(&app_data, &auth, &request).await?;
+    let allowed = roles.iter()
+        .filter(|x| !extra_roles.contains(*x))
+        .cloned()
+        .collect::<Vec<_>>();
+    let uid: i32 = uid_path.uid.clone().into();
+    let mut db = app_data.db().await?;
+    let db = &mut *db;
+    let (removed, user_roles) = db.query.user
+        .remove_roles_from_user(&mut db.client, &auth.actor()?, uid, &allowed[..])
+        .await
+        .map_err(|err| match err {
+            QueryError::UserNotFound => ApiError::UserNotFound,
+            e => internal_server_error!(e),
+        })?;
+    if !removed.is_empty() {
+        app_data.send_all(
+            removed.into_iter()
+                .map(|role| UserRoleDeleted {
+                    user: uid,
+                    role,
+                }.into())
+                .collect(),
+            &auth
+        )
+            .await
+            .map_err(|e| internal_server_error!(e))?;
+    }
+    respond(UpdateUserRolesResponse {
+        results: user_roles_results(roles, &extra_roles, None),
+        roles: user_roles,
+    })
+}
+
 pub fn users_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
-    if let Err(e) = std::fs::create_dir_all(Path::new(&state.config.media.root)
-        .join(crate::constants::AVATAR_FOLDER))  {
-        error!("failed to create directory {}", e);
+    if let AvatarStoreKind::Filesystem = state.config.media.avatar_store {
+        if let Err(e) = std::fs::create_dir_all(Path::new(&state.config.media.root)
+            .join(crate::constants::AVATAR_FOLDER))  {
+            error!("failed to create directory {}", e);
+        }
     }
     let state = state.clone();
     Box::new(move |cfg| {
@@ -971,51 +2696,1761 @@ pub fn users_api(state: &web::Data<AppState>) -> Box<dyn FnOnce(&mut web::Servic
             web::scope("registrations")
                 .app_data(state.clone())
                 .app_data(default_json_config())
+                .app_data(default_path_config())
+                .app_data(default_query_config())
                 .route("/{reg_id}/confirm", web::post().to(confirm_registration))
                 .route("/{reg_id}/resend", web::post().to(resend_registration_email))
                 .route("/{reg_id}", web::get().to(query_registration))
+                .route("/{reg_id}", web::delete().to(cancel_registration))
+                .route("", web::get().to(list_registrations))
                 .route("", web::post().to(register_user))
         ).service(
             web::scope("email-updating")
                 .app_data(state.clone())
                 .app_data(default_json_config())
+                .app_data(default_path_config())
+                .app_data(default_query_config())
                 .route("/{update_id}/confirm", web::post().to(confirm_email_updating))
+                .route("/{update_id}/confirm", web::get().to(confirm_email_updating_by_link))
                 .route("/{update_id}/resend", web::post().to(resend_email_updating_email))
                 .route("/{update_id}", web::get().to(query_email_updating))
+                .route("/{update_id}", web::delete().to(cancel_email_updating))
+                .route("", web::get().to(list_email_updating))
                 .route("", web::post().to(update_user_email))
+        ).service(
+            web::scope("password-resets")
+                .app_data(state.clone())
+                .app_data(default_json_config())
+                .app_data(default_path_config())
+                .route("/{reset_id}/confirm", web::post().to(confirm_password_reset))
+                .route("", web::post().to(request_password_reset))
         ).service(
             web::scope("users")
                 .app_data(state.clone())
                 .app_data(default_json_config())
+                .app_data(default_path_config())
+                .app_data(default_query_config())
                 .route("/check-username-existence", web::get().to(check_username_existence))
+                .route("/check-usernames-existence", web::post().to(check_usernames_existence))
                 .route("/check-email-existence", web::get().to(check_email_existence))
+                .route("/changes", web::get().to(read_user_changes))
+                .route("/count", web::get().to(count_users))
+                .route("/search", web::get().to(search_users))
+                .route("/bulk-block", web::post().to(bulk_block_users))
+                .route("/bulk-unblock", web::post().to(bulk_unblock_users))
                 .service(
                     web::scope("/me/avatar")
                         .app_data(state.clone())
                         .app_data(default_path_config())
                         .app_data(avatar_multer_config())
-                        .route("", web::post().to(upload_avatar_for_me))
-                        .route("", web::delete().to(delete_avatar_for_me))
+                        .route("/status", web::get().to(read_avatar_status_for_me))
+                        .service(web::resource("")
+                            .wrap(RequestMetrics::new(&state, "/users/me/avatar"))
+                            .route(web::post().to(upload_avatar_for_me))
+                            .route(web::delete().to(delete_avatar_for_me)))
                 )
                 .route("/me/password", web::post().to(update_password_for_me))
-                .route("/me", web::get().to(read_user_for_me))
-                .route("/me", web::patch().to(update_user_for_me))
+                .route("/me/tokens", web::get().to(list_tokens_for_me))
+                .route("/me/permissions", web::get().to(list_permissions_for_me))
+                .route("/me/subscriptions", web::get().to(list_subscriptions_for_me))
+                .route("/me/totp/confirm", web::post().to(confirm_totp_for_me))
+                .route("/me/totp", web::post().to(enroll_totp_for_me))
+                .route("/me/totp", web::delete().to(disable_totp_for_me))
+                .service(web::resource("/me")
+                    .wrap(RequestMetrics::new(&state, "/users/me"))
+                    .route(web::get().to(read_user_for_me))
+                    .route(web::patch().to(update_user_for_me)))
                 // .route("/me", web::delete().to(index))
                 .service(
                     web::scope("/{uid}/avatar")
-                        .app_data(state)
+                        .app_data(state.clone())
+                        .app_data(default_json_config())
                         .app_data(default_path_config())
                         .app_data(avatar_multer_config())
-                        .route("", web::post().to(upload_avatar))
-                        .route("", web::delete().to(delete_avatar))
+                        .route("/status", web::get().to(read_avatar_status))
+                        .route("/from-url", web::post().to(upload_avatar_from_url))
+                        .service(web::resource("")
+                            .wrap(RequestMetrics::new(&state, "/users/{uid}/avatar"))
+                            .route(web::post().to(upload_avatar))
+                            .route(web::delete().to(delete_avatar)))
                 )
                 .route("/{uid}/password", web::post().to(update_password))
-                // .route("/{uid}/roles", web::post().to(index))
-                .route("/{uid}", web::get().to(read_user))
-                .route("/{uid}", web::patch().to(update_user))
-                // .route("/{uid}", web::delete().to(index))
-                .route("", web::post().to(create_user))
-                // .route("", web::get().to(index))
+                .route("/{uid}/tokens", web::get().to(list_tokens))
+                .route("/{uid}/impersonate", web::post().to(impersonate_user))
+                .route("/{uid}/preview-role/{rid}", web::get().to(preview_role))
+                .route("/{uid}/role-assignments", web::get().to(list_role_assignments))
+                .route("/{uid}/roles", web::post().to(add_user_roles))
+                .route("/{uid}/roles", web::delete().to(remove_user_roles))
+                .service(web::resource("/{uid}")
+                    .wrap(RequestMetrics::new(&state, "/users/{uid}"))
+                    .route(web::get().to(read_user))
+                    .route(web::patch().to(update_user))
+                    .route(web::delete().to(delete_user)))
+                .service(web::resource("")
+                    .wrap(RequestMetrics::new(&state, "/users"))
+                    .route(web::post().to(create_user))
+                    .route(web::get().to(list_users)))
         );
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_avatar, fetch_avatar_with, is_public_ip};
+    use crate::api::{api_v1, avatar_store::join_avatar_file, testing::TestApp};
+    use crate::constants::AVATAR_PROCESSING_CAPACITY;
+    use crate::queries::{
+        codes::MemoryCodeStore,
+        email::{MemoryTransport, SentEmail},
+        totp,
+    };
+    use crate::websocket::{
+        main_subscriber::UpdateSubscribe,
+        push_messages::{InnerInternalMessage, InternalMessage},
+    };
+    use actix::{Actor, Context, Handler};
+    use actix_web::{App, http::StatusCode, test};
+    use chrono::{DateTime, Utc};
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+
+    // Keeps what is pushed to its subjects, as a websocket client would receive it
+    struct PushSink(Arc<Mutex<Vec<InnerInternalMessage>>>);
+
+    impl Actor for PushSink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<InternalMessage> for PushSink {
+        type Result = Result<(), Infallible>;
+
+        fn handle(&mut self, msg: InternalMessage, _ctx: &mut Context<Self>) -> Self::Result {
+            self.0.lock().unwrap().extend(msg.messages);
+            Ok(())
+        }
+    }
+
+    async fn subscribe_pushes(
+        app: &TestApp, subject: &str,
+    ) -> Arc<Mutex<Vec<InnerInternalMessage>>> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        app.app_data.subscriber
+            .send(UpdateSubscribe {
+                client: PushSink(received.clone()).start().recipient(),
+                subjects: std::iter::once(subject.to_owned()).collect(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        received
+    }
+
+    #[actix_rt::test]
+    async fn confirm_email_updating_by_link_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        app.db.client
+            .execute("INSERT INTO user_email_updating (id, code, \"user\", new_email, \
+                                                       created_at, expires_at, sent_at, \
+                                                       attempts) \
+                      VALUES ('abcdefghijklmnopqrstuvwx', '123456', $1, 'new@example.com', \
+                              NOW(), NOW() + INTERVAL '1 hour', NOW(), 0)", &[&uid])
+            .await
+            .unwrap();
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let confirm = |code: &str| test::TestRequest::get()
+            .uri(&format!("/api/v1/email-updating/abcdefghijklmnopqrstuvwx/confirm?code={}", code))
+            .header("Accept-Language", "en-US,en;q=0.9")
+            .to_request();
+        let client = &app.db.client;
+        let email = || async move {
+            client
+                .query_one("SELECT email FROM \"user\" WHERE id = $1", &[&uid])
+                .await
+                .unwrap()
+                .get::<_, Option<String>>(0)
+        };
+        // No Authorization header, as when the link is clicked in a mail client
+        let response = test::call_service(&mut service, confirm("654321")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers().get("Content-Type").unwrap()
+            .to_str().unwrap().starts_with("text/html"));
+        assert_eq!(email().await, Some("alice@example.com".into()));
+        let response = test::call_service(&mut service, confirm("123456")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        assert!(body.contains("E-mail verified"));
+        assert_eq!(email().await, Some("new@example.com".into()));
+        // The link is single use
+        let response = test::call_service(&mut service, confirm("123456")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    fn register(username: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/v1/registrations")
+            .set_json(&serde_json::json!({
+                "username": username,
+                "email": format!("{}@example.com", username),
+                "password": "password",
+            }))
+    }
+
+    #[actix_rt::test]
+    async fn smtp_log_mode_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let code = app.app_data.mailer.last_code().unwrap();
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/confirm",
+                          response["data"]["id"].as_str().unwrap()))
+            .set_json(&serde_json::json!({ "code": code }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn cancel_pending_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice").to_request()).await;
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let reg_id = response["data"]["id"].as_str().unwrap().to_string();
+        let code = app.app_data.mailer.last_code().unwrap();
+        let cancel = |uri: String, authorization: Option<&str>| {
+            let request = test::TestRequest::delete().uri(&uri);
+            match authorization {
+                Some(authorization) => request.header("Authorization", authorization),
+                None => request,
+            }.to_request()
+        };
+        let uri = format!("/api/v1/registrations/{}", reg_id);
+        let response = test::call_service(&mut service, cancel(uri.clone(), None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Already cancelled
+        let response = test::call_service(&mut service, cancel(uri, None)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/confirm", reg_id))
+            .set_json(&serde_json::json!({ "code": code }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let (_, bob) = app.user("bob", &["normal-user"]).await;
+        let (_, carol) = app.user("carol", &["normal-user"]).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/email-updating")
+            .header("Authorization", bob.clone())
+            .set_json(&serde_json::json!({ "email": "bob2@example.com" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let uri = format!("/api/v1/email-updating/{}", response["data"]["id"].as_str().unwrap());
+        let response = test::call_service(&mut service, cancel(uri.clone(), Some(&carol))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, cancel(uri.clone(), Some(&bob))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, cancel(uri, Some(&bob))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn supersede_email_updating_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let mut ids = Vec::new();
+        let mut codes = Vec::new();
+        for email in &["alice2@example.com", "alice3@example.com"] {
+            let response = test::call_service(&mut service, test::TestRequest::post()
+                .uri("/api/v1/email-updating")
+                .header("Authorization", alice.clone())
+                .set_json(&serde_json::json!({ "email": email }))
+                .to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response: serde_json::Value =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            ids.push(response["data"]["id"].as_str().unwrap().to_string());
+            codes.push(app.app_data.mailer.last_code().unwrap());
+        }
+        let confirm = |id: &str, code: &str| test::TestRequest::post()
+            .uri(&format!("/api/v1/email-updating/{}/confirm", id))
+            .header("Authorization", alice.clone())
+            .set_json(&serde_json::json!({ "code": code }))
+            .to_request();
+        // Superseded by the second one
+        let response = test::call_service(&mut service, confirm(&ids[0], &codes[0])).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri(&format!("/api/v1/email-updating/{}", ids[0]))
+            .header("Authorization", alice.clone())
+            .to_request()).await;
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["completed"], false);
+        let response = test::call_service(&mut service, confirm(&ids[1], &codes[1])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let pending: i64 = app.db.client
+            .query_one("SELECT COUNT(*) FROM user_email_updating WHERE completed IS NULL", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(pending, 0);
+    }
+
+    #[actix_rt::test]
+    async fn list_pending_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        for username in &["bob", "carol"] {
+            let response = test::call_service(&mut service, register(username).to_request())
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/email-updating")
+            .header("Authorization", alice.clone())
+            .set_json(&serde_json::json!({ "email": "alice2@example.com" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let list = |uri: &str, authorization: &str| test::TestRequest::get()
+            .uri(uri)
+            .header("Authorization", authorization)
+            .to_request();
+        let response = test::call_service(&mut service,
+                                          list("/api/v1/registrations", &alice)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, list(
+            "/api/v1/registrations?filter=completed:null%20and%20username:bob", &admin)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["total"], 1);
+        let item = &response["data"]["items"][0];
+        assert_eq!(item["username"], "bob");
+        assert!(item.get("code").is_none() && item.get("password").is_none());
+        let response = test::call_service(&mut service,
+                                          list("/api/v1/registrations?limit=1", &admin)).await;
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["total"], 2);
+        assert_eq!(response["data"]["items"].as_array().unwrap().len(), 1);
+        let response = test::call_service(&mut service,
+                                          list("/api/v1/email-updating", &alice)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service,
+                                          list("/api/v1/email-updating?sort=new_email", &admin))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["total"], 1);
+        let item = &response["data"]["items"][0];
+        assert_eq!(item["new_email"], "alice2@example.com");
+        assert!(item.get("code").is_none());
+        let response = test::call_service(&mut service,
+                                          list("/api/v1/email-updating?filter=code:1", &admin))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn registration_ttl_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log", "--registration-ttl", "60"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let time = |field: &str| response["data"][field].as_str().unwrap()
+            .parse::<DateTime<Utc>>()
+            .unwrap();
+        assert_eq!((time("expires_at") - time("created_at")).num_seconds(), 60);
+    }
+
+    #[actix_rt::test]
+    async fn resend_cooldown_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log", "--smtp-resend-interval", "60"])
+            .await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice").to_request()).await;
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let reg_id = response["data"]["id"].as_str().unwrap().to_string();
+        let resend = || test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/resend", reg_id))
+            .to_request();
+        // Just sent by the registration
+        let response = test::call_service(&mut service, resend()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers().get("Retry-After").unwrap()
+            .to_str().unwrap().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["type"], "ResendTooSoon");
+        app.db.client
+            .execute("UPDATE user_registration SET sent_at = sent_at - INTERVAL '1 minute'", &[])
+            .await
+            .unwrap();
+        let response = test::call_service(&mut service, resend()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // The resend starts the window over
+        let response = test::call_service(&mut service, resend()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn smtp_disabled_mode_test() {
+        let app = match TestApp::new(&["--smtp-mode", "disabled"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice").to_request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["type"], "EmailNotConfigured");
+        assert_eq!(app.db.client
+                       .query_one("SELECT COUNT(*) FROM user_registration", &[])
+                       .await
+                       .unwrap()
+                       .get::<_, i64>(0), 0);
+    }
+
+    #[actix_rt::test]
+    async fn validation_error_fields_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/registrations")
+            .set_json(&serde_json::json!({
+                "username": "alice",
+                "email": "alice",
+                "password": "pass",
+            }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["type"], "ValidationError");
+        let mut fields = response["data"]["errors"].as_array().unwrap().iter()
+            .map(|x| (x["field"].as_str().unwrap(), x["code"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        fields.sort();
+        assert_eq!(fields, vec![("email", "email"), ("password", "length")]);
+        // Query strings are reported the same way
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/check-email-existence?email=alice")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["type"], "ValidationError");
+        assert_eq!(response["data"]["errors"][0]["field"], "email");
+    }
+
+    #[actix_rt::test]
+    async fn password_strength_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log", "--password-require-digit"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice").to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["errors"][0]["field"], "password");
+        assert_eq!(response["data"]["errors"][0]["code"], "digit");
+    }
+
+    #[actix_rt::test]
+    async fn case_insensitive_duplicated_user_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("ALICE").to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["type"], "DuplicatedUser");
+        assert_eq!(response["data"]["field"], "username");
+        // Only the email collides here
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/registrations")
+            .set_json(&serde_json::json!({
+                "username": "bob",
+                "email": "Alice@Example.com",
+                "password": "password",
+            }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["field"], "email");
+    }
+
+    #[actix_rt::test]
+    async fn password_reset_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let request = |email: &str| test::TestRequest::post()
+            .uri("/api/v1/password-resets")
+            .set_json(&serde_json::json!({ "email": email }))
+            .to_request();
+        // Unknown emails look the same to the requester
+        let response = test::call_service(&mut service, request("bob@example.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(app.app_data.mailer.last_code(), None);
+        let response = test::call_service(&mut service, request("alice@example.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let code = app.app_data.mailer.last_code().unwrap();
+        let id: String = app.db.client
+            .query_one("SELECT id FROM user_password_reset WHERE \"user\" = $1", &[&uid])
+            .await
+            .unwrap()
+            .get(0);
+        let confirm = |code: &str| test::TestRequest::post()
+            .uri(&format!("/api/v1/password-resets/{}/confirm", id))
+            .set_json(&serde_json::json!({ "code": code, "password": "new-password" }))
+            .to_request();
+        let wrong_code = if code == "000000" { "111111" } else { "000000" };
+        let response = test::call_service(&mut service, confirm(wrong_code)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&mut service, confirm(&code)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(app.db.client
+                       .query_one("SELECT COUNT(*) FROM token WHERE \"user\" = $1 AND NOT revoked",
+                                  &[&uid])
+                       .await
+                       .unwrap()
+                       .get::<_, i64>(0), 0);
+        let row = app.db.client
+            .query_one("SELECT actor, detail FROM audit_log \
+                WHERE action = 'user-password.reset' AND target = $1", &[&uid])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, i32>("actor"), uid);
+        assert_eq!(row.get::<_, serde_json::Value>("detail"), serde_json::json!({ "reset": id }));
+        let response = test::call_service(&mut service, confirm(&code)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // Inserts an APP1 segment with nothing but the orientation into a JPEG
+    fn with_exif_orientation(jpeg: &[u8], orientation: u8) -> Vec<u8> {
+        let mut result = jpeg[..2].to_vec();
+        result.extend(&b"\xff\xe1\x00\x22Exif\x00\x00MM\x00\x2a\x00\x00\x00\x08\x00\x01\
+                         \x01\x12\x00\x03\x00\x00\x00\x01\x00"[..]);
+        result.extend(&[orientation, 0, 0, 0, 0, 0, 0]);
+        result.extend(&jpeg[2..]);
+        result
+    }
+
+    #[actix_rt::test]
+    async fn avatar_exif_orientation_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, authorization) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        // Red on the left and blue on the right as stored, which is red on the top and blue on
+        // the bottom once rotated clockwise as orientation 6 tells
+        let stored = image::RgbImage::from_fn(64, 32, |x, _| if x < 32 {
+            image::Rgb([255, 0, 0])
+        } else {
+            image::Rgb([0, 0, 255])
+        });
+        let mut jpeg = Vec::new();
+        image::DynamicImage::ImageRgb8(stored)
+            .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(95))
+            .unwrap();
+        let mut body = Vec::from(&b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"avatar.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\r\n"[..]);
+        body.extend(with_exif_orientation(&jpeg, 6));
+        body.extend(&b"\r\n--boundary--\r\n"[..]);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users/me/avatar")
+            .header("Authorization", authorization)
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .set_payload(body)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let db = app.app_data.db().await.unwrap();
+        let avatars = db.query.user.fetch_avatars(&db.client, uid).await.unwrap();
+        let root = &app.app_data.config.media.root;
+        let avatar = image::open(join_avatar_file(root, avatars.avatar.as_ref().unwrap()))
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(avatar.dimensions(), (32, 32));
+        // The bottom left corner would be red if the orientation were ignored
+        let top_left = avatar.get_pixel(4, 4);
+        let bottom_left = avatar.get_pixel(4, 27);
+        assert!(top_left[0] > 200 && top_left[2] < 60);
+        assert!(bottom_left[0] < 60 && bottom_left[2] > 200);
+        for file in avatars.files() {
+            app.app_data.avatar_store.delete(file).await.unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn avatar_async_test() {
+        let app = match TestApp::new(&["--media-avatar-async"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, authorization) = app.user("alice", &["normal-user"]).await;
+        let received = subscribe_pushes(&app, "user-updated").await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(256, 200))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut body = Vec::from(&b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"avatar.png\"\r\n\
+            Content-Type: image/png\r\n\r\n"[..]);
+        body.extend(png);
+        body.extend(&b"\r\n--boundary--\r\n"[..]);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users/me/avatar")
+            .header("Authorization", authorization.clone())
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .set_payload(body)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["processing"], true);
+        assert_eq!(response["data"]["avatar_thumbnails"], serde_json::json!({}));
+        let provisional = response["data"]["avatar"].as_str().unwrap().rsplit('/').next().unwrap()
+            .to_owned();
+        assert!(provisional.ends_with(".upload.png"));
+        let mut status = serde_json::Value::Null;
+        for _ in 0..100 {
+            let response = test::call_service(&mut service, test::TestRequest::get()
+                .uri("/api/v1/users/me/avatar/status")
+                .header("Authorization", authorization.clone())
+                .to_request()).await;
+            status = serde_json::from_slice(&test::read_body(response).await).unwrap();
+            if status["data"]["status"] != "Processing" {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(50)).await;
+        }
+        assert_eq!(status["data"]["status"], "Idle");
+        let db = app.app_data.db().await.unwrap();
+        let avatars = db.query.user.fetch_avatars(&db.client, uid).await.unwrap();
+        assert!(avatars.avatar.as_ref().unwrap().ends_with(".200x200.png"));
+        assert!(avatars.avatar_thumbnails[&128].ends_with(".thumb.128x128.png"));
+        // The processed avatar is pushed once it replaces the provisional one
+        let processed = |messages: &[InnerInternalMessage]| messages.iter().any(|x| match x {
+            InnerInternalMessage::UserUpdated(x) => x.id == uid && x.avatar.as_ref()
+                .and_then(|x| x.as_ref())
+                .is_some_and(|x| x.ends_with(".200x200.png")),
+            _ => false,
+        });
+        for _ in 0..100 {
+            if processed(&received.lock().unwrap()) {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(processed(&received.lock().unwrap()));
+        // The provisional avatar was the avatar of the user until then, and is removed after
+        assert!(received.lock().unwrap().iter().any(|x| match x {
+            InnerInternalMessage::UserUpdated(x) => x.id == uid && x.avatar.as_ref()
+                .and_then(|x| x.as_ref())
+                .is_some_and(|x| x.ends_with(&provisional)),
+            _ => false,
+        }));
+        let root = &app.app_data.config.media.root;
+        assert!(!join_avatar_file(root, &provisional).exists());
+        for file in avatars.files() {
+            app.app_data.avatar_store.delete(file).await.unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn avatar_queue_full_test() {
+        let app = match TestApp::new(&["--media-avatar-async"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, authorization) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(64, 64))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut body = Vec::from(&b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"avatar.png\"\r\n\
+            Content-Type: image/png\r\n\r\n"[..]);
+        body.extend(png);
+        body.extend(&b"\r\n--boundary--\r\n"[..]);
+        let upload = || test::TestRequest::post()
+            .uri("/api/v1/users/me/avatar")
+            .header("Authorization", authorization.clone())
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .set_payload(body.clone())
+            .to_request();
+        let permits = (0..AVATAR_PROCESSING_CAPACITY)
+            .map(|_| app.app_data.avatar_jobs.clone().try_acquire_owned().unwrap())
+            .collect::<Vec<_>>();
+        let response = test::call_service(&mut service, upload()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let db = app.app_data.db().await.unwrap();
+        assert!(db.query.user.fetch_avatars(&db.client, uid).await.unwrap().avatar.is_none());
+        drop(db);
+        drop(permits);
+        let response = test::call_service(&mut service, upload()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for _ in 0..100 {
+            if app.app_data.avatar_jobs.available_permits() == AVATAR_PROCESSING_CAPACITY {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(app.app_data.avatar_jobs.available_permits(), AVATAR_PROCESSING_CAPACITY);
+        let db = app.app_data.db().await.unwrap();
+        let avatars = db.query.user.fetch_avatars(&db.client, uid).await.unwrap();
+        for file in avatars.files() {
+            app.app_data.avatar_store.delete(file).await.unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn avatar_gif_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, authorization) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let mut gif = Vec::new();
+        image::DynamicImage::ImageRgba8(image::RgbaImage::new(64, 64))
+            .write_to(&mut gif, image::ImageOutputFormat::Gif)
+            .unwrap();
+        let mut body = Vec::from(&b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"avatar.gif\"\r\n\
+            Content-Type: image/gif\r\n\r\n"[..]);
+        body.extend(gif);
+        body.extend(&b"\r\n--boundary--\r\n"[..]);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users/me/avatar")
+            .header("Authorization", authorization.clone())
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .set_payload(body)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let db = app.app_data.db().await.unwrap();
+        let avatars = db.query.user.fetch_avatars(&db.client, uid).await.unwrap();
+        for file in avatars.files() {
+            app.app_data.avatar_store.delete(file).await.unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn avatar_jpeg_test() {
+        let app = match TestApp::new(&["--media-avatar-format", "jpeg",
+                                             "--media-avatar-sizes", "256,32,128"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, authorization) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(image::RgbaImage::new(256, 200))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut body = Vec::from(&b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"avatar.png\"\r\n\
+            Content-Type: image/png\r\n\r\n"[..]);
+        body.extend(png);
+        body.extend(&b"\r\n--boundary--\r\n"[..]);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users/me/avatar")
+            .header("Authorization", authorization)
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .set_payload(body)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let db = app.app_data.db().await.unwrap();
+        let avatars = db.query.user.fetch_avatars(&db.client, uid).await.unwrap();
+        assert!(avatars.avatar.as_ref().unwrap().ends_with(".200x200.jpg"));
+        assert!(avatars.avatar_webp.as_ref().unwrap().ends_with(".200x200.webp"));
+        // The 256 one is larger than the upload
+        assert_eq!(avatars.avatar_thumbnails.keys().collect::<Vec<_>>(), vec![&32, &128]);
+        assert_eq!(avatars.avatar_thumbnails_webp.keys().collect::<Vec<_>>(), vec![&32, &128]);
+        assert!(avatars.avatar_thumbnails[&32].ends_with(".thumb.32x32.jpg"));
+        assert!(avatars.avatar_thumbnails_webp[&128].ends_with(".thumb.128x128.webp"));
+        let root = &app.app_data.config.media.root;
+        let thumbnail = std::fs::read(join_avatar_file(root, &avatars.avatar_thumbnails[&128]))
+            .unwrap();
+        assert_eq!(image::guess_format(&thumbnail).unwrap(), image::ImageFormat::Jpeg);
+        let thumbnail = std::fs::read(
+            join_avatar_file(root, &avatars.avatar_thumbnails_webp[&128])).unwrap();
+        assert_eq!(&thumbnail[8..12], b"WEBP");
+        for file in avatars.files() {
+            assert!(join_avatar_file(root, file).exists());
+            app.app_data.avatar_store.delete(file).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn check_avatar_test() {
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(256, 200))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        assert!(check_avatar(&png, 256 * 200).is_ok());
+        // Larger than allowed, rejected without decoding
+        let error = check_avatar(&png, 256 * 200 - 1).unwrap_err().to_string();
+        assert!(error.contains("256x200"));
+        // The signature alone, without the IHDR chunk
+        let error = check_avatar(&png[..8], 256 * 200).unwrap_err().to_string();
+        assert!(error.contains("truncated"));
+        let error = check_avatar(b"GIF", 256 * 200).unwrap_err().to_string();
+        assert!(error.contains("not a PNG"));
+        assert!(check_avatar(b"<svg></svg>", 256 * 200).is_err());
+    }
+
+    #[test]
+    fn is_public_ip_test() {
+        for ip in &["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+                    "100.64.0.1", "0.0.0.0", "::1", "::", "fd00::1", "fe80::1",
+                    "::ffff:127.0.0.1", "::ffff:192.168.1.1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn fetch_avatar_rebinding_test() {
+        // A server at the address the host is rebound to, which should never be connected
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let local = listener.local_addr().unwrap();
+        let lookups = std::cell::Cell::new(0);
+        let resolve = |_: String, port: u16| {
+            lookups.set(lookups.get() + 1);
+            let address = if lookups.get() == 1 {
+                std::net::SocketAddr::new("93.184.216.34".parse().unwrap(), port)
+            } else {
+                local
+            };
+            async move { Ok(vec![address]) }
+        };
+        let url = format!("http://rebinding.example.com:{}/avatar.png", local.port());
+        // The public address may not be reachable from here, only where it leads matters
+        let result = actix_rt::time::timeout(std::time::Duration::from_secs(1),
+                                             fetch_avatar_with(&url, resolve)).await;
+        assert!(!matches!(result, Ok(Ok(_))));
+        assert_eq!(lookups.get(), 1);
+        assert_eq!(listener.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[actix_rt::test]
+    async fn upload_avatar_from_url_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        for url in &["ftp://example.com/avatar.png", "http://127.0.0.1/avatar.png",
+                     "http://[::1]/avatar.png", "http://localhost:8080/avatar.png",
+                     "http://169.254.169.254/latest/meta-data"] {
+            let response = test::call_service(&mut service, test::TestRequest::post()
+                .uri(&format!("/api/v1/users/{}/avatar/from-url", uid))
+                .header("Authorization", admin.clone())
+                .set_json(&serde_json::json!({ "url": url }))
+                .to_request()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", url);
+        }
+        let db = app.app_data.db().await.unwrap();
+        let avatars = db.query.user.fetch_avatars(&db.client, uid).await.unwrap();
+        assert!(avatars.avatar.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn avatar_max_pixels_test() {
+        let app = match TestApp::new(&["--media-avatar-max-pixels", "1000"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, authorization) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(256, 200))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut body = Vec::from(&b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"avatar.png\"\r\n\
+            Content-Type: image/png\r\n\r\n"[..]);
+        body.extend(png);
+        body.extend(&b"\r\n--boundary--\r\n"[..]);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users/me/avatar")
+            .header("Authorization", authorization)
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .set_payload(body)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let db = app.app_data.db().await.unwrap();
+        let avatars = db.query.user.fetch_avatars(&db.client, uid).await.unwrap();
+        assert!(avatars.avatar.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn redact_email_test() {
+        let app = match TestApp::new(&["--redact-email"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        // Support staff may read users but not their emails
+        app.db.client
+            .execute("INSERT INTO role (name, display_name, description, \"default\", \
+                                        created_at, updated_at, deleted) \
+                      VALUES ('support', 'Support', '', FALSE, NOW(), NOW(), FALSE)", &[])
+            .await
+            .unwrap();
+        app.db.client
+            .execute("INSERT INTO role_permission (role, permission) \
+                      SELECT role.id, permission.id FROM role, permission \
+                      WHERE role.name = 'support' AND permission.subject = 'user' \
+                          AND permission.action = 'read'", &[])
+            .await
+            .unwrap();
+        let (alice, _) = app.user("alice", &["normal-user"]).await;
+        let (_, support) = app.user("support", &["support"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        for (authorization, email) in [(support, "a***@example.com"),
+                                           (admin, "alice@example.com")] {
+            let response = test::call_service(&mut service, test::TestRequest::get()
+                .uri(&format!("/api/v1/users/{}", alice))
+                .header("Authorization", authorization)
+                .to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response: serde_json::Value =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            assert_eq!(response["data"]["user"]["email"], email);
+        }
+    }
+    #[actix_rt::test]
+    async fn read_user_etag_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (alice, _) = app.user("alice", &["normal-user"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let read = |query: &str, etag: Option<&str>| {
+            let request = test::TestRequest::get()
+                .uri(&format!("/api/v1/users/{}{}", alice, query))
+                .header("Authorization", admin.clone());
+            match etag {
+                Some(etag) => request.header("If-None-Match", etag),
+                None => request,
+            }.to_request()
+        };
+        let response = test::call_service(&mut service, read("", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+        let response = test::call_service(&mut service, read("", Some(&etag))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = test::call_service(&mut service,
+                                          read("", Some(&format!("\"x\", W/{}", etag)))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        // Another representation of the same user
+        let response = test::call_service(&mut service,
+                                          read("?populate-user=public", Some(&etag))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers().get("ETag").unwrap().to_str().unwrap(), etag);
+        let response = test::call_service(&mut service, test::TestRequest::patch()
+            .uri(&format!("/api/v1/users/{}", alice))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "nickname": "Alice" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, read("", Some(&etag))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["user"]["nickname"], "Alice");
+    }
+    #[actix_rt::test]
+    async fn search_users_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        app.user("alicia", &["normal-user"]).await;
+        app.user("malice", &["normal-user"]).await;
+        app.user("bob", &["normal-user"]).await;
+        app.db.client
+            .execute("UPDATE \"user\" SET nickname = CASE username \
+                          WHEN 'alice' THEN 'Alice' WHEN 'malice' THEN 'Mal_ice' \
+                          WHEN 'bob' THEN '100%' END", &[])
+            .await
+            .unwrap();
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        for (query, expected) in [
+            ("q=alice", vec!["alice", "malice"]),
+            // Exact, then prefix with shorter names first, then contains
+            ("q=AL", vec!["alice", "alicia", "malice"]),
+            ("q=al&limit=2", vec!["alice", "alicia"]),
+            // Wildcards of LIKE are matched literally
+            ("q=_", vec!["malice"]),
+            ("q=%25", vec!["bob"]),
+            ("q=nobody", vec![]),
+        ] {
+            let response = test::call_service(&mut service, test::TestRequest::get()
+                .uri(&format!("/api/v1/users/search?{}", query))
+                .header("Authorization", alice.clone())
+                .to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response: serde_json::Value =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            let items = response["data"]["items"].as_array().unwrap();
+            assert_eq!(items.iter().map(|x| x["username"].as_str().unwrap()).collect::<Vec<_>>(),
+                       expected);
+            assert!(items.iter().all(|x| x.get("email").is_none()));
+        }
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/search?q=")
+            .header("Authorization", alice)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    #[actix_rt::test]
+    async fn bulk_block_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (alice, alice_token) = app.user("alice", &["normal-user"]).await;
+        let (bob, _) = app.user("bob", &["normal-user"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let bulk = |action: &str, uids: Vec<i32>| test::TestRequest::post()
+            .uri(&format!("/api/v1/users/bulk-{}", action))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "uids": uids }))
+            .to_request();
+        let response = test::call_service(&mut service,
+                                          bulk("block", vec![bob, alice, 99999, bob])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let results = &response["data"]["results"];
+        assert_eq!(results.as_array().unwrap().len(), 3);
+        assert_eq!(results[0], serde_json::json!({ "id": bob, "status": "ok" }));
+        assert_eq!(results[1], serde_json::json!({ "id": alice, "status": "ok" }));
+        assert_eq!(results[2]["id"], 99999);
+        assert_eq!(results[2]["status"], "error");
+        assert_eq!(results[2]["error"]["data"]["type"], "UserNotFound");
+        assert_eq!(response["data"]["notified"], true);
+        // The tokens of the blocked users are revoked
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/me")
+            .header("Authorization", alice_token)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // Already blocked
+        let response = test::call_service(&mut service, bulk("block", vec![alice])).await;
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["results"],
+                   serde_json::json!([{ "id": alice, "status": "ok" }]));
+        let response = test::call_service(&mut service, bulk("unblock", vec![alice])).await;
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["results"],
+                   serde_json::json!([{ "id": alice, "status": "ok" }]));
+        let blocked: Option<bool> = app.db.client
+            .query_one("SELECT blocked FROM \"user\" WHERE id = $1", &[&alice])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(blocked, Some(false));
+        let response = test::call_service(&mut service, bulk("block", vec![])).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    #[actix_rt::test]
+    async fn last_login_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (alice, _) = app.user("alice", &["normal-user"]).await;
+        app.user("bob", &["normal-user"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let list = |query: &str| test::TestRequest::get()
+            .uri(&format!("/api/v1/users?{}", query))
+            .header("Authorization", admin.clone())
+            .to_request();
+        let response = test::call_service(&mut service, list("filter=last_login_at:null")).await;
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["total"], 3);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/tokens/acquire")
+            .set_json(&serde_json::json!({ "identifier": "alice", "password": "password" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, list("filter=last_login_at:null")).await;
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["total"], 2);
+        // Users who never logged in come last in ascending order
+        let response = test::call_service(&mut service, list("sort=last_login_at%20asc")).await;
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let first = &response["data"]["items"][0];
+        assert_eq!(first["id"], alice);
+        assert!(first["last_login_at"].is_string());
+    }
+    #[actix_rt::test]
+    async fn delete_user_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (alice, alice_token) = app.user("alice", &["normal-user"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let delete = |authorization: &str| test::TestRequest::delete()
+            .uri(&format!("/api/v1/users/{}", alice))
+            .header("Authorization", authorization)
+            .to_request();
+        let response = test::call_service(&mut service, delete(&alice_token)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, delete(&admin)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Already deleted
+        let response = test::call_service(&mut service, delete(&admin)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // The tokens of alice are revoked along with her
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/me")
+            .header("Authorization", alice_token)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    #[actix_rt::test]
+    async fn update_user_roles_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        app.db.client
+            .execute("INSERT INTO role_permission (role, permission) \
+                      SELECT role.id, permission.id FROM role, permission \
+                      WHERE role.name = 'user-admin' AND permission.subject = 'user-role' \
+                          AND permission.action = 'update'", &[])
+            .await
+            .unwrap();
+        let (alice, _) = app.user("alice", &[]).await;
+        let (_, admin) = app.user("admin", &["user-admin", "normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let request = |method: test::TestRequest, roles: &[&str]| method
+            .uri(&format!("/api/v1/users/{}/roles", alice))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "roles": roles }))
+            .to_request();
+        let response = test::call_service(&mut service, request(test::TestRequest::post(),
+                                                                &["normal-user"])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["roles"][0]["name"], "normal-user");
+        assert_eq!(response["data"]["results"],
+                   serde_json::json!([{ "id": "normal-user", "status": "ok" }]));
+        // Neither granting nor revoking roles the admin does not have, without failing the others
+        for method in [test::TestRequest::post(), test::TestRequest::delete()] {
+            let response = test::call_service(&mut service,
+                                              request(method, &["permission-admin",
+                                                                "user-admin"])).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response: serde_json::Value =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            let results = &response["data"]["results"];
+            assert_eq!(results[0]["id"], "permission-admin");
+            assert_eq!(results[0]["error"]["code"], 403);
+            assert_eq!(results[1], serde_json::json!({ "id": "user-admin", "status": "ok" }));
+        }
+        let response = test::call_service(&mut service, request(test::TestRequest::delete(),
+                                                                &["normal-user"])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["roles"], serde_json::json!([]));
+    }
+
+    #[actix_rt::test]
+    async fn check_usernames_existence_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, authorization) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users/check-usernames-existence")
+            .header("Authorization", authorization)
+            .set_json(&serde_json::json!({ "usernames": ["alice", "bob", "", "alice"] }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let results = response["data"]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], serde_json::json!({ "id": "alice", "status": "ok" }));
+        assert_eq!(results[1], serde_json::json!({ "id": "bob", "status": "ok" }));
+        assert_eq!(results[2]["error"]["code"], 400);
+        assert_eq!(response["data"]["exists"], serde_json::json!({ "alice": true, "bob": false }));
+    }
+
+    #[actix_rt::test]
+    async fn preview_role_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, authorization) = app.user("admin", &["permission-admin"]).await;
+        let (uid, _) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let client = &app.db.client;
+        let role_id = |name: &'static str| async move {
+            client.query_one("SELECT id FROM role WHERE name = $1", &[&name])
+                .await.unwrap().get::<_, i32>(0)
+        };
+        let preview = |rid: i32| test::TestRequest::get()
+            .uri(&format!("/api/v1/users/{}/preview-role/{}", uid, rid))
+            .header("Authorization", authorization.clone())
+            .to_request();
+        let response = test::call_service(&mut service, preview(role_id("user-admin").await))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let would_gain = response["data"]["wouldGain"].as_array().unwrap().iter()
+            .map(|x| format!("{}:{}", x["subject"].as_str().unwrap(),
+                             x["action"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert!(would_gain.contains(&"user:delete".to_string()));
+        // Already granted by the default role
+        assert!(!would_gain.contains(&"token:acquire-by-username".to_string()));
+        // A role the user already has adds nothing
+        let response = test::call_service(&mut service, preview(role_id("normal-user").await))
+            .await;
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["wouldGain"], serde_json::json!([]));
+        let response = test::call_service(&mut service, preview(-1)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+    #[actix_rt::test]
+    async fn role_assignments_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (admin_id, admin) = app.user("admin", &["user-admin", "normal-user"]).await;
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users")
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({
+                "username": "bob",
+                "password": "password",
+                "roles": ["normal-user"],
+            }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let uid = response["data"]["id"].as_i64().unwrap();
+        let assignments = |uid: i64, authorization: &str| test::TestRequest::get()
+            .uri(&format!("/api/v1/users/{}/role-assignments", uid))
+            .header("Authorization", authorization)
+            .to_request();
+        let response = test::call_service(&mut service, assignments(uid, &alice)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, assignments(uid, &admin)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let roles = response["data"]["roles"].as_array().unwrap();
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0]["name"], "normal-user");
+        assert_eq!(roles[0]["granted_by"], admin_id);
+        assert!(roles[0]["granted_at"].is_string());
+        let response = test::call_service(&mut service, assignments(-1, &admin)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+    #[actix_rt::test]
+    async fn query_status_code_test() {
+        let app = match TestApp::new(&["--smtp-mode", "log"]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice").to_request()).await;
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let id = response["data"]["id"].as_str().unwrap().to_string();
+        let missing = "x".repeat(24);
+        let query = |id: &str, query: &str| test::TestRequest::get()
+            .uri(&format!("/api/v1/registrations/{}{}", id, query))
+            .to_request();
+        // Existing clients keep getting 200 whatever the status
+        let response = test::call_service(&mut service, query(&missing, "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, query(&missing, "?http-status=true"))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["status"], "NotFound");
+        let response = test::call_service(&mut service, query(&id, "?http-status=true")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        app.db.client
+            .execute("UPDATE user_registration SET expires_at = NOW() - INTERVAL '1 minute'", &[])
+            .await
+            .unwrap();
+        let response = test::call_service(&mut service, query(&id, "?http-status=true")).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["status"], "Expired");
+        assert!(response["data"]["expiresAt"].is_string());
+        let response = test::call_service(&mut service, query(&id, "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/confirm", id))
+            .set_json(&serde_json::json!({ "code": "000000" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["reason"], "Expired");
+        assert_eq!(response["data"]["hint"], "RegisterAgain");
+        assert!(response["data"]["expires_at"].is_string());
+    }
+    #[actix_rt::test]
+    async fn memory_code_store_test() {
+        let app = match TestApp::with_code_store(
+            &["--smtp-mode", "log", "--smtp-resend-interval", "0"],
+            Some(Box::new(MemoryCodeStore::default())),
+        ).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let confirm = |id: &str, code: &str| test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/confirm", id))
+            .set_json(&serde_json::json!({ "code": code }))
+            .to_request();
+        let mut ids = Vec::new();
+        let mut codes = Vec::new();
+        for username in &["alice", "bob"] {
+            let response = test::call_service(&mut service, register(username).to_request())
+                .await;
+            let response: serde_json::Value =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            ids.push(response["data"]["id"].as_str().unwrap().to_string());
+            codes.push(app.app_data.mailer.last_code().unwrap());
+        }
+        let bob_code = codes[1].clone();
+        // Nothing is left for the database
+        let stored: i64 = app.db.client
+            .query_one("SELECT COUNT(code) FROM user_registration", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(stored, 0);
+        let wrong_code = if bob_code == "000000" { "111111" } else { "000000" };
+        for _ in 0..crate::constants::CONFIRMATION_CODE_MAX_ATTEMPTS {
+            let response = test::call_service(&mut service, confirm(&ids[1], wrong_code)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        // Dropped after too many attempts
+        let response = test::call_service(&mut service, confirm(&ids[1], &bob_code)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/resend", ids[1]))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let bob_code = app.app_data.mailer.last_code().unwrap();
+        let response = test::call_service(&mut service, confirm(&ids[1], &bob_code)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Consumed by the confirmation
+        let response = test::call_service(&mut service, confirm(&ids[1], &bob_code)).await;
+        assert_ne!(response.status(), StatusCode::OK);
+        // A code still in the store is resent as is
+        test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/resend", ids[0]))
+            .to_request()).await;
+        assert_eq!(app.app_data.mailer.last_code().as_ref(), Some(&codes[0]));
+        let response = test::call_service(&mut service, confirm(&ids[0], &codes[0])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn create_user_duplicated_roles_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, admin) = app.user("admin", &["user-admin", "normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users")
+            .header("Authorization", admin)
+            .set_json(&serde_json::json!({
+                "username": "alice",
+                "password": "password",
+                "roles": ["normal-user", "user-admin", "normal-user"],
+            }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        assert_eq!(response["data"]["roles"], serde_json::json!(["normal-user", "user-admin"]));
+        let uid = response["data"]["id"].as_i64().unwrap() as i32;
+        let roles: Vec<String> = app.db.client
+            .query("SELECT role.name FROM user_role, role \
+                    WHERE user_role.\"user\" = $1 AND role.id = user_role.role \
+                    ORDER BY role.name", &[&uid])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(roles, vec!["normal-user", "user-admin"]);
+    }
+
+    // The queue sends on a thread of its own
+    async fn wait_for_emails(transport: &MemoryTransport, count: usize) -> Vec<SentEmail> {
+        for _ in 0..100 {
+            if transport.sent().len() >= count {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        transport.sent()
+    }
+
+    #[actix_rt::test]
+    async fn memory_email_transport_test() {
+        let transport = MemoryTransport::default();
+        let app = match TestApp::with_email_transport(&[], transport.clone()).await {
+            Some(app) => app,
+            None => return,
+        };
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, register("alice")
+            .header("Accept-Language", "en")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let sent = wait_for_emails(&transport, 1).await;
+        assert_eq!(sent[0].to, vec!["alice@example.com".to_string()]);
+        assert!(sent[0].subject.ends_with("is your Hawthorn Ledger verification code"));
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/registrations/{}/confirm",
+                          response["data"]["id"].as_str().unwrap()))
+            .set_json(&serde_json::json!({ "code": sent[0].code().unwrap() }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (_, authorization) = app.user("bob", &["normal-user"]).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/email-updating")
+            .header("Authorization", authorization.clone())
+            .set_json(&serde_json::json!({ "email": "bob2@example.com" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value = serde_json::from_slice(&test::read_body(response).await)
+            .unwrap();
+        let sent = wait_for_emails(&transport, 2).await;
+        assert_eq!(sent[1].to, vec!["bob2@example.com".to_string()]);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri(&format!("/api/v1/email-updating/{}/confirm",
+                          response["data"]["id"].as_str().unwrap()))
+            .header("Authorization", authorization)
+            .set_json(&serde_json::json!({ "code": sent[1].code().unwrap() }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn list_tokens_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, alice) = app.user("alice", &["normal-user"]).await;
+        let (_, bob) = app.user("bob", &["normal-user"]).await;
+        let (_, admin) = app.user("admin", &["user-admin"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/tokens/acquire-by-username")
+            .set_json(&serde_json::json!({ "username": "alice", "password": "password" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let list = |uri: &str, authorization: &str| test::TestRequest::get()
+            .uri(uri)
+            .header("Authorization", authorization)
+            .to_request();
+        let response = test::call_service(&mut service, list("/api/v1/users/me/tokens", &alice))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let methods = response["data"]["tokens"].as_array().unwrap().iter()
+            .map(|x| x["acquire_method"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(methods, vec!["username", "test"]);
+        let uri = format!("/api/v1/users/{}/tokens", uid);
+        let response = test::call_service(&mut service, list(&uri, &bob)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, list(&uri, &admin)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn impersonate_user_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (uid, alice) = app.user("alice", &["normal-user"]).await;
+        let (root, _) = app.user("root", &["permission-admin"]).await;
+        let (admin_id, admin) = app.user("admin", &["user-admin", "normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let impersonate = |uid: i32, authorization: &str| test::TestRequest::post()
+            .uri(&format!("/api/v1/users/{}/impersonate", uid))
+            .header("Authorization", authorization)
+            .to_request();
+        let response = test::call_service(&mut service, impersonate(admin_id, &alice)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        // root has a role admin lacks
+        let response = test::call_service(&mut service, impersonate(root, &admin)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, impersonate(uid, &admin)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let token = format!("Bearer {}", response["data"]["jwt"].as_str().unwrap());
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/me")
+            .header("Authorization", &token[..])
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["user"]["id"], uid);
+        let response = test::call_service(&mut service, test::TestRequest::post()
+            .uri("/api/v1/users/me/password")
+            .header("Authorization", &token[..])
+            .set_json(&serde_json::json!({ "password": "password2", "oldPassword": "password" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn list_permissions_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/me/permissions")
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/me/permissions")
+            .header("Authorization", alice)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let permissions = response["data"]["permissions"].as_array().unwrap().iter()
+            .map(|x| (x["subject"].as_str().unwrap(), x["action"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        let mut sorted = permissions.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(permissions, sorted);
+        assert!(permissions.contains(&("permission", "read-self")));
+        // From the default role
+        assert!(permissions.contains(&("token", "acquire-by-username")));
+    }
+
+    #[actix_rt::test]
+    async fn list_subscriptions_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let response = test::call_service(&mut service, test::TestRequest::get()
+            .uri("/api/v1/users/me/subscriptions")
+            .header("Authorization", alice)
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["subjects"], serde_json::json!([
+            "token-acquired-self",
+            "token-revoked-self",
+            "user-deleted-self",
+            "user-updated-self",
+        ]));
+    }
+
+    #[actix_rt::test]
+    async fn totp_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, alice) = app.user("alice", &["normal-user"]).await;
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        let login = |totp_code: Option<&str>| test::TestRequest::post()
+            .uri("/api/v1/tokens/acquire-by-username")
+            .set_json(&serde_json::json!({
+                "username": "alice",
+                "password": "password",
+                "totp_code": totp_code,
+            }))
+            .to_request();
+        let request = |method: test::TestRequest, uri: &str| method
+            .uri(uri)
+            .header("Authorization", alice.clone());
+        let confirm = |code: &str| request(test::TestRequest::post(),
+                                           "/api/v1/users/me/totp/confirm")
+            .set_json(&serde_json::json!({ "code": code }))
+            .to_request();
+        let response = test::call_service(
+            &mut service,
+            request(test::TestRequest::post(), "/api/v1/users/me/totp").to_request(),
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let uri = response["data"]["uri"].as_str().unwrap();
+        let secret = uri.split("secret=").nth(1).unwrap().split('&').next().unwrap();
+        let code = || totp::code(secret, chrono::Utc::now().timestamp() as u64).unwrap();
+        // Not enabled until confirmed
+        let response = test::call_service(&mut service, login(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let wrong_code = if code() == "000000" { "111111" } else { "000000" };
+        let response = test::call_service(&mut service, confirm(wrong_code)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&mut service, confirm(&code())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, login(None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(response["data"]["type"], "TotpRequired");
+        let response = test::call_service(&mut service, login(Some(wrong_code))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(&mut service, login(Some(&code()))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Disabling needs a current code or the password
+        let disable = |body: serde_json::Value| request(test::TestRequest::delete(),
+                                                        "/api/v1/users/me/totp")
+            .set_json(&body)
+            .to_request();
+        let response = test::call_service(&mut service, disable(serde_json::json!({}))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(
+            &mut service, disable(serde_json::json!({ "code": wrong_code }))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(
+            &mut service, disable(serde_json::json!({ "password": "wrong-password" }))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(&mut service, login(None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(
+            &mut service, disable(serde_json::json!({ "code": code() }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, login(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Or the password once enrolled again
+        let response = test::call_service(
+            &mut service,
+            request(test::TestRequest::post(), "/api/v1/users/me/totp").to_request(),
+        ).await;
+        let response: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let uri = response["data"]["uri"].as_str().unwrap();
+        let secret = uri.split("secret=").nth(1).unwrap().split('&').next().unwrap();
+        let code = totp::code(secret, chrono::Utc::now().timestamp() as u64).unwrap();
+        let response = test::call_service(&mut service, confirm(&code)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(
+            &mut service, disable(serde_json::json!({ "password": "password" }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&mut service, login(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
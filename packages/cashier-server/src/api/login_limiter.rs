@@ -0,0 +1,243 @@
+// Token buckets of failed logins, see LoginLimitConfig. Every client IP and every submitted
+// identifier has a bucket of `attempts` tokens, refilled over `window` seconds. A login takes a
+// token from each of its buckets before it is verified, and gives them back unless it fails, so
+// that concurrent attempts cannot get past an empty bucket together. A login is refused while
+// one of them is empty. Kept in memory, so that every replica limits on its own.
+use crate::config::LoginLimitConfig;
+use crate::constants::{LOGIN_LIMITER_MAX_BUCKETS, LOGIN_LIMITER_SWEEP_INTERVAL};
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    swept_at: Instant,
+}
+
+pub struct LoginLimiter {
+    attempts: u32,
+    window: u64,
+    trusted_proxies: Vec<IpAddr>,
+    buckets: Mutex<Buckets>,
+}
+
+// The bucket keys of a login
+pub fn login_keys(ip: Option<IpAddr>, identifier: &str) -> Vec<String> {
+    let mut keys = vec![format!("account:{}", identifier.to_lowercase())];
+    if let Some(ip) = ip {
+        keys.push(format!("ip:{}", ip));
+    }
+    keys
+}
+
+impl LoginLimiter {
+    pub fn new(config: &LoginLimitConfig) -> Self {
+        Self {
+            attempts: config.attempts,
+            window: config.window,
+            trusted_proxies: config.trusted_proxies.clone(),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+    // The peer address, unless it is a trusted proxy. Then X-Forwarded-For is followed from its
+    // right end, where the proxies append, to the first address that is not a trusted proxy. The
+    // ones left of it are made up by the client, as is any Forwarded header
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let mut ip = req.peer_addr()?.ip();
+        let forwarded_for = req.headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| hop.trim().parse::<IpAddr>())
+            .collect::<Vec<_>>();
+        for hop in forwarded_for.into_iter().rev() {
+            match hop {
+                Ok(hop) if self.trusted_proxies.contains(&ip) => ip = hop,
+                _ => break,
+            }
+        }
+        Some(ip)
+    }
+    // Tokens per second
+    fn rate(&self) -> f64 {
+        f64::from(self.attempts) / self.window as f64
+    }
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate()).min(f64::from(self.attempts));
+        bucket.updated_at = now;
+    }
+    // Takes a token from each of the buckets, or none of them with the seconds before the next
+    // login may be attempted if one is empty
+    pub fn acquire(&self, keys: &[String]) -> Result<(), u64> {
+        self.acquire_at(keys, Instant::now())
+    }
+    fn acquire_at(&self, keys: &[String], now: Instant) -> Result<(), u64> {
+        if self.attempts == 0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        // Full buckets are the same as missing ones
+        if now.saturating_duration_since(buckets.swept_at)
+            >= Duration::from_secs(LOGIN_LIMITER_SWEEP_INTERVAL) {
+            buckets.map.retain(|_, bucket| {
+                self.refill(bucket, now);
+                bucket.tokens < f64::from(self.attempts)
+            });
+            buckets.swept_at = now;
+        }
+        let buckets = &mut buckets.map;
+        let retry_after = keys.iter()
+            .filter_map(|key| {
+                let bucket = buckets.get_mut(key)?;
+                self.refill(bucket, now);
+                if bucket.tokens >= 1.0 {
+                    None
+                } else {
+                    Some(((1.0 - bucket.tokens) / self.rate()).ceil().max(1.0) as u64)
+                }
+            })
+            .max();
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+        if buckets.len() + keys.len() > LOGIN_LIMITER_MAX_BUCKETS {
+            self.evict(buckets, now);
+        }
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: f64::from(self.attempts),
+                updated_at: now,
+            });
+            self.refill(bucket, now);
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+    // Drops the fullest buckets, which limit the least, until a tenth of the buckets is free.
+    // That many logins go by before the next time, so that a spray pays for it only once in a
+    // while
+    fn evict(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let mut fullest = buckets.iter_mut()
+            .map(|(key, bucket)| {
+                self.refill(bucket, now);
+                (bucket.tokens, key.clone())
+            })
+            .collect::<Vec<_>>();
+        fullest.sort_unstable_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        let excess = fullest.len().saturating_sub(LOGIN_LIMITER_MAX_BUCKETS * 9 / 10);
+        for (_, key) in &fullest[..excess] {
+            buckets.remove(key);
+        }
+    }
+    // Gives back the tokens of a login that did not fail on its credentials
+    pub fn release(&self, keys: &[String]) {
+        self.release_at(keys, Instant::now())
+    }
+    fn release_at(&self, keys: &[String], now: Instant) {
+        if self.attempts == 0 {
+            return;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        for key in keys {
+            if let Some(bucket) = buckets.map.get_mut(key) {
+                self.refill(bucket, now);
+                bucket.tokens = (bucket.tokens + 1.0).min(f64::from(self.attempts));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn limiter(trusted_proxies: &[&str]) -> LoginLimiter {
+        LoginLimiter::new(&LoginLimitConfig {
+            attempts: 2,
+            window: 64,
+            trusted_proxies: trusted_proxies.iter().map(|x| x.parse().unwrap()).collect(),
+        })
+    }
+
+    #[test]
+    fn login_limiter_test() {
+        let limiter = limiter(&[]);
+        let now = Instant::now();
+        let ip = |ip: &str| Some(ip.parse().unwrap());
+        let keys = login_keys(ip("127.0.0.1"), "Alice");
+        assert_eq!(keys, vec![String::from("account:alice"), String::from("ip:127.0.0.1")]);
+        // A login that does not fail gives its tokens back
+        assert_eq!(limiter.acquire_at(&keys, now), Ok(()));
+        limiter.release_at(&keys, now);
+        assert_eq!(limiter.acquire_at(&keys, now), Ok(()));
+        // Attempts in flight hold their tokens
+        assert_eq!(limiter.acquire_at(&keys, now), Ok(()));
+        assert_eq!(limiter.acquire_at(&keys, now), Err(32));
+        // Another account from the same IP is limited too, and a refused login takes nothing
+        assert_eq!(limiter.acquire_at(&login_keys(ip("127.0.0.1"), "bob"), now), Err(32));
+        assert_eq!(limiter.acquire_at(&login_keys(ip("10.0.0.1"), "bob"), now), Ok(()));
+        assert_eq!(limiter.acquire_at(&keys, now + Duration::from_secs(16)), Err(16));
+        assert_eq!(limiter.acquire_at(&keys, now + Duration::from_secs(32)), Ok(()));
+    }
+
+    #[test]
+    fn login_limiter_cap_test() {
+        let limiter = limiter(&[]);
+        let now = Instant::now();
+        let drain = |identifier: &str, now: Instant| {
+            let keys = login_keys(None, identifier);
+            limiter.acquire_at(&keys, now).unwrap();
+            limiter.acquire_at(&keys, now).unwrap();
+        };
+        // A spray leaves every bucket drained, the most of them half way
+        drain("alice", now);
+        for i in 0..LOGIN_LIMITER_MAX_BUCKETS * 2 {
+            limiter.acquire_at(&login_keys(None, &format!("user{}", i)), now).unwrap();
+            assert!(limiter.buckets.lock().unwrap().map.len() <= LOGIN_LIMITER_MAX_BUCKETS);
+        }
+        // The emptiest are the last to go
+        assert!(limiter.acquire_at(&login_keys(None, "alice"), now).is_err());
+        // Drained ones alone are capped too
+        for i in 0..LOGIN_LIMITER_MAX_BUCKETS + 10 {
+            drain(&format!("drained{}", i), now);
+        }
+        assert!(limiter.buckets.lock().unwrap().map.len() <= LOGIN_LIMITER_MAX_BUCKETS);
+        // Buckets back to full a window later are dropped by the next sweep
+        let later = now + Duration::from_secs(LOGIN_LIMITER_SWEEP_INTERVAL + 64);
+        limiter.acquire_at(&login_keys(None, "bob"), later).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().map.len(), 1);
+    }
+
+    #[test]
+    fn client_ip_test() {
+        let client_ip = |limiter: &LoginLimiter, peer: &str, forwarded_for: &str| {
+            let req = TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .header("X-Forwarded-For", forwarded_for)
+                .header("Forwarded", "for=192.0.2.9")
+                .to_http_request();
+            limiter.client_ip(&req).unwrap().to_string()
+        };
+        // Made up by the client without a trusted proxy
+        let untrusting = limiter(&[]);
+        assert_eq!(client_ip(&untrusting, "203.0.113.1:1234", "192.0.2.1"), "203.0.113.1");
+        // Only the hops appended by trusted proxies are believed
+        let trusting = limiter(&["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(client_ip(&trusting, "10.0.0.1:1234", "192.0.2.1, 203.0.113.1, 10.0.0.2"),
+                   "203.0.113.1");
+        assert_eq!(client_ip(&trusting, "10.0.0.1:1234", "garbage"), "10.0.0.1");
+        assert_eq!(client_ip(&trusting, "203.0.113.1:1234", "192.0.2.1"), "203.0.113.1");
+    }
+}
@@ -0,0 +1,199 @@
+// Request counts and latencies of the routes wrapped with RequestMetrics, rendered in the
+// Prometheus text format by GET /metrics on StartConfig::metrics_bind. Kept in memory, so every
+// replica reports its own requests
+use crate::{
+    api::app_state::AppState,
+    queries::pool::PoolStatus,
+};
+use actix_web::{
+    web,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures::future::{ok, Ready};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// Upper bounds in seconds, the defaults of the Prometheus clients
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    // Not cumulative, summed up on rendering
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+// Keyed by route, method and status
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, String, u16), Histogram>>,
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn observe(&self, route: &'static str, method: &str, status: u16, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut requests = self.requests.lock().unwrap();
+        let histogram = requests.entry((route, method.into(), status)).or_default();
+        if let Some(i) = BUCKETS.iter().position(|x| seconds <= *x) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+    // websocket_clients is the count of websocket connections subscribed to any push message
+    pub fn render(&self, websocket_clients: usize, pool: &PoolStatus) -> String {
+        let requests = self.requests.lock().unwrap();
+        let mut text = String::new();
+        text.push_str("# HELP cashier_http_requests_total Requests handled.\n\
+            # TYPE cashier_http_requests_total counter\n");
+        let labels = |route: &str, method: &str, status: u16| format!(
+            "route=\"{}\",method=\"{}\",status=\"{}\"", escape(route), escape(method), status);
+        for ((route, method, status), histogram) in requests.iter() {
+            writeln!(text, "cashier_http_requests_total{{{}}} {}",
+                     labels(route, method, *status), histogram.count).unwrap();
+        }
+        text.push_str("# HELP cashier_http_request_duration_seconds Latencies of requests.\n\
+            # TYPE cashier_http_request_duration_seconds histogram\n");
+        for ((route, method, status), histogram) in requests.iter() {
+            let labels = labels(route, method, *status);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                writeln!(text, "cashier_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                         labels, bound, cumulative).unwrap();
+            }
+            writeln!(text, "cashier_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                     labels, histogram.count).unwrap();
+            writeln!(text, "cashier_http_request_duration_seconds_sum{{{}}} {}",
+                     labels, histogram.sum).unwrap();
+            writeln!(text, "cashier_http_request_duration_seconds_count{{{}}} {}",
+                     labels, histogram.count).unwrap();
+        }
+        text.push_str("# HELP cashier_websocket_clients Websocket connections with subscriptions.\n\
+            # TYPE cashier_websocket_clients gauge\n");
+        writeln!(text, "cashier_websocket_clients {}", websocket_clients).unwrap();
+        text.push_str("# HELP cashier_db_pool_size Most database connections held at once.\n\
+            # TYPE cashier_db_pool_size gauge\n");
+        writeln!(text, "cashier_db_pool_size {}", pool.size).unwrap();
+        text.push_str("# HELP cashier_db_pool_idle Open database connections not in use.\n\
+            # TYPE cashier_db_pool_idle gauge\n");
+        writeln!(text, "cashier_db_pool_idle {}", pool.idle).unwrap();
+        text
+    }
+}
+
+// Records the requests of a resource under its route pattern, so that the ids in paths do not
+// make up new series
+pub struct RequestMetrics {
+    state: web::Data<AppState>,
+    route: &'static str,
+}
+
+impl RequestMetrics {
+    pub fn new(state: &web::Data<AppState>, route: &'static str) -> Self {
+        Self {
+            state: state.clone(),
+            route,
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RequestMetrics
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware {
+            service,
+            state: self.state.clone(),
+            route: self.route,
+        })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+    state: web::Data<AppState>,
+    route: &'static str,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started_at = Instant::now();
+        let state = self.state.clone();
+        let route = self.route;
+        let method = req.method().to_string();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let response = response.await;
+            let status = match &response {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            state.metrics.observe(route, &method, status.as_u16(), started_at.elapsed());
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_test() {
+        let metrics = Metrics::default();
+        metrics.observe("/users/{uid}", "GET", 200, Duration::from_millis(20));
+        metrics.observe("/users/{uid}", "GET", 200, Duration::from_secs(20));
+        metrics.observe("/users/{uid}", "GET", 404, Duration::from_millis(1));
+        let text = metrics.render(3, &PoolStatus { size: 10, idle: 4 });
+        let labels = "route=\"/users/{uid}\",method=\"GET\",status=\"200\"";
+        assert!(text.contains(&format!("cashier_http_requests_total{{{}}} 2\n", labels)));
+        let bucket = |le: &str, count: u64| format!(
+            "cashier_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n", labels, le, count);
+        assert!(text.contains(&bucket("0.01", 0)));
+        assert!(text.contains(&bucket("0.025", 1)));
+        assert!(text.contains(&bucket("10", 1)));
+        assert!(text.contains(&bucket("+Inf", 2)));
+        assert!(text.contains("status=\"404\"} 1\n"));
+        assert!(text.contains("cashier_websocket_clients 3\n"));
+        assert!(text.contains("cashier_db_pool_size 10\n"));
+        assert!(text.contains("cashier_db_pool_idle 4\n"));
+    }
+}
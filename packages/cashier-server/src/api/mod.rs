@@ -1,8 +1,14 @@
 pub mod app_state;
+pub mod avatar_store;
 pub mod errors;
 pub mod handlers;
 pub mod extractors;
 pub mod fields;
+pub mod login_limiter;
+pub mod metrics;
+pub mod permission_cache;
+#[cfg(test)]
+pub mod testing;
 
 use crate::{
     websocket::client_subscriber::ClientSubscriber,
@@ -28,12 +34,20 @@ async fn websocket(
 pub fn api_v1(state: &web::Data<app_state::AppState>) -> Box<dyn FnOnce(&mut web::ServiceConfig)> {
     let tokens_api = handlers::tokens::tokens_api(state);
     let users_api = handlers::users::users_api(state);
+    let roles_api = handlers::roles::roles_api(state);
+    let admin_api = handlers::admin::admin_api(state);
+    let audit_api = handlers::audit::audit_api(state);
+    let media_api = handlers::media::media_api(state);
     let state = state.clone();
     Box::new(move |cfg| {
         cfg.service(
             web::scope("/api/v1")
                 .configure(tokens_api)
                 .configure(users_api)
+                .configure(roles_api)
+                .configure(admin_api)
+                .configure(audit_api)
+                .configure(media_api)
                 .service(
                     web::scope("/ws")
                         .app_data(state)
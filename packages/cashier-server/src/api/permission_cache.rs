@@ -0,0 +1,120 @@
+// Permissions the Auth extractor derives, per uid and under None for requests without a token,
+// see PermissionCacheConfig. Entries expire after `ttl` seconds, and AppState::send_all drops the
+// ones a change of users, roles or permissions affects. A lookup racing with a change may still
+// cache the permissions from before it, for at most `ttl` seconds. Kept in memory, so that every
+// replica caches on its own and only drops entries for the changes made through it.
+use crate::config::PermissionCacheConfig;
+use crate::queries::users::PermissionSubjectAction;
+use crate::websocket::push_messages::InnerInternalMessage;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    permissions: Vec<PermissionSubjectAction>,
+    cached_at: Instant,
+}
+
+pub struct PermissionCache {
+    ttl: Duration,
+    size: usize,
+    entries: Mutex<HashMap<Option<i32>, Entry>>,
+}
+
+impl PermissionCache {
+    pub fn new(config: &PermissionCacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl),
+            size: config.size,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn get(&self, uid: Option<i32>) -> Option<Vec<PermissionSubjectAction>> {
+        self.get_at(uid, Instant::now())
+    }
+    fn get_at(&self, uid: Option<i32>, now: Instant) -> Option<Vec<PermissionSubjectAction>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(&uid)
+            .filter(|entry| now.duration_since(entry.cached_at) < self.ttl)
+            .map(|entry| entry.permissions.clone())
+    }
+    pub fn insert(&self, uid: Option<i32>, permissions: Vec<PermissionSubjectAction>) {
+        self.insert_at(uid, permissions, Instant::now())
+    }
+    fn insert_at(&self, uid: Option<i32>, permissions: Vec<PermissionSubjectAction>,
+                 now: Instant) {
+        if self.ttl == Duration::from_secs(0) || self.size == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.size && !entries.contains_key(&uid) {
+            entries.retain(|_, entry| now.duration_since(entry.cached_at) < self.ttl);
+            // All of them are fresh, starting over is cheaper than finding the oldest
+            if entries.len() >= self.size {
+                entries.clear();
+            }
+        }
+        entries.insert(uid, Entry {
+            permissions,
+            cached_at: now,
+        });
+    }
+    pub fn invalidate(&self, uid: i32) {
+        self.entries.lock().unwrap().remove(&Some(uid));
+    }
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+    pub fn apply(&self, message: &InnerInternalMessage) {
+        match message {
+            InnerInternalMessage::TokenRevoked(msg) => self.invalidate(msg.uid),
+            InnerInternalMessage::UserUpdated(msg) => self.invalidate(msg.id),
+            InnerInternalMessage::UserDeleted(msg) => self.invalidate(msg.id),
+            InnerInternalMessage::UserRoleCreated(msg) => self.invalidate(msg.user),
+            InnerInternalMessage::UserRoleDeleted(msg) => self.invalidate(msg.user),
+            // Any user, including the anonymous ones, may hold the role or inherit from it
+            InnerInternalMessage::RolePermissionCreated(_)
+            | InnerInternalMessage::RolePermissionDeleted(_)
+            | InnerInternalMessage::RoleUpdated(_)
+            | InnerInternalMessage::RoleDeleted(_)
+            | InnerInternalMessage::RoleParentUpdated(_)
+            | InnerInternalMessage::PermissionUpdated(_) => self.clear(),
+            InnerInternalMessage::TokenAcquired(_)
+            | InnerInternalMessage::UserCreated(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::push_messages::{RoleDeleted, UserRoleDeleted};
+
+    #[test]
+    fn permission_cache_test() {
+        let cache = PermissionCache::new(&PermissionCacheConfig {
+            ttl: 30,
+            size: 2,
+        });
+        let now = Instant::now();
+        let permissions = vec![PermissionSubjectAction {
+            subject: "user".into(),
+            action: "read".into(),
+        }];
+        cache.insert_at(Some(1), permissions.clone(), now);
+        cache.insert_at(None, Vec::new(), now);
+        assert_eq!(cache.get_at(Some(1), now), Some(permissions.clone()));
+        assert_eq!(cache.get_at(None, now), Some(Vec::new()));
+        assert_eq!(cache.get_at(Some(1), now + Duration::from_secs(30)), None);
+        cache.apply(&UserRoleDeleted { user: 1, role: 2 }.into());
+        assert_eq!(cache.get_at(Some(1), now), None);
+        assert_eq!(cache.get_at(None, now), Some(Vec::new()));
+        // Full of fresh entries
+        cache.insert_at(Some(1), permissions.clone(), now);
+        cache.insert_at(Some(2), permissions.clone(), now);
+        assert_eq!(cache.get_at(Some(2), now), Some(permissions.clone()));
+        assert_eq!(cache.get_at(None, now), None);
+        cache.apply(&RoleDeleted { id: 2 }.into());
+        assert_eq!(cache.get_at(Some(2), now), None);
+    }
+}
@@ -0,0 +1,119 @@
+// Tests sending requests through the API need Redis at CASHIER_TEST_REDIS, e.g.
+// redis://127.0.0.1, besides the database of TestDatabase, and are skipped unless both are set.
+use super::{
+    app_state::{AppState, Mailer},
+    avatar_store::new_avatar_store,
+    login_limiter::LoginLimiter,
+    metrics::Metrics,
+    permission_cache::PermissionCache,
+};
+use crate::{
+    config::SmtpMode,
+    constants::{AVATAR_PROCESSING_CAPACITY, EMAIL_QUEUE_CAPACITY},
+    queries::{
+        codes::CodeStore,
+        email::{EmailQueue, MemoryTransport},
+        pool::Pool,
+        testing::TestDatabase,
+    },
+    websocket::main_subscriber::MainSubscriber,
+};
+use actix::Actor;
+use actix_web::web;
+use lettre::SmtpTransport;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Semaphore;
+
+pub struct TestApp {
+    pub db: TestDatabase,
+    pub app_data: web::Data<AppState>,
+}
+
+impl TestApp {
+    // Extra arguments go before the subcommand
+    pub async fn new(args: &[&str]) -> Option<Self> {
+        Self::with_code_store(args, None).await
+    }
+    // Keeps confirmation codes in the given store instead of the configured one
+    pub async fn with_code_store(
+        args: &[&str], codes: Option<Box<dyn CodeStore>>,
+    ) -> Option<Self> {
+        Self::build(args, codes, None).await
+    }
+    // Hands e-mails to the given transport whatever the SMTP mode is
+    pub async fn with_email_transport(
+        args: &[&str], transport: MemoryTransport,
+    ) -> Option<Self> {
+        Self::build(args, None, Some(transport)).await
+    }
+    async fn build(
+        args: &[&str], codes: Option<Box<dyn CodeStore>>, transport: Option<MemoryTransport>,
+    ) -> Option<Self> {
+        std::env::var("CASHIER_TEST_REDIS").ok()?;
+        let db = TestDatabase::new(args).await?;
+        let config = db.config.clone();
+        let redis_client = redis::Client::open(&config.redis[..]).unwrap();
+        let subscriber = MainSubscriber::new(
+            redis_client.get_async_connection().await.unwrap(),
+            redis_client.get_async_connection().await.unwrap().into_pubsub(),
+        ).start();
+        let pool = Pool::new(&config, codes.map(Arc::from)).await.unwrap();
+        let media_secret = {
+            let db = pool.get().await.unwrap();
+            db.query.token.get_secret(&db.client).await.unwrap()
+        };
+        let app_data = web::Data::new(AppState {
+            media_secret,
+            avatar_store: new_avatar_store(&config.media),
+            pool,
+            subscriber,
+            mailer: match (config.smtp.mode, &config.smtp.server, transport) {
+                (_, _, Some(transport)) =>
+                    Mailer::Queue(EmailQueue::start(transport, EMAIL_QUEUE_CAPACITY,
+                                                    config.smtp.max_attempts)),
+                (SmtpMode::Send, Some(server), None) =>
+                    Mailer::Queue(EmailQueue::start(SmtpTransport::builder(server.clone()).build(),
+                                                    EMAIL_QUEUE_CAPACITY,
+                                                    config.smtp.max_attempts)),
+                (SmtpMode::Log, _, None) => Mailer::log(),
+                _ => Mailer::Disabled,
+            },
+            avatar_processing: Mutex::new(HashMap::new()),
+            avatar_jobs: Arc::new(Semaphore::new(AVATAR_PROCESSING_CAPACITY)),
+            resend_progress: Mutex::new(None),
+            login_limiter: LoginLimiter::new(&config.login_limit),
+            permission_cache: PermissionCache::new(&config.permission_cache),
+            metrics: Metrics::default(),
+            websocket_sessions: Mutex::new(HashSet::new()),
+            config,
+        });
+        Some(Self {
+            db,
+            app_data,
+        })
+    }
+    // Creates a user holding the given roles, returns its id and an Authorization header
+    pub async fn user(&self, username: &str, roles: &[&str]) -> (i32, String) {
+        let email = Some(format!("{}@example.com", username));
+        let mut db = self.app_data.db().await.unwrap();
+        let db = &mut *db;
+        let id = db.query.user
+            .insert_one(&mut db.client, username, "password", &[], None, &email, &None)
+            .await
+            .unwrap()
+            .id;
+        self.db.client
+            .execute("INSERT INTO user_role (\"user\", role) \
+                      SELECT $1, id FROM role WHERE name = ANY($2) AND NOT deleted", &[&id, &roles])
+            .await
+            .unwrap();
+        let (jwt, _) = db.query.token
+            .create_token(&db.client, id, "test", "localhost", None, None)
+            .await
+            .unwrap();
+        (id, format!("Bearer {}", jwt))
+    }
+}
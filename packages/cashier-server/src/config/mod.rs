@@ -1,11 +1,17 @@
 use clap::{Arg, App};
 use err_derive::Error;
+use lettre::transport::smtp::SMTP_PORT;
 use serde::{Serialize, Deserialize};
 use shell_macro::shell;
 use std::ffi::OsString;
 use std::env;
 use std::fs::File;
+use std::net::IpAddr;
 use std::path::Path;
+use crate::constants::{
+    ACCESS_TOKEN_MAX_TTL, ACCESS_TOKEN_TTL, AVATAR_MAX_PIXELS, BCRYPT_COST, EMAIL_MAX_ATTEMPTS,
+    CONFIRMATION_MAX_TTL, CONFIRMATION_TTL, DB_POOL_SIZE,
+};
 
 pub const BUILD_VERSION: &str = shell!("git describe --tags $(git rev-list --tags --max-count=1)");
 pub const BUILD_COMMIT_ID: &str = shell!("git log --format=\"%h\" -n 1");
@@ -22,6 +28,8 @@ pub enum ConfigError {
     InvalidSubcommand,
     #[error(display = "missing {} argument", _0)]
     MissingArgument(String),
+    #[error(display = "invalid {} argument", _0)]
+    InvalidArgument(String),
 }
 
 #[derive(Debug, Clone)]
@@ -31,6 +39,35 @@ pub struct InitConfig {
     pub reset: bool,
     pub superuser_username: Option<String>,
     pub superuser_password: Option<String>,
+    pub password_algorithm: PasswordAlgorithm,
+    pub bcrypt_cost: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AvatarFormat {
+    Png,
+    // Smaller for photos, but without transparency
+    Jpeg { quality: u8 },
+}
+
+// Where processed avatars are kept, see api::avatar_store
+#[derive(Debug, Clone)]
+pub enum AvatarStoreKind {
+    // Under the media root, served from the media url
+    Filesystem,
+    // In a bucket of an S3-compatible object storage, shared by every replica
+    S3(S3Config),
+}
+
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // Prefix of the avatar URLs, the bucket at the endpoint by default
+    pub public_url: String,
 }
 
 #[derive(Debug, Clone)]
@@ -38,37 +75,210 @@ pub struct MediaConfig {
     pub root: String,
     pub url: String,
     pub serve: bool,
+    // Processes uploaded avatars in the background instead of within the request, at most
+    // AVATAR_PROCESSING_CAPACITY at once. The status of the processing is kept in memory, see
+    // AppState::avatar_processing
+    pub avatar_async: bool,
+    // Of the processed avatars and their thumbnails
+    pub avatar_format: AvatarFormat,
+    // Of the thumbnails made for every avatar, in ascending order. Ones not smaller than the
+    // avatar are skipped
+    pub avatar_sizes: Vec<u32>,
+    // Uploaded avatars with more pixels are rejected before being decoded
+    pub avatar_max_pixels: u64,
+    // Seconds signed avatar URLs stay valid. Avatars are only served through the signed URLs
+    // when set, instead of the public media url. Only for the filesystem store
+    pub avatar_url_ttl: Option<u64>,
+    pub avatar_store: AvatarStoreKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpMode {
+    // Sends e-mails through the SMTP server
+    Send,
+    // For development, logs the codes instead of sending e-mails
+    Log,
+    // Rejects requests that need to send e-mails
+    Disabled,
 }
 
 #[derive(Debug, Clone)]
 pub struct SmtpConfig {
-    pub server: String,
+    pub mode: SmtpMode,
+    // Always present in send mode
+    pub server: Option<String>,
+    // Sent to with STARTTLS, and connected to by the readiness check
+    pub port: u16,
     pub sender: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    // Seconds before a confirmation e-mail is sent again, whether by POST
+    // /api/v1/admin/resend-pending or for the user who asks, 0 for no limit
+    pub resend_interval: u64,
+    // E-mails per minute sent by POST /api/v1/admin/resend-pending
+    pub batch_rate: u64,
+    // Attempts to send an e-mail, counting the first one, before it is dropped. Permanent
+    // failures are never retried
+    pub max_attempts: u32,
+    // Whether GET /readyz connects to the SMTP server, which is slow and hence opt-in
+    pub readiness_check: bool,
+}
+
+// Of new password hashes, see queries::passwords
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PasswordAlgorithm {
+    Bcrypt,
+    Argon2id,
+}
+
+// Where confirmation codes are kept, see queries::codes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodeStoreKind {
+    // In the pending registration and email updating rows
+    Postgres,
+    // In Redis at the redis connection, expiring by itself
+    Redis,
+}
+
+// Exposed to clients via GET /api/v1/auth/config
+#[derive(Debug, Clone, Serialize)]
+pub struct UsernameConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    // Allowed besides ASCII letters and digits
+    pub extra_chars: String,
+    pub lowercase_only: bool,
+}
+
+// Where known compromised passwords are looked up, see queries::breached_passwords
+#[derive(Debug, Clone)]
+pub enum BreachedPasswordsKind {
+    // Skips the check, for air-gapped deployments
+    Disabled,
+    // In a bloom filter built at startup from a file of SHA-1 hashes
+    BloomFilter(String),
+    // At a k-anonymity range API, such as the one of Pwned Passwords. Passwords are rejected
+    // while it is unreachable, unless fail_open lets them through unchecked
+    RangeApi {
+        url: String,
+        fail_open: bool,
+    },
+}
+
+// Also exposed via GET /api/v1/auth/config. Passwords always have 6 to 24 non-whitespace chars
+#[derive(Debug, Clone, Serialize)]
+pub struct PasswordConfig {
+    pub min_length: usize,
+    pub require_mixed_case: bool,
+    pub require_digit: bool,
+    // Anything but letters and digits
+    pub require_symbol: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NicknameConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub allow_emoji: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebsocketConfig {
+    // Seconds between pings from the server
+    pub heartbeat_interval: u64,
+    // Seconds without a ping or pong from the client before the connection is closed, always
+    // longer than the interval
+    pub client_timeout: u64,
+}
+
+// Failed logins per client IP and per submitted identifier, see api::login_limiter
+#[derive(Debug, Clone)]
+pub struct LoginLimitConfig {
+    // Failures allowed in a row, 0 disables the limit
+    pub attempts: u32,
+    // Seconds for a client or an account to get all its attempts back
+    pub window: u64,
+    // Reverse proxies whose X-Forwarded-For tells the client IP, which is the peer otherwise
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+// Permissions of users kept between requests, see api::permission_cache
+#[derive(Debug, Clone)]
+pub struct PermissionCacheConfig {
+    // Seconds an entry is used for, 0 disables the cache
+    pub ttl: u64,
+    // Entries kept at most
+    pub size: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct StartConfig {
     pub db: String,
+    // PostgreSQL connections kept at most, each with statements of its own
+    pub db_pool_size: usize,
     pub redis: String,
     pub bind: String,
+    // Address of the Prometheus /metrics endpoint, kept apart from the API. Not served without it
+    pub metrics_bind: Option<String>,
+    // Seconds a shutdown waits for in-flight requests, and then for queued e-mails
+    pub drain_timeout: u64,
     pub site: String,
     pub media: MediaConfig,
     pub smtp: SmtpConfig,
+    pub code_store: CodeStoreKind,
+    // Seconds the username and email of a deleted user stay occupied
+    pub deleted_user_reservation: u64,
+    // Masks emails of other users for callers without user/read-email permission
+    pub redact_email: bool,
+    // The most users a page of GET /api/users returns
+    pub user_list_max_limit: u64,
+    // Seconds new access tokens stay valid, from 1 to ACCESS_TOKEN_MAX_TTL
+    pub access_token_ttl: u64,
+    // Seconds the codes sent for registrations, e-mail updates and password resets stay valid
+    pub registration_ttl: u64,
+    pub email_updating_ttl: u64,
+    pub password_reset_ttl: u64,
+    // Algorithm of new password hashes. Existing hashes are verified with the algorithm they were
+    // made with, and upgraded on login
+    pub password_algorithm: PasswordAlgorithm,
+    // Cost of new bcrypt hashes. Existing hashes are verified with the cost they were made with
+    pub bcrypt_cost: u32,
+    pub username: UsernameConfig,
+    pub password: PasswordConfig,
+    // Checked whenever a password is set
+    pub breached_passwords: BreachedPasswordsKind,
+    pub nickname: NicknameConfig,
+    pub websocket: WebsocketConfig,
+    pub login_limit: LoginLimitConfig,
+    pub permission_cache: PermissionCacheConfig,
 }
 
+// Parsed once at startup, so the size of StartConfig does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum Config {
     Init(InitConfig),
     Start(StartConfig),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct MediaConfigFile {
     root: Option<String>,
     url: Option<String>,
     serve: Option<bool>,
+    avatar_async: Option<bool>,
+    avatar_format: Option<String>,
+    avatar_jpeg_quality: Option<u8>,
+    avatar_sizes: Option<Vec<u32>>,
+    avatar_max_pixels: Option<u64>,
+    avatar_url_ttl: Option<u64>,
+    avatar_store: Option<String>,
+    s3_endpoint: Option<String>,
+    s3_region: Option<String>,
+    s3_bucket: Option<String>,
+    s3_access_key: Option<String>,
+    s3_secret_key: Option<String>,
+    s3_public_url: Option<String>,
 }
 
 impl MediaConfigFile {
@@ -77,48 +287,221 @@ impl MediaConfigFile {
             root: None,
             url: None,
             serve: None,
+            avatar_async: None,
+            avatar_format: None,
+            avatar_jpeg_quality: None,
+            avatar_sizes: None,
+            avatar_max_pixels: None,
+            avatar_url_ttl: None,
+            avatar_store: None,
+            s3_endpoint: None,
+            s3_region: None,
+            s3_bucket: None,
+            s3_access_key: None,
+            s3_secret_key: None,
+            s3_public_url: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct SmtpConfigFile {
+    pub mode: Option<String>,
     pub server: Option<String>,
+    pub port: Option<u16>,
     pub sender: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
+    pub resend_interval: Option<u64>,
+    pub batch_rate: Option<u64>,
+    pub max_attempts: Option<u32>,
+    pub readiness_check: Option<bool>,
 }
 
 impl SmtpConfigFile {
     pub fn new() -> Self {
         Self {
+            mode: None,
             server: None,
+            port: None,
             sender: None,
             username: None,
             password: None,
+            resend_interval: None,
+            batch_rate: None,
+            max_attempts: None,
+            readiness_check: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct UsernameConfigFile {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    extra_chars: Option<String>,
+    lowercase_only: Option<bool>,
+}
+
+impl UsernameConfigFile {
+    pub fn new() -> Self {
+        Self {
+            min_length: None,
+            max_length: None,
+            extra_chars: None,
+            lowercase_only: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PasswordConfigFile {
+    min_length: Option<usize>,
+    require_mixed_case: Option<bool>,
+    require_digit: Option<bool>,
+    require_symbol: Option<bool>,
+    breached: Option<String>,
+    breached_file: Option<String>,
+    breached_url: Option<String>,
+    breached_fail_open: Option<bool>,
+}
+
+impl PasswordConfigFile {
+    pub fn new() -> Self {
+        Self {
+            min_length: None,
+            require_mixed_case: None,
+            require_digit: None,
+            require_symbol: None,
+            breached: None,
+            breached_file: None,
+            breached_url: None,
+            breached_fail_open: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct NicknameConfigFile {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    allow_emoji: Option<bool>,
+}
+
+impl NicknameConfigFile {
+    pub fn new() -> Self {
+        Self {
+            min_length: None,
+            max_length: None,
+            allow_emoji: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct WebsocketConfigFile {
+    heartbeat_interval: Option<u64>,
+    client_timeout: Option<u64>,
+}
+
+impl WebsocketConfigFile {
+    pub fn new() -> Self {
+        Self {
+            heartbeat_interval: None,
+            client_timeout: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct LoginLimitConfigFile {
+    attempts: Option<u32>,
+    window: Option<u64>,
+    trusted_proxies: Option<Vec<IpAddr>>,
+}
+
+impl LoginLimitConfigFile {
+    pub fn new() -> Self {
+        Self {
+            attempts: None,
+            window: None,
+            trusted_proxies: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PermissionCacheConfigFile {
+    ttl: Option<u64>,
+    size: Option<usize>,
+}
+
+impl PermissionCacheConfigFile {
+    pub fn new() -> Self {
+        Self {
+            ttl: None,
+            size: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct ConfigFile {
     db: Option<String>,
+    db_pool_size: Option<usize>,
     redis: Option<String>,
     bind: Option<String>,
+    metrics_bind: Option<String>,
+    drain_timeout: Option<u64>,
     site: Option<String>,
     media: Option<MediaConfigFile>,
     smtp: Option<SmtpConfigFile>,
+    code_store: Option<String>,
+    deleted_user_reservation: Option<u64>,
+    redact_email: Option<bool>,
+    user_list_max_limit: Option<u64>,
+    access_token_ttl: Option<u64>,
+    registration_ttl: Option<u64>,
+    email_updating_ttl: Option<u64>,
+    password_reset_ttl: Option<u64>,
+    password_algorithm: Option<String>,
+    bcrypt_cost: Option<u32>,
+    username: Option<UsernameConfigFile>,
+    password: Option<PasswordConfigFile>,
+    nickname: Option<NicknameConfigFile>,
+    websocket: Option<WebsocketConfigFile>,
+    login_limit: Option<LoginLimitConfigFile>,
+    permission_cache: Option<PermissionCacheConfigFile>,
 }
 
 impl ConfigFile {
     pub fn new() -> Self {
         Self {
             db: None,
+            db_pool_size: None,
             redis: None,
             bind: None,
+            metrics_bind: None,
+            drain_timeout: None,
             site: None,
             media: None,
             smtp: None,
+            code_store: None,
+            deleted_user_reservation: None,
+            redact_email: None,
+            user_list_max_limit: None,
+            access_token_ttl: None,
+            registration_ttl: None,
+            email_updating_ttl: None,
+            password_reset_ttl: None,
+            password_algorithm: None,
+            bcrypt_cost: None,
+            username: None,
+            password: None,
+            nickname: None,
+            websocket: None,
+            login_limit: None,
+            permission_cache: None,
         }
     }
 
@@ -130,7 +513,7 @@ impl ConfigFile {
 
 impl Config {
     pub fn from_env() -> Result<Self, ConfigError> {
-        Self::from(&mut env::args_os())
+        Self::from(env::args_os())
     }
 
     pub fn from<I, T>(itr: I) -> Result<Self, ConfigError>
@@ -154,6 +537,11 @@ impl Config {
                 .value_name("URL")
                 .about("Sets the PostgreSQL connection")
                 .takes_value(true))
+            .arg(Arg::with_name("db-pool-size")
+                .long("db-pool-size")
+                .value_name("COUNT")
+                .about("PostgreSQL connections kept at most, defaults to 10")
+                .takes_value(true))
             .arg(Arg::with_name("redis")
                 .long("redis")
                 .value_name("URL")
@@ -164,6 +552,17 @@ impl Config {
                 .value_name("ADDR:PORT")
                 .about("Address to bind")
                 .takes_value(true))
+            .arg(Arg::with_name("metrics-bind")
+                .long("metrics-bind")
+                .value_name("ADDR:PORT")
+                .about("Address to serve Prometheus metrics on, apart from the API")
+                .takes_value(true))
+            .arg(Arg::with_name("drain-timeout")
+                .long("drain-timeout")
+                .value_name("SECONDS")
+                .about("How long a shutdown waits for in-flight requests and then for queued \
+                        e-mails, defaults to 30")
+                .takes_value(true))
             .arg(Arg::with_name("site")
                 .long("site")
                 .about("Site for front-end")
@@ -181,10 +580,84 @@ impl Config {
             .arg(Arg::with_name("media-serve")
                 .long("media-serve")
                 .about("Serves user-uploaded contents"))
+            .arg(Arg::with_name("media-avatar-async")
+                .long("media-avatar-async")
+                .about("Processes uploaded avatars in the background"))
+            .arg(Arg::with_name("media-avatar-format")
+                .long("media-avatar-format")
+                .value_name("FORMAT")
+                .about("Format of the processed avatars: \"png\" (default) or \"jpeg\"")
+                .takes_value(true))
+            .arg(Arg::with_name("media-avatar-jpeg-quality")
+                .long("media-avatar-jpeg-quality")
+                .value_name("QUALITY")
+                .about("Quality of JPEG avatars, from 1 to 100, defaults to 85")
+                .takes_value(true))
+            .arg(Arg::with_name("media-avatar-sizes")
+                .long("media-avatar-sizes")
+                .value_name("SIZES")
+                .about("Comma-separated sizes of the avatar thumbnails, defaults to 128")
+                .takes_value(true))
+            .arg(Arg::with_name("media-avatar-max-pixels")
+                .long("media-avatar-max-pixels")
+                .value_name("PIXELS")
+                .about("Largest width times height of uploaded avatars, defaults to 16777216")
+                .takes_value(true))
+            .arg(Arg::with_name("media-avatar-url-ttl")
+                .long("media-avatar-url-ttl")
+                .value_name("SECONDS")
+                .about("Serves avatars only through signed URLs valid for the given seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("media-avatar-store")
+                .long("media-avatar-store")
+                .value_name("STORE")
+                .about("Where avatars are kept: \"filesystem\" (default) or \"s3\"")
+                .takes_value(true))
+            .arg(Arg::with_name("media-s3-endpoint")
+                .long("media-s3-endpoint")
+                .value_name("URL")
+                .about("Endpoint of the S3-compatible object storage")
+                .takes_value(true))
+            .arg(Arg::with_name("media-s3-region")
+                .long("media-s3-region")
+                .value_name("REGION")
+                .about("Region of the object storage, defaults to us-east-1")
+                .takes_value(true))
+            .arg(Arg::with_name("media-s3-bucket")
+                .long("media-s3-bucket")
+                .value_name("BUCKET")
+                .about("Bucket to keep avatars in")
+                .takes_value(true))
+            .arg(Arg::with_name("media-s3-access-key")
+                .long("media-s3-access-key")
+                .value_name("KEY")
+                .about("Access key of the object storage")
+                .takes_value(true))
+            .arg(Arg::with_name("media-s3-secret-key")
+                .long("media-s3-secret-key")
+                .value_name("KEY")
+                .about("Secret key of the object storage")
+                .takes_value(true))
+            .arg(Arg::with_name("media-s3-public-url")
+                .long("media-s3-public-url")
+                .value_name("URL")
+                .about("Prefix of avatar URLs, defaults to the bucket at the endpoint")
+                .takes_value(true))
+            .arg(Arg::with_name("smtp-mode")
+                .long("smtp-mode")
+                .value_name("MODE")
+                .about("How to deliver e-mails: \"send\" (default), \"log\" for development, \
+                        or \"disabled\"")
+                .takes_value(true))
             .arg(Arg::with_name("smtp-server")
                 .long("smtp-server")
                 .about("SMTP server used to send e-mail")
                 .takes_value(true))
+            .arg(Arg::with_name("smtp-port")
+                .long("smtp-port")
+                .value_name("PORT")
+                .about("Port of the SMTP server, defaults to 25")
+                .takes_value(true))
             .arg(Arg::with_name("smtp-sender")
                 .long("smtp-sender")
                 .about("SMTP sender information in the \"From\" header")
@@ -197,6 +670,181 @@ impl Config {
                 .long("smtp-password")
                 .about("SMTP password for authentication")
                 .takes_value(true))
+            .arg(Arg::with_name("smtp-resend-interval")
+                .long("smtp-resend-interval")
+                .value_name("SECONDS")
+                .about("How long before a confirmation e-mail is resent, to a user who asks or \
+                        by batch resend, 0 for no limit, defaults to 60")
+                .takes_value(true))
+            .arg(Arg::with_name("smtp-readiness-check")
+                .long("smtp-readiness-check")
+                .about("Checks the connection to the SMTP server in GET /readyz"))
+            .arg(Arg::with_name("smtp-batch-rate")
+                .long("smtp-batch-rate")
+                .value_name("PER_MINUTE")
+                .about("E-mails sent per minute by batch resend, defaults to 60")
+                .takes_value(true))
+            .arg(Arg::with_name("smtp-max-attempts")
+                .long("smtp-max-attempts")
+                .value_name("ATTEMPTS")
+                .about("Attempts to send an e-mail on transient failures, defaults to 3")
+                .takes_value(true))
+            .arg(Arg::with_name("code-store")
+                .long("code-store")
+                .value_name("STORE")
+                .about("Where to keep confirmation codes: \"postgres\" (default) or \"redis\"")
+                .takes_value(true))
+            .arg(Arg::with_name("deleted-user-reservation")
+                .long("deleted-user-reservation")
+                .value_name("SECONDS")
+                .about("How long the username and email of a deleted user stay occupied, \
+                        defaults to 30 days")
+                .takes_value(true))
+            .arg(Arg::with_name("redact-email")
+                .long("redact-email")
+                .about("Masks emails of other users unless having user/read-email permission"))
+            .arg(Arg::with_name("user-list-max-limit")
+                .long("user-list-max-limit")
+                .value_name("COUNT")
+                .about("The most users a page of the user list returns, defaults to 100")
+                .takes_value(true))
+            .arg(Arg::with_name("access-token-ttl")
+                .long("access-token-ttl")
+                .value_name("SECONDS")
+                .about("How long new access tokens stay valid, up to a year, defaults to 10 days")
+                .takes_value(true))
+            .arg(Arg::with_name("registration-ttl")
+                .long("registration-ttl")
+                .value_name("SECONDS")
+                .about("How long registration codes stay valid, up to 7 days, defaults to 30 \
+                        minutes")
+                .takes_value(true))
+            .arg(Arg::with_name("email-updating-ttl")
+                .long("email-updating-ttl")
+                .value_name("SECONDS")
+                .about("How long e-mail updating codes stay valid, up to 7 days, defaults to 30 \
+                        minutes")
+                .takes_value(true))
+            .arg(Arg::with_name("password-reset-ttl")
+                .long("password-reset-ttl")
+                .value_name("SECONDS")
+                .about("How long password reset codes stay valid, up to 7 days, defaults to 30 \
+                        minutes")
+                .takes_value(true))
+            .arg(Arg::with_name("password-algorithm")
+                .long("password-algorithm")
+                .value_name("ALGORITHM")
+                .about("Algorithm of new password hashes: \"bcrypt\" (default) or \"argon2id\"")
+                .takes_value(true))
+            .arg(Arg::with_name("bcrypt-cost")
+                .long("bcrypt-cost")
+                .value_name("COST")
+                .about("Cost of new password hashes, from 4 to 31, defaults to 10")
+                .takes_value(true))
+            .arg(Arg::with_name("username-min-length")
+                .long("username-min-length")
+                .value_name("CHARS")
+                .about("Minimum length of usernames, defaults to 3")
+                .takes_value(true))
+            .arg(Arg::with_name("username-max-length")
+                .long("username-max-length")
+                .value_name("CHARS")
+                .about("Maximum length of usernames, defaults to 24")
+                .takes_value(true))
+            .arg(Arg::with_name("username-extra-chars")
+                .long("username-extra-chars")
+                .value_name("CHARS")
+                .about("Chars allowed in usernames besides ASCII letters and digits, defaults to \"_-\"")
+                .takes_value(true))
+            .arg(Arg::with_name("username-lowercase-only")
+                .long("username-lowercase-only")
+                .about("Rejects usernames containing uppercase letters"))
+            .arg(Arg::with_name("password-min-length")
+                .long("password-min-length")
+                .value_name("CHARS")
+                .about("Minimum length of passwords, from 6 to 24, defaults to 6")
+                .takes_value(true))
+            .arg(Arg::with_name("password-require-mixed-case")
+                .long("password-require-mixed-case")
+                .about("Rejects passwords without both uppercase and lowercase letters"))
+            .arg(Arg::with_name("password-require-digit")
+                .long("password-require-digit")
+                .about("Rejects passwords without digits"))
+            .arg(Arg::with_name("password-require-symbol")
+                .long("password-require-symbol")
+                .about("Rejects passwords without chars other than letters and digits"))
+            .arg(Arg::with_name("password-breached")
+                .long("password-breached")
+                .value_name("CHECK")
+                .about("Where breached passwords are looked up: \"disabled\" (default), \
+                        \"bloom-filter\" or \"range-api\"")
+                .takes_value(true))
+            .arg(Arg::with_name("password-breached-file")
+                .long("password-breached-file")
+                .value_name("PATH")
+                .about("SHA-1 hashes of breached passwords for the bloom filter, one per line")
+                .takes_value(true))
+            .arg(Arg::with_name("password-breached-url")
+                .long("password-breached-url")
+                .value_name("URL")
+                .about("Range API of breached passwords, defaults to \
+                        https://api.pwnedpasswords.com/range")
+                .takes_value(true))
+            .arg(Arg::with_name("password-breached-fail-open")
+                .long("password-breached-fail-open")
+                .about("Lets passwords through unchecked while the range API is unreachable, \
+                        instead of rejecting them"))
+            .arg(Arg::with_name("nickname-min-length")
+                .long("nickname-min-length")
+                .value_name("CHARS")
+                .about("Minimum length of nicknames, defaults to 3")
+                .takes_value(true))
+            .arg(Arg::with_name("nickname-max-length")
+                .long("nickname-max-length")
+                .value_name("CHARS")
+                .about("Maximum length of nicknames, defaults to 24")
+                .takes_value(true))
+            .arg(Arg::with_name("nickname-no-emoji")
+                .long("nickname-no-emoji")
+                .about("Rejects nicknames containing emoji"))
+            .arg(Arg::with_name("websocket-heartbeat-interval")
+                .long("websocket-heartbeat-interval")
+                .value_name("SECONDS")
+                .about("How often WebSocket clients are pinged, defaults to 30")
+                .takes_value(true))
+            .arg(Arg::with_name("websocket-client-timeout")
+                .long("websocket-client-timeout")
+                .value_name("SECONDS")
+                .about("How long a silent WebSocket client is kept, defaults to 60")
+                .takes_value(true))
+            .arg(Arg::with_name("login-limit-attempts")
+                .long("login-limit-attempts")
+                .value_name("ATTEMPTS")
+                .about("Failed logins allowed in a row per client IP and per account, 0 for no \
+                        limit, defaults to 10")
+                .takes_value(true))
+            .arg(Arg::with_name("login-limit-window")
+                .long("login-limit-window")
+                .value_name("SECONDS")
+                .about("How long it takes to get all the login attempts back, defaults to 300")
+                .takes_value(true))
+            .arg(Arg::with_name("login-limit-trusted-proxies")
+                .long("login-limit-trusted-proxies")
+                .value_name("IPS")
+                .about("Comma-separated IPs of the reverse proxies whose X-Forwarded-For header \
+                        is trusted, defaults to none")
+                .takes_value(true))
+            .arg(Arg::with_name("permission-cache-ttl")
+                .long("permission-cache-ttl")
+                .value_name("SECONDS")
+                .about("How long the permissions of a user are cached, 0 for no cache, defaults \
+                        to 30")
+                .takes_value(true))
+            .arg(Arg::with_name("permission-cache-size")
+                .long("permission-cache-size")
+                .value_name("ENTRIES")
+                .about("Users whose permissions are cached at most, defaults to 10000")
+                .takes_value(true))
             .subcommand(App::new("init")
                 .about("Initializes all databases")
                 .arg(Arg::with_name("reset")
@@ -222,6 +870,8 @@ impl Config {
         config_file.db = matches.value_of("db").map(String::from).or(config_file.db);
         config_file.redis = matches.value_of("redis").map(String::from).or(config_file.redis);
         config_file.bind = matches.value_of("bind").map(String::from).or(config_file.bind);
+        config_file.metrics_bind = matches.value_of("metrics-bind").map(String::from)
+            .or(config_file.metrics_bind);
         config_file.site = matches.value_of("site").map(String::from).or(config_file.site);
         let mut default_media_config_file = MediaConfigFile::new();
         let media_config_file = config_file.media.as_mut()
@@ -233,17 +883,332 @@ impl Config {
         if matches.is_present("media-serve") {
             media_config_file.serve = Some(true);
         }
+        if matches.is_present("media-avatar-async") {
+            media_config_file.avatar_async = Some(true);
+        }
+        media_config_file.avatar_format = matches.value_of("media-avatar-format").map(String::from)
+            .or(media_config_file.avatar_format.clone());
+        if let Some(quality) = matches.value_of("media-avatar-jpeg-quality") {
+            media_config_file.avatar_jpeg_quality = Some(quality.parse()
+                .map_err(|_| ConfigError::InvalidArgument("media.avatar_jpeg_quality".into()))?);
+        }
+        let avatar_jpeg_quality = media_config_file.avatar_jpeg_quality.unwrap_or(85);
+        if !(1..=100).contains(&avatar_jpeg_quality) {
+            return Err(ConfigError::InvalidArgument("media.avatar_jpeg_quality".into()));
+        }
+        let avatar_format = match media_config_file.avatar_format.as_deref() {
+            None | Some("png") => AvatarFormat::Png,
+            Some("jpeg") => AvatarFormat::Jpeg { quality: avatar_jpeg_quality },
+            Some(_) => return Err(ConfigError::InvalidArgument("media.avatar_format".into())),
+        };
+        if let Some(sizes) = matches.value_of("media-avatar-sizes") {
+            media_config_file.avatar_sizes = Some(sizes.split(',')
+                .map(|x| x.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| ConfigError::InvalidArgument("media.avatar_sizes".into()))?);
+        }
+        let mut avatar_sizes = media_config_file.avatar_sizes.clone().unwrap_or_else(|| vec![128]);
+        if avatar_sizes.contains(&0) {
+            return Err(ConfigError::InvalidArgument("media.avatar_sizes".into()));
+        }
+        avatar_sizes.sort_unstable();
+        avatar_sizes.dedup();
+        if let Some(pixels) = matches.value_of("media-avatar-max-pixels") {
+            media_config_file.avatar_max_pixels = Some(pixels.parse()
+                .map_err(|_| ConfigError::InvalidArgument("media.avatar_max_pixels".into()))?);
+        }
+        if let Some(ttl) = matches.value_of("media-avatar-url-ttl") {
+            media_config_file.avatar_url_ttl = Some(ttl.parse()
+                .map_err(|_| ConfigError::InvalidArgument("media.avatar_url_ttl".into()))?);
+        }
+        media_config_file.avatar_store = matches.value_of("media-avatar-store").map(String::from)
+            .or(media_config_file.avatar_store.clone());
+        media_config_file.s3_endpoint = matches.value_of("media-s3-endpoint").map(String::from)
+            .or(media_config_file.s3_endpoint.clone());
+        media_config_file.s3_region = matches.value_of("media-s3-region").map(String::from)
+            .or(media_config_file.s3_region.clone());
+        media_config_file.s3_bucket = matches.value_of("media-s3-bucket").map(String::from)
+            .or(media_config_file.s3_bucket.clone());
+        media_config_file.s3_access_key = matches.value_of("media-s3-access-key").map(String::from)
+            .or(media_config_file.s3_access_key.clone());
+        media_config_file.s3_secret_key = matches.value_of("media-s3-secret-key").map(String::from)
+            .or(media_config_file.s3_secret_key.clone());
+        media_config_file.s3_public_url = matches.value_of("media-s3-public-url").map(String::from)
+            .or(media_config_file.s3_public_url.clone());
+        let avatar_store = match media_config_file.avatar_store.as_deref() {
+            None | Some("filesystem") => AvatarStoreKind::Filesystem,
+            Some("s3") => {
+                // The signed URLs are served from the media root
+                if media_config_file.avatar_url_ttl.is_some() {
+                    return Err(ConfigError::InvalidArgument("media.avatar_url_ttl".into()));
+                }
+                let endpoint = media_config_file.s3_endpoint.clone()
+                    .ok_or_else(|| ConfigError::MissingArgument("media.s3_endpoint".into()))?;
+                let bucket = media_config_file.s3_bucket.clone()
+                    .ok_or_else(|| ConfigError::MissingArgument("media.s3_bucket".into()))?;
+                AvatarStoreKind::S3(S3Config {
+                    public_url: media_config_file.s3_public_url.clone()
+                        .unwrap_or_else(|| format!("{}/{}", endpoint.trim_end_matches('/'),
+                                                   bucket)),
+                    endpoint,
+                    region: media_config_file.s3_region.clone()
+                        .unwrap_or_else(|| "us-east-1".into()),
+                    bucket,
+                    access_key: media_config_file.s3_access_key.clone()
+                        .ok_or_else(|| ConfigError::MissingArgument("media.s3_access_key".into()))?,
+                    secret_key: media_config_file.s3_secret_key.clone()
+                        .ok_or_else(|| ConfigError::MissingArgument("media.s3_secret_key".into()))?,
+                })
+            }
+            Some(_) => return Err(ConfigError::InvalidArgument("media.avatar_store".into())),
+        };
         let mut default_smtp_config_file = SmtpConfigFile::new();
         let smtp_config_file = config_file.smtp.as_mut()
             .unwrap_or(&mut default_smtp_config_file);
+        smtp_config_file.mode = matches.value_of("smtp-mode").map(String::from)
+            .or(smtp_config_file.mode.clone());
         smtp_config_file.server = matches.value_of("smtp-server").map(String::from)
             .or(smtp_config_file.server.clone());
+        if let Some(port) = matches.value_of("smtp-port") {
+            smtp_config_file.port = Some(port.parse()
+                .map_err(|_| ConfigError::InvalidArgument("smtp.port".into()))?);
+        }
         smtp_config_file.sender = matches.value_of("smtp-sender").map(String::from)
             .or(smtp_config_file.sender.clone());
         smtp_config_file.username = matches.value_of("smtp-username").map(String::from)
             .or(smtp_config_file.username.clone());
         smtp_config_file.password = matches.value_of("smtp-password").map(String::from)
             .or(smtp_config_file.password.clone());
+        if let Some(interval) = matches.value_of("smtp-resend-interval") {
+            smtp_config_file.resend_interval = Some(interval.parse()
+                .map_err(|_| ConfigError::InvalidArgument("smtp.resend_interval".into()))?);
+        }
+        if matches.is_present("smtp-readiness-check") {
+            smtp_config_file.readiness_check = Some(true);
+        }
+        if let Some(rate) = matches.value_of("smtp-batch-rate") {
+            smtp_config_file.batch_rate = Some(rate.parse()
+                .map_err(|_| ConfigError::InvalidArgument("smtp.batch_rate".into()))?);
+        }
+        if smtp_config_file.batch_rate == Some(0) {
+            return Err(ConfigError::InvalidArgument("smtp.batch_rate".into()));
+        }
+        if let Some(attempts) = matches.value_of("smtp-max-attempts") {
+            smtp_config_file.max_attempts = Some(attempts.parse()
+                .map_err(|_| ConfigError::InvalidArgument("smtp.max_attempts".into()))?);
+        }
+        if smtp_config_file.max_attempts == Some(0) {
+            return Err(ConfigError::InvalidArgument("smtp.max_attempts".into()));
+        }
+        if let Some(size) = matches.value_of("db-pool-size") {
+            config_file.db_pool_size = Some(size.parse()
+                .map_err(|_| ConfigError::InvalidArgument("db_pool_size".into()))?);
+        }
+        if config_file.db_pool_size == Some(0) {
+            return Err(ConfigError::InvalidArgument("db_pool_size".into()));
+        }
+        if let Some(timeout) = matches.value_of("drain-timeout") {
+            config_file.drain_timeout = Some(timeout.parse()
+                .map_err(|_| ConfigError::InvalidArgument("drain_timeout".into()))?);
+        }
+        if let Some(reservation) = matches.value_of("deleted-user-reservation") {
+            config_file.deleted_user_reservation = Some(reservation.parse()
+                .map_err(|_| ConfigError::InvalidArgument("deleted_user_reservation".into()))?);
+        }
+        if matches.is_present("redact-email") {
+            config_file.redact_email = Some(true);
+        }
+        if let Some(limit) = matches.value_of("user-list-max-limit") {
+            config_file.user_list_max_limit = Some(limit.parse()
+                .map_err(|_| ConfigError::InvalidArgument("user_list_max_limit".into()))?);
+        }
+        if config_file.user_list_max_limit == Some(0) {
+            return Err(ConfigError::InvalidArgument("user_list_max_limit".into()));
+        }
+        if let Some(ttl) = matches.value_of("access-token-ttl") {
+            config_file.access_token_ttl = Some(ttl.parse()
+                .map_err(|_| ConfigError::InvalidArgument("access_token_ttl".into()))?);
+        }
+        let access_token_ttl = config_file.access_token_ttl.unwrap_or(ACCESS_TOKEN_TTL);
+        if !(1..=ACCESS_TOKEN_MAX_TTL).contains(&access_token_ttl) {
+            return Err(ConfigError::InvalidArgument("access_token_ttl".into()));
+        }
+        let confirmation_ttl = |name: &str, ttl: Option<u64>| -> Result<u64, ConfigError> {
+            let ttl = match matches.value_of(&name.replace('_', "-")[..]) {
+                Some(ttl) => ttl.parse()
+                    .map_err(|_| ConfigError::InvalidArgument(name.into()))?,
+                None => ttl.unwrap_or(CONFIRMATION_TTL),
+            };
+            if !(1..=CONFIRMATION_MAX_TTL).contains(&ttl) {
+                return Err(ConfigError::InvalidArgument(name.into()));
+            }
+            Ok(ttl)
+        };
+        let registration_ttl = confirmation_ttl("registration_ttl", config_file.registration_ttl)?;
+        let email_updating_ttl =
+            confirmation_ttl("email_updating_ttl", config_file.email_updating_ttl)?;
+        let password_reset_ttl =
+            confirmation_ttl("password_reset_ttl", config_file.password_reset_ttl)?;
+        if let Some(cost) = matches.value_of("bcrypt-cost") {
+            config_file.bcrypt_cost = Some(cost.parse()
+                .map_err(|_| ConfigError::InvalidArgument("bcrypt_cost".into()))?);
+        }
+        let bcrypt_cost = config_file.bcrypt_cost.unwrap_or(BCRYPT_COST);
+        if !(4..=31).contains(&bcrypt_cost) {
+            return Err(ConfigError::InvalidArgument("bcrypt_cost".into()));
+        }
+        let mut default_username_config_file = UsernameConfigFile::new();
+        let username_config_file = config_file.username.as_mut()
+            .unwrap_or(&mut default_username_config_file);
+        if let Some(min_length) = matches.value_of("username-min-length") {
+            username_config_file.min_length = Some(min_length.parse()
+                .map_err(|_| ConfigError::InvalidArgument("username.min_length".into()))?);
+        }
+        if let Some(max_length) = matches.value_of("username-max-length") {
+            username_config_file.max_length = Some(max_length.parse()
+                .map_err(|_| ConfigError::InvalidArgument("username.max_length".into()))?);
+        }
+        username_config_file.extra_chars = matches.value_of("username-extra-chars").map(String::from)
+            .or(username_config_file.extra_chars.clone());
+        if matches.is_present("username-lowercase-only") {
+            username_config_file.lowercase_only = Some(true);
+        }
+        let mut default_password_config_file = PasswordConfigFile::new();
+        let password_config_file = config_file.password.as_mut()
+            .unwrap_or(&mut default_password_config_file);
+        if let Some(min_length) = matches.value_of("password-min-length") {
+            password_config_file.min_length = Some(min_length.parse()
+                .map_err(|_| ConfigError::InvalidArgument("password.min_length".into()))?);
+        }
+        if matches.is_present("password-require-mixed-case") {
+            password_config_file.require_mixed_case = Some(true);
+        }
+        if matches.is_present("password-require-digit") {
+            password_config_file.require_digit = Some(true);
+        }
+        if matches.is_present("password-require-symbol") {
+            password_config_file.require_symbol = Some(true);
+        }
+        password_config_file.breached = matches.value_of("password-breached").map(String::from)
+            .or(password_config_file.breached.clone());
+        password_config_file.breached_file = matches.value_of("password-breached-file")
+            .map(String::from)
+            .or(password_config_file.breached_file.clone());
+        password_config_file.breached_url = matches.value_of("password-breached-url")
+            .map(String::from)
+            .or(password_config_file.breached_url.clone());
+        if matches.is_present("password-breached-fail-open") {
+            password_config_file.breached_fail_open = Some(true);
+        }
+        let breached_passwords = match password_config_file.breached.as_deref() {
+            None | Some("disabled") => BreachedPasswordsKind::Disabled,
+            Some("bloom-filter") => BreachedPasswordsKind::BloomFilter(
+                password_config_file.breached_file.clone()
+                    .ok_or_else(|| ConfigError::MissingArgument("password.breached_file".into()))?),
+            Some("range-api") => BreachedPasswordsKind::RangeApi {
+                url: password_config_file.breached_url.clone()
+                    .unwrap_or_else(|| "https://api.pwnedpasswords.com/range".into()),
+                fail_open: password_config_file.breached_fail_open == Some(true),
+            },
+            Some(_) => return Err(ConfigError::InvalidArgument("password.breached".into())),
+        };
+        let password_min_length = password_config_file.min_length.unwrap_or(6);
+        if !(6..=24).contains(&password_min_length) {
+            return Err(ConfigError::InvalidArgument("password.min_length".into()));
+        }
+        let mut default_nickname_config_file = NicknameConfigFile::new();
+        let nickname_config_file = config_file.nickname.as_mut()
+            .unwrap_or(&mut default_nickname_config_file);
+        if let Some(min_length) = matches.value_of("nickname-min-length") {
+            nickname_config_file.min_length = Some(min_length.parse()
+                .map_err(|_| ConfigError::InvalidArgument("nickname.min_length".into()))?);
+        }
+        if let Some(max_length) = matches.value_of("nickname-max-length") {
+            nickname_config_file.max_length = Some(max_length.parse()
+                .map_err(|_| ConfigError::InvalidArgument("nickname.max_length".into()))?);
+        }
+        if matches.is_present("nickname-no-emoji") {
+            nickname_config_file.allow_emoji = Some(false);
+        }
+        let mut default_websocket_config_file = WebsocketConfigFile::new();
+        let websocket_config_file = config_file.websocket.as_mut()
+            .unwrap_or(&mut default_websocket_config_file);
+        if let Some(interval) = matches.value_of("websocket-heartbeat-interval") {
+            websocket_config_file.heartbeat_interval = Some(interval.parse()
+                .map_err(|_| ConfigError::InvalidArgument("websocket.heartbeat_interval".into()))?);
+        }
+        if let Some(timeout) = matches.value_of("websocket-client-timeout") {
+            websocket_config_file.client_timeout = Some(timeout.parse()
+                .map_err(|_| ConfigError::InvalidArgument("websocket.client_timeout".into()))?);
+        }
+        let heartbeat_interval = websocket_config_file.heartbeat_interval.unwrap_or(30);
+        let client_timeout = websocket_config_file.client_timeout.unwrap_or(60);
+        if heartbeat_interval == 0 {
+            return Err(ConfigError::InvalidArgument("websocket.heartbeat_interval".into()));
+        }
+        if client_timeout <= heartbeat_interval {
+            return Err(ConfigError::InvalidArgument("websocket.client_timeout".into()));
+        }
+        let mut default_login_limit_config_file = LoginLimitConfigFile::new();
+        let login_limit_config_file = config_file.login_limit.as_mut()
+            .unwrap_or(&mut default_login_limit_config_file);
+        if let Some(attempts) = matches.value_of("login-limit-attempts") {
+            login_limit_config_file.attempts = Some(attempts.parse()
+                .map_err(|_| ConfigError::InvalidArgument("login_limit.attempts".into()))?);
+        }
+        if let Some(window) = matches.value_of("login-limit-window") {
+            login_limit_config_file.window = Some(window.parse()
+                .map_err(|_| ConfigError::InvalidArgument("login_limit.window".into()))?);
+        }
+        if let Some(proxies) = matches.value_of("login-limit-trusted-proxies") {
+            login_limit_config_file.trusted_proxies = Some(proxies.split(',')
+                .map(|x| x.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| ConfigError::InvalidArgument("login_limit.trusted_proxies".into()))?);
+        }
+        let login_limit = LoginLimitConfig {
+            attempts: login_limit_config_file.attempts.unwrap_or(10),
+            window: login_limit_config_file.window.unwrap_or(300),
+            trusted_proxies: login_limit_config_file.trusted_proxies.clone().unwrap_or_default(),
+        };
+        if login_limit.window == 0 {
+            return Err(ConfigError::InvalidArgument("login_limit.window".into()));
+        }
+        let mut default_permission_cache_config_file = PermissionCacheConfigFile::new();
+        let permission_cache_config_file = config_file.permission_cache.as_mut()
+            .unwrap_or(&mut default_permission_cache_config_file);
+        if let Some(ttl) = matches.value_of("permission-cache-ttl") {
+            permission_cache_config_file.ttl = Some(ttl.parse()
+                .map_err(|_| ConfigError::InvalidArgument("permission_cache.ttl".into()))?);
+        }
+        if let Some(size) = matches.value_of("permission-cache-size") {
+            permission_cache_config_file.size = Some(size.parse()
+                .map_err(|_| ConfigError::InvalidArgument("permission_cache.size".into()))?);
+        }
+        let permission_cache = PermissionCacheConfig {
+            ttl: permission_cache_config_file.ttl.unwrap_or(30),
+            size: permission_cache_config_file.size.unwrap_or(10000),
+        };
+        config_file.code_store = matches.value_of("code-store").map(String::from)
+            .or(config_file.code_store);
+        let code_store = match config_file.code_store.as_deref() {
+            None | Some("postgres") => CodeStoreKind::Postgres,
+            Some("redis") => CodeStoreKind::Redis,
+            Some(_) => return Err(ConfigError::InvalidArgument("code_store".into())),
+        };
+        config_file.password_algorithm = matches.value_of("password-algorithm").map(String::from)
+            .or(config_file.password_algorithm);
+        let password_algorithm = match config_file.password_algorithm.as_deref() {
+            None | Some("bcrypt") => PasswordAlgorithm::Bcrypt,
+            Some("argon2id") => PasswordAlgorithm::Argon2id,
+            Some(_) => return Err(ConfigError::InvalidArgument("password_algorithm".into())),
+        };
+        let smtp_mode = match smtp_config_file.mode.as_deref() {
+            None | Some("send") => SmtpMode::Send,
+            Some("log") => SmtpMode::Log,
+            Some("disabled") => SmtpMode::Disabled,
+            Some(_) => return Err(ConfigError::InvalidArgument("smtp.mode".into())),
+        };
         match matches.subcommand() {
             ("init", Some(sub_matches)) => Ok(Config::Init(InitConfig {
                 db: config_file.db
@@ -253,14 +1218,19 @@ impl Config {
                 reset: sub_matches.is_present("reset"),
                 superuser_username: sub_matches.value_of("superuser-username").map(String::from),
                 superuser_password: sub_matches.value_of("superuser-password").map(String::from),
+                password_algorithm,
+                bcrypt_cost,
             })),
             ("start", Some(_)) => Ok(Config::Start(StartConfig {
                 db: config_file.db
                     .ok_or_else(|| ConfigError::MissingArgument("database".into()))?,
+                db_pool_size: config_file.db_pool_size.unwrap_or(DB_POOL_SIZE),
                 redis: config_file.redis
                     .ok_or_else(|| ConfigError::MissingArgument("redis".into()))?,
                 bind: config_file.bind
                     .ok_or_else(|| ConfigError::MissingArgument("bind".into()))?,
+                metrics_bind: config_file.metrics_bind,
+                drain_timeout: config_file.drain_timeout.unwrap_or(30),
                 site: config_file.site
                     .ok_or_else(|| ConfigError::MissingArgument("site".into()))?,
                 media: MediaConfig {
@@ -268,16 +1238,72 @@ impl Config {
                         .ok_or_else(|| ConfigError::MissingArgument("media.root".into()))?,
                     url: media_config_file.url.clone()
                         .ok_or_else(|| ConfigError::MissingArgument("media.url".into()))?,
-                    serve: media_config_file.serve.contains(&true),
+                    serve: media_config_file.serve == Some(true),
+                    avatar_async: media_config_file.avatar_async == Some(true),
+                    avatar_format,
+                    avatar_sizes,
+                    avatar_max_pixels: media_config_file.avatar_max_pixels
+                        .unwrap_or(AVATAR_MAX_PIXELS),
+                    avatar_url_ttl: media_config_file.avatar_url_ttl,
+                    avatar_store,
                 },
                 smtp: SmtpConfig {
-                    server: smtp_config_file.server.clone()
-                        .ok_or_else(|| ConfigError::MissingArgument("smtp.server".into()))?,
-                    sender: smtp_config_file.sender.clone()
-                        .ok_or_else(|| ConfigError::MissingArgument("smtp.sender".into()))?,
+                    mode: smtp_mode,
+                    server: match smtp_mode {
+                        SmtpMode::Send => Some(smtp_config_file.server.clone()
+                            .ok_or_else(|| ConfigError::MissingArgument("smtp.server".into()))?),
+                        _ => smtp_config_file.server.clone(),
+                    },
+                    port: smtp_config_file.port.unwrap_or(SMTP_PORT),
+                    sender: match smtp_mode {
+                        SmtpMode::Send => smtp_config_file.sender.clone()
+                            .ok_or_else(|| ConfigError::MissingArgument("smtp.sender".into()))?,
+                        _ => smtp_config_file.sender.clone()
+                            .unwrap_or_else(|| "Cashier <cashier@localhost>".into()),
+                    },
                     username: smtp_config_file.username.clone(),
                     password: smtp_config_file.password.clone(),
+                    resend_interval: smtp_config_file.resend_interval.unwrap_or(60),
+                    batch_rate: smtp_config_file.batch_rate.unwrap_or(60),
+                    max_attempts: smtp_config_file.max_attempts.unwrap_or(EMAIL_MAX_ATTEMPTS),
+                    readiness_check: smtp_config_file.readiness_check == Some(true),
+                },
+                code_store,
+                deleted_user_reservation: config_file.deleted_user_reservation
+                    .unwrap_or(30 * 24 * 60 * 60),
+                redact_email: config_file.redact_email == Some(true),
+                user_list_max_limit: config_file.user_list_max_limit.unwrap_or(100),
+                access_token_ttl,
+                registration_ttl,
+                email_updating_ttl,
+                password_reset_ttl,
+                password_algorithm,
+                bcrypt_cost,
+                username: UsernameConfig {
+                    min_length: username_config_file.min_length.unwrap_or(3),
+                    max_length: username_config_file.max_length.unwrap_or(24),
+                    extra_chars: username_config_file.extra_chars.clone()
+                        .unwrap_or_else(|| "_-".into()),
+                    lowercase_only: username_config_file.lowercase_only == Some(true),
+                },
+                password: PasswordConfig {
+                    min_length: password_min_length,
+                    require_mixed_case: password_config_file.require_mixed_case == Some(true),
+                    require_digit: password_config_file.require_digit == Some(true),
+                    require_symbol: password_config_file.require_symbol == Some(true),
+                },
+                breached_passwords,
+                nickname: NicknameConfig {
+                    min_length: nickname_config_file.min_length.unwrap_or(3),
+                    max_length: nickname_config_file.max_length.unwrap_or(24),
+                    allow_emoji: nickname_config_file.allow_emoji != Some(false),
+                },
+                websocket: WebsocketConfig {
+                    heartbeat_interval,
+                    client_timeout,
                 },
+                login_limit,
+                permission_cache,
             })),
             _ => Err(ConfigError::InvalidSubcommand)
         }
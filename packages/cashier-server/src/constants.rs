@@ -2,19 +2,81 @@ use chrono::Duration;
 use lazy_static::lazy_static;
 
 pub const JWT_SECRET_LENGTH: u32 = 256;
-pub const JWT_EXPIRE: &str = "10 days";
+// Default of StartConfig::db_pool_size
+pub const DB_POOL_SIZE: usize = 10;
+// Attempts to open a PostgreSQL connection before a request fails, and the milliseconds between
+pub const DB_CONNECT_ATTEMPTS: u32 = 3;
+pub const DB_CONNECT_RETRY_DELAY: u64 = 500;
+// Default and maximum of StartConfig::access_token_ttl, in seconds
+pub const ACCESS_TOKEN_TTL: u64 = 10 * 24 * 60 * 60;
+pub const ACCESS_TOKEN_MAX_TTL: u64 = 365 * 24 * 60 * 60;
+// Seconds a token issued by POST /api/users/:id/impersonate stays valid
+pub const IMPERSONATION_TOKEN_TTL: u64 = 60 * 60;
+pub const REFRESH_TOKEN_EXPIRE: &str = "30 days";
+pub const REFRESH_TOKEN_LENGTH: usize = 48;
 pub const BCRYPT_COST: u32 = 10;
+// Memory in KiB and iterations of Argon2id, as recommended by OWASP
+pub const ARGON2_MEMORY_COST: u32 = 19456;
+pub const ARGON2_TIME_COST: u32 = 2;
+pub const ARGON2_SALT_LENGTH: usize = 16;
+// Seconds to wait for the range API of breached passwords
+pub const BREACHED_PASSWORDS_TIMEOUT: u64 = 5;
 
 pub const AVATAR_FOLDER: &str = "images/avatars";
 pub const AVATAR_FILENAME_LENGTH: usize = 24;
+// Bytes of an uploaded or fetched avatar
+pub const AVATAR_MAX_SIZE: usize = 1024 * 1024;
+// Seconds to fetch an avatar from a URL
+pub const AVATAR_FETCH_TIMEOUT: u64 = 10;
+// Lossy quality, 0 to 100, of the WebP variant kept beside every avatar
+pub const AVATAR_WEBP_QUALITY: f32 = 80.0;
+// 4096x4096, decoded to 64 MiB of RGBA
+pub const AVATAR_MAX_PIXELS: u64 = 16_777_216;
+// Avatars uploaded for background processing and not done yet, uploads beyond fail until there
+// is room, see MediaConfig::avatar_async
+pub const AVATAR_PROCESSING_CAPACITY: usize = 16;
+// Seconds browsers may cache a public avatar, whose file is never changed once written
+pub const AVATAR_CACHE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+// The most entries a page of GET /api/users/changes returns
+pub const USER_CHANGES_PAGE_SIZE: i64 = 100;
+// The most and the default entries GET /api/users/search returns
+pub const USER_SEARCH_MAX_LIMIT: i64 = 20;
+// The most and the default entries a page of GET /api/audit-log returns
+pub const AUDIT_LOG_MAX_LIMIT: i64 = 100;
+// The most and the default entries a page of GET /api/registrations or /api/email-updating returns
+pub const PENDING_LIST_MAX_LIMIT: i64 = 100;
 
 pub const CHANNEL_NAME: &str = "cashier-server-channel";
 
-pub const USER_REGISTRATION_EXPIRE: &str = "30 minutes";
-pub const USER_UPDATING_EMAIL_EXPIRE: &str = "30 minutes";
+// Default and maximum of StartConfig::registration_ttl, email_updating_ttl and
+// password_reset_ttl, in seconds
+pub const CONFIRMATION_TTL: u64 = 30 * 60;
+pub const CONFIRMATION_MAX_TTL: u64 = 7 * 24 * 60 * 60;
+// A code is dropped after this many wrong attempts, a resend then comes with a new one
+pub const CONFIRMATION_CODE_MAX_ATTEMPTS: u32 = 5;
+pub const CODE_KEY_PREFIX: &str = "cashier-server-code";
+// E-mails waiting for the SMTP server, requests sending more fail until there is room
+pub const EMAIL_QUEUE_CAPACITY: usize = 256;
+// Default of SmtpConfig::max_attempts
+pub const EMAIL_MAX_ATTEMPTS: u32 = 3;
+// Seconds before the first retry of an e-mail
+pub const EMAIL_RETRY_DELAY: u64 = 5;
+// Seconds each dependency has to answer GET /readyz
+pub const HEALTH_CHECK_TIMEOUT: u64 = 5;
+// About 1% false positives for the breached password list
+pub const BLOOM_FILTER_BITS_PER_HASH: usize = 10;
+pub const BLOOM_FILTER_HASH_FUNCTIONS: usize = 7;
+// Login limiter buckets kept at most. Beyond, the fullest are dropped until a tenth is free
+pub const LOGIN_LIMITER_MAX_BUCKETS: usize = 10000;
+// Seconds between drops of the login limiter buckets back to full
+pub const LOGIN_LIMITER_SWEEP_INTERVAL: u64 = 60;
+
+pub const TOTP_ISSUER: &str = "cashier-server";
+pub const TOTP_SECRET_LENGTH: usize = 20;
+pub const TOTP_DIGITS: u32 = 6;
+pub const TOTP_STEP: u64 = 30;
 
 lazy_static! {
-    pub static ref WEBSOCKET_HEARTBEAT_INTERVAL: Duration = Duration::seconds(30);
-    pub static ref WEBSOCKET_CLIENT_TIMEOUT: Duration = Duration::minutes(1);
     pub static ref WEBSOCKET_PERMISSION_REFRESH_INTERVAL: Duration = Duration::minutes(5);
 }
@@ -1,4 +1,5 @@
-#![feature(option_result_contains)]
+// err-derive puts its impls in named consts, which newer compilers warn about
+#![allow(non_local_definitions)]
 
 pub mod config;
 pub mod services;
@@ -0,0 +1,142 @@
+// Trail of security-sensitive actions. Entries are written in the transaction of the action
+// itself, so that a rolled back action leaves no entry behind, and are never changed afterwards
+use super::errors::Result;
+use cashier_query::generator::{QueryConfig, FieldConfig, escape_value};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio_postgres::{
+    Client, Statement, Transaction, types::{Type, ToSql},
+    Row,
+};
+
+// Who an entry is recorded for. Actions taken with an impersonation token are recorded for the
+// impersonated user along with the admin behind it
+#[derive(Debug, Clone, Copy)]
+pub struct Actor {
+    pub uid: i32,
+    pub impersonator: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub actor: i32,
+    pub impersonator: Option<i32>,
+    // In the form of <subject>.<verb>, e.g. user.block or role-permission.add
+    pub action: String,
+    // A user or a role, depending on the action
+    pub target: i32,
+    pub detail: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<&Row> for AuditEntry {
+    fn from(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            actor: row.get("actor"),
+            impersonator: row.get("impersonator"),
+            action: row.get("action"),
+            target: row.get("target"),
+            detail: row.get("detail"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+// Fields of the filter and sort of GET /api/audit-log
+fn audit_log_config() -> QueryConfig {
+    QueryConfig::new()
+        .field(FieldConfig::new("id")
+            .partial_equal()
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<i32>())
+        )
+        .field(FieldConfig::new("actor")
+            .partial_equal()
+            .escape_handler(escape_value::<i32>())
+        )
+        .field(FieldConfig::new("impersonator")
+            .partial_equal()
+            .nullable()
+            .escape_handler(escape_value::<i32>())
+        )
+        .field(FieldConfig::new("action")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+            .sortable()
+        )
+        .field(FieldConfig::new("target")
+            .partial_equal()
+            .escape_handler(escape_value::<i32>())
+        )
+        .field(FieldConfig::new("created_at")
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .max_clauses(64)
+        .max_depth(8)
+}
+
+pub struct Query {
+    insert: Statement,
+}
+
+impl Query {
+    pub async fn new(client: &Client) -> Result<Self> {
+        let insert = client.prepare_typed(
+            "INSERT INTO audit_log (actor, impersonator, action, target, detail, created_at) \
+                VALUES ($1, $2, $3, $4, $5, NOW())",
+            &[Type::INT4, Type::INT4, Type::TEXT, Type::INT4, Type::JSONB],
+        ).await?;
+        Ok(Self {
+            insert,
+        })
+    }
+    pub async fn record(
+        &self, transaction: &Transaction<'_>, actor: &Actor, action: &str, target: i32,
+        detail: Value,
+    ) -> Result<()> {
+        transaction
+            .execute(&self.insert, &[&actor.uid, &actor.impersonator, &action, &target, &detail])
+            .await?;
+        Ok(())
+    }
+    // The same filter and sort syntax as the users list, newest first by default
+    pub async fn list(
+        &self, client: &Client, filter: Option<&str>, sort: Option<&str>, limit: i64, offset: i64,
+    ) -> Result<(Vec<AuditEntry>, i64)> {
+        let config = audit_log_config();
+        let (condition, mut params) = match filter {
+            Some(filter) => config.parse_to_postgres_params(filter)?,
+            None => None,
+        }.unwrap_or_else(|| ("TRUE".into(), Vec::new()));
+        let order = match sort {
+            Some(sort) => config.parse_order_by(sort)?,
+            None => None,
+        }.map(|x| x + ", id DESC").unwrap_or_else(|| "id DESC".into());
+        let total = client
+            .query_one(format!("SELECT COUNT(*) FROM audit_log WHERE {}", condition).as_str(),
+                       &params.iter().map(|x| x as &(dyn ToSql + Sync)).collect::<Vec<_>>())
+            .await?
+            .get(0);
+        let (limit_param, offset_param) = (params.len() + 1, params.len() + 2);
+        params.push(limit.into());
+        params.push(offset.into());
+        let entries = client
+            .query(format!("SELECT id, actor, impersonator, action, target, detail, created_at \
+                FROM audit_log WHERE {} \
+                ORDER BY {} LIMIT ${} OFFSET ${}", condition, order, limit_param, offset_param)
+                       .as_str(),
+                   &params.iter().map(|x| x as &(dyn ToSql + Sync)).collect::<Vec<_>>())
+            .await?
+            .iter()
+            .map(AuditEntry::from)
+            .collect();
+        Ok((entries, total))
+    }
+}
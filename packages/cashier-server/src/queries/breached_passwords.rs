@@ -0,0 +1,214 @@
+// Known compromised passwords, see StartConfig::breached_passwords. Passwords are looked up by
+// their SHA-1 hash, as in the published lists.
+use crate::config::BreachedPasswordsKind;
+use crate::constants::{
+    BLOOM_FILTER_BITS_PER_HASH, BLOOM_FILTER_HASH_FUNCTIONS, BREACHED_PASSWORDS_TIMEOUT,
+};
+use actix_web::client::Client;
+use err_derive::Error;
+use log::warn;
+use ring::digest;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+#[derive(Debug, Error)]
+pub enum BreachedPasswordsError {
+    #[error(display = "{}", _0)]
+    Io(#[error(source)] #[error(from)] std::io::Error),
+    #[error(display = "invalid SHA-1 hash on line {}", _0)]
+    InvalidHash(usize),
+    #[error(display = "range API request failed: {}", _0)]
+    Request(String),
+}
+
+pub type Result<T> = std::result::Result<T, BreachedPasswordsError>;
+
+fn sha1(password: &str) -> [u8; 20] {
+    let mut hash = [0; 20];
+    hash.copy_from_slice(
+        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes()).as_ref());
+    hash
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 20];
+    for (i, x) in hash.iter_mut().enumerate() {
+        *x = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    pub fn new(hashes: usize) -> Self {
+        let words = (hashes * BLOOM_FILTER_BITS_PER_HASH).div_ceil(64);
+        Self {
+            bits: vec![0; words.max(1)],
+        }
+    }
+    // Double hashing with two halves of the SHA-1 hash, which is already uniform
+    fn positions(&self, hash: &[u8; 20]) -> impl Iterator<Item = usize> {
+        let bits = self.bits.len() as u64 * 64;
+        let h1 = u64::from_be_bytes(hash[..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(hash[8..16].try_into().unwrap());
+        (0..BLOOM_FILTER_HASH_FUNCTIONS as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+    pub fn insert(&mut self, hash: &[u8; 20]) {
+        for position in self.positions(hash) {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+    pub fn contains(&self, hash: &[u8; 20]) -> bool {
+        self.positions(hash)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+    // One hex hash per line, optionally followed by ":<count>" as in the Pwned Passwords
+    // downloads. Read twice, first to size the filter
+    pub fn load(path: &str) -> Result<Self> {
+        let count = BufReader::new(File::open(path)?).lines().count();
+        let mut filter = Self::new(count);
+        for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let hash = line.split(':').next().and_then(parse_sha1)
+                .ok_or(BreachedPasswordsError::InvalidHash(index + 1))?;
+            filter.insert(&hash);
+        }
+        Ok(filter)
+    }
+}
+
+pub enum BreachedPasswords {
+    Disabled,
+    BloomFilter(BloomFilter),
+    // Only sent the first 5 hex chars of the hash, answers the suffixes sharing them
+    RangeApi {
+        url: String,
+        fail_open: bool,
+    },
+}
+
+impl BreachedPasswords {
+    pub fn new(kind: &BreachedPasswordsKind) -> Result<Self> {
+        Ok(match kind {
+            BreachedPasswordsKind::Disabled => BreachedPasswords::Disabled,
+            BreachedPasswordsKind::BloomFilter(path) =>
+                BreachedPasswords::BloomFilter(BloomFilter::load(path)?),
+            BreachedPasswordsKind::RangeApi { url, fail_open } => BreachedPasswords::RangeApi {
+                url: url.clone(),
+                fail_open: *fail_open,
+            },
+        })
+    }
+    // Queries the range API ahead of contains, so that contains never waits for the network.
+    // None for the other kinds. An unreachable or failing range API is an error, which rejects
+    // the password, unless it fails open and the password goes unchecked
+    pub async fn fetch_range(&self, password: &str) -> Result<Option<String>> {
+        let (url, fail_open) = match self {
+            BreachedPasswords::RangeApi { url, fail_open } => (url, *fail_open),
+            _ => return Ok(None),
+        };
+        match request_range(url, &hex(&sha1(password))[..5]).await {
+            Ok(range) => Ok(Some(range)),
+            Err(e) if fail_open => {
+                warn!("password not checked against breached passwords: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+    // Called within web::block, range is what fetch_range returned
+    pub fn contains(&self, password: &str, range: Option<&str>) -> bool {
+        match self {
+            BreachedPasswords::Disabled => false,
+            BreachedPasswords::BloomFilter(filter) => filter.contains(&sha1(password)),
+            BreachedPasswords::RangeApi { .. } => {
+                let hash = hex(&sha1(password));
+                // Lines are "<suffix>:<count>", padding lines have a count of 0
+                range.is_some_and(|range| range.lines()
+                    .filter_map(|line| {
+                        let mut parts = line.trim().splitn(2, ':');
+                        Some((parts.next()?, parts.next()?))
+                    })
+                    .any(|(suffix, count)| suffix.eq_ignore_ascii_case(&hash[5..])
+                        && count != "0"))
+            }
+        }
+    }
+}
+
+async fn request_range(url: &str, prefix: &str) -> Result<String> {
+    let mut response = Client::new()
+        .get(format!("{}/{}", url.trim_end_matches('/'), prefix))
+        .timeout(Duration::from_secs(BREACHED_PASSWORDS_TIMEOUT))
+        .send()
+        .await
+        .map_err(|e| BreachedPasswordsError::Request(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(BreachedPasswordsError::Request(
+            format!("responded {}", response.status().as_u16())));
+    }
+    let body = response.body()
+        .await
+        .map_err(|e| BreachedPasswordsError::Request(e.to_string()))?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02X}", x)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_filter_test() {
+        let mut filter = BloomFilter::new(2);
+        filter.insert(&sha1("password"));
+        filter.insert(&parse_sha1(&hex(&sha1("123456"))).unwrap());
+        let breached = BreachedPasswords::BloomFilter(filter);
+        assert!(breached.contains("password", None));
+        assert!(breached.contains("123456", None));
+        assert!(!breached.contains("correct horse battery staple", None));
+        assert_eq!(parse_sha1("5BAA61E4"), None);
+    }
+
+    #[test]
+    fn range_api_test() {
+        // SHA-1 of "password" is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8
+        let breached = BreachedPasswords::RangeApi {
+            url: "https://api.pwnedpasswords.com/range".into(),
+            fail_open: false,
+        };
+        assert!(breached.contains("password", Some("0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n\
+                                                  1E4C9B93F3F0682250B6CF8331B7EE68FD8:3861493")));
+        assert!(!breached.contains("password", Some("1E4C9B93F3F0682250B6CF8331B7EE68FD8:0")));
+        assert!(!breached.contains("password", None));
+        assert!(!BreachedPasswords::Disabled.contains("password", None));
+    }
+
+    #[actix_rt::test]
+    async fn range_api_unreachable_test() {
+        // Nothing listens on port 1
+        let breached = |fail_open| BreachedPasswords::RangeApi {
+            url: "http://127.0.0.1:1/range".into(),
+            fail_open,
+        };
+        assert!(breached(false).fetch_range("password").await.is_err());
+        assert!(breached(true).fetch_range("password").await.unwrap().is_none());
+        assert!(BreachedPasswords::Disabled.fetch_range("password").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,328 @@
+// Confirmation codes of pending registrations, email updatings and password resets, see
+// StartConfig::code_store. The pending rows themselves always stay in PostgreSQL, only the codes
+// move.
+use super::errors::Result;
+use crate::config::{StartConfig, CodeStoreKind};
+use futures::future::{FutureExt, LocalBoxFuture};
+use redis::{aio::Connection, RedisResult, Script};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, Statement, types::Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CodeKind {
+    Registration,
+    EmailUpdating,
+    PasswordReset,
+}
+
+impl CodeKind {
+    fn name(self) -> &'static str {
+        match self {
+            CodeKind::Registration => "registration",
+            CodeKind::EmailUpdating => "email-updating",
+            CodeKind::PasswordReset => "password-reset",
+        }
+    }
+}
+
+// Stores that do not need the database ignore the client. The ttl of put is only used by stores
+// that expire codes themselves, PostgresCodeStore ignores it as the pending rows expire on their
+// expires_at
+pub trait CodeStore: Send + Sync {
+    fn put<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str, code: &'a str, ttl: Duration,
+    ) -> LocalBoxFuture<'a, Result<()>>;
+    // None once consumed or expired
+    fn get<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>>;
+    // Whether the code was still there, so that only one of concurrent consumers wins
+    fn consume<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool>>;
+    // Counts a wrong attempt and returns the count so far, 0 if the code is gone
+    fn attempts_inc<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<u32>>;
+}
+
+pub async fn new_code_store(
+    client: &Client, config: &StartConfig,
+) -> Result<Box<dyn CodeStore>> {
+    Ok(match config.code_store {
+        CodeStoreKind::Postgres => Box::new(PostgresCodeStore::new(client).await?),
+        CodeStoreKind::Redis => Box::new(RedisCodeStore::new(&config.redis).await?),
+    })
+}
+
+// For a store shared by the connections of a pool::Pool
+impl<T: CodeStore + ?Sized> CodeStore for Arc<T> {
+    fn put<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str, code: &'a str, ttl: Duration,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        (**self).put(client, kind, id, code, ttl)
+    }
+    fn get<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>> {
+        (**self).get(client, kind, id)
+    }
+    fn consume<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool>> {
+        (**self).consume(client, kind, id)
+    }
+    fn attempts_inc<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<u32>> {
+        (**self).attempts_inc(client, kind, id)
+    }
+}
+
+struct PostgresStatements {
+    put: Statement,
+    get: Statement,
+    consume: Statement,
+    attempts_inc: Statement,
+}
+
+impl PostgresStatements {
+    async fn new(client: &Client, table: &str) -> Result<Self> {
+        let put = client.prepare_typed(
+            &format!("UPDATE {} SET code = $2, attempts = 0 WHERE id = $1", table),
+            &[Type::TEXT, Type::TEXT],
+        ).await?;
+        let get = client.prepare_typed(
+            &format!("SELECT code FROM {} \
+                WHERE id = $1 AND code IS NOT NULL AND completed IS NULL AND expires_at > NOW() \
+                LIMIT 1", table),
+            &[Type::TEXT],
+        ).await?;
+        let consume = client.prepare_typed(
+            &format!("UPDATE {} SET code = NULL \
+                WHERE id = $1 AND code IS NOT NULL AND expires_at > NOW() \
+                RETURNING id", table),
+            &[Type::TEXT],
+        ).await?;
+        let attempts_inc = client.prepare_typed(
+            &format!("UPDATE {} SET attempts = attempts + 1 \
+                WHERE id = $1 AND code IS NOT NULL \
+                RETURNING attempts", table),
+            &[Type::TEXT],
+        ).await?;
+        Ok(Self {
+            put,
+            get,
+            consume,
+            attempts_inc,
+        })
+    }
+}
+
+// Keeps the codes in the code column of the pending rows
+pub struct PostgresCodeStore {
+    registration: PostgresStatements,
+    email_updating: PostgresStatements,
+    password_reset: PostgresStatements,
+}
+
+impl PostgresCodeStore {
+    pub async fn new(client: &Client) -> Result<Self> {
+        Ok(Self {
+            registration: PostgresStatements::new(client, "user_registration").await?,
+            email_updating: PostgresStatements::new(client, "user_email_updating").await?,
+            password_reset: PostgresStatements::new(client, "user_password_reset").await?,
+        })
+    }
+    fn statements(&self, kind: CodeKind) -> &PostgresStatements {
+        match kind {
+            CodeKind::Registration => &self.registration,
+            CodeKind::EmailUpdating => &self.email_updating,
+            CodeKind::PasswordReset => &self.password_reset,
+        }
+    }
+}
+
+impl CodeStore for PostgresCodeStore {
+    fn put<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str, code: &'a str, _ttl: Duration,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            client.execute(&self.statements(kind).put, &[&id, &code]).await?;
+            Ok(())
+        }.boxed_local()
+    }
+    fn get<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>> {
+        async move {
+            Ok(client
+                .query(&self.statements(kind).get, &[&id])
+                .await?
+                .first()
+                .map(|row| row.get("code")))
+        }.boxed_local()
+    }
+    fn consume<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool>> {
+        async move {
+            Ok(!client
+                .query(&self.statements(kind).consume, &[&id])
+                .await?
+                .is_empty())
+        }.boxed_local()
+    }
+    fn attempts_inc<'a>(
+        &'a self, client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<u32>> {
+        async move {
+            Ok(client
+                .query(&self.statements(kind).attempts_inc, &[&id])
+                .await?
+                .first()
+                .map(|row| row.get::<_, i32>("attempts") as u32)
+                .unwrap_or(0))
+        }.boxed_local()
+    }
+}
+
+// Keeps each code in a hash of code and attempts, expired by Redis itself
+pub struct RedisCodeStore {
+    connection: Mutex<Connection>,
+}
+
+impl RedisCodeStore {
+    pub async fn new(url: &str) -> RedisResult<Self> {
+        Ok(Self {
+            connection: Mutex::new(redis::Client::open(url)?.get_async_connection().await?),
+        })
+    }
+}
+
+fn redis_key(kind: CodeKind, id: &str) -> String {
+    format!("{}:{}:{}", crate::constants::CODE_KEY_PREFIX, kind.name(), id)
+}
+
+impl CodeStore for RedisCodeStore {
+    fn put<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str, code: &'a str, ttl: Duration,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let key = redis_key(kind, id);
+            redis::pipe()
+                .atomic()
+                .cmd("HSET").arg(&key).arg("code").arg(code).arg("attempts").arg(0).ignore()
+                .cmd("PEXPIRE").arg(&key).arg(ttl.as_millis() as u64).ignore()
+                .query_async::<_, ()>(&mut *self.connection.lock().await)
+                .await?;
+            Ok(())
+        }.boxed_local()
+    }
+    fn get<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>> {
+        async move {
+            Ok(redis::cmd("HGET").arg(redis_key(kind, id)).arg("code")
+                .query_async(&mut *self.connection.lock().await)
+                .await?)
+        }.boxed_local()
+    }
+    fn consume<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool>> {
+        async move {
+            let deleted: u32 = redis::cmd("DEL").arg(redis_key(kind, id))
+                .query_async(&mut *self.connection.lock().await)
+                .await?;
+            Ok(deleted != 0)
+        }.boxed_local()
+    }
+    fn attempts_inc<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<u32>> {
+        async move {
+            // HINCRBY alone would bring back an expired code without a TTL
+            let script = Script::new("\
+                if redis.call('EXISTS', KEYS[1]) == 1 then \
+                    return redis.call('HINCRBY', KEYS[1], 'attempts', 1) \
+                end \
+                return 0");
+            Ok(script.key(redis_key(kind, id))
+                .invoke_async(&mut *self.connection.lock().await)
+                .await?)
+        }.boxed_local()
+    }
+}
+
+// Keeps the codes in memory, for tests that should not depend on where codes are stored
+#[cfg(test)]
+struct MemoryCode {
+    code: String,
+    attempts: u32,
+    expires_at: std::time::Instant,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryCodeStore {
+    codes: std::sync::Mutex<std::collections::HashMap<(CodeKind, String), MemoryCode>>,
+}
+
+#[cfg(test)]
+impl MemoryCodeStore {
+    // Expired codes are dropped before f sees them
+    fn with_code<T>(
+        &self, kind: CodeKind, id: &str,
+        f: impl FnOnce(&mut std::collections::HashMap<(CodeKind, String), MemoryCode>,
+                       (CodeKind, String)) -> T,
+    ) -> T {
+        let mut codes = self.codes.lock().unwrap();
+        let key = (kind, id.to_string());
+        if codes.get(&key).map(|x| x.expires_at <= std::time::Instant::now()) == Some(true) {
+            codes.remove(&key);
+        }
+        f(&mut codes, key)
+    }
+}
+
+#[cfg(test)]
+impl CodeStore for MemoryCodeStore {
+    fn put<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str, code: &'a str, ttl: Duration,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        self.with_code(kind, id, |codes, key| {
+            codes.insert(key, MemoryCode {
+                code: code.into(),
+                attempts: 0,
+                expires_at: std::time::Instant::now() + ttl,
+            });
+        });
+        futures::future::ok(()).boxed_local()
+    }
+    fn get<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<String>>> {
+        let code = self.with_code(kind, id, |codes, key| codes.get(&key)
+            .map(|x| x.code.clone()));
+        futures::future::ok(code).boxed_local()
+    }
+    fn consume<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool>> {
+        let consumed = self.with_code(kind, id, |codes, key| codes.remove(&key).is_some());
+        futures::future::ok(consumed).boxed_local()
+    }
+    fn attempts_inc<'a>(
+        &'a self, _client: &'a Client, kind: CodeKind, id: &'a str,
+    ) -> LocalBoxFuture<'a, Result<u32>> {
+        let attempts = self.with_code(kind, id, |codes, key| codes.get_mut(&key)
+            .map(|x| {
+                x.attempts += 1;
+                x.attempts
+            })
+            .unwrap_or(0));
+        futures::future::ok(attempts).boxed_local()
+    }
+}
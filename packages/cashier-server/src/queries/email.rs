@@ -1,6 +1,17 @@
+use super::errors::Error;
+use crate::api::app_state::{AppState, Mailer};
+use crate::constants::EMAIL_RETRY_DELAY;
+use actix_web::web;
 use lettre::message::{header, Message, MultiPart, SinglePart};
 use lettre::error::{Error as EmailError};
-use lettre::Mailbox;
+use lettre::{Mailbox, SmtpTransport, Transport};
+use lettre::transport::smtp::error::Error as SmtpError;
+use log::{error, info, warn};
+use std::fmt::Display;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{SyncSender, TrySendError, sync_channel};
+use std::thread;
+use std::time::Duration;
 
 const EMAIL_CSS: &str = "\
     p {\
@@ -45,118 +56,499 @@ const EMAIL_CSS: &str = "\
       margin: 0 auto;\
     }";
 
-pub fn register_user_email(from: Mailbox, to: Mailbox, site: &str,
-                           username: &str, id: &str, code: &str) -> Result<Message, EmailError> {
-    let link= format!("{}/?action=confirm-registration&id={}&code={}", site, id, code);
-    Message::builder()
-        .from(from)
-        .to(to)
-        .subject(format!("{} 是你的山楂记账验证码", code))
-        .multipart(
-            MultiPart::alternative()
-                .singlepart(
-                    SinglePart::quoted_printable()
-                        .header(header::ContentType("text/plain; charset=utf8".parse().unwrap()))
-                        .body(format!("\
-                        待完成操作：验证 山楂记账 账户\n\
-                        ========================================\n\
-                        @{}，你好：\n\
-                        你已经注册了 山楂记账 。请验证账户，完成注册步骤。\n\
-                        在浏览器中打开下方连接：\n\
-                        {}\n\
-                        或，输入验证码：\n\
-                        {}\n\
-                        \n\
-                        注册成功之后，你可以在山楂记账上享受云同步等服务。\
-                        同时，我们会尽全力保护您的用户隐私和数据完整。", username, link, code))
-                )
-                .singlepart(
-                    SinglePart::quoted_printable()
-                        .header(header::ContentType("text/html; charset=utf8".parse().unwrap()))
-                        .body(format!("\
-                        <!doctype html>\
-                        <html lang=\"zh\">\
-                          <head>\
-                            <meta charset=\"utf-8\">\
-                            <title>山楂记账</title>\
-                            <style>{}</style>\
-                          </head>\
-                          <body>\
-                            <div class=\"content\">\
-                              <h1 class=\"title\">待完成操作：验证 山楂记账 账户</h1>\
-                              <div class=\"hr\"></div>\
-                              <p>@{}，你好：</p>\
-                              <p>你已经注册了 山楂记账 。请验证账户，完成注册步骤。</p>\
-                              <p>\
-                                <a class=\"confirm-button\" href=\"{}\">验证账户</a>\
-                              </p>\
-                              <p>或，输入验证码：</p>\
-                              <div class=\"code-box-wrapper\">\
-                                <div class=\"code-box\">{}</div>\
-                              </div>\
-                              <p class=\"info\">注册成功之后，你可以在山楂记账上享受云同步等服务。\
-                              同时，我们会尽全力保护您的用户隐私和数据完整。</p>\
-                            </div>\
-                          </body>\
-                        </html>\
-                        ", EMAIL_CSS, username, link, code))
-                )
-        )
+// Locales e-mails are written in, the default one is used when none of the accepted is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    Zh,
+    En,
+}
+
+impl Locale {
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag.split('-').next()?.to_ascii_lowercase().as_str() {
+            "zh" => Some(Locale::Zh),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+    pub fn lang(self) -> &'static str {
+        self.bundle().lang
+    }
+    pub fn email_updating_link_texts(self) -> &'static LinkPageTexts {
+        &self.bundle().email_updating_link
+    }
+    // Picks the known language with the highest quality from an Accept-Language header
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut languages = header.split(',')
+            .filter_map(|language| {
+                let mut parts = language.split(';').map(str::trim);
+                let tag = parts.next()?;
+                let quality = parts
+                    .find(|x| x.starts_with("q="))
+                    .map(|x| &x[2..])
+                    .map_or(Some(1.0), |x| x.parse::<f32>().ok())?;
+                Some((Locale::from_tag(tag)?, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+        // Stable, so the earlier one wins between equal qualities
+        languages.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        languages.first().map(|(locale, _)| *locale)
+    }
+    fn bundle(self) -> &'static EmailBundle {
+        match self {
+            Locale::Zh => &ZH_BUNDLE,
+            Locale::En => &EN_BUNDLE,
+        }
+    }
 }
 
-pub fn update_user_email(from: Mailbox, to: Mailbox, site: &str,
-                         username: &str, id: &str, code: &str) -> Result<Message, EmailError> {
-    let link= format!("{}/?action=confirm-email-updating&id={}&code={}", site, id, code);
+// Wording of one kind of e-mail
+struct EmailTexts {
+    title: &'static str,
+    intro: &'static str,
+    button: &'static str,
+    info: &'static str,
+}
+
+// Wording of the page opened by the link of an e-mail updating
+pub struct LinkPageTexts {
+    pub confirmed_title: &'static str,
+    pub confirmed: &'static str,
+    pub failed_title: &'static str,
+}
+
+// Wording of every kind of e-mail in one locale. {username} and {code} are replaced
+struct EmailBundle {
+    lang: &'static str,
+    app_name: &'static str,
+    subject: &'static str,
+    greeting: &'static str,
+    open_link: &'static str,
+    enter_code: &'static str,
+    registration: EmailTexts,
+    email_updating: EmailTexts,
+    password_reset: EmailTexts,
+    email_updating_link: LinkPageTexts,
+}
+
+impl EmailBundle {
+    fn subject(&self, code: &str) -> String {
+        self.subject.replace("{code}", code)
+    }
+}
+
+static ZH_BUNDLE: EmailBundle = EmailBundle {
+    lang: "zh",
+    app_name: "山楂记账",
+    subject: "{code} 是你的山楂记账验证码",
+    greeting: "@{username}，你好：",
+    open_link: "在浏览器中打开下方连接：",
+    enter_code: "或，输入验证码：",
+    registration: EmailTexts {
+        title: "待完成操作：验证 山楂记账 账户",
+        intro: "你已经注册了 山楂记账 。请验证账户，完成注册步骤。",
+        button: "验证账户",
+        info: "注册成功之后，你可以在山楂记账上享受云同步等服务。\
+               同时，我们会尽全力保护您的用户隐私和数据完整。",
+    },
+    email_updating: EmailTexts {
+        title: "待完成操作：更改 山楂记账 账户的邮箱",
+        intro: "你申请更改了 山楂记账 的邮箱。请验证邮箱，完成更新邮箱步骤。",
+        button: "验证邮箱",
+        info: "我们会尽全力保护您的用户隐私和数据完整。",
+    },
+    password_reset: EmailTexts {
+        title: "待完成操作：重置 山楂记账 账户的密码",
+        intro: "你申请重置了 山楂记账 的密码。请验证邮箱，完成重置密码步骤。",
+        button: "重置密码",
+        info: "如果这不是你本人的操作，请忽略这封邮件。",
+    },
+    email_updating_link: LinkPageTexts {
+        confirmed_title: "邮箱验证成功",
+        confirmed: "您的邮箱已更新，现在可以关闭此页面。",
+        failed_title: "邮箱验证失败",
+    },
+};
+
+static EN_BUNDLE: EmailBundle = EmailBundle {
+    lang: "en",
+    app_name: "Hawthorn Ledger",
+    subject: "{code} is your Hawthorn Ledger verification code",
+    greeting: "Hi @{username},",
+    open_link: "Open the link below in your browser:",
+    enter_code: "Or enter the verification code:",
+    registration: EmailTexts {
+        title: "Action required: verify your Hawthorn Ledger account",
+        intro: "You have signed up for Hawthorn Ledger. Please verify your account to complete \
+                the registration.",
+        button: "Verify account",
+        info: "Once registered, you can enjoy cloud sync and more on Hawthorn Ledger. \
+               We will do our best to protect your privacy and the integrity of your data.",
+    },
+    email_updating: EmailTexts {
+        title: "Action required: change the e-mail of your Hawthorn Ledger account",
+        intro: "You have asked to change your e-mail on Hawthorn Ledger. Please verify the \
+                e-mail to complete the change.",
+        button: "Verify e-mail",
+        info: "We will do our best to protect your privacy and the integrity of your data.",
+    },
+    password_reset: EmailTexts {
+        title: "Action required: reset the password of your Hawthorn Ledger account",
+        intro: "You have asked to reset your password on Hawthorn Ledger. Please verify the \
+                e-mail to complete the reset.",
+        button: "Reset password",
+        info: "If this was not you, please ignore this e-mail.",
+    },
+    email_updating_link: LinkPageTexts {
+        confirmed_title: "E-mail verified",
+        confirmed: "Your e-mail has been updated, you may close this page now.",
+        failed_title: "E-mail verification failed",
+    },
+};
+
+// For text put into HTML, in elements or quoted attributes
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Only the HTML part is escaped, the username and the link go into the plain text part as they are
+fn verification_html(bundle: &EmailBundle, texts: &EmailTexts, username: &str, link: &str,
+                     code: &str) -> String {
+    let greeting = bundle.greeting.replace("{username}", &escape_html(username));
+    format!("\
+        <!doctype html>\
+        <html lang=\"{}\">\
+          <head>\
+            <meta charset=\"utf-8\">\
+            <title>{}</title>\
+            <style>{}</style>\
+          </head>\
+          <body>\
+            <div class=\"content\">\
+              <h1 class=\"title\">{}</h1>\
+              <div class=\"hr\"></div>\
+              <p>{}</p>\
+              <p>{}</p>\
+              <p>\
+                <a class=\"confirm-button\" href=\"{}\">{}</a>\
+              </p>\
+              <p>{}</p>\
+              <div class=\"code-box-wrapper\">\
+                <div class=\"code-box\">{}</div>\
+              </div>\
+              <p class=\"info\">{}</p>\
+            </div>\
+          </body>\
+        </html>\
+        ", bundle.lang, bundle.app_name, EMAIL_CSS, texts.title, greeting, texts.intro,
+            escape_html(link), texts.button, bundle.enter_code, code, texts.info)
+}
+
+fn verification_email(from: Mailbox, to: Mailbox, bundle: &EmailBundle, texts: &EmailTexts,
+                      username: &str, link: &str, code: &str) -> Result<Message, EmailError> {
+    let greeting = bundle.greeting.replace("{username}", username);
     Message::builder()
         .from(from)
         .to(to)
-        .subject(format!("{} 是你的山楂记账验证码", code))
+        .subject(bundle.subject(code))
         .multipart(
             MultiPart::alternative()
                 .singlepart(
                     SinglePart::quoted_printable()
-                        .header(header::ContentType("text/plain; charset=utf8".parse().unwrap()))
+                        .header(header::ContentType("text/plain; charset=utf-8".parse().unwrap()))
                         .body(format!("\
-                        待完成操作：更改 山楂记账 账户的邮箱\n\
+                        {}\n\
                         ========================================\n\
-                        @{}，你好：\n\
-                        你申请更改了 山楂记账 的邮箱。请验证邮箱，完成更新邮箱步骤。\n\
-                        在浏览器中打开下方连接：\n\
                         {}\n\
-                        或，输入验证码：\n\
+                        {}\n\
+                        {}\n\
+                        {}\n\
+                        {}\n\
                         {}\n\
                         \n\
-                        我们会尽全力保护您的用户隐私和数据完整。", username, link, code))
+                        {}", texts.title, greeting, texts.intro, bundle.open_link, link,
+                                      bundle.enter_code, code, texts.info))
                 )
                 .singlepart(
                     SinglePart::quoted_printable()
-                        .header(header::ContentType("text/html; charset=utf8".parse().unwrap()))
-                        .body(format!("\
-                        <!doctype html>\
-                        <html lang=\"zh\">\
-                          <head>\
-                            <meta charset=\"utf-8\">\
-                            <title>山楂记账</title>\
-                            <style>{}</style>\
-                          </head>\
-                          <body>\
-                            <div class=\"content\">\
-                              <h1 class=\"title\">待完成操作：更改 山楂记账 账户的邮箱</h1>\
-                              <div class=\"hr\"></div>\
-                              <p>@{}，你好：</p>\
-                              <p>你申请更改了 山楂记账 的邮箱。请验证邮箱，完成更新邮箱步骤。</p>\
-                              <p>\
-                                <a class=\"confirm-button\" href=\"{}\">验证邮箱</a>\
-                              </p>\
-                              <p>或，输入验证码：</p>\
-                              <div class=\"code-box-wrapper\">\
-                                <div class=\"code-box\">{}</div>\
-                              </div>\
-                              <p class=\"info\">我们会尽全力保护您的用户隐私和数据完整。</p>\
-                            </div>\
-                          </body>\
-                        </html>\
-                        ", EMAIL_CSS, username, link, code))
+                        .header(header::ContentType("text/html; charset=utf-8".parse().unwrap()))
+                        .body(verification_html(bundle, texts, username, link, code))
                 )
         )
-}
\ No newline at end of file
+}
+
+pub fn register_user_email(from: Mailbox, to: Mailbox, site: &str, username: &str, id: &str,
+                           code: &str, locale: Option<Locale>) -> Result<Message, EmailError> {
+    let link = format!("{}/?action=confirm-registration&id={}&code={}", site, id, code);
+    let bundle = locale.unwrap_or_default().bundle();
+    verification_email(from, to, bundle, &bundle.registration, username, &link, code)
+}
+
+pub fn update_user_email(from: Mailbox, to: Mailbox, site: &str, username: &str, id: &str,
+                         code: &str, locale: Option<Locale>) -> Result<Message, EmailError> {
+    let link = format!("{}/api/v1/email-updating/{}/confirm?code={}", site, id, code);
+    let bundle = locale.unwrap_or_default().bundle();
+    verification_email(from, to, bundle, &bundle.email_updating, username, &link, code)
+}
+
+pub fn reset_password_email(from: Mailbox, to: Mailbox, site: &str,
+                            username: &str, id: &str, code: &str) -> Result<Message, EmailError> {
+    let link = format!("{}/?action=confirm-password-reset&id={}&code={}", site, id, code);
+    let bundle = Locale::default().bundle();
+    verification_email(from, to, bundle, &bundle.password_reset, username, &link, code)
+}
+
+// What the queue hands e-mails to, called on the thread of the queue
+pub trait EmailTransport: Send + 'static {
+    fn send(&self, message: &Message) -> Result<(), SmtpError>;
+}
+
+impl EmailTransport for SmtpTransport {
+    fn send(&self, message: &Message) -> Result<(), SmtpError> {
+        Transport::send(self, message).map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentEmail {
+    pub to: Vec<String>,
+    pub subject: String,
+}
+
+impl SentEmail {
+    // Every subject starts with the confirmation code
+    pub fn code(&self) -> Option<&str> {
+        self.subject.split_whitespace().next()
+            .filter(|x| x.len() == 6 && x.bytes().all(|x| x.is_ascii_digit()))
+    }
+}
+
+// Keeps e-mails instead of sending them, clones share the kept ones. For tests to inspect
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    sent: Arc<Mutex<Vec<SentEmail>>>,
+}
+
+impl MemoryTransport {
+    pub fn sent(&self) -> Vec<SentEmail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl EmailTransport for MemoryTransport {
+    fn send(&self, message: &Message) -> Result<(), SmtpError> {
+        self.sent.lock().unwrap().push(SentEmail {
+            to: message.envelope().to().iter().map(|x| x.to_string()).collect(),
+            subject: message.headers().get::<header::Subject>()
+                .map(|x| x.0.clone())
+                .unwrap_or_default(),
+        });
+        Ok(())
+    }
+}
+
+// Delivers e-mails on a thread of its own, so that requests never wait for the SMTP server
+pub struct EmailQueue {
+    sender: SyncSender<Message>,
+    // E-mails pushed and neither delivered nor given up on yet
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl EmailQueue {
+    pub fn start<T: EmailTransport>(transport: T, capacity: usize, max_attempts: u32) -> Self {
+        let (sender, receiver) = sync_channel::<Message>(capacity);
+        let pending = Arc::new((Mutex::new(0), Condvar::new()));
+        let worker_pending = pending.clone();
+        thread::Builder::new()
+            .name("email-queue".into())
+            .spawn(move || {
+                for message in receiver {
+                    deliver_email(&transport, &message, max_attempts);
+                    let (count, done) = &*worker_pending;
+                    *count.lock().unwrap() -= 1;
+                    done.notify_all();
+                }
+            })
+            .unwrap();
+        EmailQueue { sender, pending }
+    }
+    // The worker only stops by panicking, which leaves the queue as good as full
+    pub fn push(&self, message: Message) -> Result<(), Error> {
+        let (count, _) = &*self.pending;
+        *count.lock().unwrap() += 1;
+        self.sender.try_send(message).map_err(|e| {
+            *count.lock().unwrap() -= 1;
+            match e {
+                TrySendError::Full(_) | TrySendError::Disconnected(_) => Error::EmailQueueFull,
+            }
+        })
+    }
+    // Waits up to timeout for the pending e-mails to be sent, returns how many were and how many
+    // are left. Blocks the calling thread
+    pub fn drain(&self, timeout: Duration) -> (usize, usize) {
+        let (count, done) = &*self.pending;
+        let count = count.lock().unwrap();
+        let before = *count;
+        let (count, _) = done.wait_timeout_while(count, timeout, |count| *count > 0).unwrap();
+        (before - *count, *count)
+    }
+}
+
+// Retries transient failures with the delay doubled every time, returns the last failure
+fn send_with_retries<E: Display>(
+    max_attempts: u32, mut delay: Duration,
+    mut send: impl FnMut() -> Result<(), E>, is_transient: impl Fn(&E) -> bool,
+) -> Result<(), E> {
+    let mut attempt = 1;
+    loop {
+        match send() {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < max_attempts && is_transient(&e) => {
+                warn!("attempt {} of {} to send e-mail failed, retrying in {:?}: {}",
+                      attempt, max_attempts, delay, e);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// A permanent failure, like an unknown recipient, fails again however many times it is retried.
+// The e-mail is dropped on failure, a resend brings it back
+fn deliver_email<T: EmailTransport>(transport: &T, message: &Message, max_attempts: u32) {
+    let result = send_with_retries(
+        max_attempts, Duration::from_secs(EMAIL_RETRY_DELAY),
+        || transport.send(message),
+        |e| !matches!(e, SmtpError::Permanent(_)),
+    );
+    if let Err(e) = result {
+        error!("failed to send e-mail to {:?}: {}", message.envelope().to(), e);
+    }
+}
+
+// The code is what log mode prints in place of the message
+pub fn send_email(
+    app_data: web::Data<AppState>, message: Message, code: &str,
+) -> Result<(), Error> {
+    match &app_data.mailer {
+        Mailer::Queue(queue) => queue.push(message)?,
+        Mailer::Log { last_code } => {
+            info!("e-mail to {:?} not sent, code {}", message.envelope().to(), code);
+            *last_code.lock().unwrap() = Some(code.into());
+        }
+        Mailer::Disabled => return Err(Error::EmailNotConfigured),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_accept_language_test() {
+        assert_eq!(Locale::from_accept_language("en-US,en;q=0.9"), Some(Locale::En));
+        assert_eq!(Locale::from_accept_language("fr, en;q=0.5, zh-CN;q=0.8"), Some(Locale::Zh));
+        assert_eq!(Locale::from_accept_language("en;q=0, zh;q=0.1"), Some(Locale::Zh));
+        assert_eq!(Locale::from_accept_language("fr-FR, *;q=0.5"), None);
+        assert_eq!(Locale::from_accept_language(""), None);
+    }
+
+    #[test]
+    fn localized_subject_test() {
+        let zh = Locale::Zh.bundle().subject("123456");
+        let en = Locale::En.bundle().subject("123456");
+        assert_ne!(zh, en);
+        assert_eq!(zh, "123456 是你的山楂记账验证码");
+        assert_eq!(en, "123456 is your Hawthorn Ledger verification code");
+        assert_eq!(Locale::default().bundle().subject("123456"), zh);
+        for locale in &[None, Some(Locale::Zh), Some(Locale::En)] {
+            register_user_email("sender@example.com".parse().unwrap(),
+                                "alice@example.com".parse().unwrap(), "https://example.com",
+                                "alice", "id", "123456", *locale).unwrap();
+            update_user_email("sender@example.com".parse().unwrap(),
+                              "alice@example.com".parse().unwrap(), "https://example.com",
+                              "alice", "id", "123456", *locale).unwrap();
+        }
+    }
+
+    #[test]
+    fn escape_html_test() {
+        assert_eq!(escape_html("<a href=\"x?a=1&b='2'\">"),
+                   "&lt;a href=&quot;x?a=1&amp;b=&#39;2&#39;&quot;&gt;");
+        assert_eq!(escape_html("邮箱"), "邮箱");
+    }
+
+    #[test]
+    fn verification_html_test() {
+        let bundle = Locale::En.bundle();
+        let html = verification_html(bundle, &bundle.registration, "<b>alice</b>",
+                                     "https://example.com/?id=1&code=123456", "123456");
+        assert!(html.contains("Hi @&lt;b&gt;alice&lt;/b&gt;,"));
+        assert!(html.contains("href=\"https://example.com/?id=1&amp;code=123456\""));
+        assert!(!html.contains("<b>alice"));
+    }
+
+    #[test]
+    fn send_with_retries_test() {
+        let delay = Duration::from_millis(1);
+        let attempts = |failures: u32, transient: bool| {
+            let mut attempts = 0;
+            let result = send_with_retries(3, delay, || {
+                attempts += 1;
+                if attempts <= failures { Err("failure") } else { Ok(()) }
+            }, |_| transient);
+            (result.is_ok(), attempts)
+        };
+        assert_eq!(attempts(0, true), (true, 1));
+        assert_eq!(attempts(2, true), (true, 3));
+        assert_eq!(attempts(5, true), (false, 3));
+        assert_eq!(attempts(5, false), (false, 1));
+    }
+
+    #[test]
+    fn email_queue_full_test() {
+        let (sender, receiver) = sync_channel(1);
+        let queue = EmailQueue { sender, pending: Default::default() };
+        let message = || register_user_email(
+            "sender@example.com".parse().unwrap(), "alice@example.com".parse().unwrap(),
+            "https://example.com", "alice", "id", "123456", None).unwrap();
+        queue.push(message()).unwrap();
+        assert!(matches!(queue.push(message()), Err(Error::EmailQueueFull)));
+        receiver.recv().unwrap();
+        queue.push(message()).unwrap();
+        drop(receiver);
+        assert!(matches!(queue.push(message()), Err(Error::EmailQueueFull)));
+    }
+
+    #[test]
+    fn email_queue_drain_test() {
+        let transport = MemoryTransport::default();
+        let queue = EmailQueue::start(transport.clone(), 8, 1);
+        assert_eq!(queue.drain(Duration::from_millis(1)), (0, 0));
+        for _ in 0..3 {
+            queue.push(register_user_email(
+                "sender@example.com".parse().unwrap(), "alice@example.com".parse().unwrap(),
+                "https://example.com", "alice", "id", "123456", None).unwrap()).unwrap();
+        }
+        let (drained, left) = queue.drain(Duration::from_secs(10));
+        assert_eq!(left, 0);
+        assert!(drained <= 3);
+        assert_eq!(transport.sent().len(), 3);
+    }
+}
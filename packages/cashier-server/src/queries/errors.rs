@@ -1,18 +1,27 @@
 use actix_web::error::BlockingError;
-use bcrypt::BcryptError;
+use cashier_query::generator::Error as FilterError;
+use chrono::{DateTime, Utc};
 use err_derive::Error;
+use std::error::Error as _;
 use jsonwebtoken::errors::Error as JsonWebTokenError;
 use tokio_postgres::error::Error as PostgresError;
 use lettre::address::AddressError;
 use lettre::error::Error as EmailError;
-use lettre::transport::smtp::error::Error as SmtpError;
+use redis::RedisError;
+use super::tokens::TokenIdUser;
+use super::passwords::PasswordHashError;
+use super::breached_passwords::BreachedPasswordsError;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(display = "{}", _0)]
     Db(#[error(source)]#[error(from)] PostgresError),
     #[error(display = "{}", _0)]
+    Redis(#[error(source)]#[error(from)] RedisError),
+    #[error(display = "{}", _0)]
     JsonWebToken(#[error(source)]#[error(from)] JsonWebTokenError),
+    #[error(display = "{}", _0)]
+    InvalidFilter(#[error(source)]#[error(from)] FilterError),
     #[error(display = "user not found")]
     UserNotFound,
     #[error(display = "wrong password")]
@@ -21,6 +30,29 @@ pub enum Error {
     UserBlocked,
     #[error(display = "token not found")]
     TokenNotFound,
+    #[error(display = "TOTP code required")]
+    TotpRequired,
+    #[error(display = "wrong TOTP code")]
+    WrongTotpCode,
+    #[error(display = "TOTP not enrolled")]
+    TotpNotEnrolled,
+    #[error(display = "TOTP already enabled")]
+    TotpAlreadyEnabled,
+    #[error(display = "refresh token not found")]
+    RefreshTokenNotFound,
+    #[error(display = "refresh token expired")]
+    RefreshTokenExpired,
+    // The whole chain of the reused token is revoked, including these access tokens
+    #[error(display = "refresh token reused")]
+    RefreshTokenReused {
+        revoked: Vec<TokenIdUser>,
+    },
+    #[error(display = "role not found")]
+    RoleNotFound,
+    #[error(display = "duplicated role with same name")]
+    DuplicatedRole,
+    #[error(display = "role would inherit from itself")]
+    RoleCycle,
     #[error(display = "invalid token causing by {}", error)]
     InvalidToken {
         error: String,
@@ -30,17 +62,25 @@ pub enum Error {
         field: String,
     },
     #[error(display = "{}", _0)]
-    Bcrypt(#[error(source)]#[error(from)] BlockingError<BcryptError>),
+    PasswordHash(#[error(source)]#[error(from)] BlockingError<PasswordHashError>),
+    #[error(display = "{}", _0)]
+    BreachedPasswords(#[error(source)]#[error(from)] BreachedPasswordsError),
+    #[error(display = "password found in a breached password list")]
+    PasswordCompromised,
     #[error(display = "{}", _0)]
     Address(#[error(source)]#[error(from)] AddressError),
     #[error(display = "{}", _0)]
     Email(#[error(source)]#[error(from)] EmailError),
-    #[error(display = "{}", _0)]
-    Smtp(#[error(source)]#[error(from)] BlockingError<SmtpError>),
+    #[error(display = "e-mail is not configured")]
+    EmailNotConfigured,
+    #[error(display = "e-mail queue is full")]
+    EmailQueueFull,
     #[error(display = "user registration not found")]
     UserRegistrationNotFound,
     #[error(display = "user registration expired")]
-    UserRegistrationExpired,
+    UserRegistrationExpired {
+        expires_at: DateTime<Utc>,
+    },
     #[error(display = "user registration wrong code")]
     UserRegistrationWrongCode,
     #[error(display = "user email updating not found")]
@@ -49,11 +89,50 @@ pub enum Error {
     UserEmailUpdatingExpired,
     #[error(display = "user registration wrong code")]
     UserEmailUpdatingWrongCode,
+    #[error(display = "e-mail resent too soon, retry after {} seconds", retry_after)]
+    ResendTooSoon {
+        retry_after: u64,
+    },
+    #[error(display = "password reset not found")]
+    PasswordResetNotFound,
+    #[error(display = "password reset expired")]
+    PasswordResetExpired,
+    #[error(display = "password reset wrong code")]
+    PasswordResetWrongCode,
     #[error(display = "permission denied, requires {} {} permission", action, subject)]
     PermissionDenied {
         subject: String,
         action: String,
     },
+    #[error(display = "permissions {:?} not found", ids)]
+    PermissionsNotFound {
+        ids: Vec<i32>,
+    },
+    #[error(display = "permission {}:{} not found", subject, action)]
+    PermissionNotFound {
+        subject: String,
+        action: String,
+    },
+    #[error(display = "seed document references unknown roles {:?} or permissions {:?}", roles, permissions)]
+    InvalidSeedReference {
+        roles: Vec<String>,
+        permissions: Vec<String>,
+    },
+}
+
+impl Error {
+    // The connection was lost under the query, e.g. to a restart of PostgreSQL, instead of the
+    // query itself failing. SQLSTATE classes 08 and 57P are connection and shutdown errors, and
+    // a socket error comes with an io::Error as the source. A connection closed meanwhile has
+    // neither, see Client::is_closed
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            Error::Db(e) => e.code().is_some_and(|code| {
+                code.code().starts_with("08") || code.code().starts_with("57P")
+            }) || e.source().is_some_and(|source| source.is::<std::io::Error>()),
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
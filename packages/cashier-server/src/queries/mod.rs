@@ -2,7 +2,22 @@ pub mod errors;
 pub mod users;
 pub mod tokens;
 pub mod email;
+pub mod roles;
+pub mod codes;
+pub mod totp;
+pub mod passwords;
+pub mod breached_passwords;
+pub mod audit;
+pub mod pool;
+#[cfg(test)]
+pub mod testing;
 
+use crate::config::StartConfig;
+use breached_passwords::BreachedPasswords;
+use codes::CodeStore;
+use errors::Result;
+use passwords::Passwords;
+use std::sync::Arc;
 use tokio_postgres::{
     Client,
 };
@@ -10,15 +25,31 @@ use tokio_postgres::{
 pub struct Query {
     pub user: users::Query,
     pub token: tokens::Query,
+    pub role: roles::Query,
+    pub audit: Arc<audit::Query>,
 }
 
 impl Query {
-    pub async fn new(client: &Client) -> Self {
-        let user = users::Query::new(client).await;
-        let token = tokens::Query::new(client).await;
-        Self {
+    // The breached password list is loaded once and shared by the queries of every connection
+    pub async fn new(
+        client: &Client, config: &StartConfig, codes: Box<dyn CodeStore>,
+        breached_passwords: Arc<BreachedPasswords>,
+    ) -> Result<Self> {
+        let audit = Arc::new(audit::Query::new(client).await?);
+        let user = users::Query::new(
+            client, config.deleted_user_reservation, config.registration_ttl,
+            config.email_updating_ttl, config.password_reset_ttl,
+            Passwords::new(config.password_algorithm, config.bcrypt_cost),
+            breached_passwords, audit.clone(),
+            codes,
+        ).await?;
+        let token = tokens::Query::new(client, config.access_token_ttl, audit.clone()).await?;
+        let role = roles::Query::new(client, audit.clone()).await?;
+        Ok(Self {
             user,
             token,
-        }
+            role,
+            audit,
+        })
     }
 }
\ No newline at end of file
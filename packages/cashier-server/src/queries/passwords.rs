@@ -0,0 +1,155 @@
+// Password hashing, see StartConfig::password_algorithm. New passwords are hashed with the
+// configured algorithm, while stored hashes are verified with the algorithm they were made with,
+// told apart by their prefix, so that both kinds coexist until users log in again.
+use crate::config::PasswordAlgorithm;
+use crate::constants::{ARGON2_MEMORY_COST, ARGON2_TIME_COST, ARGON2_SALT_LENGTH};
+use argon2::{Variant, Version};
+use bcrypt::BcryptError;
+use err_derive::Error;
+use rand::{Rng, thread_rng};
+
+#[derive(Debug, Error)]
+pub enum PasswordHashError {
+    #[error(display = "{}", _0)]
+    Bcrypt(#[error(source)]#[error(from)] BcryptError),
+    #[error(display = "{}", _0)]
+    Argon2(#[error(source)]#[error(from)] argon2::Error),
+}
+
+pub type Result<T> = std::result::Result<T, PasswordHashError>;
+
+// Called within web::block, as hashing takes a while by design
+pub trait PasswordHasher {
+    fn hash(&self, password: &str) -> Result<String>;
+    fn verify(&self, password: &str, hash: &str) -> Result<bool>;
+    // Whether hash is cheaper to crack than the ones made now, and should be made again
+    fn is_weaker(&self, hash: &str) -> bool;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BcryptHasher {
+    pub cost: u32,
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn hash(&self, password: &str) -> Result<String> {
+        Ok(bcrypt::hash(password, self.cost)?)
+    }
+    fn verify(&self, password: &str, hash: &str) -> Result<bool> {
+        Ok(bcrypt::verify(password, hash)?)
+    }
+    // $2b$<cost>$<salt and hash>
+    fn is_weaker(&self, hash: &str) -> bool {
+        hash.split('$').nth(2)
+            .and_then(|x| x.parse::<u32>().ok())
+            .is_some_and(|cost| cost < self.cost)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Argon2Hasher;
+
+impl Argon2Hasher {
+    fn config<'a>() -> argon2::Config<'a> {
+        argon2::Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: ARGON2_MEMORY_COST,
+            time_cost: ARGON2_TIME_COST,
+            ..argon2::Config::default()
+        }
+    }
+}
+
+impl PasswordHasher for Argon2Hasher {
+    fn hash(&self, password: &str) -> Result<String> {
+        let mut rng = thread_rng();
+        let salt = (0..ARGON2_SALT_LENGTH).map(|_| rng.gen()).collect::<Vec<u8>>();
+        Ok(argon2::hash_encoded(password.as_bytes(), &salt, &Self::config())?)
+    }
+    fn verify(&self, password: &str, hash: &str) -> Result<bool> {
+        Ok(argon2::verify_encoded(hash, password.as_bytes())?)
+    }
+    // $argon2id$v=19$m=<memory cost>,t=<time cost>,p=<lanes>$<salt>$<hash>
+    fn is_weaker(&self, hash: &str) -> bool {
+        let params = match hash.split('$').nth(3) {
+            Some(params) => params,
+            None => return false,
+        };
+        let param = |name: &str| params.split(',')
+            .filter(|x| x.starts_with(name))
+            .find_map(|x| x[name.len()..].parse::<u32>().ok());
+        param("m=").is_some_and(|x| x < ARGON2_MEMORY_COST)
+            || param("t=").is_some_and(|x| x < ARGON2_TIME_COST)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Passwords {
+    algorithm: PasswordAlgorithm,
+    bcrypt: BcryptHasher,
+    argon2: Argon2Hasher,
+}
+
+impl Passwords {
+    pub fn new(algorithm: PasswordAlgorithm, bcrypt_cost: u32) -> Self {
+        Self {
+            algorithm,
+            bcrypt: BcryptHasher { cost: bcrypt_cost },
+            argon2: Argon2Hasher,
+        }
+    }
+    fn algorithm_of(hash: &str) -> PasswordAlgorithm {
+        if hash.starts_with("$argon2id$") {
+            PasswordAlgorithm::Argon2id
+        } else {
+            PasswordAlgorithm::Bcrypt
+        }
+    }
+    fn hasher(&self, algorithm: PasswordAlgorithm) -> &dyn PasswordHasher {
+        match algorithm {
+            PasswordAlgorithm::Bcrypt => &self.bcrypt,
+            PasswordAlgorithm::Argon2id => &self.argon2,
+        }
+    }
+}
+
+// Hashes made with the other algorithm are weaker too, so that they are migrated
+impl PasswordHasher for Passwords {
+    fn hash(&self, password: &str) -> Result<String> {
+        self.hasher(self.algorithm).hash(password)
+    }
+    fn verify(&self, password: &str, hash: &str) -> Result<bool> {
+        self.hasher(Self::algorithm_of(hash)).verify(password, hash)
+    }
+    fn is_weaker(&self, hash: &str) -> bool {
+        let algorithm = Self::algorithm_of(hash);
+        algorithm != self.algorithm || self.hasher(algorithm).is_weaker(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_test() {
+        let bcrypt = Passwords::new(PasswordAlgorithm::Bcrypt, 4);
+        let argon2 = Passwords::new(PasswordAlgorithm::Argon2id, 4);
+        let bcrypt_hash = bcrypt.hash("password").unwrap();
+        let argon2_hash = argon2.hash("password").unwrap();
+        assert!(argon2_hash.starts_with("$argon2id$"));
+        for passwords in &[bcrypt, argon2] {
+            for hash in &[&bcrypt_hash, &argon2_hash] {
+                assert!(passwords.verify("password", hash).unwrap());
+                assert!(!passwords.verify("wrong-password", hash).unwrap());
+            }
+        }
+        assert!(!bcrypt.is_weaker(&bcrypt_hash));
+        assert!(bcrypt.is_weaker(&argon2_hash));
+        assert!(Passwords::new(PasswordAlgorithm::Bcrypt, 5).is_weaker(&bcrypt_hash));
+        assert!(!argon2.is_weaker(&argon2_hash));
+        assert!(argon2.is_weaker(&bcrypt_hash));
+        assert!(argon2.is_weaker("$argon2id$v=19$m=4096,t=1,p=1$c2FsdHNhbHQ$aGFzaA"));
+    }
+}
@@ -0,0 +1,230 @@
+// PostgreSQL connections of the API, see StartConfig::db_pool_size. Statements are prepared on a
+// connection and cannot be used on another, so every connection carries a Query of its own, which
+// is prepared again whenever a broken connection is replaced.
+use super::{
+    Query,
+    breached_passwords::BreachedPasswords,
+    codes::{CodeStore, new_code_store},
+    errors::Result,
+};
+use crate::{
+    config::StartConfig,
+    constants::{DB_CONNECT_ATTEMPTS, DB_CONNECT_RETRY_DELAY},
+};
+use actix_rt::time::delay_for;
+use log::{error, warn};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_postgres::{Client, NoTls};
+
+pub struct Connection {
+    pub client: Client,
+    pub query: Query,
+}
+
+// Reported by GET /metrics
+pub struct PoolStatus {
+    // StartConfig::db_pool_size, the most connections held at once
+    pub size: usize,
+    // Open and waiting to be handed out
+    pub idle: usize,
+}
+
+pub struct Pool {
+    config: StartConfig,
+    breached_passwords: Arc<BreachedPasswords>,
+    // Shared by all connections when set, instead of a store made for each of them
+    codes: Option<Arc<dyn CodeStore>>,
+    idle: Mutex<Vec<Connection>>,
+    permits: Semaphore,
+}
+
+impl Pool {
+    // Connects once right away, so that a wrong URL fails the start
+    pub async fn new(config: &StartConfig, codes: Option<Arc<dyn CodeStore>>) -> Result<Self> {
+        let pool = Self {
+            config: config.clone(),
+            breached_passwords: Arc::new(BreachedPasswords::new(&config.breached_passwords)?),
+            codes,
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(config.db_pool_size),
+        };
+        let connection = pool.connect().await?;
+        pool.idle.lock().unwrap().push(connection);
+        Ok(pool)
+    }
+    async fn connect(&self) -> Result<Connection> {
+        let (client, connection) = tokio_postgres::connect(&self.config.db, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("connection error: {}", e);
+            }
+        });
+        let codes: Box<dyn CodeStore> = match &self.codes {
+            Some(codes) => Box::new(codes.clone()),
+            None => new_code_store(&client, &self.config).await?,
+        };
+        let breached_passwords = self.breached_passwords.clone();
+        let query = Query::new(&client, &self.config, codes, breached_passwords).await?;
+        Ok(Connection { client, query })
+    }
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            size: self.config.db_pool_size,
+            idle: self.idle.lock().unwrap().len(),
+        }
+    }
+    // Waits for a free connection. Idle ones that were closed meanwhile, e.g. by a restart of
+    // PostgreSQL, are dropped, and a new connection is tried a few times before giving up
+    pub async fn get(&self) -> Result<PooledConnection<'_>> {
+        let permit = self.permits.acquire().await;
+        loop {
+            let connection = self.idle.lock().unwrap().pop();
+            match connection {
+                Some(connection) if connection.client.is_closed() =>
+                    warn!("dropped a closed database connection"),
+                Some(connection) => return Ok(PooledConnection {
+                    pool: self,
+                    connection: Some(connection),
+                    _permit: permit,
+                }),
+                None => break,
+            }
+        }
+        let mut attempts = 1;
+        let connection = loop {
+            match self.connect().await {
+                Ok(connection) => break connection,
+                Err(e) if attempts < DB_CONNECT_ATTEMPTS => {
+                    warn!("cannot connect to the database, retrying: {}", e);
+                    attempts += 1;
+                    delay_for(Duration::from_millis(DB_CONNECT_RETRY_DELAY)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(PooledConnection {
+            pool: self,
+            connection: Some(connection),
+            _permit: permit,
+        })
+    }
+}
+
+// Goes back to the pool on drop, unless it was closed meanwhile
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    connection: Option<Connection>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl PooledConnection<'_> {
+    // Drops a connection that was lost under a query, even if the client did not notice yet
+    pub fn discard(mut self) {
+        self.connection.take();
+    }
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if !connection.client.is_closed() {
+                self.pool.idle.lock().unwrap().push(connection);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::{errors::Error as QueryError, testing::TestDatabase}, Connection};
+    use actix_rt::time::delay_for;
+    use std::time::Duration;
+
+    async fn backend_pid(connection: &Connection) -> i32 {
+        connection.client
+            .query_one("SELECT pg_backend_pid()", &[])
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[actix_rt::test]
+    async fn pool_reconnect_test() {
+        let db = match TestDatabase::new(&["--db-pool-size", "2"]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let pool = db.pool().await;
+        let first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        let pids = [backend_pid(&first).await, backend_pid(&second).await];
+        assert_ne!(pids[0], pids[1]);
+        drop(first);
+        assert_eq!(pool.status().idle, 1);
+        // Handed back and reused
+        let first = pool.get().await.unwrap();
+        assert_eq!(backend_pid(&first).await, pids[0]);
+        drop(first);
+        drop(second);
+        assert_eq!(pool.status().size, 2);
+        assert_eq!(pool.status().idle, 2);
+        for pid in &pids {
+            db.client
+                .execute("SELECT pg_terminate_backend($1)", &[pid])
+                .await
+                .unwrap();
+        }
+        for _ in 0..100 {
+            if pool.idle.lock().unwrap().iter().all(|connection| connection.client.is_closed()) {
+                break;
+            }
+            delay_for(Duration::from_millis(20)).await;
+        }
+        // Replaced, with the statements prepared again
+        let connection = pool.get().await.unwrap();
+        assert!(!pids.contains(&backend_pid(&connection).await));
+        assert!(!connection.query.user
+            .check_username_existence(&connection.client, "nobody")
+            .await
+            .unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn pool_discard_test() {
+        let db = match TestDatabase::new(&["--db-pool-size", "1"]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let pool = db.pool().await;
+        let connection = pool.get().await.unwrap();
+        let pid = backend_pid(&connection).await;
+        // Lost under the query, maybe before the client notices
+        let e = connection.client
+            .execute("SELECT pg_terminate_backend(pg_backend_pid())", &[])
+            .await
+            .unwrap_err();
+        assert!(QueryError::from(e).is_connection_error());
+        connection.discard();
+        let connection = pool.get().await.unwrap();
+        assert_ne!(backend_pid(&connection).await, pid);
+    }
+}
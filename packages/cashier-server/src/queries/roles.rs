@@ -0,0 +1,837 @@
+use super::errors::{Error, Result};
+use super::audit::{self, Actor};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio_postgres::{
+    Client, Statement, types::Type,
+    IsolationLevel,
+};
+
+#[derive(Debug, Clone)]
+pub struct SeedPermission {
+    pub subject: String,
+    pub action: String,
+    pub display_name: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SeedRole {
+    pub name: String,
+    pub display_name: String,
+    pub description: String,
+    pub default: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SeedLink {
+    pub role: String,
+    pub subject: String,
+    pub action: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SeedDocument {
+    pub permissions: Vec<SeedPermission>,
+    pub roles: Vec<SeedRole>,
+    pub links: Vec<SeedLink>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SeededPermission {
+    pub id: i32,
+    pub subject: String,
+    pub action: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SeededRole {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SeededLink {
+    pub role: i32,
+    pub permission: i32,
+    pub role_name: String,
+    pub subject: String,
+    pub action: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SeedItems<T> {
+    pub created: Vec<T>,
+    pub existing: Vec<T>,
+}
+
+impl<T> Default for SeedItems<T> {
+    fn default() -> Self {
+        Self {
+            created: Vec::new(),
+            existing: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SeedResult {
+    pub permissions: SeedItems<SeededPermission>,
+    pub roles: SeedItems<SeededRole>,
+    pub links: SeedItems<SeededLink>,
+}
+
+impl SeedDocument {
+    // Links may only reference roles and permissions declared in the same document
+    pub fn unknown_references(&self) -> (Vec<String>, Vec<String>) {
+        let roles = self.roles.iter()
+            .map(|x| &x.name[..])
+            .collect::<HashSet<_>>();
+        let permissions = self.permissions.iter()
+            .map(|x| (&x.subject[..], &x.action[..]))
+            .collect::<HashSet<_>>();
+        let mut unknown_roles = self.links.iter()
+            .filter(|x| !roles.contains(&x.role[..]))
+            .map(|x| x.role.clone())
+            .collect::<Vec<_>>();
+        unknown_roles.sort();
+        unknown_roles.dedup();
+        let mut unknown_permissions = self.links.iter()
+            .filter(|x| !permissions.contains(&(&x.subject[..], &x.action[..])))
+            .map(|x| format!("{}:{}", x.subject, x.action))
+            .collect::<Vec<_>>();
+        unknown_permissions.sort();
+        unknown_permissions.dedup();
+        (unknown_roles, unknown_permissions)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleIdCreatedAt {
+    pub id: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkedPermission {
+    pub id: i32,
+    pub subject: String,
+    pub action: String,
+}
+
+// What a deleted role leaves behind, the users and permissions it was linked to
+#[derive(Debug, Clone)]
+pub struct DeletedRole {
+    pub deleted_at: DateTime<Utc>,
+    pub users: Vec<i32>,
+    pub permissions: Vec<i32>,
+}
+
+// Permissions are never renamed, so resolved pairs stay valid. Misses are not cached since the
+// permission may be seeded later
+#[derive(Default)]
+struct PermissionCache {
+    ids: HashMap<(String, String), i32>,
+    pairs: HashMap<i32, (String, String)>,
+}
+
+impl PermissionCache {
+    fn insert(&mut self, id: i32, subject: &str, action: &str) {
+        self.ids.insert((subject.into(), action.into()), id);
+        self.pairs.insert(id, (subject.into(), action.into()));
+    }
+}
+
+pub struct Query {
+    seed_permission: Statement,
+    find_permission_id: Statement,
+    find_permission_pair: Statement,
+    permission_cache: Mutex<PermissionCache>,
+    seed_role: Statement,
+    find_role_id: Statement,
+    seed_link: Statement,
+    create_role: Statement,
+    update_role: Statement,
+    soft_delete_role: Statement,
+    delete_role_users: Statement,
+    delete_role_permissions: Statement,
+    lock_role: Statement,
+    find_unknown_permissions: Statement,
+    add_permissions_to_role: Statement,
+    remove_permissions_from_role: Statement,
+    delete_role_parents: Statement,
+    find_role_ancestor: Statement,
+    add_parent_to_role: Statement,
+    remove_parent_from_role: Statement,
+    audit: Arc<audit::Query>,
+}
+
+impl Query {
+    pub async fn new(client: &Client, audit: Arc<audit::Query>) -> Result<Self> {
+        let seed_permission = client.prepare_typed(
+            "INSERT INTO permission (subject, action, display_name, description, \
+                                     created_at, updated_at, deleted) \
+                VALUES ($1, $2, $3, $4, NOW(), NOW(), FALSE) \
+                ON CONFLICT (subject, action) WHERE NOT deleted DO NOTHING \
+                RETURNING id",
+            &[Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT],
+        ).await?;
+        let find_permission_id = client.prepare_typed(
+            "SELECT id FROM permission \
+                WHERE subject = $1 AND action = $2 AND NOT deleted LIMIT 1",
+            &[Type::TEXT, Type::TEXT],
+        ).await?;
+        let find_permission_pair = client.prepare_typed(
+            "SELECT subject, action FROM permission WHERE id = $1 AND NOT deleted LIMIT 1",
+            &[Type::INT4],
+        ).await?;
+        let seed_role = client.prepare_typed(
+            "INSERT INTO role (name, display_name, description, \"default\", \
+                               created_at, updated_at, deleted) \
+                VALUES ($1, $2, $3, $4, NOW(), NOW(), FALSE) \
+                ON CONFLICT (name) WHERE NOT deleted DO NOTHING \
+                RETURNING id",
+            &[Type::TEXT, Type::TEXT, Type::TEXT, Type::BOOL],
+        ).await?;
+        let find_role_id = client.prepare_typed(
+            "SELECT id FROM role WHERE name = $1 AND NOT deleted LIMIT 1",
+            &[Type::TEXT],
+        ).await?;
+        let seed_link = client.prepare_typed(
+            "INSERT INTO role_permission (role, permission) VALUES ($1, $2) \
+                ON CONFLICT (role, permission) DO NOTHING \
+                RETURNING role",
+            &[Type::INT4, Type::INT4],
+        ).await?;
+        let create_role = client.prepare_typed(
+            "INSERT INTO role (name, display_name, description, \"default\", \
+                               created_at, updated_at, deleted) \
+                VALUES ($1, $2, $3, FALSE, NOW(), NOW(), FALSE) \
+                ON CONFLICT (name) WHERE NOT deleted DO NOTHING \
+                RETURNING id, created_at",
+            &[Type::TEXT, Type::TEXT, Type::TEXT],
+        ).await?;
+        // Fields that are NULL are left as they are
+        let update_role = client.prepare_typed(
+            "UPDATE role SET name = COALESCE($2, name), \
+                    display_name = COALESCE($3, display_name), \
+                    description = COALESCE($4, description), updated_at = NOW() \
+                WHERE id = $1 AND NOT deleted \
+                RETURNING updated_at",
+            &[Type::INT4, Type::TEXT, Type::TEXT, Type::TEXT],
+        ).await?;
+        let soft_delete_role = client.prepare_typed(
+            "UPDATE role SET deleted = TRUE, updated_at = NOW() \
+                WHERE id = $1 AND NOT deleted \
+                RETURNING updated_at",
+            &[Type::INT4],
+        ).await?;
+        let delete_role_users = client.prepare_typed(
+            "DELETE FROM user_role WHERE role = $1 RETURNING \"user\"",
+            &[Type::INT4],
+        ).await?;
+        let delete_role_permissions = client.prepare_typed(
+            "DELETE FROM role_permission WHERE role = $1 RETURNING permission",
+            &[Type::INT4],
+        ).await?;
+        let lock_role = client.prepare_typed(
+            "SELECT id FROM role WHERE id = $1 AND NOT deleted LIMIT 1 FOR UPDATE",
+            &[Type::INT4],
+        ).await?;
+        let find_unknown_permissions = client.prepare_typed(
+            "SELECT UNNEST($1) EXCEPT SELECT id FROM permission WHERE NOT deleted",
+            &[Type::INT4_ARRAY],
+        ).await?;
+        let add_permissions_to_role = client.prepare_typed(
+            "WITH added AS (\
+                    INSERT INTO role_permission (role, permission) SELECT $1, UNNEST($2) \
+                        ON CONFLICT (role, permission) DO NOTHING \
+                        RETURNING permission\
+                ) \
+                SELECT id, subject, action FROM added, permission WHERE id = added.permission",
+            &[Type::INT4, Type::INT4_ARRAY],
+        ).await?;
+        let remove_permissions_from_role = client.prepare_typed(
+            "WITH removed AS (\
+                    DELETE FROM role_permission WHERE role = $1 AND permission = ANY($2) \
+                        RETURNING permission\
+                ) \
+                SELECT id, subject, action FROM removed, permission WHERE id = removed.permission",
+            &[Type::INT4, Type::INT4_ARRAY],
+        ).await?;
+        let delete_role_parents = client.prepare_typed(
+            "DELETE FROM role_parent WHERE role = $1 OR parent = $1",
+            &[Type::INT4],
+        ).await?;
+        // Whether $2 is $1 itself or one of the roles $1 inherits from
+        let find_role_ancestor = client.prepare_typed(
+            "WITH RECURSIVE ancestor(id) AS (\
+                    SELECT $1::INTEGER \
+                    UNION \
+                    SELECT role_parent.parent FROM ancestor, role_parent \
+                        WHERE role_parent.role = ancestor.id\
+                ) \
+                SELECT id FROM ancestor WHERE id = $2 LIMIT 1",
+            &[Type::INT4, Type::INT4],
+        ).await?;
+        let add_parent_to_role = client.prepare_typed(
+            "INSERT INTO role_parent (role, parent) VALUES ($1, $2) \
+                ON CONFLICT (role, parent) DO NOTHING \
+                RETURNING role",
+            &[Type::INT4, Type::INT4],
+        ).await?;
+        let remove_parent_from_role = client.prepare_typed(
+            "DELETE FROM role_parent WHERE role = $1 AND parent = $2 RETURNING role",
+            &[Type::INT4, Type::INT4],
+        ).await?;
+        Ok(Self {
+            seed_permission,
+            find_permission_id,
+            find_permission_pair,
+            permission_cache: Mutex::new(PermissionCache::default()),
+            seed_role,
+            find_role_id,
+            seed_link,
+            create_role,
+            update_role,
+            soft_delete_role,
+            delete_role_users,
+            delete_role_permissions,
+            lock_role,
+            find_unknown_permissions,
+            add_permissions_to_role,
+            remove_permissions_from_role,
+            delete_role_parents,
+            find_role_ancestor,
+            add_parent_to_role,
+            remove_parent_from_role,
+            audit,
+        })
+    }
+    // Returns the permissions actually added, which the role did not have yet
+    pub async fn add_permissions_to_role(
+        &self, client: &mut Client, actor: &Actor, role_id: i32, permission_ids: &[i32],
+    ) -> Result<Vec<LinkedPermission>> {
+        self.update_permissions_of_role(client, actor, role_id, permission_ids,
+                                        &self.add_permissions_to_role,
+                                        "role-permission.add").await
+    }
+    // Returns the permissions actually removed
+    pub async fn remove_permissions_from_role(
+        &self, client: &mut Client, actor: &Actor, role_id: i32, permission_ids: &[i32],
+    ) -> Result<Vec<LinkedPermission>> {
+        self.update_permissions_of_role(client, actor, role_id, permission_ids,
+                                        &self.remove_permissions_from_role,
+                                        "role-permission.remove").await
+    }
+    // Either all the permissions are changed or, if any of them is unknown, none of them. Audited
+    // only if any permission is changed
+    async fn update_permissions_of_role(
+        &self, client: &mut Client, actor: &Actor, role_id: i32, permission_ids: &[i32],
+        statement: &Statement, action: &str,
+    ) -> Result<Vec<LinkedPermission>> {
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        if transaction.query(&self.lock_role, &[&role_id]).await?.is_empty() {
+            return Err(Error::RoleNotFound);
+        }
+        let mut unknown = transaction
+            .query(&self.find_unknown_permissions, &[&permission_ids])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect::<Vec<i32>>();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(Error::PermissionsNotFound { ids: unknown });
+        }
+        let permissions = transaction
+            .query(statement, &[&role_id, &permission_ids])
+            .await?
+            .iter()
+            .map(|row| LinkedPermission {
+                id: row.get("id"),
+                subject: row.get("subject"),
+                action: row.get("action"),
+            })
+            .collect::<Vec<_>>();
+        if !permissions.is_empty() {
+            let ids = permissions.iter().map(|x| x.id).collect::<Vec<_>>();
+            self.audit
+                .record(&transaction, actor, action, role_id,
+                        serde_json::json!({ "permissions": ids }))
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(permissions)
+    }
+    // The role inherits the permissions of the parent, and of all the ancestors of the parent.
+    // Both roles are locked, in the order of their ids, so that two concurrent additions cannot
+    // close a cycle between them. Returns whether the parent is newly added
+    pub async fn add_parent_to_role(
+        &self, client: &mut Client, actor: &Actor, role_id: i32, parent_id: i32,
+    ) -> Result<bool> {
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        for id in &[role_id.min(parent_id), role_id.max(parent_id)] {
+            if transaction.query(&self.lock_role, &[id]).await?.is_empty() {
+                return Err(Error::RoleNotFound);
+            }
+        }
+        if !transaction.query(&self.find_role_ancestor, &[&parent_id, &role_id]).await?.is_empty() {
+            return Err(Error::RoleCycle);
+        }
+        let added = !transaction
+            .query(&self.add_parent_to_role, &[&role_id, &parent_id])
+            .await?
+            .is_empty();
+        if added {
+            self.audit
+                .record(&transaction, actor, "role-parent.add", role_id,
+                        serde_json::json!({ "parent": parent_id }))
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(added)
+    }
+    // Returns whether the role did inherit from the parent
+    pub async fn remove_parent_from_role(
+        &self, client: &mut Client, actor: &Actor, role_id: i32, parent_id: i32,
+    ) -> Result<bool> {
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        if transaction.query(&self.lock_role, &[&role_id]).await?.is_empty() {
+            return Err(Error::RoleNotFound);
+        }
+        let removed = !transaction
+            .query(&self.remove_parent_from_role, &[&role_id, &parent_id])
+            .await?
+            .is_empty();
+        if removed {
+            self.audit
+                .record(&transaction, actor, "role-parent.remove", role_id,
+                        serde_json::json!({ "parent": parent_id }))
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(removed)
+    }
+    // A new role is never a default one and has no permissions yet
+    pub async fn create_role(
+        &self, client: &mut Client, actor: &Actor, name: &str, display_name: &str,
+        description: &str,
+    ) -> Result<RoleIdCreatedAt> {
+        let transaction = client.transaction().await?;
+        let rows = transaction
+            .query(&self.create_role, &[&name, &display_name, &description])
+            .await?;
+        let row = rows
+            .first()
+            .ok_or(Error::DuplicatedRole)?;
+        let role = RoleIdCreatedAt {
+            id: row.get("id"),
+            created_at: row.get("created_at"),
+        };
+        self.audit
+            .record(&transaction, actor, "role.create", role.id,
+                    serde_json::json!({ "name": name }))
+            .await?;
+        transaction.commit().await?;
+        Ok(role)
+    }
+    pub async fn update_role(
+        &self, client: &mut Client, actor: &Actor, id: i32, name: &Option<String>,
+        display_name: &Option<String>, description: &Option<String>,
+    ) -> Result<DateTime<Utc>> {
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        if let Some(name) = name {
+            let rows = transaction
+                .query(&self.find_role_id, &[name])
+                .await?;
+            if rows.first().map(|row| row.get::<_, i32>("id") != id) == Some(true) {
+                return Err(Error::DuplicatedRole);
+            }
+        }
+        let updated_at = transaction
+            .query(&self.update_role, &[&id, name, display_name, description])
+            .await?
+            .first()
+            .ok_or(Error::RoleNotFound)?
+            .get("updated_at");
+        self.audit
+            .record(&transaction, actor, "role.update", id, serde_json::json!({
+                "name": name,
+                "displayName": display_name,
+                "description": description,
+            }))
+            .await?;
+        transaction.commit().await?;
+        Ok(updated_at)
+    }
+    // The links of the role to users, permissions and other roles go along with it
+    pub async fn soft_delete_role(
+        &self, client: &mut Client, actor: &Actor, id: i32,
+    ) -> Result<DeletedRole> {
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        let deleted_at = transaction
+            .query(&self.soft_delete_role, &[&id])
+            .await?
+            .first()
+            .ok_or(Error::RoleNotFound)?
+            .get("updated_at");
+        let users = transaction
+            .query(&self.delete_role_users, &[&id])
+            .await?
+            .iter()
+            .map(|row| row.get("user"))
+            .collect();
+        let permissions = transaction
+            .query(&self.delete_role_permissions, &[&id])
+            .await?
+            .iter()
+            .map(|row| row.get("permission"))
+            .collect();
+        transaction
+            .execute(&self.delete_role_parents, &[&id])
+            .await?;
+        self.audit
+            .record(&transaction, actor, "role.delete", id, serde_json::Value::Null)
+            .await?;
+        transaction.commit().await?;
+        Ok(DeletedRole {
+            deleted_at,
+            users,
+            permissions,
+        })
+    }
+    pub async fn find_permission_id(
+        &self, client: &Client, subject: &str, action: &str,
+    ) -> Result<Option<i32>> {
+        if let Some(id) = self.permission_cache.lock().unwrap()
+            .ids.get(&(subject.into(), action.into())) {
+            return Ok(Some(*id));
+        }
+        let id = client
+            .query(&self.find_permission_id, &[&subject, &action])
+            .await?
+            .first()
+            .map(|row| row.get("id"));
+        if let Some(id) = id {
+            self.permission_cache.lock().unwrap().insert(id, subject, action);
+        }
+        Ok(id)
+    }
+    // Returns the subject and action
+    pub async fn find_permission_pair(
+        &self, client: &Client, id: i32,
+    ) -> Result<Option<(String, String)>> {
+        if let Some(pair) = self.permission_cache.lock().unwrap().pairs.get(&id) {
+            return Ok(Some(pair.clone()));
+        }
+        let pair = client
+            .query(&self.find_permission_pair, &[&id])
+            .await?
+            .first()
+            .map(|row| (row.get::<_, String>("subject"), row.get::<_, String>("action")));
+        if let Some((subject, action)) = &pair {
+            self.permission_cache.lock().unwrap().insert(id, subject, action);
+        }
+        Ok(pair)
+    }
+    // For endpoints taking a permission by its subject and action instead of its id
+    pub async fn resolve_permission(
+        &self, client: &Client, subject: &str, action: &str,
+    ) -> Result<i32> {
+        self.find_permission_id(client, subject, action)
+            .await?
+            .ok_or_else(|| Error::PermissionNotFound {
+                subject: subject.into(),
+                action: action.into(),
+            })
+    }
+    pub async fn seed(
+        &self, client: &mut Client, document: &SeedDocument,
+    ) -> Result<SeedResult> {
+        let (roles, permissions) = document.unknown_references();
+        if !roles.is_empty() || !permissions.is_empty() {
+            return Err(Error::InvalidSeedReference { roles, permissions });
+        }
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        let mut result = SeedResult::default();
+        for permission in document.permissions.iter() {
+            let rows = transaction
+                .query(&self.seed_permission, &[&permission.subject, &permission.action,
+                    &permission.display_name, &permission.description])
+                .await?;
+            let (id, created) = match rows.first() {
+                Some(row) => (row.get("id"), true),
+                None => (transaction
+                    .query_one(&self.find_permission_id, &[&permission.subject, &permission.action])
+                    .await?
+                    .get("id"), false),
+            };
+            let item = SeededPermission {
+                id,
+                subject: permission.subject.clone(),
+                action: permission.action.clone(),
+            };
+            if created { result.permissions.created.push(item) }
+            else { result.permissions.existing.push(item) }
+        }
+        for role in document.roles.iter() {
+            let rows = transaction
+                .query(&self.seed_role, &[&role.name, &role.display_name,
+                    &role.description, &role.default])
+                .await?;
+            let (id, created) = match rows.first() {
+                Some(row) => (row.get("id"), true),
+                None => (transaction
+                    .query_one(&self.find_role_id, &[&role.name])
+                    .await?
+                    .get("id"), false),
+            };
+            let item = SeededRole {
+                id,
+                name: role.name.clone(),
+            };
+            if created { result.roles.created.push(item) }
+            else { result.roles.existing.push(item) }
+        }
+        for link in document.links.iter() {
+            let role = result.roles.created.iter()
+                .chain(result.roles.existing.iter())
+                .find(|x| x.name == link.role)
+                .map(|x| x.id)
+                .unwrap();
+            let permission = result.permissions.created.iter()
+                .chain(result.permissions.existing.iter())
+                .find(|x| x.subject == link.subject && x.action == link.action)
+                .map(|x| x.id)
+                .unwrap();
+            let rows = transaction
+                .query(&self.seed_link, &[&role, &permission])
+                .await?;
+            let item = SeededLink {
+                role,
+                permission,
+                role_name: link.role.clone(),
+                subject: link.subject.clone(),
+                action: link.action.clone(),
+            };
+            if rows.is_empty() { result.links.existing.push(item) }
+            else { result.links.created.push(item) }
+        }
+        transaction.commit().await?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::testing::TestDatabase;
+
+    const ACTOR: Actor = Actor { uid: 0, impersonator: None };
+
+    #[actix_rt::test]
+    async fn seed_again_test() {
+        let mut db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let query = db.query().await.role;
+        let client = &mut db.client;
+        // post:read is new while user:read is predefined
+        let document = SeedDocument {
+            permissions: vec![("post", "read"), ("user", "read")].into_iter()
+                .map(|(subject, action)| SeedPermission {
+                    subject: subject.into(),
+                    action: action.into(),
+                    display_name: "Name".into(),
+                    description: "Description".into(),
+                })
+                .collect(),
+            roles: vec![SeedRole {
+                name: "editor".into(),
+                display_name: "Editor".into(),
+                description: "Description".into(),
+                default: false,
+            }],
+            links: vec![("post", "read"), ("user", "read")].into_iter()
+                .map(|(subject, action)| SeedLink {
+                    role: "editor".into(),
+                    subject: subject.into(),
+                    action: action.into(),
+                })
+                .collect(),
+        };
+        let first = query.seed(client, &document).await.unwrap();
+        assert_eq!(first.permissions.created.iter().map(|x| &x.subject[..]).collect::<Vec<_>>(),
+                   vec!["post"]);
+        assert_eq!(first.permissions.existing.iter().map(|x| &x.subject[..]).collect::<Vec<_>>(),
+                   vec!["user"]);
+        assert_eq!(first.roles.created.len(), 1);
+        assert_eq!(first.links.created.len(), 2);
+        // Seeding again finds everything in place and changes nothing
+        let second = query.seed(client, &document).await.unwrap();
+        assert!(second.permissions.created.is_empty());
+        assert!(second.roles.created.is_empty());
+        assert!(second.links.created.is_empty());
+        let first_ids = first.permissions.created.iter()
+            .chain(first.permissions.existing.iter())
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        assert_eq!(second.permissions.existing.iter().map(|x| x.id).collect::<Vec<_>>(), first_ids);
+        assert_eq!(second.roles.existing[0].id, first.roles.created[0].id);
+        assert_eq!(second.links.existing.len(), 2);
+        let links: i64 = client
+            .query_one("SELECT COUNT(*) FROM role_permission WHERE role = $1",
+                       &[&first.roles.created[0].id])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(links, 2);
+    }
+    #[actix_rt::test]
+    async fn find_permission_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let query = db.query().await.role;
+        let client = &db.client;
+        let id = query.find_permission_id(client, "user", "read").await.unwrap().unwrap();
+        // Served from the cache the second time
+        assert_eq!(query.find_permission_id(client, "user", "read").await.unwrap(), Some(id));
+        assert_eq!(query.find_permission_pair(client, id).await.unwrap(),
+                   Some(("user".into(), "read".into())));
+        assert_eq!(query.resolve_permission(client, "user", "read").await.unwrap(), id);
+        assert_eq!(query.find_permission_id(client, "user", "fly").await.unwrap(), None);
+        match query.resolve_permission(client, "user", "fly").await {
+            Err(Error::PermissionNotFound { subject, action }) =>
+                assert_eq!((&subject[..], &action[..]), ("user", "fly")),
+            _ => panic!("expected PermissionNotFound"),
+        }
+        assert_eq!(query.find_permission_pair(client, -1).await.unwrap(), None);
+    }
+    #[actix_rt::test]
+    async fn role_crud_test() {
+        let mut db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let query = db.query().await.role;
+        let client = &mut db.client;
+        let id = query.create_role(client, &ACTOR, "editor", "Editor", "Edits posts")
+            .await
+            .unwrap()
+            .id;
+        match query.create_role(client, &ACTOR, "editor", "Editor", "Edits posts").await {
+            Err(Error::DuplicatedRole) => (),
+            e => panic!("unexpected result {:?}", e),
+        }
+        match query.update_role(client, &ACTOR, id, &Some("user-admin".into()), &None, &None)
+            .await {
+            Err(Error::DuplicatedRole) => (),
+            e => panic!("unexpected result {:?}", e),
+        }
+        // Keeping its own name is fine
+        query.update_role(client, &ACTOR, id, &Some("editor".into()), &None, &Some("Edits".into()))
+            .await
+            .unwrap();
+        let row = client
+            .query_one("SELECT display_name, description FROM role WHERE id = $1", &[&id])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>("display_name"), "Editor");
+        assert_eq!(row.get::<_, String>("description"), "Edits");
+        let permission = query.resolve_permission(client, "user", "read").await.unwrap();
+        client
+            .execute("INSERT INTO role_permission (role, permission) VALUES ($1, $2)",
+                     &[&id, &permission])
+            .await
+            .unwrap();
+        let deleted = query.soft_delete_role(client, &ACTOR, id).await.unwrap();
+        assert!(deleted.users.is_empty());
+        assert_eq!(deleted.permissions, vec![permission]);
+        match query.soft_delete_role(client, &ACTOR, id).await {
+            Err(Error::RoleNotFound) => (),
+            e => panic!("unexpected result {:?}", e.map(|x| x.permissions)),
+        }
+        // The name is free again
+        query.create_role(client, &ACTOR, "editor", "Editor", "Edits posts").await.unwrap();
+    }
+    #[actix_rt::test]
+    async fn update_permissions_of_role_test() {
+        let mut db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let query = db.query().await.role;
+        let client = &mut db.client;
+        let role = query.create_role(client, &ACTOR, "editor", "Editor", "Edits posts")
+            .await
+            .unwrap()
+            .id;
+        let read = query.resolve_permission(client, "user", "read").await.unwrap();
+        let list = query.resolve_permission(client, "user", "list").await.unwrap();
+        match query.add_permissions_to_role(client, &ACTOR, role, &[read, -2, -1]).await {
+            Err(Error::PermissionsNotFound { ids }) => assert_eq!(ids, vec![-2, -1]),
+            e => panic!("unexpected result {:?}", e),
+        }
+        // Nothing is added when any of them is unknown
+        let added = query.add_permissions_to_role(client, &ACTOR, role, &[read, list])
+            .await
+            .unwrap();
+        let mut added = added.iter().map(|x| x.id).collect::<Vec<_>>();
+        added.sort();
+        let mut expected = vec![read, list];
+        expected.sort();
+        assert_eq!(added, expected);
+        assert!(query.add_permissions_to_role(client, &ACTOR, role, &[read])
+            .await
+            .unwrap()
+            .is_empty());
+        let removed = query.remove_permissions_from_role(client, &ACTOR, role, &[read])
+            .await
+            .unwrap();
+        assert_eq!(removed.iter().map(|x| (x.id, &x.action[..])).collect::<Vec<_>>(),
+                   vec![(read, "read")]);
+        match query.remove_permissions_from_role(client, &ACTOR, role + 1000, &[list]).await {
+            Err(Error::RoleNotFound) => (),
+            e => panic!("unexpected result {:?}", e),
+        }
+        // Neither the rejected nor the idle change is audited
+        let actions = client
+            .query("SELECT action FROM audit_log WHERE target = $1 ORDER BY id", &[&role])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<_, String>("action"))
+            .collect::<Vec<_>>();
+        assert_eq!(actions, vec!["role.create", "role-permission.add", "role-permission.remove"]);
+    }
+}
@@ -0,0 +1,124 @@
+// Tests needing PostgreSQL connect to the database at CASHIER_TEST_DB, e.g.
+// postgresql://postgres@127.0.0.1/cashier_test, and are skipped when it is unset. Each test works
+// in a schema of its own, which is dropped afterwards, so tests can run concurrently.
+use super::{
+    Query,
+    breached_passwords::BreachedPasswords,
+    codes::new_code_store,
+    pool::Pool,
+};
+use crate::{
+    config::{Config, InitConfig, StartConfig},
+    services::init,
+};
+use log::error;
+use rand::{Rng, thread_rng, distributions::Alphanumeric};
+use std::sync::Arc;
+use tokio_postgres::{Client, NoTls};
+
+pub struct TestDatabase {
+    pub client: Client,
+    pub config: StartConfig,
+    pub init_config: InitConfig,
+    url: String,
+    schema: String,
+}
+
+async fn connect(url: &str) -> Client {
+    let (client, connection) = tokio_postgres::connect(url, NoTls).await.unwrap();
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("connection error: {}", e);
+        }
+    });
+    client
+}
+
+impl TestDatabase {
+    // Creates all tables in a new schema. Extra arguments go before the subcommand
+    pub async fn new(args: &[&str]) -> Option<Self> {
+        let url = std::env::var("CASHIER_TEST_DB").ok()?;
+        let schema = format!("test_{}", thread_rng().sample_iter(&Alphanumeric)
+            .take(16)
+            .collect::<String>()
+            .to_lowercase());
+        connect(&url).await
+            .execute(format!("CREATE SCHEMA {}", schema).as_str(), &[])
+            .await
+            .unwrap();
+        let db = format!("{}{}options=-csearch_path%3D{}&application_name={}", url,
+                         if url.contains('?') { '&' } else { '?' }, schema, schema);
+        let redis = std::env::var("CASHIER_TEST_REDIS")
+            .unwrap_or_else(|_| "redis://127.0.0.1".into());
+        let config = |subcommand: &str| Config::from(
+            ["cashier-server", "--db", &db, "--redis", &redis, "--bind", "127.0.0.1:0",
+                "--site", "http://localhost", "--media-root", "media", "--media-url", "/media",
+                "--smtp-server", "localhost", "--smtp-sender", "Cashier <cashier@localhost>"].iter()
+                .chain(args)
+                .chain(&[subcommand])
+                .cloned()
+        ).unwrap();
+        let init_config = match config("init") {
+            Config::Init(config) => config,
+            _ => unreachable!(),
+        };
+        init::init(&init_config).await.unwrap();
+        let config = match config("start") {
+            Config::Start(config) => config,
+            _ => unreachable!(),
+        };
+        Some(Self {
+            client: connect(&db).await,
+            config,
+            init_config,
+            url,
+            schema,
+        })
+    }
+    // Another client of this database
+    pub async fn connect(&self) -> Client {
+        connect(&self.config.db).await
+    }
+    // Statements prepared on the client of this database
+    pub async fn query(&self) -> Query {
+        self.prepare(&self.client).await
+    }
+    // Another client of this database with statements of its own, which cannot be used on any
+    // other client
+    pub async fn connect_with_query(&self) -> (Client, Query) {
+        let client = self.connect().await;
+        let query = self.prepare(&client).await;
+        (client, query)
+    }
+    async fn prepare(&self, client: &Client) -> Query {
+        let codes = new_code_store(client, &self.config).await.unwrap();
+        let breached_passwords = BreachedPasswords::new(&self.config.breached_passwords).unwrap();
+        Query::new(client, &self.config, codes, Arc::new(breached_passwords)).await.unwrap()
+    }
+    // Connections of their own, the way the API gets them
+    pub async fn pool(&self) -> Pool {
+        Pool::new(&self.config, None).await.unwrap()
+    }
+}
+
+impl Drop for TestDatabase {
+    // The runtime of the test is blocked here, so the schema is dropped from another one. The
+    // connections of the test cannot finish their transactions then and are terminated first
+    fn drop(&mut self) {
+        let (url, schema) = (self.url.clone(), self.schema.clone());
+        let _ = std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let client = connect(&url).await;
+                client
+                    .execute("SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+                        WHERE application_name = $1", &[&schema])
+                    .await
+                    .unwrap();
+                client
+                    .execute(format!("DROP SCHEMA {} CASCADE", schema).as_str(), &[])
+                    .await
+                    .unwrap();
+            });
+        }).join();
+    }
+}
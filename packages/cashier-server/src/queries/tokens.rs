@@ -1,6 +1,10 @@
 use super::errors::{Error, Result};
+use super::audit::{self, Actor};
 use chrono::{DateTime, Utc};
+use rand::{Rng, thread_rng, distributions::Alphanumeric};
 use serde::{Serialize, Deserialize};
+use std::iter;
+use std::sync::Arc;
 use tokio_postgres::{
     Client, Statement,
     types::Type
@@ -12,12 +16,21 @@ use jsonwebtoken::{
 
 pub struct Query {
     create_token: Statement,
+    create_impersonation_token: Statement,
     get_secret: Statement,
     check_token_revoked: Statement,
     revoke_token: Statement,
     revoke_token_with_uid: Statement,
     find_tokens_from_user: Statement,
+    list_active_tokens: Statement,
     revoke_tokens_from_user: Statement,
+    revoke_tokens_from_users: Statement,
+    create_refresh_token: Statement,
+    find_refresh_token: Statement,
+    rotate_refresh_token: Statement,
+    revoke_refresh_token_chain: Statement,
+    revoke_tokens_of_chain: Statement,
+    audit: Arc<audit::Query>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,6 +39,9 @@ pub struct JwtClaims {
     pub iat: i64,
     pub exp: i64,
     pub jti: i32,
+    // The uid of the admin acting as the user, see Query::create_impersonation_token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -46,55 +62,152 @@ pub struct TokenIdUser {
     pub user: i32,
 }
 
+// A session of a user, as shown to the user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenInfo {
+    pub jti: i32,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub acquire_method: String,
+    pub acquire_host: String,
+    pub acquire_remote: Option<String>,
+    pub acquire_user_agent: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RotatedToken {
+    pub jwt: String,
+    pub claims: JwtClaims,
+    pub refresh_token: String,
+    // The access token issued along with the rotated refresh token
+    pub superseded: TokenIdUser,
+}
+
+fn generate_refresh_token() -> String {
+    let mut rng = thread_rng();
+    iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .take(crate::constants::REFRESH_TOKEN_LENGTH)
+        .collect()
+}
+
 impl Query {
-    pub async fn new(client: &Client) -> Self {
+    // The exp claim of new tokens is access_token_ttl seconds after issuing, while issued tokens
+    // keep the one they were signed with
+    pub async fn new(
+        client: &Client, access_token_ttl: u64, audit: Arc<audit::Query>,
+    ) -> Result<Self> {
         let create_token = client.prepare_typed(
             &format!("INSERT INTO token (\"user\", issued_at, expires_at, acquire_method, \
                                          acquire_host, acquire_remote, acquire_user_agent, revoked) \
-                VALUES ($1, NOW(), NOW() + INTERVAL '{}', $2, $3, $4, $5, false) \
-                RETURNING id, issued_at, expires_at", crate::constants::JWT_EXPIRE),
+                VALUES ($1, NOW(), NOW() + INTERVAL '{} seconds', $2, $3, $4, $5, false) \
+                RETURNING id, issued_at, expires_at", access_token_ttl),
             &[Type::INT4, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT],
-        ).await.unwrap();
+        ).await?;
+        let create_impersonation_token = client.prepare_typed(
+            &format!("INSERT INTO token (\"user\", issued_at, expires_at, acquire_method, \
+                                         acquire_host, acquire_remote, acquire_user_agent, revoked) \
+                VALUES ($1, NOW(), NOW() + INTERVAL '{} seconds', 'impersonation', $2, $3, $4, \
+                        false) \
+                RETURNING id, issued_at, expires_at",
+                     crate::constants::IMPERSONATION_TOKEN_TTL),
+            &[Type::INT4, Type::TEXT, Type::TEXT, Type::TEXT],
+        ).await?;
         let get_secret = client.prepare(
             "SELECT jwt_secret FROM global_settings LIMIT 1",
-        ).await.unwrap();
+        ).await?;
         let check_token_revoked = client.prepare_typed(
             "SELECT 0 FROM token WHERE id = $1 AND NOT revoked LIMIT 1",
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
         let revoke_token = client.prepare_typed(
             "UPDATE token SET revoked = true \
                 WHERE id = $1 AND NOT revoked \
                 RETURNING id, \"user\"",
             &[Type::INT4]
-        ).await.unwrap();
+        ).await?;
         let revoke_token_with_uid = client.prepare_typed(
             "UPDATE token SET revoked = true \
                 WHERE id = $1 AND \"user\" = $2 AND NOT revoked \
                 RETURNING id, \"user\"",
             &[Type::INT4, Type::INT4]
-        ).await.unwrap();
+        ).await?;
         let find_tokens_from_user = client.prepare_typed(
             "SELECT id, \"user\", issued_at, expires_at, acquire_method, \
                     acquire_host, acquire_remote, acquire_user_agent FROM token \
                  WHERE \"user\" = $1 AND expires_at > NOW() AND NOT revoked",
             &[Type::INT4]
-        ).await.unwrap();
+        ).await?;
+        let list_active_tokens = client.prepare_typed(
+            "SELECT id, issued_at, expires_at, acquire_method, \
+                    acquire_host, acquire_remote, acquire_user_agent FROM token \
+                WHERE \"user\" = $1 AND expires_at > NOW() AND NOT revoked \
+                ORDER BY issued_at DESC, id DESC",
+            &[Type::INT4]
+        ).await?;
         let revoke_tokens_from_user = client.prepare_typed(
             "UPDATE token SET revoked = true \
                 WHERE \"user\" = $1 AND NOT revoked \
                 RETURNING id, \"user\"",
             &[Type::INT4]
-        ).await.unwrap();
-        Self {
+        ).await?;
+        let revoke_tokens_from_users = client.prepare_typed(
+            "UPDATE token SET revoked = true \
+                WHERE \"user\" = ANY($1) AND NOT revoked \
+                RETURNING id, \"user\"",
+            &[Type::INT4_ARRAY]
+        ).await?;
+        let create_refresh_token = client.prepare_typed(
+            &format!("INSERT INTO refresh_token (hash, token, \"user\", chain, issued_at, \
+                                                 expires_at, rotated, revoked) \
+                VALUES (sha256(convert_to($1, 'UTF8')), $2, $3, $4, NOW(), \
+                        NOW() + INTERVAL '{}', false, false)", crate::constants::REFRESH_TOKEN_EXPIRE),
+            &[Type::TEXT, Type::INT4, Type::INT4, Type::INT4],
+        ).await?;
+        // A refresh token dies with its access token, so revoking the latter revokes both
+        let find_refresh_token = client.prepare_typed(
+            "SELECT refresh_token.id, refresh_token.token, refresh_token.user, chain, \
+                    refresh_token.expires_at, rotated, refresh_token.revoked, \
+                    token.revoked AS token_revoked, \"user\".blocked \
+                FROM refresh_token \
+                JOIN token ON token.id = refresh_token.token \
+                JOIN \"user\" ON \"user\".id = refresh_token.user AND NOT \"user\".deleted \
+                WHERE hash = sha256(convert_to($1, 'UTF8')) \
+                FOR UPDATE OF refresh_token",
+            &[Type::TEXT],
+        ).await?;
+        let rotate_refresh_token = client.prepare_typed(
+            "UPDATE refresh_token SET rotated = true WHERE id = $1",
+            &[Type::INT4],
+        ).await?;
+        let revoke_refresh_token_chain = client.prepare_typed(
+            "UPDATE refresh_token SET revoked = true WHERE chain = $1",
+            &[Type::INT4],
+        ).await?;
+        let revoke_tokens_of_chain = client.prepare_typed(
+            "UPDATE token SET revoked = true \
+                WHERE id IN (SELECT token FROM refresh_token WHERE chain = $1) AND NOT revoked \
+                RETURNING id, \"user\"",
+            &[Type::INT4],
+        ).await?;
+        Ok(Self {
             create_token,
+            create_impersonation_token,
             get_secret,
             check_token_revoked,
             revoke_token,
             revoke_token_with_uid,
             find_tokens_from_user,
+            list_active_tokens,
             revoke_tokens_from_user,
-        }
+            revoke_tokens_from_users,
+            create_refresh_token,
+            find_refresh_token,
+            rotate_refresh_token,
+            revoke_refresh_token_chain,
+            revoke_tokens_of_chain,
+            audit,
+        })
     }
     pub async fn create_token(
         &self, client: &Client, user: i32, method: &str,
@@ -111,18 +224,51 @@ impl Query {
             iat: issued_at.timestamp(),
             exp: expires_at.timestamp(),
             jti: id,
+            impersonator: None,
         };
         let secret = self.get_secret(client).await?;
         let jwt = encode(&Header::default(), &claims, &EncodingKey::from_secret(&secret))?;
         Ok((jwt, claims))
     }
+    // Short-lived and without a refresh token. The impersonator is recorded in the claims and in
+    // the audit log
+    pub async fn create_impersonation_token(
+        &self, client: &mut Client, user: i32, impersonator: i32,
+        host: &str, remote: Option<&str>, user_agent: Option<&str>,
+    ) -> Result<(String, JwtClaims)> {
+        let secret = self.get_secret(client).await?;
+        let transaction = client.transaction().await?;
+        let row = transaction
+            .query_one(&self.create_impersonation_token, &[&user, &host, &remote, &user_agent])
+            .await?;
+        let issued_at: DateTime<Utc> = row.get("issued_at");
+        let expires_at: DateTime<Utc> = row.get("expires_at");
+        let claims = JwtClaims {
+            uid: user,
+            iat: issued_at.timestamp(),
+            exp: expires_at.timestamp(),
+            jti: row.get("id"),
+            impersonator: Some(impersonator),
+        };
+        let actor = Actor {
+            uid: impersonator,
+            impersonator: None,
+        };
+        self.audit
+            .record(&transaction, &actor, "user.impersonate", user,
+                    serde_json::json!({ "token": claims.jti }))
+            .await?;
+        transaction.commit().await?;
+        let jwt = encode(&Header::default(), &claims, &EncodingKey::from_secret(&secret))?;
+        Ok((jwt, claims))
+    }
     pub async fn get_secret(&self, client: &Client) -> Result<Vec<u8>> {
         let row = client.query_one(&self.get_secret, &[]).await?;
         Ok(row.get("jwt_secret"))
     }
     pub async fn verify_token(&self, client: &Client, token: &str) -> Result<JwtClaims> {
         let secret = self.get_secret(client).await?;
-        let claims = decode::<JwtClaims>(&token, &DecodingKey::from_secret(&secret), &Validation::default())
+        let claims = decode::<JwtClaims>(token, &DecodingKey::from_secret(&secret), &Validation::default())
             .map_err(|err| Error::InvalidToken { error: format!("{:?}", err.into_kind()) })?
             .claims;
         Ok(claims)
@@ -146,7 +292,7 @@ impl Query {
                 .await?,
         };
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::TokenNotFound)?;
         Ok(TokenIdUser {
             id: row.get("id"),
@@ -171,6 +317,24 @@ impl Query {
             .collect();
         Ok(results)
     }
+    // Newest first
+    pub async fn list_active_tokens(&self, client: &Client, uid: i32) -> Result<Vec<TokenInfo>> {
+        let rows = client
+            .query(&self.list_active_tokens, &[&uid])
+            .await?;
+        let results = rows.iter()
+            .map(|row| TokenInfo {
+                jti: row.get("id"),
+                issued_at: row.get("issued_at"),
+                expires_at: row.get("expires_at"),
+                acquire_method: row.get("acquire_method"),
+                acquire_host: row.get("acquire_host"),
+                acquire_remote: row.get("acquire_remote"),
+                acquire_user_agent: row.get("acquire_user_agent"),
+            })
+            .collect();
+        Ok(results)
+    }
     pub async fn revoke_tokens_from_user(&self, client: &Client, user: i32) -> Result<Vec<TokenIdUser>> {
         let rows = client
             .query(&self.revoke_tokens_from_user, &[&user])
@@ -183,4 +347,116 @@ impl Query {
             .collect();
         Ok(results)
     }
+    pub async fn revoke_tokens_from_users(
+        &self, client: &Client, users: &[i32],
+    ) -> Result<Vec<TokenIdUser>> {
+        let rows = client
+            .query(&self.revoke_tokens_from_users, &[&users])
+            .await?;
+        let results = rows.iter()
+            .map(|row| TokenIdUser {
+                id: row.get("id"),
+                user: row.get("user"),
+            })
+            .collect();
+        Ok(results)
+    }
+    // Starts a new chain, named after the access token
+    pub async fn create_refresh_token(
+        &self, client: &Client, token: i32, user: i32,
+    ) -> Result<String> {
+        let refresh_token = generate_refresh_token();
+        client
+            .execute(&self.create_refresh_token, &[&refresh_token, &token, &user, &token])
+            .await?;
+        Ok(refresh_token)
+    }
+    // Trades a refresh token for a new pair of tokens, revoking the old access token. The row
+    // is locked so that only one of concurrent rotations wins, and presenting an already rotated
+    // token revokes its whole chain, as it has been stolen either by the caller or by whoever
+    // rotated it before
+    pub async fn rotate_refresh_token(
+        &self, client: &mut Client, refresh_token: &str, host: &str,
+        remote: Option<&str>, user_agent: Option<&str>,
+    ) -> Result<RotatedToken> {
+        let secret = self.get_secret(client).await?;
+        let transaction = client.transaction().await?;
+        let rows = transaction
+            .query(&self.find_refresh_token, &[&refresh_token])
+            .await?;
+        let row = rows
+            .first()
+            .ok_or_else(|| Error::RefreshTokenNotFound)?;
+        let id: i32 = row.get("id");
+        let token: i32 = row.get("token");
+        let user: i32 = row.get("user");
+        let chain: i32 = row.get("chain");
+        let expires_at: DateTime<Utc> = row.get("expires_at");
+        let revoked: bool = row.get("revoked");
+        if row.get("rotated") {
+            if revoked {
+                return Err(Error::RefreshTokenNotFound);
+            }
+            transaction
+                .execute(&self.revoke_refresh_token_chain, &[&chain])
+                .await?;
+            let revoked = transaction
+                .query(&self.revoke_tokens_of_chain, &[&chain])
+                .await?
+                .iter()
+                .map(|row| TokenIdUser {
+                    id: row.get("id"),
+                    user: row.get("user"),
+                })
+                .collect();
+            transaction.commit().await?;
+            return Err(Error::RefreshTokenReused { revoked });
+        }
+        if revoked || row.get("token_revoked") {
+            return Err(Error::RefreshTokenNotFound);
+        }
+        if expires_at < Utc::now() {
+            return Err(Error::RefreshTokenExpired);
+        }
+        if row.get::<_, Option<bool>>("blocked") == Some(true) {
+            return Err(Error::UserBlocked);
+        }
+        transaction
+            .execute(&self.rotate_refresh_token, &[&id])
+            .await?;
+        let superseded = transaction
+            .query(&self.revoke_token, &[&token])
+            .await?;
+        let superseded = superseded
+            .first()
+            .ok_or_else(|| Error::RefreshTokenNotFound)?;
+        let row = transaction
+            .query_one(&self.create_token, &[&user, &"refresh", &host, &remote, &user_agent])
+            .await?;
+        let issued_at: DateTime<Utc> = row.get("issued_at");
+        let expires_at: DateTime<Utc> = row.get("expires_at");
+        let claims = JwtClaims {
+            uid: user,
+            iat: issued_at.timestamp(),
+            exp: expires_at.timestamp(),
+            jti: row.get("id"),
+            impersonator: None,
+        };
+        let new_refresh_token = generate_refresh_token();
+        transaction
+            .execute(&self.create_refresh_token,
+                     &[&new_refresh_token, &claims.jti, &user, &chain])
+            .await?;
+        transaction.commit().await?;
+        let jwt = encode(&Header::default(), &claims, &EncodingKey::from_secret(&secret))?;
+        Ok(RotatedToken {
+            jwt,
+            claims,
+            refresh_token: new_refresh_token,
+            superseded: TokenIdUser {
+                id: superseded.get("id"),
+                user: superseded.get("user"),
+            },
+        })
+    }
 }
\ No newline at end of file
@@ -0,0 +1,115 @@
+// Time-based one-time passwords of RFC 6238, as understood by common authenticator apps: HMAC-SHA1,
+// 6 digits and a 30-second step. Secrets are kept in base32, the way the apps take them.
+use crate::constants::{TOTP_DIGITS, TOTP_STEP, TOTP_SECRET_LENGTH};
+use rand::{Rng, thread_rng};
+use ring::{constant_time::verify_slices_are_equal, hmac};
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// Without padding, which otpauth URIs leave out
+fn base32_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    result
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&x| x == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+pub fn generate_secret() -> String {
+    let mut rng = thread_rng();
+    let secret = (0..TOTP_SECRET_LENGTH).map(|_| rng.gen()).collect::<Vec<u8>>();
+    base32_encode(&secret)
+}
+
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            issuer, account, secret, issuer, TOTP_DIGITS, TOTP_STEP)
+}
+
+fn code_at(key: &hmac::Key, step: u64) -> String {
+    let tag = hmac::sign(key, &step.to_be_bytes());
+    let digest = tag.as_ref();
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3],
+    ]);
+    format!("{:0width$}", value % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+pub fn code(secret: &str, unix_time: u64) -> Option<String> {
+    let secret = base32_decode(secret)?;
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &secret);
+    Some(code_at(&key, unix_time / TOTP_STEP))
+}
+
+// Accepts the codes of the previous and the next step too, for clocks that drift a little
+pub fn verify(secret: &str, code: &str, unix_time: u64) -> bool {
+    let secret = match base32_decode(secret) {
+        Some(secret) => secret,
+        None => return false,
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &secret);
+    let step = unix_time / TOTP_STEP;
+    // Every step is checked, so the time taken does not tell which one matched
+    [step.saturating_sub(1), step, step + 1].iter()
+        .map(|&step| verify_slices_are_equal(code_at(&key, step).as_bytes(), code.as_bytes())
+            .is_ok())
+        .fold(false, |matched, x| matched | x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base32_test() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("MZXW6YTBOI======"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("mzxw6ytboi"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW1"), None);
+        let secret = generate_secret();
+        assert_eq!(base32_decode(&secret).unwrap().len(), TOTP_SECRET_LENGTH);
+    }
+
+    #[test]
+    fn verify_test() {
+        // The SHA-1 test vector of RFC 6238, truncated to 6 digits
+        let secret = base32_encode(b"12345678901234567890");
+        assert!(verify(&secret, "287082", 59));
+        assert_eq!(code(&secret, 59).as_deref(), Some("287082"));
+        assert!(verify(&secret, "081804", 1111111109));
+        // One step off either way is accepted, two are not
+        assert!(verify(&secret, "081804", 1111111109 + TOTP_STEP));
+        assert!(verify(&secret, "081804", 1111111109 - TOTP_STEP));
+        assert!(!verify(&secret, "081804", 1111111109 + 2 * TOTP_STEP));
+        assert!(!verify(&secret, "081805", 1111111109));
+        assert!(!verify(&secret, "81804", 1111111109));
+        assert!(!verify("not base32!", "081804", 1111111109));
+    }
+}
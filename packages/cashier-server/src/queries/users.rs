@@ -1,21 +1,35 @@
 use super::errors::{Error, Result};
-use super::email::{register_user_email, update_user_email};
+use super::email::{
+    Locale, register_user_email, update_user_email, reset_password_email, send_email,
+};
+use super::codes::{CodeKind, CodeStore};
+use super::totp;
+use super::passwords::{Passwords, PasswordHasher};
+use super::breached_passwords::BreachedPasswords;
+use super::audit::{self, Actor};
 use actix_web::web::{self, block};
+use cashier_query::{
+    generator::{QueryConfig, FieldConfig, escape_value},
+    query::Query as Filter,
+};
 use chrono::{DateTime, Utc};
 use derive_more::From;
-use lettre::Transport;
 use rand::{Rng, thread_rng};
 use rand::distributions::{Alphanumeric, Distribution};
-use serde::{Serialize, Deserialize};
-use std::collections::{HashSet, HashMap};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashSet, HashMap};
+use std::error::Error as _;
 use std::iter;
+use std::sync::Arc;
 use tokio_postgres::{
-    Client, Statement, types::Type,
+    Client, Statement, types::{Type, ToSql, Json},
     IsolationLevel, Row,
+    error::{DbError, Error as PostgresError, SqlState},
 };
-use crate::api::app_state::AppState;
+use crate::api::app_state::{AppState, Mailer};
 use crate::api::extractors::auth::Auth;
 use crate::queries::errors::Error::DuplicatedUser;
+use log::warn;
 
 struct Digit;
 
@@ -27,6 +41,60 @@ impl Distribution<char> for Digit {
     }
 }
 
+fn generate_code() -> String {
+    let mut rng = thread_rng();
+    iter::repeat(())
+        .map(|()| rng.sample(Digit))
+        .take(6)
+        .collect()
+}
+
+fn time_to_live(expires_at: DateTime<Utc>) -> std::time::Duration {
+    (expires_at - Utc::now()).to_std().unwrap_or_default()
+}
+
+// Refuses to resend a confirmation e-mail within `cooldown` seconds after the last one, so that
+// nobody can flood the mailbox with codes
+fn check_resend_cooldown(sent_at: DateTime<Utc>, cooldown: u64) -> Result<()> {
+    let left = sent_at + chrono::Duration::seconds(cooldown as i64) - Utc::now();
+    if left > chrono::Duration::zero() {
+        return Err(Error::ResendTooSoon {
+            retry_after: ((left.num_milliseconds() + 999) / 1000) as u64,
+        });
+    }
+    Ok(())
+}
+
+// Concurrent requests may all pass the checks for a duplicated user, in which case the unique
+// indexes let only one of them through and the others fail as if they had been caught
+fn duplicated_user(err: PostgresError) -> Error {
+    let field = err.source()
+        .and_then(|e| e.downcast_ref::<DbError>())
+        .filter(|e| *e.code() == SqlState::UNIQUE_VIOLATION)
+        .and_then(|e| match e.constraint() {
+            Some("user_username") | Some("user_username_lower") => Some("username"),
+            Some("user_email") | Some("user_email_lower") => Some("email"),
+            _ => None,
+        });
+    match field {
+        Some(field) => DuplicatedUser { field: field.into() },
+        None => err.into(),
+    }
+}
+
+// Prefixes a statement with the table ancestor(root, id), pairing every role selected by `roles`
+// with itself and all the roles it inherits from through role_parent. UNION skips the pairs
+// already found, so that the recursion stops at a cycle
+fn with_ancestors(roles: &str) -> String {
+    format!("WITH RECURSIVE ancestor(root, id) AS (\
+                SELECT id, id FROM ({}) AS held \
+                UNION \
+                SELECT ancestor.root, role.id FROM ancestor, role_parent, role \
+                    WHERE role_parent.role = ancestor.id AND role_parent.parent = role.id \
+                    AND NOT role.deleted\
+            ) ", roles)
+}
+
 pub trait HasId {
     fn get_id(&self) -> i32;
 }
@@ -36,12 +104,24 @@ pub struct UserIdPasswordBlocked {
     pub id: i32,
     pub password: String,
     pub blocked: Option<bool>,
+    // Only once confirmed
+    pub totp_secret: Option<String>,
+}
+
+// A hash weaker than the ones made now, with the plaintext verified against it at a login, left
+// for upgrade_password to replace after the login returns. Not Debug, as it holds the plaintext
+pub struct PasswordUpgrade {
+    pub id: i32,
+    password: String,
+    hash: String,
 }
 
 #[derive(Debug)]
 pub struct UserIdCreatedAt {
     pub id: i32,
     pub created_at: DateTime<Utc>,
+    // Names of the roles linked, without the requested ones that do not exist, sorted
+    pub roles: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -53,6 +133,18 @@ pub struct UserCreatedByRegistration {
     pub created_at: DateTime<Utc>,
 }
 
+// A confirmation e-mail of a registration or an email updating that is neither completed nor
+// expired
+#[derive(Debug, Clone)]
+pub struct PendingEmail {
+    pub kind: CodeKind,
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub expires_at: DateTime<Utc>,
+    pub sent_at: DateTime<Utc>,
+}
+
 #[derive(Debug)]
 pub struct UserIdEmailUpdatedAt {
     pub id: i32,
@@ -60,16 +152,39 @@ pub struct UserIdEmailUpdatedAt {
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[derive(Serialize, Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct PermissionSubjectAction {
     pub subject: String,
     pub action: String,
 }
 
-#[derive(Debug)]
+// Filenames of the thumbnails of an avatar by their size, see MediaConfig::avatar_sizes
+pub type AvatarThumbnails = BTreeMap<u32, String>;
+
+fn get_avatar_thumbnails(row: &Row, column: &str) -> AvatarThumbnails {
+    row.get::<_, Json<AvatarThumbnails>>(column).0
+}
+
+fn map_avatar_thumbnails(thumbnails: &mut AvatarThumbnails, mapping: impl Fn(&String) -> String) {
+    thumbnails.values_mut().for_each(|x| *x = mapping(x));
+}
+
+// Every avatar is also kept as WebP, for clients that can pick the smaller one
+#[derive(Debug, Clone, Default)]
 pub struct UserAvatars {
     pub avatar: Option<String>,
-    pub avatar128: Option<String>,
+    pub avatar_thumbnails: AvatarThumbnails,
+    pub avatar_webp: Option<String>,
+    pub avatar_thumbnails_webp: AvatarThumbnails,
+}
+
+impl UserAvatars {
+    pub fn files(&self) -> impl Iterator<Item = &String> {
+        self.avatar.iter()
+            .chain(self.avatar_thumbnails.values())
+            .chain(self.avatar_webp.iter())
+            .chain(self.avatar_thumbnails_webp.values())
+    }
 }
 
 pub enum EitherUsernameOrEmail {
@@ -77,13 +192,28 @@ pub enum EitherUsernameOrEmail {
     Email(String),
 }
 
+impl EitherUsernameOrEmail {
+    // A username may contain '@' too, so users not found by such an email are then looked up by
+    // username, see Query::check_user_valid
+    pub fn detect(identifier: &str) -> Self {
+        if identifier.contains('@') && validator::validate_email(identifier) {
+            EitherUsernameOrEmail::Email(identifier.into())
+        } else {
+            EitherUsernameOrEmail::Username(identifier.into())
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct UserPublic {
     pub id: i32,
     pub username: String,
     pub nickname: Option<String>,
     pub avatar: Option<String>,
-    pub avatar128: Option<String>,
+    pub avatar_thumbnails: AvatarThumbnails,
+    pub avatar_webp: Option<String>,
+    pub avatar_thumbnails_webp: AvatarThumbnails,
     pub created_at: DateTime<Utc>,
 }
 
@@ -94,23 +224,30 @@ impl From<&Row> for UserPublic {
             username: row.get("username"),
             nickname: row.get("nickname"),
             avatar: row.get("avatar"),
-            avatar128: row.get("avatar128"),
+            avatar_thumbnails: get_avatar_thumbnails(row, "avatar_thumbnails"),
+            avatar_webp: row.get("avatar_webp"),
+            avatar_thumbnails_webp: get_avatar_thumbnails(row, "avatar_thumbnails_webp"),
             created_at: row.get("created_at"),
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct UserWithoutRoles {
     pub id: i32,
     pub username: String,
     pub email: Option<String>,
     pub nickname: Option<String>,
     pub avatar: Option<String>,
-    pub avatar128: Option<String>,
+    pub avatar_thumbnails: AvatarThumbnails,
+    pub avatar_webp: Option<String>,
+    pub avatar_thumbnails_webp: AvatarThumbnails,
     pub blocked: Option<bool>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    // None for users who have not logged in since it is tracked
+    pub last_login_at: Option<DateTime<Utc>>,
 }
 
 impl From<&Row> for UserWithoutRoles {
@@ -121,15 +258,19 @@ impl From<&Row> for UserWithoutRoles {
             email: row.get("email"),
             nickname: row.get("nickname"),
             avatar: row.get("avatar"),
-            avatar128: row.get("avatar128"),
+            avatar_thumbnails: get_avatar_thumbnails(row, "avatar_thumbnails"),
+            avatar_webp: row.get("avatar_webp"),
+            avatar_thumbnails_webp: get_avatar_thumbnails(row, "avatar_thumbnails_webp"),
             blocked: row.get("blocked"),
             created_at: row.get("created_at"),
             updated_at: row.get("updated_at"),
+            last_login_at: row.get("last_login_at"),
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct UserAll {
     pub id: i32,
     pub username: String,
@@ -137,10 +278,14 @@ pub struct UserAll {
     pub email: Option<String>,
     pub nickname: Option<String>,
     pub avatar: Option<String>,
-    pub avatar128: Option<String>,
+    pub avatar_thumbnails: AvatarThumbnails,
+    pub avatar_webp: Option<String>,
+    pub avatar_thumbnails_webp: AvatarThumbnails,
     pub blocked: Option<bool>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    // None for users who have not logged in since it is tracked
+    pub last_login_at: Option<DateTime<Utc>>,
 }
 
 impl From<(UserWithoutRoles, Vec<i32>)> for UserAll {
@@ -152,29 +297,70 @@ impl From<(UserWithoutRoles, Vec<i32>)> for UserAll {
             email: data.0.email,
             nickname: data.0.nickname,
             avatar: data.0.avatar,
-            avatar128: data.0.avatar128,
+            avatar_thumbnails: data.0.avatar_thumbnails,
+            avatar_webp: data.0.avatar_webp,
+            avatar_thumbnails_webp: data.0.avatar_thumbnails_webp,
             blocked: data.0.blocked,
             created_at: data.0.created_at,
             updated_at: data.0.updated_at,
+            last_login_at: data.0.last_login_at,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Serializes a user with the derived impl of serde(remote = "Self") and adds display_name, the
+// nickname or else the username. It is computed here rather than stored, so that it always
+// follows the nickname. Deserializing ignores it
+macro_rules! serde_with_display_name {
+    ($user:ident) => {
+        impl<'de> Deserialize<'de> for $user {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                $user::deserialize(deserializer)
+            }
+        }
+        impl Serialize for $user {
+            fn serialize<S: Serializer>(
+                &self, serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                struct Derived<'a>(&'a $user);
+                impl Serialize for Derived<'_> {
+                    fn serialize<S: Serializer>(
+                        &self, serializer: S,
+                    ) -> std::result::Result<S::Ok, S::Error> {
+                        $user::serialize(self.0, serializer)
+                    }
+                }
+                #[derive(Serialize)]
+                struct WithDisplayName<'a> {
+                    #[serde(flatten)]
+                    user: Derived<'a>,
+                    display_name: &'a str,
+                }
+                WithDisplayName {
+                    user: Derived(self),
+                    display_name: self.nickname.as_deref().unwrap_or(&self.username),
+                }.serialize(serializer)
+            }
+        }
+    };
+}
+
+serde_with_display_name!(UserPublic);
+serde_with_display_name!(UserWithoutRoles);
+serde_with_display_name!(UserAll);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(tag = "populate-user")]
 #[serde(rename_all = "kebab-case")]
 pub enum UserAccessLevel {
+    #[default]
     Public,
     WithoutRoles,
     All,
 }
 
-impl Default for UserAccessLevel {
-    fn default() -> Self {
-        UserAccessLevel::Public
-    }
-}
-
 #[derive(From, Serialize, Deserialize, Debug)]
 #[serde(tag = "access")]
 #[serde(rename_all = "kebab-case")]
@@ -189,20 +375,266 @@ impl User {
         match self {
             User::Public(user) => {
                 user.avatar = user.avatar.as_ref().map(&mapping);
-                user.avatar128 = user.avatar128.as_ref().map(&mapping);
+                map_avatar_thumbnails(&mut user.avatar_thumbnails, &mapping);
+                user.avatar_webp = user.avatar_webp.as_ref().map(&mapping);
+                map_avatar_thumbnails(&mut user.avatar_thumbnails_webp, &mapping);
             }
             User::WithoutRoles(user) => {
                 user.avatar = user.avatar.as_ref().map(&mapping);
-                user.avatar128 = user.avatar128.as_ref().map(&mapping);
+                map_avatar_thumbnails(&mut user.avatar_thumbnails, &mapping);
+                user.avatar_webp = user.avatar_webp.as_ref().map(&mapping);
+                map_avatar_thumbnails(&mut user.avatar_thumbnails_webp, &mapping);
             }
             User::All(user) => {
                 user.avatar = user.avatar.as_ref().map(&mapping);
-                user.avatar128 = user.avatar128.as_ref().map(&mapping);
+                map_avatar_thumbnails(&mut user.avatar_thumbnails, &mapping);
+                user.avatar_webp = user.avatar_webp.as_ref().map(&mapping);
+                map_avatar_thumbnails(&mut user.avatar_thumbnails_webp, &mapping);
             }
         }
     }
 }
 
+// An entry of the changes feed, where a deleted user is a tombstone without the user. Together
+// with id, xid is where a reader continues from
+#[derive(Debug, Serialize)]
+pub struct UserChange {
+    pub xid: i64,
+    pub id: i32,
+    pub deleted: bool,
+    pub updated_at: DateTime<Utc>,
+    pub user: Option<UserPublic>,
+}
+
+impl From<&Row> for UserChange {
+    fn from(row: &Row) -> Self {
+        let deleted = row.get("deleted");
+        Self {
+            xid: row.get("change_xid"),
+            id: row.get("id"),
+            deleted,
+            updated_at: row.get("updated_at"),
+            user: if deleted { None } else { Some(UserPublic::from(row)) },
+        }
+    }
+}
+
+// Outcome of set_blocked_bulk. Users that already were in the requested state are in neither
+// list
+#[derive(Debug)]
+pub struct BlockedBulk {
+    pub updated: Vec<(i32, DateTime<Utc>)>,
+    pub not_found: Vec<i32>,
+}
+
+// Fields of the filter and sort of GET /api/users. Without email, it can be neither filtered
+// nor sorted by, so that masked emails cannot be guessed
+fn user_list_config(with_email: bool) -> QueryConfig {
+    let config = QueryConfig::new()
+        .field(FieldConfig::new("id")
+            .partial_equal()
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<i32>())
+        )
+        .field(FieldConfig::new("username")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+            .sortable()
+        )
+        .field(FieldConfig::new("nickname")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+            .nullable()
+            .sortable()
+        )
+        .field(FieldConfig::new("blocked")
+            .partial_equal()
+            .nullable()
+            .escape_handler(escape_value::<bool>())
+        )
+        .field(FieldConfig::new("created_at")
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .field(FieldConfig::new("updated_at")
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .field(FieldConfig::new("last_login_at")
+            .partial_equal()
+            .partial_order()
+            .nullable()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .max_clauses(64)
+        .max_depth(8);
+    if with_email {
+        config.field(FieldConfig::new("email")
+            .partial_equal()
+            .use_like()
+            .nullable()
+            .sortable()
+        )
+    } else {
+        config
+    }
+}
+
+// Fields GET /api/users/search matches the text against. Never email, which is not public
+fn user_search_config() -> QueryConfig {
+    QueryConfig::new()
+        .field(FieldConfig::new("username")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+        )
+        .field(FieldConfig::new("nickname")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+        )
+}
+
+// Fields of the filter and sort of GET /api/registrations, e.g. completed: null for the pending
+// ones. Codes and passwords are neither filterable nor returned
+fn registration_list_config() -> QueryConfig {
+    QueryConfig::new()
+        .field(FieldConfig::new("id")
+            .partial_equal()
+        )
+        .field(FieldConfig::new("username")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+            .sortable()
+        )
+        .field(FieldConfig::new("email")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+            .sortable()
+        )
+        .field(FieldConfig::new("created_at")
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .field(FieldConfig::new("expires_at")
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .field(FieldConfig::new("completed")
+            .partial_equal()
+            .nullable()
+            .escape_handler(escape_value::<bool>())
+        )
+        .max_clauses(64)
+        .max_depth(8)
+}
+
+// Fields of the filter and sort of GET /api/email-updating, the same as the registrations
+fn email_updating_list_config() -> QueryConfig {
+    QueryConfig::new()
+        .field(FieldConfig::new("id")
+            .partial_equal()
+        )
+        .field(FieldConfig::new("user")
+            .rename("\"user\"")
+            .partial_equal()
+            .escape_handler(escape_value::<i32>())
+        )
+        .field(FieldConfig::new("new_email")
+            .wildcard()
+            .partial_equal()
+            .use_like()
+            .sortable()
+        )
+        .field(FieldConfig::new("created_at")
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .field(FieldConfig::new("expires_at")
+            .partial_order()
+            .sortable()
+            .escape_handler(escape_value::<DateTime<Utc>>())
+        )
+        .field(FieldConfig::new("completed")
+            .partial_equal()
+            .nullable()
+            .escape_handler(escape_value::<bool>())
+        )
+        .max_clauses(64)
+        .max_depth(8)
+}
+
+// What a client asks of a listing, filter and sort in the syntax of cashier-query
+struct Page<'a> {
+    filter: Option<&'a str>,
+    sort: Option<&'a str>,
+    limit: i64,
+    offset: i64,
+}
+
+// A page of the rows of table matching the filter, newest first by default, and the count of all
+// of them. Only the given columns are selected
+async fn list_page<T: for<'a> From<&'a Row>>(
+    client: &Client, config: QueryConfig, table: &str, columns: &str, page: Page<'_>,
+) -> Result<(Vec<T>, i64)> {
+    let Page { filter, sort, limit, offset } = page;
+    let (condition, mut params) = match filter {
+        Some(filter) => config.parse_to_postgres_params(filter)?,
+        None => None,
+    }.unwrap_or_else(|| ("TRUE".into(), Vec::new()));
+    let order = match sort {
+        Some(sort) => config.parse_order_by(sort)?,
+        None => None,
+    }.map(|x| x + ", created_at DESC, id ASC").unwrap_or_else(|| "created_at DESC, id ASC".into());
+    let total = client
+        .query_one(format!("SELECT COUNT(*) FROM {} WHERE {}", table, condition).as_str(),
+                   &params.iter().map(|x| x as &(dyn ToSql + Sync)).collect::<Vec<_>>())
+        .await?
+        .get(0);
+    let (limit_param, offset_param) = (params.len() + 1, params.len() + 2);
+    params.push(limit.into());
+    params.push(offset.into());
+    let items = client
+        .query(format!("SELECT {} FROM {} WHERE {} ORDER BY {} LIMIT ${} OFFSET ${}",
+                       columns, table, condition, order, limit_param, offset_param).as_str(),
+               &params.iter().map(|x| x as &(dyn ToSql + Sync)).collect::<Vec<_>>())
+        .await?
+        .iter()
+        .map(T::from)
+        .collect();
+    Ok((items, total))
+}
+
+// Keeps the first char of the local part and the domain, e.g. a***@example.com
+pub fn mask_email(email: &str) -> String {
+    match email.find('@') {
+        Some(at) => format!("{}***{}", email[..at].chars().next().map(String::from)
+            .unwrap_or_default(), &email[at..]),
+        None => "***".into(),
+    }
+}
+
+impl User {
+    pub fn redact_email(&mut self) {
+        match self {
+            User::Public(_) => (),
+            User::WithoutRoles(user) => user.email = user.email.as_deref().map(mask_email),
+            User::All(user) => user.email = user.email.as_deref().map(mask_email),
+        }
+    }
+}
+
 impl HasId for User {
     fn get_id(&self) -> i32 {
         match self {
@@ -215,8 +647,8 @@ impl HasId for User {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RoleShort {
-    id: i32,
-    name: String,
+    pub id: i32,
+    pub name: String,
 }
 
 impl From<&Row> for RoleShort {
@@ -228,6 +660,28 @@ impl From<&Row> for RoleShort {
     }
 }
 
+// A role of a user along with who granted it and when, see GET /api/users/:id/role-assignments
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoleAssignment {
+    pub id: i32,
+    pub name: String,
+    // None for roles granted by nobody, such as the default roles of registered users, and for
+    // the ones granted before these were recorded
+    pub granted_by: Option<i32>,
+    pub granted_at: Option<DateTime<Utc>>,
+}
+
+impl From<&Row> for RoleAssignment {
+    fn from(x: &Row) -> Self {
+        Self {
+            id: x.get("id"),
+            name: x.get("name"),
+            granted_by: x.get("granted_by"),
+            granted_at: x.get("granted_at"),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RoleShortWithPermissions {
     id: i32,
@@ -382,14 +836,21 @@ pub enum Permission {
     All(PermissionAll),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// The permissions of a websocket client by the roles it holds, each with the ones inherited from
+// its ancestors. Changes of roles, permissions or parents are applied by fetching it again
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct PermissionTree {
     map: HashMap<i32, HashMap<i32, PermissionSubjectAction>>,
+    // Every held role with itself and all its ancestors
+    ancestors: HashMap<i32, HashSet<i32>>,
 }
 
 impl PermissionTree {
-    pub fn new(map: HashMap<i32, HashMap<i32, PermissionSubjectAction>>) -> Self {
-        Self { map }
+    pub fn new(
+        map: HashMap<i32, HashMap<i32, PermissionSubjectAction>>,
+        ancestors: HashMap<i32, HashSet<i32>>,
+    ) -> Self {
+        Self { map, ancestors }
     }
     pub fn get(&self) -> HashMap<i32, PermissionSubjectAction> {
         self.map.values()
@@ -403,30 +864,12 @@ impl PermissionTree {
             .map(|x| x.subject.clone())
             .collect()
     }
-    pub fn add_role(&mut self, role: i32, permissions: HashMap<i32, PermissionSubjectAction>) {
-        self.map.insert(role, permissions);
-    }
-    pub fn remove_role(&mut self, role: i32) {
-        self.map.remove(&role);
+    // Whether the role is held or inherited from
+    pub fn has_role(&self, role: i32) -> bool {
+        self.ancestors.values().any(|x| x.contains(&role))
     }
-    pub fn add_permission(&mut self, role: i32, permission: i32, subject: String, action: String) {
-        if let Some(permissions) = self.map.get_mut(&role) {
-            permissions.insert(permission, PermissionSubjectAction {
-                subject,
-                action,
-            });
-        }
-    }
-    pub fn remove_permission(&mut self, role: i32, permission: i32) {
-        if let Some(permissions) = self.map.get_mut(&role) {
-            permissions.remove(&permission);
-        }
-    }
-}
-
-impl Default for PermissionTree {
-    fn default() -> Self {
-        Self { map: HashMap::new() }
+    pub fn has_permission(&self, permission: i32) -> bool {
+        self.map.values().any(|x| x.contains_key(&permission))
     }
 }
 
@@ -492,182 +935,326 @@ impl From<&Row> for UserEmailUpdatingPublic {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserPasswordReset {
+    pub id: String,
+    pub code: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct PasswordResetConfirmed {
+    pub id: i32,
+    pub updated_at: DateTime<Utc>,
+    pub revoked_tokens: Vec<i32>,
+}
+
 pub struct Query {
     find_one_from_username_to_id_password_blocked: Statement,
     find_one_from_email_to_id_password_blocked: Statement,
     find_one_from_username_to_id: Statement,
+    upgrade_password: Statement,
+    touch_last_login: Statement,
+    check_usernames_existence: Statement,
     find_one_from_email_to_id: Statement,
     check_user_blocked: Statement,
     fetch_permission: Statement,
     fetch_default_permission: Statement,
+    fetch_role_name: Statement,
     check_extra_roles: Statement,
     find_one_from_username_to_username_email: Statement,
     find_one_from_username_email_to_username_email: Statement,
     insert_one: Statement,
     insert_one_roles: Statement,
+    add_roles_to_user: Statement,
+    remove_roles_from_user: Statement,
     fetch_avatars: Statement,
     update_avatars: Statement,
+    replace_avatars: Statement,
     find_one: Statement,
     find_one_public: Statement,
     find_one_to_username: Statement,
+    find_changes: Statement,
     find_roles_only_id: Statement,
     find_roles_short: Statement,
+    find_role_assignments: Statement,
     find_roles_without_permissions: Statement,
     find_permissions_only_id: Statement,
     find_permissions_short: Statement,
     find_permissions_all: Statement,
     fetch_permission_tree: Statement,
     fetch_default_permission_tree: Statement,
+    fetch_role_permissions: Statement,
     insert_one_into_user_registration: Statement,
     find_one_from_user_registration: Statement,
     find_one_from_user_registration_without_password: Statement,
     insert_one_registered_user: Statement,
     find_default_roles: Statement,
     complete_registration: Statement,
+    cancel_registration: Statement,
     query_registration: Statement,
+    find_pending_from_user_registration: Statement,
+    touch_user_registration: Statement,
+    lock_one: Statement,
     update_user: Statement,
+    set_blocked_bulk: Statement,
+    find_ids: Statement,
     insert_one_into_user_email_updating: Statement,
     find_one_from_user_email_updating: Statement,
     find_one_from_user_email_updating_join_user: Statement,
     update_email: Statement,
     complete_email_updating: Statement,
+    cancel_email_updating: Statement,
+    supersede_email_updating: Statement,
     query_email_updating: Statement,
+    find_pending_from_user_email_updating_join_user: Statement,
+    touch_user_email_updating: Statement,
+    find_one_from_email_to_id_username: Statement,
+    insert_one_into_user_password_reset: Statement,
+    find_one_from_user_password_reset: Statement,
+    complete_password_resets: Statement,
+    revoke_tokens_from_user: Statement,
+    find_pending_from_user_password_reset_join_user: Statement,
+    touch_user_password_reset: Statement,
     find_one_to_password: Statement,
     update_password: Statement,
+    soft_delete_user: Statement,
+    enroll_totp: Statement,
+    find_one_to_totp: Statement,
+    enable_totp: Statement,
+    disable_totp: Statement,
+    codes: Box<dyn CodeStore>,
+    passwords: Passwords,
+    breached_passwords: Arc<BreachedPasswords>,
+    audit: Arc<audit::Query>,
+    deleted_user_reservation: i64,
+    registration_ttl: i64,
+    email_updating_ttl: i64,
+    password_reset_ttl: i64,
 }
 
 impl Query {
-    pub async fn new(client: &Client) -> Self {
+    // Deleted users keep their username and email for deleted_user_reservation seconds after
+    // deletion. Codes sent by e-mail stay valid for the given ttls, in seconds
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        client: &Client, deleted_user_reservation: u64, registration_ttl: u64,
+        email_updating_ttl: u64, password_reset_ttl: u64, passwords: Passwords,
+        breached_passwords: Arc<BreachedPasswords>, audit: Arc<audit::Query>,
+        codes: Box<dyn CodeStore>,
+    ) -> Result<Self> {
+        // The reservation is bound as the parameter numbered n
+        let occupied = |n: usize| format!(
+            "(NOT deleted OR deleted_at > NOW() - ${} * INTERVAL '1 second')", n);
         let find_one_from_username_to_id_password_blocked = client.prepare_typed(
-            "SELECT id, password, blocked FROM \"user\" \
-                WHERE username = $1 AND NOT deleted LIMIT 1",
+            "SELECT id, password, blocked, \
+                    CASE WHEN totp_enabled THEN totp_secret END AS totp_secret FROM \"user\" \
+                WHERE LOWER(username) = LOWER($1) AND NOT deleted LIMIT 1",
             &[Type::TEXT],
-        ).await.unwrap();
+        ).await?;
         let find_one_from_email_to_id_password_blocked = client.prepare_typed(
-            "SELECT id, password, blocked FROM \"user\" \
-                WHERE email = $1 AND NOT deleted LIMIT 1",
+            "SELECT id, password, blocked, \
+                    CASE WHEN totp_enabled THEN totp_secret END AS totp_secret FROM \"user\" \
+                WHERE LOWER(email) = LOWER($1) AND NOT deleted LIMIT 1",
             &[Type::TEXT],
-        ).await.unwrap();
+        ).await?;
         let find_one_from_username_to_id = client.prepare_typed(
-            "SELECT id FROM \"user\" \
-                WHERE username = $1 AND NOT deleted LIMIT 1",
-            &[Type::TEXT],
-        ).await.unwrap();
+            &format!("SELECT id FROM \"user\" \
+                WHERE LOWER(username) = LOWER($1) AND {} LIMIT 1", occupied(2)),
+            &[Type::TEXT, Type::INT8],
+        ).await?;
+        let check_usernames_existence = client.prepare_typed(
+            &format!("SELECT DISTINCT input.username, EXISTS ( \
+                    SELECT 1 FROM \"user\" WHERE LOWER(\"user\".username) = LOWER(input.username) \
+                        AND {} \
+                ) AS exists FROM UNNEST($1::TEXT[]) AS input(username)", occupied(2)),
+            &[Type::TEXT_ARRAY, Type::INT8],
+        ).await?;
         let find_one_from_email_to_id = client.prepare_typed(
-            "SELECT id FROM \"user\" \
-                WHERE email = $1 AND NOT deleted LIMIT 1",
-            &[Type::TEXT],
-        ).await.unwrap();
+            &format!("SELECT id FROM \"user\" \
+                WHERE LOWER(email) = LOWER($1) AND {} LIMIT 1", occupied(2)),
+            &[Type::TEXT, Type::INT8],
+        ).await?;
         let check_user_blocked = client.prepare_typed(
             "SELECT blocked FROM \"user\" \
                 WHERE id = $1 AND NOT deleted LIMIT 1",
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
+        // The roles of a user, including the default one, whose ancestors are held as well
+        let held_roles = "SELECT role.id from user_role, role \
+                WHERE user_role.user = $1 AND user_role.role = role.id AND NOT role.deleted \
+            UNION \
+            SELECT role.id from role \
+                WHERE role.name = 'default' AND NOT role.deleted";
+        let default_role = "SELECT id FROM role WHERE name = 'default' AND NOT deleted";
         let fetch_permission = client.prepare_typed(
-            "SELECT DISTINCT subject, action from ( \
-                    SELECT role.id from user_role, role \
-                        WHERE user_role.user = $1 AND user_role.role = role.id AND NOT role.deleted \
-                    UNION \
-                    SELECT role.id from role \
-                        WHERE role.name = 'default' AND NOT role.deleted \
-                ) as role, role_permission, permission \
-                    WHERE role.id = role_permission.role \
+            &format!("{}SELECT DISTINCT subject, action from ancestor, role_permission, permission \
+                    WHERE ancestor.id = role_permission.role \
                     AND role_permission.permission = permission.id AND NOT permission.deleted",
+                     with_ancestors(held_roles)),
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
         let fetch_default_permission = client.prepare(
-            "SELECT DISTINCT subject, action from role, role_permission, permission \
-                WHERE role.name = 'default' AND NOT role.deleted AND role.id = role_permission.role \
-                AND role_permission.permission = permission.id AND NOT permission.deleted"
-        ).await.unwrap();
+            &format!("{}SELECT DISTINCT subject, action from ancestor, role_permission, permission \
+                    WHERE ancestor.id = role_permission.role \
+                    AND role_permission.permission = permission.id AND NOT permission.deleted",
+                     with_ancestors(default_role))
+        ).await?;
+        let fetch_role_name = client.prepare_typed(
+            "SELECT role.name from user_role, role \
+                    WHERE user_role.user = $1 AND user_role.role = role.id AND NOT role.deleted \
+                UNION \
+                SELECT role.name from role \
+                    WHERE role.name = 'default' AND NOT role.deleted \
+                ORDER BY name",
+            &[Type::INT4],
+        ).await?;
+        // Holding a role counts as holding all of its ancestors, whose permissions it inherits
         let check_extra_roles = client.prepare_typed(
-            "SELECT UNNEST($1) EXCEPT \
-                SELECT role.name from user_role, role WHERE user_role.user = $2 \
-                AND user_role.role = role.id AND NOT role.deleted",
+            &format!("{}SELECT UNNEST($1) EXCEPT \
+                    SELECT role.name from ancestor, role WHERE ancestor.id = role.id",
+                     with_ancestors("SELECT role.id from user_role, role WHERE user_role.user = $2 \
+                         AND user_role.role = role.id AND NOT role.deleted")),
             &[Type::TEXT_ARRAY, Type::INT4]
-        ).await.unwrap();
+        ).await?;
         let find_one_from_username_to_username_email = client.prepare_typed(
-            "SELECT username, email FROM \"user\" \
-                WHERE username = $1 AND NOT deleted LIMIT 1",
-            &[Type::TEXT],
-        ).await.unwrap();
+            &format!("SELECT username, email FROM \"user\" \
+                WHERE LOWER(username) = LOWER($1) AND {} LIMIT 1", occupied(2)),
+            &[Type::TEXT, Type::INT8],
+        ).await?;
         let find_one_from_username_email_to_username_email = client.prepare_typed(
-            "SELECT username, email FROM \"user\" \
-                WHERE (username = $1 OR email = $2) AND NOT deleted LIMIT 1",
-            &[Type::TEXT, Type::TEXT],
-        ).await.unwrap();
+            &format!("SELECT username, email FROM \"user\" \
+                WHERE (LOWER(username) = LOWER($1) OR LOWER(email) = LOWER($2)) \
+                    AND {} LIMIT 1", occupied(3)),
+            &[Type::TEXT, Type::TEXT, Type::INT8],
+        ).await?;
         let insert_one = client.prepare_typed(
             "INSERT INTO \"user\" (username, password, email, nickname, \
                                    created_at, updated_at, deleted) \
                 VALUES ($1, $2, $3, $4, NOW(), NOW(), false) \
                 RETURNING id, created_at",
             &[Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT],
-        ).await.unwrap();
+        ).await?;
         let insert_one_roles = client.prepare_typed(
-            "INSERT INTO user_role (\"user\", role) \
-                SELECT $1, role.id FROM (SELECT UNNEST($2) AS role) AS temp, role \
-                WHERE role.name = temp.role AND NOT role.deleted",
+            "WITH inserted AS (\
+                INSERT INTO user_role (\"user\", role, granted_by, granted_at) \
+                    SELECT $1, role.id, $3, NOW() FROM (SELECT UNNEST($2) AS role) AS temp, role \
+                    WHERE role.name = temp.role AND NOT role.deleted \
+                    RETURNING role\
+            ) \
+            SELECT role.name FROM inserted, role WHERE role.id = inserted.role ORDER BY role.name",
+            &[Type::INT4, Type::TEXT_ARRAY, Type::INT4],
+        ).await?;
+        let add_roles_to_user = client.prepare_typed(
+            "INSERT INTO user_role (\"user\", role, granted_by, granted_at) \
+                SELECT $1, role.id, $3, NOW() FROM (SELECT UNNEST($2) AS role) AS temp, role \
+                WHERE role.name = temp.role AND NOT role.deleted \
+                ON CONFLICT DO NOTHING \
+                RETURNING role",
+            &[Type::INT4, Type::TEXT_ARRAY, Type::INT4],
+        ).await?;
+        let remove_roles_from_user = client.prepare_typed(
+            "DELETE FROM user_role USING role \
+                WHERE user_role.user = $1 AND user_role.role = role.id AND role.name = ANY($2) \
+                RETURNING role.id",
             &[Type::INT4, Type::TEXT_ARRAY],
-        ).await.unwrap();
+        ).await?;
         let fetch_avatars = client.prepare_typed(
-            "SELECT avatar, avatar128 FROM \"user\" \
+            "SELECT avatar, avatar_thumbnails, avatar_webp, avatar_thumbnails_webp \
+                FROM \"user\" \
                 WHERE id = $1 AND NOT deleted LIMIT 1",
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
         let update_avatars = client.prepare_typed(
-            "UPDATE \"user\" SET avatar = $1, avatar128 = $2, updated_at = NOW() \
-                WHERE id = $3 AND NOT deleted \
+            "UPDATE \"user\" SET avatar = $1, avatar_thumbnails = $2, avatar_webp = $3, \
+                    avatar_thumbnails_webp = $4, updated_at = NOW(), \
+                    change_xid = txid_current() \
+                WHERE id = $5 AND NOT deleted \
                 RETURNING updated_at",
-            &[Type::TEXT, Type::TEXT, Type::INT4]
-        ).await.unwrap();
+            &[Type::TEXT, Type::JSONB, Type::TEXT, Type::JSONB, Type::INT4]
+        ).await?;
+        // Only while the avatar is still the given one, which a later upload may have replaced
+        let replace_avatars = client.prepare_typed(
+            "UPDATE \"user\" SET avatar = $1, avatar_thumbnails = $2, avatar_webp = $3, \
+                    avatar_thumbnails_webp = $4, updated_at = NOW(), \
+                    change_xid = txid_current() \
+                WHERE id = $5 AND NOT deleted AND avatar = $6 \
+                RETURNING updated_at",
+            &[Type::TEXT, Type::JSONB, Type::TEXT, Type::JSONB, Type::INT4, Type::TEXT]
+        ).await?;
         let find_one = client.prepare_typed(
-            "SELECT id, username, email, nickname, avatar, avatar128, \
-                        blocked, created_at, updated_at FROM \"user\" \
+            "SELECT id, username, email, nickname, avatar, avatar_thumbnails, avatar_webp, \
+                        avatar_thumbnails_webp, blocked, created_at, updated_at, last_login_at \
+                    FROM \"user\" \
                 WHERE id = $1 AND NOT deleted LIMIT 1",
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
+        // Only the latest change of a user is kept, which is all a mirror needs. Changes are
+        // ordered by the transaction that made them, and only those of transactions older than
+        // any still running are returned, so a transaction committing later never lands behind
+        // a reader. A long running transaction, in any database of the server, holds the feed
+        // back until it ends
+        let find_changes = client.prepare_typed(
+            "SELECT id, username, nickname, avatar, avatar_thumbnails, avatar_webp, \
+                        avatar_thumbnails_webp, \
+                        created_at, updated_at, deleted, change_xid FROM \"user\" \
+                WHERE (change_xid, id) > ($1, $2) \
+                    AND change_xid < txid_snapshot_xmin(txid_current_snapshot()) \
+                ORDER BY change_xid, id LIMIT $3",
+            &[Type::INT8, Type::INT4, Type::INT8],
+        ).await?;
         let find_one_public = client.prepare_typed(
-            "SELECT id, username, nickname, avatar, avatar128, created_at FROM \"user\" \
+            "SELECT id, username, nickname, avatar, avatar_thumbnails, avatar_webp, \
+                        avatar_thumbnails_webp, \
+                        created_at FROM \"user\" \
                 WHERE id = $1 AND NOT deleted LIMIT 1",
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
         let find_one_to_username = client.prepare_typed(
             "SELECT username FROM \"user\" \
                 WHERE id = $1 AND NOT deleted LIMIT 1",
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
         let find_roles_only_id = client.prepare_typed(
             "SELECT DISTINCT \"user_id\", id \
                     FROM (SELECT UNNEST($1) AS user_id) AS temp, user_role, role \
                 WHERE user_id = user_role.user AND role.id = user_role.role AND NOT role.deleted",
             &[Type::INT4_ARRAY],
-        ).await.unwrap();
+        ).await?;
         let find_roles_short = client.prepare_typed(
             "SELECT DISTINCT \"user_id\", id, name \
                     FROM (SELECT UNNEST($1) AS user_id) AS temp, user_role, role \
                 WHERE user_id = user_role.user AND role.id = user_role.role AND NOT role.deleted",
             &[Type::INT4_ARRAY],
-        ).await.unwrap();
+        ).await?;
+        let find_role_assignments = client.prepare_typed(
+            "SELECT role.id, role.name, user_role.granted_by, user_role.granted_at \
+                    FROM user_role, role \
+                WHERE user_role.user = $1 AND role.id = user_role.role AND NOT role.deleted \
+                ORDER BY role.name",
+            &[Type::INT4],
+        ).await?;
         let find_roles_without_permissions = client.prepare_typed(
             "SELECT DISTINCT \"user_id\", id, name, display_name, description, created_at, updated_at \
                     FROM (SELECT UNNEST($1) AS user_id) AS temp, user_role, role \
                 WHERE user_id = user_role.user AND role.id = user_role.role AND NOT role.deleted",
             &[Type::INT4_ARRAY],
-        ).await.unwrap();
+        ).await?;
         let find_permissions_only_id = client.prepare_typed(
             "SELECT DISTINCT role_id, id \
                     FROM (SELECT UNNEST($1) as role_id) as temp, role_permission, permission \
                 WHERE role_id = role_permission.role AND permission.id = role_permission.permission \
                     AND NOT permission.deleted",
             &[Type::INT4_ARRAY],
-        ).await.unwrap();
+        ).await?;
         let find_permissions_short = client.prepare_typed(
             "SELECT DISTINCT role_id, id, subject, action \
                     FROM (SELECT UNNEST($1) as role_id) as temp, role_permission, permission \
                 WHERE role_id = role_permission.role AND permission.id = role_permission.permission \
                     AND NOT permission.deleted",
             &[Type::INT4_ARRAY],
-        ).await.unwrap();
+        ).await?;
         let find_permissions_all = client.prepare_typed(
             "SELECT DISTINCT role_id, id, subject, action, display_name, description, \
                         created_at, updated_at \
@@ -675,165 +1262,371 @@ impl Query {
                 WHERE role_id = role_permission.role AND permission.id = role_permission.permission \
                     AND NOT permission.deleted",
             &[Type::INT4_ARRAY],
-        ).await.unwrap();
+        ).await?;
+        // Inherited permissions are filed under the held role, the same as fetch_role_permissions
+        // does for a newly added role
+        // Every ancestor of a held role yields a row, with a null permission id if it has none
+        let permission_tree = |roles: &str| format!(
+            "{}SELECT DISTINCT ancestor.root as role_id, ancestor.id as ancestor_id, \
+                    permission.id as permission_id, subject, action FROM ancestor \
+                LEFT JOIN (role_permission JOIN permission \
+                    ON role_permission.permission = permission.id AND NOT permission.deleted) \
+                ON ancestor.id = role_permission.role",
+            with_ancestors(roles));
         let fetch_permission_tree = client.prepare_typed(
-            "SELECT DISTINCT role.id as role_id, permission.id as permission_id, subject, action from ( \
-                    SELECT role.id from user_role, role \
-                        WHERE user_role.user = $1 AND user_role.role = role.id AND NOT role.deleted \
-                    UNION \
-                    SELECT role.id from role \
-                        WHERE role.name = 'default' AND NOT role.deleted \
-                ) as role, role_permission, permission \
-                    WHERE role.id = role_permission.role \
-                    AND role_permission.permission = permission.id AND NOT permission.deleted",
+            &permission_tree(held_roles),
             &[Type::INT4],
-        ).await.unwrap();
+        ).await?;
         let fetch_default_permission_tree = client.prepare(
-            "SELECT DISTINCT role.id as role_id, permission.id as permission_id, subject, action \
-                    from role, role_permission, permission \
-                WHERE role.name = 'default' AND NOT role.deleted AND role.id = role_permission.role \
-                AND role_permission.permission = permission.id AND NOT permission.deleted"
-        ).await.unwrap();
+            &permission_tree(default_role)
+        ).await?;
+        // A role without permissions still yields one row, with a null permission id. Includes
+        // the permissions inherited from the ancestors of the role
+        let fetch_role_permissions = client.prepare_typed(
+            &format!("{}SELECT permission.id as permission_id, subject, action FROM ancestor \
+                    LEFT JOIN (role_permission JOIN permission \
+                        ON role_permission.permission = permission.id AND NOT permission.deleted) \
+                    ON ancestor.id = role_permission.role",
+                     with_ancestors("SELECT id FROM role WHERE id = $1 AND NOT deleted")),
+            &[Type::INT4],
+        ).await?;
         let insert_one_into_user_registration = client.prepare_typed(
-            &format!("INSERT INTO user_registration (id, code, username, password, \
-                                                     email, created_at, expires_at) \
-                VALUES ($1, $2, $3, $4, $5, NOW(), NOW() + INTERVAL '{}') \
-                RETURNING created_at, expires_at", crate::constants::USER_REGISTRATION_EXPIRE),
-            &[Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT],
-        ).await.unwrap();
+            "INSERT INTO user_registration (id, username, password, email, \
+                                            created_at, expires_at, sent_at, attempts) \
+                VALUES ($1, $2, $3, $4, NOW(), NOW() + $5 * INTERVAL '1 second', NOW(), 0) \
+                RETURNING created_at, expires_at",
+            &[Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::INT8],
+        ).await?;
         let find_one_from_user_registration = client.prepare_typed(
-            "SELECT code, username, password, email, expires_at FROM user_registration \
+            "SELECT username, password, email, expires_at FROM user_registration \
             WHERE id = $1 AND completed IS NULL LIMIT 1",
             &[Type::TEXT]
-        ).await.unwrap();
+        ).await?;
         let find_one_from_user_registration_without_password = client.prepare_typed(
-            "SELECT code, username, email, expires_at FROM user_registration \
+            "SELECT username, email, expires_at, sent_at FROM user_registration \
             WHERE id = $1 AND completed IS NULL LIMIT 1",
             &[Type::TEXT]
-        ).await.unwrap();
+        ).await?;
         let insert_one_registered_user = client.prepare_typed(
             "INSERT INTO \"user\" (username, password, email, \
                                    created_at, updated_at, deleted) \
                 VALUES ($1, $2, $3, NOW(), NOW(), false) \
                 RETURNING id, created_at",
             &[Type::TEXT, Type::TEXT, Type::TEXT],
-        ).await.unwrap();
+        ).await?;
         let find_default_roles = client.prepare(
             "SELECT name FROM role \
             WHERE \"default\" = TRUE AND NOT deleted",
-        ).await.unwrap();
+        ).await?;
         let complete_registration = client.prepare_typed(
             "UPDATE user_registration SET completed = TRUE \
              WHERE id = $1 AND completed IS NULL",
             &[Type::TEXT],
-        ).await.unwrap();
+        ).await?;
+        // Marks it rejected, the same as query_registration reports it
+        let cancel_registration = client.prepare_typed(
+            "UPDATE user_registration SET completed = FALSE \
+             WHERE id = $1 AND completed IS NULL \
+             RETURNING id",
+            &[Type::TEXT],
+        ).await?;
         let query_registration = client.prepare_typed(
             "SELECT id, username, email, created_at, expires_at, completed \
             FROM user_registration WHERE id = $1 LIMIT 1",
             &[Type::TEXT]
-        ).await.unwrap();
+        ).await?;
+        let find_pending_from_user_registration = client.prepare(
+            "SELECT id, username, email, expires_at, sent_at FROM user_registration \
+            WHERE completed IS NULL AND expires_at > NOW() \
+            ORDER BY sent_at",
+        ).await?;
+        let touch_user_registration = client.prepare_typed(
+            "UPDATE user_registration SET sent_at = NOW() WHERE id = $1",
+            &[Type::TEXT]
+        ).await?;
+        let lock_one = client.prepare_typed(
+            "SELECT id, blocked FROM \"user\" \
+                WHERE id = $1 AND NOT deleted LIMIT 1 FOR UPDATE",
+            &[Type::INT4],
+        ).await?;
         let update_user = client.prepare_typed(
             "UPDATE \"user\" \
                 SET username = CASE WHEN $1 THEN $2 ELSE username END, \
                     email = CASE WHEN $3 THEN $4 ELSE email END, \
                     nickname = CASE WHEN $5 THEN $6 ELSE nickname END, \
                     blocked = CASE WHEN $7 THEN $8 ELSE blocked END, \
-                    updated_at = NOW() \
+                    updated_at = NOW(), \
+                    change_xid = txid_current() \
                 WHERE id = $9 AND NOT DELETED \
                 RETURNING updated_at",
             &[Type::BOOL, Type::TEXT, Type::BOOL, Type::TEXT,
                 Type::BOOL, Type::TEXT, Type::BOOL, Type::BOOL, Type::INT4]
-        ).await.unwrap();
+        ).await?;
+        // Only the users whose state changes are touched, NULL counting as not blocked
+        let set_blocked_bulk = client.prepare_typed(
+            "UPDATE \"user\" \
+                SET blocked = $2, \
+                    updated_at = NOW(), \
+                    change_xid = txid_current() \
+                WHERE id = ANY($1) AND NOT deleted AND (blocked IS TRUE) <> $2 \
+                RETURNING id, updated_at",
+            &[Type::INT4_ARRAY, Type::BOOL]
+        ).await?;
+        let find_ids = client.prepare_typed(
+            "SELECT id FROM \"user\" WHERE id = ANY($1) AND NOT deleted",
+            &[Type::INT4_ARRAY]
+        ).await?;
         let insert_one_into_user_email_updating = client.prepare_typed(
-            &format!("INSERT INTO user_email_updating (id, code, \"user\", new_email, \
-                                                       created_at, expires_at) \
-                VALUES ($1, $2, $3, $4, NOW(), NOW() + INTERVAL '{}') \
-                RETURNING created_at, expires_at", crate::constants::USER_UPDATING_EMAIL_EXPIRE),
-            &[Type::TEXT, Type::TEXT, Type::INT4, Type::TEXT]
-        ).await.unwrap();
+            "INSERT INTO user_email_updating (id, \"user\", new_email, \
+                                              created_at, expires_at, sent_at, attempts) \
+                VALUES ($1, $2, $3, NOW(), NOW() + $4 * INTERVAL '1 second', NOW(), 0) \
+                RETURNING created_at, expires_at",
+            &[Type::TEXT, Type::INT4, Type::TEXT, Type::INT8]
+        ).await?;
         let find_one_from_user_email_updating = client.prepare_typed(
-            "SELECT code, \"user\", new_email, expires_at FROM user_email_updating \
+            "SELECT \"user\", new_email, expires_at FROM user_email_updating \
             WHERE id = $1 AND completed IS NULL LIMIT 1",
             &[Type::TEXT]
-        ).await.unwrap();
+        ).await?;
         let find_one_from_user_email_updating_join_user = client.prepare_typed(
-            "SELECT code, new_email, \"user\".id as uid, \"user\".username, expires_at \
+            "SELECT new_email, \"user\".id as uid, \"user\".username, expires_at, sent_at \
             FROM user_email_updating, \"user\" \
             WHERE user_email_updating.id = $1 AND completed IS NULL AND \
                 user_email_updating.user = \"user\".id AND NOT \"user\".deleted \
             LIMIT 1",
             &[Type::TEXT],
-        ).await.unwrap();
+        ).await?;
         let update_email = client.prepare_typed(
-            "UPDATE \"user\" SET email = $1, updated_at = NOW() \
+            "UPDATE \"user\" SET email = $1, updated_at = NOW(), \
+                    change_xid = txid_current() \
                 WHERE id = $2 AND NOT deleted \
                 RETURNING updated_at",
             &[Type::TEXT, Type::INT4]
-        ).await.unwrap();
+        ).await?;
         let complete_email_updating = client.prepare_typed(
             "UPDATE user_email_updating SET completed = TRUE \
             WHERE id = $1 AND completed IS NULL",
             &[Type::TEXT]
-        ).await.unwrap();
+        ).await?;
+        let cancel_email_updating = client.prepare_typed(
+            "UPDATE user_email_updating SET completed = FALSE \
+             WHERE id = $1 AND completed IS NULL \
+             RETURNING id",
+            &[Type::TEXT],
+        ).await?;
+        let supersede_email_updating = client.prepare_typed(
+            "UPDATE user_email_updating SET completed = FALSE \
+             WHERE \"user\" = $1 AND completed IS NULL \
+             RETURNING id",
+            &[Type::INT4],
+        ).await?;
         let query_email_updating = client.prepare_typed(
             "SELECT id, \"user\", new_email, created_at, expires_at, completed \
             FROM user_email_updating WHERE id = $1 LIMIT 1",
             &[Type::TEXT]
-        ).await.unwrap();
+        ).await?;
+        let find_pending_from_user_email_updating_join_user = client.prepare(
+            "SELECT user_email_updating.id, new_email, \"user\".username, expires_at, sent_at \
+            FROM user_email_updating, \"user\" \
+            WHERE completed IS NULL AND expires_at > NOW() AND \
+                user_email_updating.user = \"user\".id AND NOT \"user\".deleted \
+            ORDER BY sent_at",
+        ).await?;
+        let touch_user_email_updating = client.prepare_typed(
+            "UPDATE user_email_updating SET sent_at = NOW() WHERE id = $1",
+            &[Type::TEXT]
+        ).await?;
+        let find_one_from_email_to_id_username = client.prepare_typed(
+            "SELECT id, username FROM \"user\" \
+                WHERE LOWER(email) = LOWER($1) AND NOT deleted LIMIT 1",
+            &[Type::TEXT],
+        ).await?;
+        let insert_one_into_user_password_reset = client.prepare_typed(
+            "INSERT INTO user_password_reset (id, \"user\", \
+                                              created_at, expires_at, sent_at, attempts) \
+                VALUES ($1, $2, NOW(), NOW() + $3 * INTERVAL '1 second', NOW(), 0) \
+                RETURNING created_at, expires_at",
+            &[Type::TEXT, Type::INT4, Type::INT8]
+        ).await?;
+        let find_one_from_user_password_reset = client.prepare_typed(
+            "SELECT \"user\", expires_at FROM user_password_reset \
+            WHERE id = $1 AND completed IS NULL LIMIT 1",
+            &[Type::TEXT]
+        ).await?;
+        // Other pending resets of the user are completed along with the confirmed one
+        let complete_password_resets = client.prepare_typed(
+            "UPDATE user_password_reset SET completed = TRUE \
+            WHERE \"user\" = $1 AND completed IS NULL",
+            &[Type::INT4]
+        ).await?;
+        let revoke_tokens_from_user = client.prepare_typed(
+            "UPDATE token SET revoked = true \
+                WHERE \"user\" = $1 AND NOT revoked \
+                RETURNING id",
+            &[Type::INT4]
+        ).await?;
+        let find_pending_from_user_password_reset_join_user = client.prepare(
+            "SELECT user_password_reset.id, \"user\".email, \"user\".username, \
+                    expires_at, sent_at \
+            FROM user_password_reset, \"user\" \
+            WHERE completed IS NULL AND expires_at > NOW() AND \
+                user_password_reset.user = \"user\".id AND NOT \"user\".deleted AND \
+                \"user\".email IS NOT NULL \
+            ORDER BY sent_at",
+        ).await?;
+        let touch_user_password_reset = client.prepare_typed(
+            "UPDATE user_password_reset SET sent_at = NOW() WHERE id = $1",
+            &[Type::TEXT]
+        ).await?;
         let find_one_to_password = client.prepare_typed(
             "SELECT password FROM \"user\" \
                 WHERE id = $1 AND NOT deleted LIMIT 1",
             &[Type::INT4]
-        ).await.unwrap();
+        ).await?;
+        // Neither a change visible to clients nor one racing with a password update
+        let upgrade_password = client.prepare_typed(
+            "UPDATE \"user\" SET password = $1 WHERE id = $2 AND password = $3",
+            &[Type::TEXT, Type::INT4, Type::TEXT]
+        ).await?;
+        // Not a change of the user, so neither updated_at nor change_xid is touched
+        let touch_last_login = client.prepare_typed(
+            "UPDATE \"user\" SET last_login_at = NOW() WHERE id = $1",
+            &[Type::INT4]
+        ).await?;
         let update_password = client.prepare_typed(
             "UPDATE \"user\" SET password = $1 \
                 WHERE id = $2 AND NOT DELETED \
                 RETURNING updated_at",
             &[Type::TEXT, Type::INT4]
-        ).await.unwrap();
-        Self {
+        ).await?;
+        let soft_delete_user = client.prepare_typed(
+            "UPDATE \"user\" SET deleted = TRUE, deleted_at = NOW(), updated_at = NOW(), \
+                    change_xid = txid_current() \
+                WHERE id = $1 AND NOT deleted \
+                RETURNING deleted_at",
+            &[Type::INT4]
+        ).await?;
+        // An enabled TOTP keeps its secret until disabled
+        let enroll_totp = client.prepare_typed(
+            "UPDATE \"user\" SET totp_secret = CASE WHEN totp_enabled THEN totp_secret ELSE $1 END \
+                WHERE id = $2 AND NOT deleted \
+                RETURNING username, totp_enabled",
+            &[Type::TEXT, Type::INT4]
+        ).await?;
+        let find_one_to_totp = client.prepare_typed(
+            "SELECT totp_secret, totp_enabled FROM \"user\" \
+                WHERE id = $1 AND NOT deleted LIMIT 1",
+            &[Type::INT4]
+        ).await?;
+        // Fails if the secret is replaced by another enrollment in the meantime
+        let enable_totp = client.prepare_typed(
+            "UPDATE \"user\" SET totp_enabled = TRUE \
+                WHERE id = $1 AND totp_secret = $2 AND NOT totp_enabled AND NOT deleted",
+            &[Type::INT4, Type::TEXT]
+        ).await?;
+        let disable_totp = client.prepare_typed(
+            "UPDATE \"user\" SET totp_secret = NULL, totp_enabled = FALSE \
+                WHERE id = $1 AND NOT deleted \
+                RETURNING id",
+            &[Type::INT4]
+        ).await?;
+        Ok(Self {
             find_one_from_username_to_id_password_blocked,
             find_one_from_email_to_id_password_blocked,
             find_one_from_username_to_id,
+            upgrade_password,
+            touch_last_login,
+            check_usernames_existence,
             find_one_from_email_to_id,
             check_user_blocked,
             fetch_permission,
             fetch_default_permission,
+            fetch_role_name,
             check_extra_roles,
             find_one_from_username_to_username_email,
             find_one_from_username_email_to_username_email,
             insert_one,
             insert_one_roles,
+            add_roles_to_user,
+            remove_roles_from_user,
             fetch_avatars,
             update_avatars,
+            replace_avatars,
             find_one,
             find_one_public,
             find_one_to_username,
+            find_changes,
             find_roles_only_id,
             find_roles_short,
+            find_role_assignments,
             find_roles_without_permissions,
             find_permissions_only_id,
             find_permissions_short,
             find_permissions_all,
             fetch_permission_tree,
             fetch_default_permission_tree,
+            fetch_role_permissions,
             insert_one_into_user_registration,
             find_one_from_user_registration,
             find_one_from_user_registration_without_password,
             insert_one_registered_user,
             find_default_roles,
             complete_registration,
+            cancel_registration,
             query_registration,
+            find_pending_from_user_registration,
+            touch_user_registration,
+            lock_one,
             update_user,
+            set_blocked_bulk,
+            find_ids,
             insert_one_into_user_email_updating,
             find_one_from_user_email_updating,
             find_one_from_user_email_updating_join_user,
             update_email,
             complete_email_updating,
+            cancel_email_updating,
+            supersede_email_updating,
             query_email_updating,
+            find_pending_from_user_email_updating_join_user,
+            touch_user_email_updating,
+            find_one_from_email_to_id_username,
+            insert_one_into_user_password_reset,
+            find_one_from_user_password_reset,
+            complete_password_resets,
+            revoke_tokens_from_user,
+            find_pending_from_user_password_reset_join_user,
+            touch_user_password_reset,
             find_one_to_password,
             update_password,
-        }
+            soft_delete_user,
+            enroll_totp,
+            find_one_to_totp,
+            enable_totp,
+            disable_totp,
+            codes,
+            passwords,
+            breached_passwords,
+            audit,
+            deleted_user_reservation: deleted_user_reservation as i64,
+            registration_ttl: registration_ttl as i64,
+            email_updating_ttl: email_updating_ttl as i64,
+            password_reset_ttl: password_reset_ttl as i64,
+        })
+    }
+    // Rejects passwords of the breached password list, which is looked up within the same
+    // web::block as the hashing. The range API may take a network round trip, so this is called
+    // before any transaction starts
+    async fn hash_new_password(&self, password: String) -> Result<String> {
+        let range = self.breached_passwords.fetch_range(&password).await?;
+        let (passwords, breached_passwords) = (self.passwords, self.breached_passwords.clone());
+        let hash = block(move || {
+            if breached_passwords.contains(&password, range.as_deref()) {
+                return Ok(None);
+            }
+            passwords.hash(&password).map(Some)
+        }).await?;
+        hash.ok_or(Error::PasswordCompromised)
     }
     pub async fn find_one_from_username_to_id_password_blocked(
         &self, client: &Client, username: &str,
@@ -842,12 +1635,13 @@ impl Query {
             .query(&self.find_one_from_username_to_id_password_blocked, &[&username])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
         Ok(UserIdPasswordBlocked {
             id: row.get("id"),
             password: row.get("password"),
             blocked: row.get("blocked"),
+            totp_secret: row.get("totp_secret"),
         })
     }
     pub async fn find_one_from_email_to_id_password_blocked(
@@ -857,12 +1651,13 @@ impl Query {
             .query(&self.find_one_from_email_to_id_password_blocked, &[&email])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
         Ok(UserIdPasswordBlocked {
             id: row.get("id"),
             password: row.get("password"),
             blocked: row.get("blocked"),
+            totp_secret: row.get("totp_secret"),
         })
     }
     pub async fn check_user_valid_by_id(
@@ -873,9 +1668,9 @@ impl Query {
             .query(&self.check_user_blocked, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
-        if row.get::<&str, Option<bool>>("blocked").contains(&true) {
+        if row.get::<&str, Option<bool>>("blocked") == Some(true) {
             return Err(Error::UserBlocked);
         }
         Ok(())
@@ -893,6 +1688,15 @@ impl Query {
             })
             .collect())
     }
+    // Sorted by subject and then action, so that clients can diff it cheaply
+    pub async fn fetch_effective_permission(
+        &self, client: &Client, id: i32,
+    ) -> Result<Vec<PermissionSubjectAction>> {
+        let mut permissions = self.fetch_permission(client, id).await?;
+        permissions.sort();
+        permissions.dedup();
+        Ok(permissions)
+    }
     pub async fn fetch_default_permission(
         &self, client: &Client,
     ) -> Result<Vec<PermissionSubjectAction>> {
@@ -906,6 +1710,28 @@ impl Query {
             })
             .collect())
     }
+    // Includes the implicit default role
+    pub async fn fetch_role_name(
+        &self, client: &Client, id: i32,
+    ) -> Result<Vec<String>> {
+        let rows = client
+            .query(&self.fetch_role_name, &[&id])
+            .await?;
+        Ok(rows.iter()
+            .map(|row| row.get("name"))
+            .collect())
+    }
+    // Sorted by name. Unlike fetch_role_name, the implicit default role is not included
+    pub async fn find_role_assignments(
+        &self, client: &Client, id: i32,
+    ) -> Result<Vec<RoleAssignment>> {
+        Ok(client
+            .query(&self.find_role_assignments, &[&id])
+            .await?
+            .iter()
+            .map(RoleAssignment::from)
+            .collect())
+    }
     pub async fn check_extra_roles(
         &self, client: &Client, id: i32, roles: &[String],
     ) -> Result<Vec<String>> {
@@ -917,69 +1743,171 @@ impl Query {
             .collect();
         Ok(results)
     }
+    // The roles are recorded as granted by granted_by. Names matching no role are skipped, which
+    // the returned roles tell
+    #[allow(clippy::too_many_arguments)]
     pub async fn insert_one(
         &self, client: &mut Client, username: &str, password: &str,
-        roles: &[String], email: &Option<String>, nickname: &Option<String>,
+        roles: &[String], granted_by: Option<i32>, email: &Option<String>,
+        nickname: &Option<String>,
     ) -> Result<UserIdCreatedAt> {
+        let password = self.hash_new_password(password.into()).await?;
         let transaction = client.build_transaction()
             .isolation_level(IsolationLevel::RepeatableRead)
             .start()
             .await?;
         let duplicated_rows = match email {
             Some(email) => transaction
-                .query(&self.find_one_from_username_email_to_username_email, &[&username, &email])
+                .query(&self.find_one_from_username_email_to_username_email,
+                       &[&username, &email, &self.deleted_user_reservation])
                 .await?,
             None => transaction
-                .query(&self.find_one_from_username_to_username_email, &[&username])
+                .query(&self.find_one_from_username_to_username_email,
+                       &[&username, &self.deleted_user_reservation])
                 .await?,
         };
-        if let Some(row) = duplicated_rows.get(0) {
+        if let Some(row) = duplicated_rows.first() {
             return Err(Error::DuplicatedUser {
-                field: if row.get::<&str, String>("username") == username { "username".into() }
-                else { "email".into() }
+                field: if row.get::<&str, String>("username").to_lowercase()
+                    == username.to_lowercase() { "username".into() } else { "email".into() }
             });
         }
-        let password = String::from(password);
-        let password = block(move || bcrypt::hash(password, crate::constants::BCRYPT_COST))
-            .await?;
         let user = transaction
             .query_one(&self.insert_one, &[&username, &password, &email, &nickname])
-            .await?;
+            .await
+            .map_err(duplicated_user)?;
         let id: i32 = user.get("id");
-        if !roles.is_empty() {
+        let roles = if roles.is_empty() {
+            Vec::new()
+        } else {
             transaction
-                .query(&self.insert_one_roles, &[&id, &roles])
-                .await?;
-        }
+                .query(&self.insert_one_roles, &[&id, &roles, &granted_by])
+                .await?
+                .iter()
+                .map(|row| row.get("name"))
+                .collect()
+        };
         transaction.commit()
             .await?;
         Ok(UserIdCreatedAt {
             id,
             created_at: user.get("created_at"),
+            roles,
         })
     }
+    // Returns the roles actually added, which the user did not have yet, along with all the
+    // roles of the user afterwards
+    pub async fn add_roles_to_user(
+        &self, client: &mut Client, actor: &Actor, id: i32, roles: &[String],
+    ) -> Result<(Vec<i32>, Vec<RoleShort>)> {
+        self.update_roles_of_user(client, actor, id, &self.add_roles_to_user,
+                                  &[&id, &roles, &actor.uid], "user-role.add").await
+    }
+    // Returns the roles actually removed, along with all the roles of the user afterwards
+    pub async fn remove_roles_from_user(
+        &self, client: &mut Client, actor: &Actor, id: i32, roles: &[String],
+    ) -> Result<(Vec<i32>, Vec<RoleShort>)> {
+        self.update_roles_of_user(client, actor, id, &self.remove_roles_from_user,
+                                  &[&id, &roles], "user-role.remove").await
+    }
+    // Audited only if any role is changed. Added roles are granted by the actor
+    async fn update_roles_of_user(
+        &self, client: &mut Client, actor: &Actor, id: i32, statement: &Statement,
+        params: &[&(dyn ToSql + Sync)], action: &str,
+    ) -> Result<(Vec<i32>, Vec<RoleShort>)> {
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        if transaction.query(&self.lock_one, &[&id]).await?.is_empty() {
+            return Err(Error::UserNotFound);
+        }
+        let changed: Vec<i32> = transaction
+            .query(statement, params)
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        if !changed.is_empty() {
+            self.audit
+                .record(&transaction, actor, action, id, serde_json::json!({ "roles": changed }))
+                .await?;
+        }
+        let roles = transaction
+            .query(&self.find_roles_short, &[&vec![id]])
+            .await?
+            .iter()
+            .map(RoleShort::from)
+            .collect();
+        transaction.commit()
+            .await?;
+        Ok((changed, roles))
+    }
+    // Users with TOTP enabled need a valid code besides the password. Emails not found are retried
+    // as usernames. last_login_at is set along the way, which never fails the login. A weak
+    // password hash is returned for upgrade_password instead of being rehashed here, so that the
+    // login does not wait for it
     pub async fn check_user_valid(
         &self, client: &Client, credit: &EitherUsernameOrEmail, password: &str,
-    ) -> Result<i32> {
+        totp_code: Option<&str>,
+    ) -> Result<(i32, Option<PasswordUpgrade>)> {
         let user = match credit {
             EitherUsernameOrEmail::Username(username) =>
                 self.find_one_from_username_to_id_password_blocked(client, username)
                     .await?,
             EitherUsernameOrEmail::Email(email) =>
-                self.find_one_from_email_to_id_password_blocked(client, email)
-                    .await?,
+                match self.find_one_from_email_to_id_password_blocked(client, email).await {
+                    Err(Error::UserNotFound) =>
+                        self.find_one_from_username_to_id_password_blocked(client, email)
+                            .await?,
+                    result => result?,
+                },
         };
-        let password = String::from(password);
+        let plaintext = String::from(password);
         let hash = user.password.clone();
-        let verified = block(move || bcrypt::verify(password, &hash))
+        let passwords = self.passwords;
+        let verified = block(move || passwords.verify(&plaintext, &hash))
             .await?;
         if !verified {
             return Err(Error::WrongPassword);
         }
-        if user.blocked.contains(&true) {
+        if user.blocked == Some(true) {
             return Err(Error::UserBlocked);
         }
-        Ok(user.id)
+        if let Some(secret) = &user.totp_secret {
+            let code = totp_code.ok_or(Error::TotpRequired)?;
+            if !totp::verify(secret, code, Utc::now().timestamp() as u64) {
+                return Err(Error::WrongTotpCode);
+            }
+        }
+        if let Err(e) = client.execute(&self.touch_last_login, &[&user.id]).await {
+            warn!("failed to record login of user {}: {}", user.id, e);
+        }
+        let upgrade = if self.passwords.is_weaker(&user.password) {
+            Some(PasswordUpgrade {
+                id: user.id,
+                password: String::from(password),
+                hash: user.password,
+            })
+        } else {
+            None
+        };
+        Ok((user.id, upgrade))
+    }
+    // Rehashes the password of a login whose hash is weaker than the ones made now, of another
+    // algorithm or a lower cost. Only the plaintext at a successful login allows that. Skipped if
+    // the password was changed meanwhile
+    pub async fn upgrade_password(
+        &self, client: &Client, upgrade: PasswordUpgrade,
+    ) -> Result<()> {
+        let PasswordUpgrade { id, password, hash } = upgrade;
+        let passwords = self.passwords;
+        let new_hash = block(move || passwords.hash(&password))
+            .await?;
+        client
+            .execute(&self.upgrade_password, &[&new_hash, &id, &hash])
+            .await?;
+        Ok(())
     }
     pub async fn fetch_avatars(
         &self, client: &Client, id: i32,
@@ -988,25 +1916,38 @@ impl Query {
             .query(&self.fetch_avatars, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
         Ok(UserAvatars {
             avatar: row.get("avatar"),
-            avatar128: row.get("avatar128"),
+            avatar_thumbnails: get_avatar_thumbnails(row, "avatar_thumbnails"),
+            avatar_webp: row.get("avatar_webp"),
+            avatar_thumbnails_webp: get_avatar_thumbnails(row, "avatar_thumbnails_webp"),
         })
     }
     pub async fn update_avatars(
-        &self, client: &Client, id: i32,
-        avatar: &Option<String>, avatar128: &Option<String>
+        &self, client: &Client, id: i32, avatars: &UserAvatars,
     ) -> Result<DateTime<Utc>> {
         let rows = client
-            .query(&self.update_avatars, &[avatar, avatar128, &id])
+            .query(&self.update_avatars, &[&avatars.avatar, &Json(&avatars.avatar_thumbnails),
+                &avatars.avatar_webp, &Json(&avatars.avatar_thumbnails_webp), &id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
         Ok(row.get("updated_at"))
     }
+    // None if the avatar is no longer current, as a later upload replaced it or the user was
+    // deleted meanwhile
+    pub async fn replace_avatars(
+        &self, client: &Client, id: i32, current: &str, avatars: &UserAvatars,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let rows = client
+            .query(&self.replace_avatars, &[&avatars.avatar, &Json(&avatars.avatar_thumbnails),
+                &avatars.avatar_webp, &Json(&avatars.avatar_thumbnails_webp), &id, &current])
+            .await?;
+        Ok(rows.first().map(|row| row.get("updated_at")))
+    }
     pub async fn find_one_with_permissions_and_roles(
         &self, client: &mut Client, uid: i32,
         user_access_level: UserAccessLevel,
@@ -1026,17 +1967,14 @@ impl Query {
                 .await?,
         };
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
         let mut user: User = match user_access_level {
             UserAccessLevel::Public => UserPublic::from(row).into(),
             UserAccessLevel::WithoutRoles | UserAccessLevel::All =>
                 UserWithoutRoles::from(row).into(),
         };
-        let add_user_roles = match user_access_level {
-            UserAccessLevel::All => true,
-            _ => false,
-        };
+        let add_user_roles = matches!(user_access_level, UserAccessLevel::All);
         let mut roles: Vec<Role> = Vec::new();
         let mut user2role: HashMap<i32, Vec<i32>> = HashMap::new();
         if add_user_roles || role_access_level.is_some() || permission_access_level.is_some() {
@@ -1045,17 +1983,17 @@ impl Query {
                     for row in transaction.query(&self.find_roles_short, &[&vec![uid]]).await? {
                         let id = row.get("id");
                         roles.push(RoleShort::from(&row).into());
-                        user2role.entry(row.get("user_id")).or_insert_with(Vec::new).push(id);
+                        user2role.entry(row.get("user_id")).or_default().push(id);
                     }
                 Some(RoleAccessLevel::WithoutPermissions) | Some(RoleAccessLevel::All) =>
                     for row in transaction.query(&self.find_roles_without_permissions, &[&vec![uid]]).await? {
                         let id = row.get("id");
                         roles.push(RoleWithoutPermissions::from(&row).into());
-                        user2role.entry(row.get("user_id")).or_insert_with(Vec::new).push(id);
+                        user2role.entry(row.get("user_id")).or_default().push(id);
                     }
                 None => {
                     for row in transaction.query(&self.find_roles_only_id, &[&vec![uid]]).await? {
-                        user2role.entry(row.get("user_id")).or_insert_with(Vec::new).push(row.get("id"));
+                        user2role.entry(row.get("user_id")).or_default().push(row.get("id"));
                     }
                 }
             }
@@ -1069,10 +2007,8 @@ impl Query {
                 _ => unreachable!(),
             }
         }
-        let add_role_permissions = match role_access_level {
-            Some(RoleAccessLevel::ShortWithPermissions) | Some(RoleAccessLevel::All) => true,
-            _ => false,
-        };
+        let add_role_permissions = matches!(role_access_level,
+            Some(RoleAccessLevel::ShortWithPermissions) | Some(RoleAccessLevel::All));
         let mut permissions: Vec<Permission> = Vec::new();
         let mut role2permission: HashMap<i32, Vec<i32>> = HashMap::new();
         if add_role_permissions || permission_access_level.is_some() {
@@ -1086,19 +2022,19 @@ impl Query {
                     for row in transaction.query(&self.find_permissions_short, &[&roles_id]).await? {
                         let id = row.get("id");
                         permissions.push(PermissionShort::from(&row).into());
-                        role2permission.entry(row.get("role_id")).or_insert_with(Vec::new).push(id);
+                        role2permission.entry(row.get("role_id")).or_default().push(id);
                     }
                 }
                 Some(PermissionAccessLevel::All) => {
                     for row in transaction.query(&self.find_permissions_all, &[&roles_id]).await? {
                         let id = row.get("id");
                         permissions.push(PermissionAll::from(&row).into());
-                        role2permission.entry(row.get("role_id")).or_insert_with(Vec::new).push(id);
+                        role2permission.entry(row.get("role_id")).or_default().push(id);
                     }
                 }
                 None => {
                     for row in transaction.query(&self.find_permissions_only_id, &[&roles_id]).await? {
-                        role2permission.entry(row.get("role_id")).or_insert_with(Vec::new).push(row.get("id"));
+                        role2permission.entry(row.get("role_id")).or_default().push(row.get("id"));
                     }
                 }
             }
@@ -1136,113 +2072,251 @@ impl Query {
                 .await?,
         };
         let mut tree = HashMap::new();
+        let mut ancestors = HashMap::new();
         for row in rows {
             let id: i32 = row.get("role_id");
-            tree.entry(id)
-                .or_insert_with(HashMap::new)
-                .insert(row.get("permission_id"), PermissionSubjectAction {
+            ancestors.entry(id)
+                .or_insert_with(HashSet::new)
+                .insert(row.get("ancestor_id"));
+            let permissions = tree.entry(id)
+                .or_insert_with(HashMap::new);
+            if let Some(permission) = row.get::<_, Option<i32>>("permission_id") {
+                permissions.insert(permission, PermissionSubjectAction {
                     subject: row.get("subject"),
                     action: row.get("action"),
                 });
+            }
+        }
+        Ok(PermissionTree::new(tree, ancestors))
+    }
+    pub async fn fetch_role_permissions(
+        &self, client: &Client, id: i32,
+    ) -> Result<HashMap<i32, PermissionSubjectAction>> {
+        let rows = client
+            .query(&self.fetch_role_permissions, &[&id])
+            .await?;
+        if rows.is_empty() {
+            return Err(Error::RoleNotFound);
         }
-        Ok(PermissionTree::new(tree))
+        Ok(rows.iter()
+            .filter_map(|row| row.get::<_, Option<i32>>("permission_id")
+                .map(|permission| (permission, PermissionSubjectAction {
+                    subject: row.get("subject"),
+                    action: row.get("action"),
+                })))
+            .collect())
+    }
+    pub async fn find_one_to_username(
+        &self, client: &Client, id: i32,
+    ) -> Result<String> {
+        Ok(client
+            .query(&self.find_one_to_username, &[&id])
+            .await?
+            .first()
+            .ok_or_else(|| Error::UserNotFound)?
+            .get("username"))
+    }
+    // Continues after the change of since_id made by since_xid
+    pub async fn find_changes(
+        &self, client: &Client, since_xid: i64, since_id: i32, limit: i64,
+    ) -> Result<Vec<UserChange>> {
+        Ok(client
+            .query(&self.find_changes, &[&since_xid, &since_id, &limit])
+            .await?
+            .iter()
+            .map(UserChange::from)
+            .collect())
+    }
+    // The count of the users matching filter, or of all of them without filter
+    pub async fn count_users(
+        &self, client: &Client, filter: Option<&str>, with_email: bool,
+    ) -> Result<i64> {
+        let (condition, params) = match filter {
+            Some(filter) => user_list_config(with_email).parse_to_postgres_params(filter)?,
+            None => None,
+        }.unwrap_or_else(|| ("TRUE".into(), Vec::new()));
+        Ok(client
+            .query_one(format!("SELECT COUNT(*) FROM \"user\" \
+                WHERE NOT deleted AND ({})", condition).as_str(),
+                       &params.iter().map(|x| x as &(dyn ToSql + Sync)).collect::<Vec<_>>())
+            .await?
+            .get(0))
+    }
+    // A page of the users matching filter in the order of sort, and the count of all of them.
+    // Values of the filter are bound as parameters instead of being put into the SQL
+    pub async fn list_users(
+        &self, client: &Client, filter: Option<&str>, sort: Option<&str>, with_email: bool,
+        limit: i64, offset: i64,
+    ) -> Result<(Vec<UserWithoutRoles>, i64)> {
+        let config = user_list_config(with_email);
+        let (condition, mut params) = match filter {
+            Some(filter) => config.parse_to_postgres_params(filter)?,
+            None => None,
+        }.unwrap_or_else(|| ("TRUE".into(), Vec::new()));
+        let order = match sort {
+            Some(sort) => config.parse_order_by(sort)?,
+            None => None,
+        }.map(|x| x + ", id ASC").unwrap_or_else(|| "id ASC".into());
+        let total = self.count_users(client, filter, with_email).await?;
+        let (limit_param, offset_param) = (params.len() + 1, params.len() + 2);
+        params.push(limit.into());
+        params.push(offset.into());
+        let users = client
+            .query(format!("SELECT id, username, email, nickname, avatar, avatar_thumbnails, \
+                        avatar_webp, avatar_thumbnails_webp, blocked, created_at, updated_at, \
+                        last_login_at \
+                FROM \"user\" \
+                WHERE NOT deleted AND ({}) \
+                ORDER BY {} LIMIT ${} OFFSET ${}", condition, order, limit_param, offset_param)
+                       .as_str(),
+                   &params.iter().map(|x| x as &(dyn ToSql + Sync)).collect::<Vec<_>>())
+            .await?
+            .iter()
+            .map(UserWithoutRoles::from)
+            .collect();
+        Ok((users, total))
+    }
+    // The users whose username or nickname contains text, exact matches first, then those
+    // starting with it, then the shorter names. The text is matched literally, with its % and _
+    // escaped by cashier-query
+    pub async fn search_users(
+        &self, client: &Client, text: &str, limit: i64,
+    ) -> Result<Vec<UserPublic>> {
+        let (condition, mut params) = user_search_config()
+            .query_to_postgres_params(&Filter::Equal {
+                field: None,
+                value: text.into(),
+            })?;
+        let (text_param, limit_param) = (params.len() + 1, params.len() + 2);
+        params.push(text.to_lowercase().into());
+        params.push(limit.into());
+        Ok(client
+            .query(format!("SELECT id, username, nickname, avatar, avatar_thumbnails, \
+                        avatar_webp, avatar_thumbnails_webp, created_at \
+                FROM \"user\" \
+                WHERE NOT deleted AND ({condition}) \
+                ORDER BY CASE \
+                    WHEN lower(username) = ${text} OR lower(nickname) = ${text} THEN 0 \
+                    WHEN position(${text} IN lower(username)) = 1 \
+                        OR position(${text} IN lower(nickname)) = 1 THEN 1 \
+                    ELSE 2 END, \
+                    length(coalesce(nickname, username)), id \
+                LIMIT ${limit}", condition = condition, text = text_param, limit = limit_param)
+                       .as_str(),
+                   &params.iter().map(|x| x as &(dyn ToSql + Sync)).collect::<Vec<_>>())
+            .await?
+            .iter()
+            .map(UserPublic::from)
+            .collect())
     }
     pub async fn check_username_existence(
         &self, client: &Client, username: &str,
     ) -> Result<bool> {
         let rows = client
-            .query(&self.find_one_from_username_to_id, &[&username])
+            .query(&self.find_one_from_username_to_id, &[&username, &self.deleted_user_reservation])
             .await?;
         Ok(!rows.is_empty())
     }
+    // Every distinct username given is a key of the result
+    pub async fn check_usernames_existence(
+        &self, client: &Client, usernames: &[String],
+    ) -> Result<HashMap<String, bool>> {
+        let rows = client
+            .query(&self.check_usernames_existence, &[&usernames, &self.deleted_user_reservation])
+            .await?;
+        Ok(rows.iter()
+            .map(|row| (row.get("username"), row.get("exists")))
+            .collect())
+    }
     pub async fn check_email_existence(
         &self, client: &Client, email: &str,
     ) -> Result<bool> {
         let rows = client
-            .query(&self.find_one_from_email_to_id, &[&email])
+            .query(&self.find_one_from_email_to_id, &[&email, &self.deleted_user_reservation])
             .await?;
         Ok(!rows.is_empty())
     }
+    #[allow(clippy::too_many_arguments)]
     pub async fn register_user(
         &self, client: &Client,
-        app_data: web::Data<AppState>, // for smtp
+        app_data: web::Data<AppState>, // for mailer
         sender: &str, site: &str,
         username: &str, email: &str, password: &str,
+        locale: Option<Locale>,
     ) -> Result<UserRegistration> {
         let duplicated_rows = client
             .query(&self.find_one_from_username_email_to_username_email,
-                   &[&username, &email])
+                   &[&username, &email, &self.deleted_user_reservation])
             .await?;
-        if let Some(row) = duplicated_rows.get(0) {
+        if let Some(row) = duplicated_rows.first() {
             return Err(Error::DuplicatedUser {
-                field: if row.get::<&str, String>("username") == username { "username".into() }
-                else { "email".into() }
+                field: if row.get::<&str, String>("username").to_lowercase()
+                    == username.to_lowercase() { "username".into() } else { "email".into() }
             });
         }
+        let password = self.hash_new_password(password.into()).await?;
         let mut rng = thread_rng();
         let id: String = iter::repeat(())
             .map(|()| rng.sample(Alphanumeric))
             .take(24)
             .collect();
-        let code: String = iter::repeat(())
-            .map(|()| rng.sample(Digit))
-            .take(6)
-            .collect();
+        let code = generate_code();
         let message = register_user_email(sender.parse()?, email.parse()?,
-                                        site, username, &id, &code)?;
-        block(move || app_data.smtp.send(&message))
-            .await?;
-        let password = String::from(password);
-        let password = block(move || bcrypt::hash(password, crate::constants::BCRYPT_COST))
-            .await?;
+                                          site, username, &id, &code, locale)?;
+        send_email(app_data, message, &code)?;
         let row = client
             .query_one(&self.insert_one_into_user_registration,
-                       &[&id, &code, &username, &password, &email])
+                       &[&id, &username, &password, &email, &self.registration_ttl])
             .await?;
-        Ok(UserRegistration {
+        let registration = UserRegistration {
             id,
             code,
             created_at: row.get("created_at"),
             expires_at: row.get("expires_at"),
-        })
-    }
-    pub async fn confirm_registration(
+        };
+        self.codes
+            .put(client, CodeKind::Registration, &registration.id, &registration.code,
+                 time_to_live(registration.expires_at))
+            .await?;
+        Ok(registration)
+    }
+    pub async fn confirm_registration(
         &self, client: &mut Client, id: &str, code: &str,
     ) -> Result<UserCreatedByRegistration> {
-        let transaction = client.build_transaction()
-            .isolation_level(IsolationLevel::RepeatableRead)
-            .start()
-            .await?;
-        let rows = transaction
+        let rows = client
             .query(&self.find_one_from_user_registration, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserRegistrationNotFound)?;
-        let real_code: String = row.get("code");
         let username: String = row.get("username");
         let password: String = row.get("password");
         let email: String = row.get("email");
         let expires_at: DateTime<Utc> = row.get("expires_at");
         if expires_at < Utc::now() {
-            return Err(Error::UserRegistrationExpired);
+            return Err(Error::UserRegistrationExpired { expires_at });
         }
-        if real_code != code {
+        if !self.consume_code(client, CodeKind::Registration, id, code).await? {
             return Err(Error::UserRegistrationWrongCode);
         }
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
         let duplicated_rows = transaction
             .query(&self.find_one_from_username_email_to_username_email,
-                   &[&username, &email])
+                   &[&username, &email, &self.deleted_user_reservation])
             .await?;
-        if let Some(row) = duplicated_rows.get(0) {
+        if let Some(row) = duplicated_rows.first() {
             return Err(Error::DuplicatedUser {
-                field: if row.get::<&str, String>("username") == username { "username".into() }
-                else { "email".into() }
+                field: if row.get::<&str, String>("username").to_lowercase()
+                    == username.to_lowercase() { "username".into() } else { "email".into() }
             });
         }
         let user = transaction
             .query_one(&self.insert_one_registered_user, &[&username, &password, &email])
-            .await?;
+            .await
+            .map_err(duplicated_user)?;
         let user_id: i32 = user.get("id");
         let roles = transaction
             .query(&self.find_default_roles, &[])
@@ -1251,7 +2325,7 @@ impl Query {
             .map(|x| x.get("name"))
             .collect::<Vec<String>>();
         transaction
-            .query(&self.insert_one_roles, &[&user_id, &roles])
+            .query(&self.insert_one_roles, &[&user_id, &roles, &None::<i32>])
             .await?;
         transaction
             .query(&self.complete_registration, &[&id])
@@ -1265,6 +2339,24 @@ impl Query {
             created_at: user.get("created_at"),
         })
     }
+    // Whoever knows the id may cancel it, the same as confirming or resending it. Its code is
+    // dropped along with it
+    pub async fn cancel_registration(
+        &self, client: &Client, id: &str,
+    ) -> Result<()> {
+        if client.query(&self.cancel_registration, &[&id]).await?.is_empty() {
+            return Err(Error::UserRegistrationNotFound);
+        }
+        self.codes.consume(client, CodeKind::Registration, id).await?;
+        Ok(())
+    }
+    pub async fn list_registrations(
+        &self, client: &Client, filter: Option<&str>, sort: Option<&str>, limit: i64, offset: i64,
+    ) -> Result<(Vec<UserRegistrationPublic>, i64)> {
+        list_page(client, registration_list_config(), "user_registration",
+                  "id, username, email, created_at, expires_at, completed",
+                  Page { filter, sort, limit, offset }).await
+    }
     pub async fn query_registration(
         &self, client: &Client, id: &str,
     ) -> Result<UserRegistrationPublic> {
@@ -1272,40 +2364,47 @@ impl Query {
             .query(&self.query_registration, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserRegistrationNotFound)?;
         let registration = UserRegistrationPublic::from(row);
         if registration.completed.is_none() && registration.expires_at < Utc::now() {
-            return Err(Error::UserRegistrationExpired);
+            return Err(Error::UserRegistrationExpired { expires_at: registration.expires_at });
         }
         Ok(registration)
     }
+    #[allow(clippy::too_many_arguments)]
     pub async fn resend_registration_email(
         &self, client: &Client,
         app_data: web::Data<AppState>,
-        sender: &str, site: &str, id: &str,
+        sender: &str, site: &str, id: &str, cooldown: u64,
+        locale: Option<Locale>,
     ) -> Result<()> {
         let rows = client
             .query(&self.find_one_from_user_registration_without_password, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserRegistrationNotFound)?;
-        let code: String = row.get("code");
         let username: String = row.get("username");
         let email: String = row.get("email");
         let expires_at: DateTime<Utc> = row.get("expires_at");
         if expires_at < Utc::now() {
-            return Err(Error::UserRegistrationExpired);
+            return Err(Error::UserRegistrationExpired { expires_at });
         }
+        check_resend_cooldown(row.get("sent_at"), cooldown)?;
+        let code = self.code_for_resend(client, CodeKind::Registration, id, expires_at).await?;
         let message = register_user_email(sender.parse()?, email.parse()?,
-                                          site, &username, &id, &code)?;
-        block(move || app_data.smtp.send(&message))
+                                          site, &username, id, &code, locale)?;
+        send_email(app_data, message, &code)?;
+        client
+            .execute(&self.touch_user_registration, &[&id])
             .await?;
         Ok(())
     }
+    // Blocking and unblocking are audited, when they change the user
+    #[allow(clippy::too_many_arguments)]
     pub async fn update_user(
-        &self, client: &mut Client, id: i32,
+        &self, client: &mut Client, actor: &Actor, id: i32,
         username: &Option<String>, email: &Option<Option<String>>,
         nickname: &Option<Option<String>>, blocked: &Option<Option<bool>>,
     ) -> Result<DateTime<Utc>> {
@@ -1313,27 +2412,32 @@ impl Query {
             .isolation_level(IsolationLevel::RepeatableRead)
             .start()
             .await?;
-        match username {
-            Some(username) => if transaction
-                .query(&self.find_one_from_username_to_id, &[&username])
+        // Every constraint is checked before anything is written. Keeping its own username or
+        // email is not a conflict
+        let was_blocked: Option<bool> = transaction
+            .query(&self.lock_one, &[&id])
+            .await?
+            .first()
+            .ok_or(Error::UserNotFound)?
+            .get("blocked");
+        if let Some(username) = username {
+            if transaction
+                .query(&self.find_one_from_username_to_id,
+                       &[&username, &self.deleted_user_reservation])
                 .await?
-                .get(0)
-                .map(|x| x.get::<&str, i32>("id"))
-                .is_some() {
+                .iter()
+                .any(|row| row.get::<_, i32>("id") != id) {
                 return Err(DuplicatedUser { field: "username".into() });
             }
-            _ => ()
         }
-        match email {
-            Some(Some(email)) => if transaction
-                .query(&self.find_one_from_email_to_id, &[&email])
+        if let Some(Some(email)) = email {
+            if transaction
+                .query(&self.find_one_from_email_to_id, &[&email, &self.deleted_user_reservation])
                 .await?
-                .get(0)
-                .map(|x| x.get::<&str, i32>("id"))
-                .is_some() {
+                .iter()
+                .any(|row| row.get::<_, i32>("id") != id) {
                 return Err(DuplicatedUser { field: "email".into() });
             }
-            _ => ()
         }
         let enable_username = username.is_some();
         let enable_email = email.is_some();
@@ -1344,30 +2448,84 @@ impl Query {
                    &[&enable_username, &username,
                        &enable_email, &email.clone().flatten(),
                        &enable_nickname, &nickname.clone().flatten(),
-                       &enable_blocked, &blocked.clone().flatten(),
+                       &enable_blocked, &(*blocked).flatten(),
                    &id])
-            .await?;
+            .await
+            .map_err(duplicated_user)?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
+        if let Some(blocked) = blocked {
+            if *blocked != was_blocked {
+                let action = if *blocked == Some(true) { "user.block" } else { "user.unblock" };
+                self.audit
+                    .record(&transaction, actor, action, id,
+                            serde_json::json!({ "blocked": blocked }))
+                    .await?;
+            }
+        }
         transaction.commit()
             .await?;
         Ok(row.get("updated_at"))
     }
+    // Blocks or unblocks all of ids at once, each change audited the same as update_user does.
+    // Missing or deleted users are reported instead of failing the others
+    pub async fn set_blocked_bulk(
+        &self, client: &mut Client, actor: &Actor, ids: &[i32], blocked: bool,
+    ) -> Result<BlockedBulk> {
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        let updated: Vec<(i32, DateTime<Utc>)> = transaction
+            .query(&self.set_blocked_bulk, &[&ids, &blocked])
+            .await?
+            .iter()
+            .map(|row| (row.get("id"), row.get("updated_at")))
+            .collect();
+        let found: HashSet<i32> = transaction
+            .query(&self.find_ids, &[&ids])
+            .await?
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+        let action = if blocked { "user.block" } else { "user.unblock" };
+        for (id, _) in updated.iter() {
+            self.audit
+                .record(&transaction, actor, action, *id,
+                        serde_json::json!({ "blocked": blocked }))
+                .await?;
+        }
+        transaction.commit()
+            .await?;
+        let mut not_found = ids.iter()
+            .filter(|id| !found.contains(*id))
+            .cloned()
+            .collect::<Vec<_>>();
+        not_found.sort_unstable();
+        not_found.dedup();
+        Ok(BlockedBulk {
+            updated,
+            not_found,
+        })
+    }
+    #[allow(clippy::too_many_arguments)]
+    // A new request supersedes the pending ones of the user, so that only the latest code works
     pub async fn update_email(
-        &self, client: &Client,
-        app_data: web::Data<AppState>, // for smtp
+        &self, client: &mut Client,
+        app_data: web::Data<AppState>, // for mailer
         sender: &str, site: &str,
         uid: i32, new_email: &str,
+        locale: Option<Locale>,
     ) -> Result<UserEmailUpdating> {
         let username: String = client
             .query(&self.find_one_to_username, &[&uid])
             .await?
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?
             .get("username");
         let rows = client
-            .query(&self.find_one_from_email_to_id, &[&new_email])
+            .query(&self.find_one_from_email_to_id, &[&new_email, &self.deleted_user_reservation])
             .await?;
         if !rows.is_empty() {
             return Err(Error::DuplicatedUser { field: "email".into() });
@@ -1377,24 +2535,48 @@ impl Query {
             .map(|()| rng.sample(Alphanumeric))
             .take(24)
             .collect();
-        let code: String = iter::repeat(())
-            .map(|()| rng.sample(Digit))
-            .take(6)
-            .collect();
+        let code = generate_code();
         let message = update_user_email(sender.parse()?, new_email.parse()?,
-                                        site, &username, &id, &code)?;
-        block(move || app_data.smtp.send(&message))
+                                        site, &username, &id, &code, locale)?;
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
             .await?;
-        let row = client
+        // Serializes the requests of the user
+        if transaction.query(&self.lock_one, &[&uid]).await?.is_empty() {
+            return Err(Error::UserNotFound);
+        }
+        let superseded: Vec<String> = transaction
+            .query(&self.supersede_email_updating, &[&uid])
+            .await?
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+        let row = transaction
             .query_one(&self.insert_one_into_user_email_updating,
-                       &[&id, &code, &uid, &new_email])
+                       &[&id, &uid, &new_email, &self.email_updating_ttl])
             .await?;
-        Ok(UserEmailUpdating {
+        transaction.commit().await?;
+        // The superseded rows can no longer be confirmed anyway, so their codes are only tidied up
+        for superseded in superseded.iter() {
+            if let Err(e) = self.codes.consume(client, CodeKind::EmailUpdating, superseded).await {
+                warn!("failed to drop the code of superseded email updating {}: {}",
+                      superseded, e);
+            }
+        }
+        let updating = UserEmailUpdating {
             id,
             code,
             created_at: row.get("created_at"),
             expires_at: row.get("expires_at"),
-        })
+        };
+        self.codes
+            .put(client, CodeKind::EmailUpdating, &updating.id, &updating.code,
+                 time_to_live(updating.expires_at))
+            .await?;
+        // Only once the request and its code are stored, so that the code always confirms it
+        send_email(app_data, message, &updating.code)?;
+        Ok(updating)
     }
     pub async fn confirm_email_updating(
         &self, client: &mut Client, auth: &Auth, id: &str, code: &str,
@@ -1406,17 +2588,12 @@ impl Query {
                 action: "confirm".into(),
             })
         }
-        let transaction = client.build_transaction()
-            .isolation_level(IsolationLevel::RepeatableRead)
-            .start()
-            .await?;
-        let rows = transaction
+        let rows = client
             .query(&self.find_one_from_user_email_updating, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserEmailUpdatingNotFound)?;
-        let real_code: String = row.get("code");
         let user: i32 = row.get("user");
         let new_email: String = row.get("new_email");
         let expires_at: DateTime<Utc> = row.get("expires_at");
@@ -1430,20 +2607,25 @@ impl Query {
         if expires_at < Utc::now() {
             return Err(Error::UserEmailUpdatingExpired);
         }
-        if real_code != code {
+        if !self.consume_code(client, CodeKind::EmailUpdating, id, code).await? {
             return Err(Error::UserEmailUpdatingWrongCode);
         }
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
         let rows = transaction
-            .query(&self.find_one_from_email_to_id, &[&new_email])
+            .query(&self.find_one_from_email_to_id, &[&new_email, &self.deleted_user_reservation])
             .await?;
         if !rows.is_empty() {
             return Err(Error::DuplicatedUser { field: "email".into() });
         }
         let rows = transaction
             .query(&self.update_email, &[&new_email, &user])
-            .await?;
+            .await
+            .map_err(duplicated_user)?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?;
         transaction
             .query(&self.complete_email_updating, &[&id])
@@ -1455,6 +2637,43 @@ impl Query {
             updated_at: row.get("updated_at"),
         })
     }
+    pub async fn cancel_email_updating(
+        &self, client: &Client, auth: &Auth, id: &str,
+    ) -> Result<()> {
+        if !auth.has_permission("user-email-updating", "cancel-self") &&
+            !auth.has_permission("user-email-updating", "cancel") {
+            return Err(Error::PermissionDenied {
+                subject: "user-email-updating".into(),
+                action: "cancel".into(),
+            })
+        }
+        let rows = client
+            .query(&self.find_one_from_user_email_updating, &[&id])
+            .await?;
+        let user: i32 = rows
+            .first()
+            .ok_or_else(|| Error::UserEmailUpdatingNotFound)?
+            .get("user");
+        if (auth.claims.is_none() || auth.claims.as_ref().unwrap().uid != user) &&
+            !auth.has_permission("user-email-updating", "cancel") {
+            return Err(Error::PermissionDenied {
+                subject: "user-email-updating".into(),
+                action: "cancel".into(),
+            })
+        }
+        if client.query(&self.cancel_email_updating, &[&id]).await?.is_empty() {
+            return Err(Error::UserEmailUpdatingNotFound);
+        }
+        self.codes.consume(client, CodeKind::EmailUpdating, id).await?;
+        Ok(())
+    }
+    pub async fn list_email_updating(
+        &self, client: &Client, filter: Option<&str>, sort: Option<&str>, limit: i64, offset: i64,
+    ) -> Result<(Vec<UserEmailUpdatingPublic>, i64)> {
+        list_page(client, email_updating_list_config(), "user_email_updating",
+                  "id, \"user\", new_email, created_at, expires_at, completed",
+                  Page { filter, sort, limit, offset }).await
+    }
     pub async fn query_email_updating(
         &self, client: &Client, auth: &Auth, id: &str,
     ) -> Result<UserEmailUpdatingPublic> {
@@ -1469,7 +2688,7 @@ impl Query {
             .query(&self.query_email_updating, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserEmailUpdatingNotFound)?;
         let registration = UserEmailUpdatingPublic::from(row);
         if (auth.claims.is_none() || auth.claims.as_ref().unwrap().uid != registration.user) &&
@@ -1484,11 +2703,13 @@ impl Query {
         }
         Ok(registration)
     }
+    #[allow(clippy::too_many_arguments)]
     pub async fn resend_email_updating_email(
         &self, client: &Client,
         app_data: web::Data<AppState>,
         auth: &Auth,
-        sender: &str, site: &str, id: &str,
+        sender: &str, site: &str, id: &str, cooldown: u64,
+        locale: Option<Locale>,
     ) -> Result<()> {
         if !auth.has_permission("user-email-updating", "resend-self") &&
             !auth.has_permission("user-email-updating", "resend") {
@@ -1501,9 +2722,8 @@ impl Query {
             .query(&self.find_one_from_user_email_updating_join_user, &[&id])
             .await?;
         let row = rows
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserEmailUpdatingNotFound)?;
-        let code: String = row.get("code");
         let uid: i32 = row.get("uid");
         let username: String = row.get("username");
         let email: String = row.get("new_email");
@@ -1518,15 +2738,223 @@ impl Query {
         if expires_at < Utc::now() {
             return Err(Error::UserEmailUpdatingExpired);
         }
+        check_resend_cooldown(row.get("sent_at"), cooldown)?;
+        let code = self.code_for_resend(client, CodeKind::EmailUpdating, id, expires_at).await?;
         let message = update_user_email(sender.parse()?, email.parse()?,
-                                        site, &username, &id, &code)?;
-        block(move || app_data.smtp.send(&message))
+                                        site, &username, id, &code, locale)?;
+        send_email(app_data, message, &code)?;
+        client
+            .execute(&self.touch_user_email_updating, &[&id])
             .await?;
         Ok(())
     }
+    // Nothing tells whether a user has the email, so None is not meant to reach the requester.
+    // The mailer is checked first for the same reason
+    pub async fn request_password_reset(
+        &self, client: &Client,
+        app_data: web::Data<AppState>, // for mailer
+        sender: &str, site: &str, email: &str,
+    ) -> Result<Option<UserPasswordReset>> {
+        if let Mailer::Disabled = app_data.mailer {
+            return Err(Error::EmailNotConfigured);
+        }
+        let rows = client
+            .query(&self.find_one_from_email_to_id_username, &[&email])
+            .await?;
+        let row = match rows.first() {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let uid: i32 = row.get("id");
+        let username: String = row.get("username");
+        let mut rng = thread_rng();
+        let id: String = iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .take(24)
+            .collect();
+        let code = generate_code();
+        let message = reset_password_email(sender.parse()?, email.parse()?,
+                                           site, &username, &id, &code)?;
+        send_email(app_data, message, &code)?;
+        let row = client
+            .query_one(&self.insert_one_into_user_password_reset,
+                       &[&id, &uid, &self.password_reset_ttl])
+            .await?;
+        let reset = UserPasswordReset {
+            id,
+            code,
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+        };
+        self.codes
+            .put(client, CodeKind::PasswordReset, &reset.id, &reset.code,
+                 time_to_live(reset.expires_at))
+            .await?;
+        Ok(Some(reset))
+    }
+    // All the tokens of the user are revoked in the same transaction. Whoever holds the code is
+    // audited as the user
+    pub async fn confirm_password_reset(
+        &self, client: &mut Client, id: &str, code: &str, new_password: String,
+    ) -> Result<PasswordResetConfirmed> {
+        let rows = client
+            .query(&self.find_one_from_user_password_reset, &[&id])
+            .await?;
+        let row = rows
+            .first()
+            .ok_or_else(|| Error::PasswordResetNotFound)?;
+        let user: i32 = row.get("user");
+        let expires_at: DateTime<Utc> = row.get("expires_at");
+        if expires_at < Utc::now() {
+            return Err(Error::PasswordResetExpired);
+        }
+        // Before the code is consumed, so that it still works with another password
+        let password_hash = self.hash_new_password(new_password).await?;
+        if !self.consume_code(client, CodeKind::PasswordReset, id, code).await? {
+            return Err(Error::PasswordResetWrongCode);
+        }
+        let transaction = client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        let updated_at = transaction
+            .query(&self.update_password, &[&password_hash, &user])
+            .await?
+            .first()
+            .ok_or_else(|| Error::UserNotFound)?
+            .get("updated_at");
+        transaction
+            .execute(&self.complete_password_resets, &[&user])
+            .await?;
+        let revoked_tokens = transaction
+            .query(&self.revoke_tokens_from_user, &[&user])
+            .await?
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+        let actor = Actor {
+            uid: user,
+            impersonator: None,
+        };
+        self.audit
+            .record(&transaction, &actor, "user-password.reset", user,
+                    serde_json::json!({ "reset": id }))
+            .await?;
+        transaction.commit().await?;
+        Ok(PasswordResetConfirmed {
+            id: user,
+            updated_at,
+            revoked_tokens,
+        })
+    }
+    // Oldest sent first
+    pub async fn find_pending_emails(&self, client: &Client) -> Result<Vec<PendingEmail>> {
+        let registrations = client
+            .query(&self.find_pending_from_user_registration, &[])
+            .await?;
+        let email_updatings = client
+            .query(&self.find_pending_from_user_email_updating_join_user, &[])
+            .await?;
+        let password_resets = client
+            .query(&self.find_pending_from_user_password_reset_join_user, &[])
+            .await?;
+        let mut pending = registrations.iter()
+            .map(|row| PendingEmail {
+                kind: CodeKind::Registration,
+                id: row.get("id"),
+                username: row.get("username"),
+                email: row.get("email"),
+                expires_at: row.get("expires_at"),
+                sent_at: row.get("sent_at"),
+            })
+            .chain(email_updatings.iter()
+                .map(|row| PendingEmail {
+                    kind: CodeKind::EmailUpdating,
+                    id: row.get("id"),
+                    username: row.get("username"),
+                    email: row.get("new_email"),
+                    expires_at: row.get("expires_at"),
+                    sent_at: row.get("sent_at"),
+                }))
+            .chain(password_resets.iter()
+                .map(|row| PendingEmail {
+                    kind: CodeKind::PasswordReset,
+                    id: row.get("id"),
+                    username: row.get("username"),
+                    email: row.get("email"),
+                    expires_at: row.get("expires_at"),
+                    sent_at: row.get("sent_at"),
+                }))
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|x| x.sent_at);
+        Ok(pending)
+    }
+    pub async fn resend_pending_email(
+        &self, client: &Client,
+        app_data: web::Data<AppState>,
+        sender: &str, site: &str, pending: &PendingEmail,
+    ) -> Result<()> {
+        let code = self.code_for_resend(client, pending.kind, &pending.id, pending.expires_at)
+            .await?;
+        let (message, touch) = match pending.kind {
+            CodeKind::Registration => (
+                register_user_email(sender.parse()?, pending.email.parse()?, site,
+                                    &pending.username, &pending.id, &code, None)?,
+                &self.touch_user_registration,
+            ),
+            CodeKind::EmailUpdating => (
+                update_user_email(sender.parse()?, pending.email.parse()?, site,
+                                  &pending.username, &pending.id, &code, None)?,
+                &self.touch_user_email_updating,
+            ),
+            CodeKind::PasswordReset => (
+                reset_password_email(sender.parse()?, pending.email.parse()?, site,
+                                     &pending.username, &pending.id, &code)?,
+                &self.touch_user_password_reset,
+            ),
+        };
+        send_email(app_data, message, &code)?;
+        client
+            .execute(touch, &[&pending.id])
+            .await?;
+        Ok(())
+    }
+    // Consumed before the confirmation is carried out so that concurrent confirmations cannot
+    // both pass. Too many wrong attempts drop the code
+    async fn consume_code(
+        &self, client: &Client, kind: CodeKind, id: &str, code: &str,
+    ) -> Result<bool> {
+        match self.codes.get(client, kind, id).await? {
+            Some(real_code) if real_code == code =>
+                Ok(self.codes.consume(client, kind, id).await?),
+            Some(_) => {
+                if self.codes.attempts_inc(client, kind, id).await? >=
+                    crate::constants::CONFIRMATION_CODE_MAX_ATTEMPTS {
+                    self.codes.consume(client, kind, id).await?;
+                }
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+    // A dropped code is replaced by a new one for the rest of the pending row's lifetime
+    async fn code_for_resend(
+        &self, client: &Client, kind: CodeKind, id: &str, expires_at: DateTime<Utc>,
+    ) -> Result<String> {
+        if let Some(code) = self.codes.get(client, kind, id).await? {
+            return Ok(code);
+        }
+        let code = generate_code();
+        self.codes
+            .put(client, kind, id, &code, time_to_live(expires_at))
+            .await?;
+        Ok(code)
+    }
     pub async fn update_password(
-        &self, client: &mut Client, id: i32, password: String, old_password: Option<String>
+        &self, client: &mut Client, actor: &Actor, id: i32, password: String,
+        old_password: Option<String>,
     ) -> Result<DateTime<Utc>> {
+        let password_hash = self.hash_new_password(password).await?;
         let transaction = client.build_transaction()
             .isolation_level(IsolationLevel::RepeatableRead)
             .start()
@@ -1535,24 +2963,845 @@ impl Query {
             let old_password_hash: String = transaction
                 .query(&self.find_one_to_password, &[&id])
                 .await?
-                .get(0)
+                .first()
                 .ok_or_else(|| Error::UserNotFound)?
                 .get("password");
-            let verified = block(move || bcrypt::verify(old_password, &old_password_hash))
+            let passwords = self.passwords;
+            let verified = block(move || passwords.verify(&old_password, &old_password_hash))
                 .await?;
             if !verified {
                 return Err(Error::WrongPassword);
             }
         }
-        let password_hash = block(move || bcrypt::hash(password, crate::constants::BCRYPT_COST))
-            .await?;
         let updated_at = transaction
             .query(&self.update_password, &[&password_hash, &id])
             .await?
-            .get(0)
+            .first()
             .ok_or_else(|| Error::UserNotFound)?
             .get("updated_at");
+        self.audit
+            .record(&transaction, actor, "user-password.update", id, serde_json::Value::Null)
+            .await?;
         transaction.commit().await?;
         Ok(updated_at)
     }
+    // Stores a new secret, which takes effect once confirmed, and returns its otpauth URI
+    pub async fn enroll_totp(&self, client: &Client, uid: i32) -> Result<String> {
+        let secret = totp::generate_secret();
+        let rows = client
+            .query(&self.enroll_totp, &[&secret, &uid])
+            .await?;
+        let row = rows
+            .first()
+            .ok_or_else(|| Error::UserNotFound)?;
+        if row.get("totp_enabled") {
+            return Err(Error::TotpAlreadyEnabled);
+        }
+        let username: String = row.get("username");
+        Ok(totp::otpauth_uri(crate::constants::TOTP_ISSUER, &username, &secret))
+    }
+    pub async fn confirm_totp(&self, client: &Client, uid: i32, code: &str) -> Result<()> {
+        let rows = client
+            .query(&self.find_one_to_totp, &[&uid])
+            .await?;
+        let row = rows
+            .first()
+            .ok_or_else(|| Error::UserNotFound)?;
+        if row.get("totp_enabled") {
+            return Err(Error::TotpAlreadyEnabled);
+        }
+        let secret: String = row.get::<_, Option<String>>("totp_secret")
+            .ok_or(Error::TotpNotEnrolled)?;
+        if !totp::verify(&secret, code, Utc::now().timestamp() as u64) {
+            return Err(Error::WrongTotpCode);
+        }
+        let updated = client
+            .execute(&self.enable_totp, &[&uid, &secret])
+            .await?;
+        if updated == 0 {
+            return Err(Error::TotpNotEnrolled);
+        }
+        Ok(())
+    }
+    // Needs a current code, or the password for a user who has lost the authenticator
+    pub async fn disable_totp(
+        &self, client: &Client, uid: i32, code: Option<&str>, password: Option<String>,
+    ) -> Result<()> {
+        if let Some(code) = code {
+            let secret: String = client
+                .query(&self.find_one_to_totp, &[&uid])
+                .await?
+                .first()
+                .ok_or_else(|| Error::UserNotFound)?
+                .get::<_, Option<String>>("totp_secret")
+                .ok_or(Error::TotpNotEnrolled)?;
+            if !totp::verify(&secret, code, Utc::now().timestamp() as u64) {
+                return Err(Error::WrongTotpCode);
+            }
+        } else if let Some(password) = password {
+            let hash: String = client
+                .query(&self.find_one_to_password, &[&uid])
+                .await?
+                .first()
+                .ok_or_else(|| Error::UserNotFound)?
+                .get("password");
+            let passwords = self.passwords;
+            let verified = block(move || passwords.verify(&password, &hash))
+                .await?;
+            if !verified {
+                return Err(Error::WrongPassword);
+            }
+        } else {
+            return Err(Error::TotpRequired);
+        }
+        client
+            .query(&self.disable_totp, &[&uid])
+            .await?
+            .first()
+            .ok_or_else(|| Error::UserNotFound)?;
+        Ok(())
+    }
+    // Returns when the user was deleted, from which its username and email stay reserved
+    pub async fn soft_delete_user(&self, client: &Client, id: i32) -> Result<DateTime<Utc>> {
+        Ok(client
+            .query(&self.soft_delete_user, &[&id])
+            .await?
+            .first()
+            .ok_or_else(|| Error::UserNotFound)?
+            .get("deleted_at"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::testing::TestDatabase;
+    use serde_json::json;
+
+    const ACTOR: Actor = Actor { uid: 0, impersonator: None };
+
+    fn user_public(nickname: Option<&str>) -> UserPublic {
+        let nickname = nickname.map(String::from);
+        UserPublic {
+            id: 1,
+            username: "alice".into(),
+            nickname,
+            avatar: None,
+            avatar_thumbnails: AvatarThumbnails::new(),
+            avatar_webp: None,
+            avatar_thumbnails_webp: AvatarThumbnails::new(),
+            created_at: Utc::now(),
+        }
+    }
+
+    async fn deleted_user_test_database() -> Option<(TestDatabase, Client, Query)> {
+        let db = TestDatabase::new(&["--deleted-user-reservation", "3600"]).await?;
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let email = Some(String::from("alice@example.com"));
+        let id = query.insert_one(&mut client, "alice", "password", &[], None, &email, &None)
+            .await
+            .unwrap()
+            .id;
+        client
+            .execute("UPDATE \"user\" SET deleted = TRUE, deleted_at = NOW(), updated_at = NOW() \
+                WHERE id = $1", &[&id])
+            .await
+            .unwrap();
+        Some((db, client, query))
+    }
+
+    #[actix_rt::test]
+    async fn deleted_user_reserved_test() {
+        let (_db, mut client, query) = match deleted_user_test_database().await {
+            Some(x) => x,
+            None => return,
+        };
+        let email = Some(String::from("bob@example.com"));
+        match query.insert_one(&mut client, "alice", "password", &[], None, &email, &None).await {
+            Err(Error::DuplicatedUser { field }) => assert_eq!(field, "username"),
+            e => panic!("unexpected result {:?}", e),
+        }
+        let email = Some(String::from("alice@example.com"));
+        match query.insert_one(&mut client, "bob", "password", &[], None, &email, &None).await {
+            Err(Error::DuplicatedUser { field }) => assert_eq!(field, "email"),
+            e => panic!("unexpected result {:?}", e),
+        }
+        assert!(query.check_username_existence(&client, "alice").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn breached_passwords_unreachable_test() {
+        // Nothing listens on port 1
+        let db = match TestDatabase::new(&[
+            "--password-breached", "range-api",
+            "--password-breached-url", "http://127.0.0.1:1/range",
+        ]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        // Rejected rather than let through unchecked
+        match query.insert_one(&mut client, "alice", "password", &[], None, &None, &None).await {
+            Err(Error::BreachedPasswords(_)) => (),
+            e => panic!("unexpected result {:?}", e.map(|x| x.id)),
+        }
+        assert_eq!(client
+                       .query_one("SELECT COUNT(*) FROM \"user\" WHERE username = 'alice'", &[])
+                       .await
+                       .unwrap()
+                       .get::<_, i64>(0), 0);
+        // Unless the deployment lets them through
+        let db = match TestDatabase::new(&[
+            "--password-breached", "range-api",
+            "--password-breached-url", "http://127.0.0.1:1/range",
+            "--password-breached-fail-open",
+        ]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let id = query.insert_one(&mut client, "alice", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        query.update_password(&mut client, &ACTOR, id, "new-password".into(), None)
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn bcrypt_cost_test() {
+        let db = match TestDatabase::new(&["--bcrypt-cost", "4"]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let id = query.insert_one(&mut client, "alice", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let hash: String = client
+            .query_one("SELECT password FROM \"user\" WHERE id = $1", &[&id])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(hash.split('$').nth(2), Some("04"));
+        let credit = EitherUsernameOrEmail::Username("alice".into());
+        let (uid, _) = query.check_user_valid(&client, &credit, "password", None)
+            .await
+            .unwrap();
+        assert_eq!(uid, id);
+        // Hashes made with another cost still verify
+        let hash = bcrypt::hash("password", 5).unwrap();
+        client
+            .execute("UPDATE \"user\" SET password = $1 WHERE id = $2", &[&hash, &id])
+            .await
+            .unwrap();
+        let (uid, _) = query.check_user_valid(&client, &credit, "password", None)
+            .await
+            .unwrap();
+        assert_eq!(uid, id);
+    }
+
+    #[actix_rt::test]
+    async fn upgrade_password_test() {
+        let db = match TestDatabase::new(&["--bcrypt-cost", "5"]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let id = query.insert_one(&mut client, "alice", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let hash = || async {
+            client
+                .query_one("SELECT password FROM \"user\" WHERE id = $1", &[&id])
+                .await
+                .unwrap()
+                .get::<_, String>(0)
+        };
+        let weak = bcrypt::hash("password", 4).unwrap();
+        client
+            .execute("UPDATE \"user\" SET password = $1 WHERE id = $2", &[&weak, &id])
+            .await
+            .unwrap();
+        let credit = EitherUsernameOrEmail::Username("alice".into());
+        assert!(query.check_user_valid(&client, &credit, "wrong-password", None).await.is_err());
+        assert_eq!(hash().await, weak);
+        let (uid, upgrade) = query.check_user_valid(&client, &credit, "password", None)
+            .await
+            .unwrap();
+        assert_eq!(uid, id);
+        // The login returns before the rehash, which is left to the caller
+        assert_eq!(hash().await, weak);
+        query.upgrade_password(&client, upgrade.unwrap()).await.unwrap();
+        let upgraded = hash().await;
+        assert_eq!(upgraded.split('$').nth(2), Some("05"));
+        // Costlier hashes are kept
+        let (_, upgrade) = query.check_user_valid(&client, &credit, "password", None)
+            .await
+            .unwrap();
+        assert!(upgrade.is_none());
+        // A password changed before the rehash is not overwritten
+        client
+            .execute("UPDATE \"user\" SET password = $1 WHERE id = $2", &[&weak, &id])
+            .await
+            .unwrap();
+        let (_, upgrade) = query.check_user_valid(&client, &credit, "password", None)
+            .await
+            .unwrap();
+        client
+            .execute("UPDATE \"user\" SET password = $1 WHERE id = $2", &[&upgraded, &id])
+            .await
+            .unwrap();
+        query.upgrade_password(&client, upgrade.unwrap()).await.unwrap();
+        assert_eq!(hash().await, upgraded);
+    }
+
+    #[actix_rt::test]
+    async fn replace_avatars_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let id = query.insert_one(&mut client, "alice", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let avatars = |avatar: &str| UserAvatars {
+            avatar: Some(avatar.into()),
+            ..UserAvatars::default()
+        };
+        query.update_avatars(&client, id, &avatars("first.upload.png")).await.unwrap();
+        query.update_avatars(&client, id, &avatars("second.upload.png")).await.unwrap();
+        // The first upload was replaced meanwhile
+        assert!(query.replace_avatars(&client, id, "first.upload.png", &avatars("first.png"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(query.replace_avatars(&client, id, "second.upload.png", &avatars("second.png"))
+            .await
+            .unwrap()
+            .is_some());
+        let current = query.fetch_avatars(&client, id).await.unwrap();
+        assert_eq!(current.avatar.as_deref(), Some("second.png"));
+    }
+
+    #[actix_rt::test]
+    async fn argon2_password_test() {
+        let db = match TestDatabase::new(&["--password-algorithm", "argon2id"]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let id = query.insert_one(&mut client, "alice", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let hash = || async {
+            client
+                .query_one("SELECT password FROM \"user\" WHERE id = $1", &[&id])
+                .await
+                .unwrap()
+                .get::<_, String>(0)
+        };
+        assert!(hash().await.starts_with("$argon2id$"));
+        // Hashes from before the migration still verify, and are migrated on login
+        let old = bcrypt::hash("password", 4).unwrap();
+        client
+            .execute("UPDATE \"user\" SET password = $1 WHERE id = $2", &[&old, &id])
+            .await
+            .unwrap();
+        let credit = EitherUsernameOrEmail::Username("alice".into());
+        let (uid, upgrade) = query.check_user_valid(&client, &credit, "password", None)
+            .await
+            .unwrap();
+        assert_eq!(uid, id);
+        query.upgrade_password(&client, upgrade.unwrap()).await.unwrap();
+        assert!(hash().await.starts_with("$argon2id$"));
+        let (uid, _) = query.check_user_valid(&client, &credit, "password", None)
+            .await
+            .unwrap();
+        assert_eq!(uid, id);
+    }
+
+    #[actix_rt::test]
+    async fn check_usernames_existence_test() {
+        let (_db, mut client, query) = match deleted_user_test_database().await {
+            Some(x) => x,
+            None => return,
+        };
+        query.insert_one(&mut client, "bob", "password", &[], None, &None, &None).await.unwrap();
+        let usernames = ["alice", "bob", "carol", "bob"].iter()
+            .map(|&x| String::from(x))
+            .collect::<Vec<_>>();
+        let result = query.check_usernames_existence(&client, &usernames).await.unwrap();
+        assert_eq!(result.len(), 3);
+        // Reserved after deletion
+        assert!(result["alice"]);
+        assert!(result["bob"]);
+        assert!(!result["carol"]);
+        assert!(query.check_usernames_existence(&client, &[]).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn deleted_user_freed_test() {
+        let (_db, mut client, query) = match deleted_user_test_database().await {
+            Some(x) => x,
+            None => return,
+        };
+        // Counted from the deletion, however recently the user was updated
+        client
+            .execute("UPDATE \"user\" SET deleted_at = NOW() - INTERVAL '2 hours', \
+                updated_at = NOW() WHERE deleted", &[])
+            .await
+            .unwrap();
+        assert!(!query.check_username_existence(&client, "alice").await.unwrap());
+        let email = Some(String::from("alice@example.com"));
+        query.insert_one(&mut client, "alice", "password", &[], None, &email, &None).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn duplicated_user_index_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let email = Some(String::from("alice@example.com"));
+        query.insert_one(&mut client, "alice", "password", &[], None, &email, &None)
+            .await
+            .unwrap();
+        // Written past the checks, the way a concurrent request gets there
+        let insert = "INSERT INTO \"user\" (username, password, email, \
+                                           created_at, updated_at, deleted) \
+            VALUES ($1, 'password', $2, NOW(), NOW(), FALSE)";
+        let err = client.execute(insert, &[&"ALICE", &None::<String>]).await.unwrap_err();
+        match duplicated_user(err) {
+            Error::DuplicatedUser { field } => assert_eq!(field, "username"),
+            e => panic!("unexpected result {:?}", e),
+        }
+        let err = client.execute(insert, &[&"bob", &"Alice@Example.com"]).await.unwrap_err();
+        match duplicated_user(err) {
+            Error::DuplicatedUser { field } => assert_eq!(field, "email"),
+            e => panic!("unexpected result {:?}", e),
+        }
+        // Deleted users are left to the checks, which reserve them for a while
+        client
+            .execute("UPDATE \"user\" SET deleted = TRUE, deleted_at = NOW()", &[])
+            .await
+            .unwrap();
+        client.execute(insert, &[&"ALICE", &"Alice@Example.com"]).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn case_insensitive_login_test() {
+        let db = match TestDatabase::new(&["--bcrypt-cost", "4"]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let email = Some(String::from("Alice@Example.com"));
+        let id = query.insert_one(&mut client, "Alice", "password", &[], None, &email, &None)
+            .await
+            .unwrap()
+            .id;
+        for credit in &[
+            EitherUsernameOrEmail::Username("alice".into()),
+            EitherUsernameOrEmail::Username("ALICE".into()),
+            EitherUsernameOrEmail::Email("alice@example.com".into()),
+        ] {
+            let (uid, _) = query.check_user_valid(&client, credit, "password", None)
+                .await
+                .unwrap();
+            assert_eq!(uid, id);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn update_user_conflict_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        for username in &["alice", "bob"] {
+            let email = Some(format!("{}@example.com", username));
+            query.insert_one(&mut client, username, "password", &[], None, &email, &None)
+                .await
+                .unwrap();
+        }
+        let bob: i32 = client
+            .query_one("SELECT id FROM \"user\" WHERE username = 'bob'", &[])
+            .await
+            .unwrap()
+            .get("id");
+        // The username and nickname are valid, but the email is taken
+        match query.update_user(&mut client, &ACTOR, bob, &Some("carol".into()),
+                                &Some(Some("alice@example.com".into())),
+                                &Some(Some("Carol".into())), &None).await {
+            Err(Error::DuplicatedUser { field }) => assert_eq!(field, "email"),
+            e => panic!("unexpected result {:?}", e),
+        }
+        let row = client
+            .query_one("SELECT username, email, nickname FROM \"user\" WHERE id = $1", &[&bob])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>("username"), "bob");
+        assert_eq!(row.get::<_, Option<String>>("email"), Some("bob@example.com".into()));
+        assert_eq!(row.get::<_, Option<String>>("nickname"), None);
+        // Keeping its own username is fine
+        query.update_user(&mut client, &ACTOR, bob, &Some("bob".into()), &None,
+                          &Some(Some("Bob".into())), &None).await.unwrap();
+    }
+
+    #[test]
+    fn mask_email_test() {
+        assert_eq!(mask_email("alice@example.com"), "a***@example.com");
+        assert_eq!(mask_email("@example.com"), "***@example.com");
+        assert_eq!(mask_email("alice"), "***");
+    }
+
+    #[test]
+    fn permission_tree_holders_test() {
+        let tree = PermissionTree::new(
+            vec![(1, vec![(10, PermissionSubjectAction {
+                subject: "user".into(),
+                action: "read".into(),
+            })].into_iter().collect()), (2, HashMap::new())].into_iter().collect(),
+            vec![(1, vec![1, 4].into_iter().collect()), (2, vec![2].into_iter().collect())]
+                .into_iter()
+                .collect(),
+        );
+        // 4 is an ancestor of 1
+        assert!(tree.has_role(1) && tree.has_role(2) && tree.has_role(4) && !tree.has_role(3));
+        assert!(tree.has_permission(10) && !tree.has_permission(11));
+    }
+
+    #[test]
+    fn display_name_test() {
+        let mut user = user_public(None);
+        let json = serde_json::to_value(&user).unwrap();
+        assert_eq!(json["display_name"], json!("alice"));
+        assert_eq!(json["nickname"], json!(null));
+        assert_eq!(json["username"], json!("alice"));
+        assert_eq!(json["id"], json!(1));
+        // Follows a nickname set afterwards
+        user.nickname = Some("Alice".into());
+        let json = serde_json::to_value(&user).unwrap();
+        assert_eq!(json["display_name"], json!("Alice"));
+        assert_eq!(json["nickname"], json!("Alice"));
+        assert_eq!(json["username"], json!("alice"));
+        let user = UserAll::from((UserWithoutRoles {
+            id: 1,
+            username: "alice".into(),
+            email: None,
+            nickname: None,
+            avatar: None,
+            avatar_thumbnails: AvatarThumbnails::new(),
+            avatar_webp: None,
+            avatar_thumbnails_webp: AvatarThumbnails::new(),
+            blocked: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            last_login_at: None,
+        }, vec![2]));
+        let json = serde_json::to_value(User::from(user)).unwrap();
+        assert_eq!(json["access"], json!("all"));
+        assert_eq!(json["display_name"], json!("alice"));
+        assert_eq!(json["roles"], json!([2]));
+        match serde_json::from_value(json).unwrap() {
+            User::All(user) => assert_eq!(user.roles, vec![2]),
+            user => panic!("unexpected user {:?}", user),
+        }
+    }
+
+    // Changes show up once the transactions running when they were made have ended, which the
+    // tests sharing the server may hold back for a moment
+    async fn wait_changes(
+        query: &Query, client: &Client, since: (i64, i32),
+        until: impl Fn(&[UserChange]) -> bool,
+    ) -> Vec<UserChange> {
+        for _ in 0..100 {
+            let changes = query.find_changes(client, since.0, since.1, 100).await.unwrap();
+            if until(&changes) {
+                return changes;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("changes did not show up");
+    }
+
+    #[actix_rt::test]
+    async fn find_changes_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let alice = query.insert_one(&mut client, "alice", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let bob = query.insert_one(&mut client, "bob", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        query.update_user(&mut client, &ACTOR, alice, &None, &None,
+                          &Some(Some("Alice".into())), &None)
+            .await
+            .unwrap();
+        client
+            .execute("UPDATE \"user\" SET deleted = TRUE, deleted_at = NOW(), updated_at = NOW(), \
+                change_xid = txid_current() WHERE id = $1", &[&bob])
+            .await
+            .unwrap();
+        let changes = wait_changes(&query, &client, (0, 0),
+                                   |x| x.iter().any(|x| x.id == bob && x.deleted)).await;
+        let at = changes.iter().position(|x| x.id == alice).unwrap();
+        // Alice is moved behind bob's creation by her update, bob behind her by his deletion
+        assert_eq!(changes[at..].iter().map(|x| (x.id, x.deleted)).collect::<Vec<_>>(),
+                   vec![(alice, false), (bob, true)]);
+        assert!(changes[at].xid < changes[at + 1].xid);
+        assert_eq!(changes[at].user.as_ref().unwrap().nickname.as_deref(), Some("Alice"));
+        assert!(changes[at + 1].user.is_none());
+        // Paging continues after the last change
+        let since = if at == 0 { (0, 0) } else { (changes[at - 1].xid, changes[at - 1].id) };
+        let first = query.find_changes(&client, since.0, since.1, 1).await.unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].id, alice);
+        let rest = query.find_changes(&client, first[0].xid, first[0].id, 1).await.unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].id, bob);
+        assert!(query.find_changes(&client, rest[0].xid, rest[0].id, 100).await.unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn find_changes_overlapping_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let alice = query.insert_one(&mut client, "alice", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let bob = query.insert_one(&mut client, "bob", "password", &[], None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let since = wait_changes(&query, &client, (0, 0), |x| x.iter().any(|x| x.id == bob))
+            .await
+            .last()
+            .map(|x| (x.xid, x.id))
+            .unwrap();
+        // Alice is updated by a transaction that is yet to commit
+        let mut first = db.connect().await;
+        let transaction = first.transaction().await.unwrap();
+        transaction
+            .execute("UPDATE \"user\" SET nickname = 'Alice', \
+                change_xid = txid_current() WHERE id = $1", &[&alice])
+            .await
+            .unwrap();
+        // Bob is updated by another one, which is not held up by the first and commits before it
+        db.connect().await
+            .execute("UPDATE \"user\" SET nickname = 'Bob', \
+                change_xid = txid_current() WHERE id = $1", &[&bob])
+            .await
+            .unwrap();
+        assert!(query.find_changes(&client, since.0, since.1, 100).await.unwrap().is_empty());
+        transaction.commit().await.unwrap();
+        let changes = wait_changes(&query, &client, since, |x| x.len() == 2).await;
+        assert_eq!(changes.iter().map(|x| x.id).collect::<Vec<_>>(), vec![alice, bob]);
+    }
+
+    #[actix_rt::test]
+    async fn list_users_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let email = Some(String::from("carol@example.com"));
+        for (username, email) in &[("list-alice", &None), ("list-bob", &None),
+                                   ("list-carol", &email)] {
+            query.insert_one(&mut client, username, "password", &[], None, email, &None)
+                .await
+                .unwrap();
+        }
+        let usernames = |users: &[UserWithoutRoles]| users.iter()
+            .map(|x| x.username.clone())
+            .collect::<Vec<_>>();
+        let (users, total) = query
+            .list_users(&client, Some("\"list-\""), Some("username desc"), false, 2, 0)
+            .await
+            .unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(usernames(&users), vec!["list-carol", "list-bob"]);
+        let (users, total) = query
+            .list_users(&client, Some("\"list-\""), Some("username desc"), false, 2, 2)
+            .await
+            .unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(usernames(&users), vec!["list-alice"]);
+        // Quotes in values are bound, not spliced into the SQL
+        let (users, total) = query
+            .list_users(&client, Some("username: \"x'; DROP TABLE role; --\""), None, false,
+                        10, 0)
+            .await
+            .unwrap();
+        assert_eq!((users.len(), total), (0, 0));
+        let (users, _) = query
+            .list_users(&client, Some("email: carol"), None, true, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(usernames(&users), vec!["list-carol"]);
+        match query.list_users(&client, Some("email: carol"), None, false, 10, 0).await {
+            Err(Error::InvalidFilter(_)) => (),
+            e => panic!("unexpected result {:?}", e.map(|x| x.1)),
+        }
+        assert_eq!(query.count_users(&client, Some("\"list-\""), false).await.unwrap(), 3);
+        let all = query.count_users(&client, None, false).await.unwrap();
+        assert_eq!(query.count_users(&client, Some(" "), false).await.unwrap(), all);
+    }
+
+    #[actix_rt::test]
+    async fn insert_one_roles_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let user = query
+            .insert_one(&mut client, "alice", "password",
+                        &["user-admin".into(), "no-such-role".into(), "normal-user".into()],
+                        None, &None, &None)
+            .await
+            .unwrap();
+        assert_eq!(user.roles, vec!["normal-user", "user-admin"]);
+        let user = query.insert_one(&mut client, "bob", "password", &[], None, &None, &None)
+            .await
+            .unwrap();
+        assert!(user.roles.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn update_roles_of_user_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let query = queries.user;
+        let id = query.insert_one(&mut client, "alice", "password", &["normal-user".into()],
+                                  None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        let role_names = |roles: &[RoleShort]| {
+            let mut names = roles.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let (added, roles) = query
+            .add_roles_to_user(&mut client, &ACTOR, id,
+                               &["normal-user".into(), "user-admin".into(), "no-such-role".into()])
+            .await
+            .unwrap();
+        assert_eq!(added.len(), 1);
+        assert_eq!(role_names(&roles), vec!["normal-user", "user-admin"]);
+        // Granting an existing role again keeps its grant
+        let assignments = query.find_role_assignments(&client, id).await.unwrap();
+        assert_eq!(assignments.iter()
+                       .map(|x| (&x.name[..], x.granted_by, x.granted_at.is_some()))
+                       .collect::<Vec<_>>(),
+                   vec![("normal-user", None, true), ("user-admin", Some(ACTOR.uid), true)]);
+        let (removed, roles) = query
+            .remove_roles_from_user(&mut client, &ACTOR, id,
+                                    &["normal-user".into(), "permission-admin".into()])
+            .await
+            .unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(role_names(&roles), vec!["user-admin"]);
+        match query.add_roles_to_user(&mut client, &ACTOR, id + 1000, &["user-admin".into()])
+            .await {
+            Err(Error::UserNotFound) => (),
+            e => panic!("unexpected result {:?}", e.map(|x| x.0)),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn role_hierarchy_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        let (mut client, queries) = db.connect_with_query().await;
+        let (query, roles) = (queries.user, queries.role);
+        let mut ids = Vec::new();
+        for (name, subject, action) in &[("viewer", "user", "read"), ("editor", "user", "list"),
+                                         ("chief", "role", "read")] {
+            let id = roles.create_role(&mut client, &ACTOR, name, name, "").await.unwrap().id;
+            let permission = roles.resolve_permission(&client, subject, action).await.unwrap();
+            roles.add_permissions_to_role(&mut client, &ACTOR, id, &[permission]).await.unwrap();
+            ids.push(id);
+        }
+        let (viewer, editor, chief) = (ids[0], ids[1], ids[2]);
+        // chief inherits from editor, which inherits from viewer
+        assert!(roles.add_parent_to_role(&mut client, &ACTOR, editor, viewer).await.unwrap());
+        assert!(roles.add_parent_to_role(&mut client, &ACTOR, chief, editor).await.unwrap());
+        assert!(!roles.add_parent_to_role(&mut client, &ACTOR, chief, editor).await.unwrap());
+        for &(role, parent) in &[(viewer, chief), (viewer, viewer)] {
+            match roles.add_parent_to_role(&mut client, &ACTOR, role, parent).await {
+                Err(Error::RoleCycle) => (),
+                e => panic!("unexpected result {:?}", e),
+            }
+        }
+        let id = query.insert_one(&mut client, "alice", "password", &["chief".into()],
+                                  None, &None, &None)
+            .await
+            .unwrap()
+            .id;
+        // Leaves out the permissions of the default role
+        let own = |permissions: Vec<PermissionSubjectAction>| permissions.into_iter()
+            .filter(|x| x.subject == "user" || x.subject == "role")
+            .map(|x| format!("{}:{}", x.subject, x.action))
+            .collect::<Vec<_>>();
+        assert_eq!(own(query.fetch_effective_permission(&client, id).await.unwrap()),
+                   vec!["role:read", "user:list", "user:read"]);
+        let tree = query.fetch_permission_tree(&client, Some(id)).await.unwrap();
+        // Inherited roles count as held, for the messages about them
+        assert!(tree.has_role(chief) && tree.has_role(editor) && tree.has_role(viewer));
+        let mut inherited = own(tree.get().into_iter().map(|x| x.1).collect());
+        inherited.sort();
+        assert_eq!(inherited, vec!["role:read", "user:list", "user:read"]);
+        assert_eq!(query.fetch_role_permissions(&client, editor).await.unwrap().len(), 2);
+        // Holding chief is holding its ancestors for the elevation check
+        assert_eq!(query.check_extra_roles(&client, id, &["viewer".into(), "editor".into(),
+                                                          "user-admin".into()])
+                       .await
+                       .unwrap(),
+                   vec!["user-admin"]);
+        assert!(roles.remove_parent_from_role(&mut client, &ACTOR, chief, editor).await.unwrap());
+        assert_eq!(own(query.fetch_effective_permission(&client, id).await.unwrap()),
+                   vec!["role:read"]);
+    }
 }
@@ -1,6 +1,7 @@
 use crate::{
     config::InitConfig,
-    constants::{BCRYPT_COST, JWT_SECRET_LENGTH},
+    constants::JWT_SECRET_LENGTH,
+    queries::passwords::{Passwords, PasswordHasher, PasswordHashError},
 };
 use super::predefined;
 use err_derive::Error;
@@ -19,7 +20,13 @@ pub enum InitError {
     #[error(display = "Failed to prompt password")]
     PromptPasswordError,
     #[error(display = "{}", _0)]
-    Bcrypt(#[error(source)] #[error(from)] bcrypt::BcryptError),
+    PasswordHash(#[error(source)] #[error(from)] PasswordHashError),
+    #[error(display = "Users differ only in the case of their {}: {}. Rename all but one of each \
+        before starting", column, values)]
+    CaseCollision {
+        column: &'static str,
+        values: String,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, InitError>;
@@ -80,6 +87,10 @@ pub async fn init_permission(client: &Client) -> Result<()> {
 }
 
 pub async fn drop_role(client: &Client) -> Result<()> {
+    // Drop role_parent table
+    client
+        .query("DROP TABLE IF EXISTS role_parent", &[])
+        .await?;
     // Drop role_permission table
     client
         .query("DROP TABLE IF EXISTS role_permission", &[])
@@ -121,6 +132,16 @@ pub async fn init_role(client: &Client) -> Result<()> {
                 UNIQUE (role, permission)\
             )", &[])
         .await?;
+    // Create role_parent table. A role inherits the permissions of its parents, transitively
+    client
+        .query("\
+            CREATE TABLE IF NOT EXISTS role_parent (\
+                role INTEGER REFERENCES role(id) ON DELETE CASCADE NOT NULL,\
+                parent INTEGER REFERENCES role(id) ON DELETE CASCADE NOT NULL,\
+                UNIQUE (role, parent),\
+                CHECK (role <> parent)\
+            )", &[])
+        .await?;
     // Insert Items
     const ITEMS: &[predefined::PredefinedRole] = predefined::PREDEFINED_ROLES;
     unzip_n!(4);
@@ -175,6 +196,24 @@ pub async fn drop_user(client: &Client) -> Result<()> {
     Ok(())
 }
 
+// Existing users differing only in case would fail the unique indexes on LOWER with a bare
+// constraint error, so they are listed instead
+async fn check_case_collisions(client: &Client, column: &'static str) -> Result<()> {
+    let rows = client
+        .query(&format!("\
+            SELECT string_agg({0}, ' and ' ORDER BY id) FROM \"user\" \
+            WHERE NOT deleted AND {0} IS NOT NULL \
+            GROUP BY LOWER({0}) HAVING COUNT(*) > 1 ORDER BY 1", column)[..], &[])
+        .await?;
+    if rows.is_empty() {
+        return Ok(());
+    }
+    Err(InitError::CaseCollision {
+        column,
+        values: rows.iter().map(|row| row.get(0)).collect::<Vec<String>>().join(", "),
+    })
+}
+
 pub async fn init_user(client: &Client, config: &InitConfig) -> Result<()> {
     // Create user table
     client
@@ -186,13 +225,76 @@ pub async fn init_user(client: &Client, config: &InitConfig) -> Result<()> {
                 email TEXT,\
                 nickname TEXT,\
                 avatar TEXT,\
-                avatar128 TEXT,\
+                avatar_thumbnails JSONB NOT NULL DEFAULT '{}',\
+                avatar_webp TEXT,\
+                avatar_thumbnails_webp JSONB NOT NULL DEFAULT '{}',\
                 blocked BOOL,\
                 created_at TIMESTAMP WITH TIME ZONE NOT NULL,\
                 updated_at TIMESTAMP WITH TIME ZONE NOT NULL,\
-                deleted BOOL NOT NULL\
+                deleted BOOL NOT NULL,\
+                deleted_at TIMESTAMP WITH TIME ZONE,\
+                change_xid BIGINT NOT NULL DEFAULT txid_current(),\
+                totp_secret TEXT,\
+                totp_enabled BOOL NOT NULL DEFAULT FALSE,\
+                last_login_at TIMESTAMP WITH TIME ZONE\
             )", &[])
         .await?;
+    // Added later, so it is left empty for existing users until they log in again
+    client
+        .query("\
+            ALTER TABLE \"user\" \
+                ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMP WITH TIME ZONE", &[])
+        .await?;
+    // Added later in place of avatar128, which is moved in as the 128px thumbnail
+    client
+        .query("\
+            ALTER TABLE \"user\" \
+                ADD COLUMN IF NOT EXISTS avatar_thumbnails JSONB NOT NULL DEFAULT '{}'", &[])
+        .await?;
+    client
+        .query("\
+            DO $$ BEGIN \
+                IF EXISTS (SELECT FROM information_schema.columns \
+                        WHERE table_schema = current_schema() AND table_name = 'user' \
+                            AND column_name = 'avatar128') THEN \
+                    UPDATE \"user\" SET avatar_thumbnails = jsonb_build_object('128', avatar128) \
+                        WHERE avatar128 IS NOT NULL; \
+                    ALTER TABLE \"user\" DROP COLUMN avatar128; \
+                END IF; \
+            END $$", &[])
+        .await?;
+    // Added later, so existing avatars have no WebP copies until uploaded again
+    client
+        .query("\
+            ALTER TABLE \"user\" \
+                ADD COLUMN IF NOT EXISTS avatar_webp TEXT, \
+                ADD COLUMN IF NOT EXISTS avatar_thumbnails_webp JSONB NOT NULL DEFAULT '{}'", &[])
+        .await?;
+    // Added later. Users deleted before were last updated by the deletion
+    client
+        .query("\
+            ALTER TABLE \"user\" \
+                ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP WITH TIME ZONE", &[])
+        .await?;
+    client
+        .query("\
+            UPDATE \"user\" SET deleted_at = updated_at \
+                WHERE deleted AND deleted_at IS NULL", &[])
+        .await?;
+    // Added later, with TOTP disabled for existing users
+    client
+        .query("\
+            ALTER TABLE \"user\" \
+                ADD COLUMN IF NOT EXISTS totp_secret TEXT, \
+                ADD COLUMN IF NOT EXISTS totp_enabled BOOL NOT NULL DEFAULT FALSE", &[])
+        .await?;
+    // Added later, with existing users changed by this transaction in the order of their ids,
+    // see find_changes
+    client
+        .query("\
+            ALTER TABLE \"user\" \
+                ADD COLUMN IF NOT EXISTS change_xid BIGINT NOT NULL DEFAULT txid_current()", &[])
+        .await?;
     // Create index
     client
         .query("\
@@ -204,15 +306,44 @@ pub async fn init_user(client: &Client, config: &InitConfig) -> Result<()> {
             CREATE UNIQUE INDEX IF NOT EXISTS user_email \
             ON \"user\" (email) WHERE NOT deleted", &[])
         .await?;
+    // Usernames and emails are compared case-insensitively. The indexes above stay, as the
+    // arbiter of the superuser upsert
+    check_case_collisions(client, "username").await?;
+    check_case_collisions(client, "email").await?;
+    client
+        .query("\
+            CREATE UNIQUE INDEX IF NOT EXISTS user_username_lower \
+            ON \"user\" (LOWER(username)) WHERE NOT deleted", &[])
+        .await?;
+    client
+        .query("\
+            CREATE UNIQUE INDEX IF NOT EXISTS user_email_lower \
+            ON \"user\" (LOWER(email)) WHERE NOT deleted", &[])
+        .await?;
+    client
+        .query("\
+            CREATE INDEX IF NOT EXISTS user_change_index \
+            ON \"user\" (change_xid, id)", &[])
+        .await?;
     // Create user_role table
     client
         .query("\
             CREATE TABLE IF NOT EXISTS user_role (\
                 \"user\" INTEGER REFERENCES \"user\"(id) ON DELETE CASCADE NOT NULL,\
                 role INTEGER REFERENCES role(id) ON DELETE CASCADE NOT NULL,\
+                granted_by INTEGER,\
+                granted_at TIMESTAMP WITH TIME ZONE,\
                 UNIQUE (\"user\", role)\
             )", &[])
         .await?;
+    // Added later, so they are nullable and left empty for the rows of existing tables. Roles of
+    // registered users and of the superuser are granted by nobody
+    client
+        .query("\
+            ALTER TABLE user_role \
+                ADD COLUMN IF NOT EXISTS granted_by INTEGER, \
+                ADD COLUMN IF NOT EXISTS granted_at TIMESTAMP WITH TIME ZONE", &[])
+        .await?;
     if let Some(superuser_username) = &config.superuser_username {
         // Insert Items
         let superuser_password = if let Some(password) = &config.superuser_password {
@@ -221,7 +352,8 @@ pub async fn init_user(client: &Client, config: &InitConfig) -> Result<()> {
             rpassword::read_password_from_tty(Some("Please enter the password for superuser: "))
                 .map_err(|_| InitError::PromptPasswordError)?
         };
-        let superuser_password = bcrypt::hash(superuser_password, BCRYPT_COST)?;
+        let superuser_password = Passwords::new(config.password_algorithm, config.bcrypt_cost)
+            .hash(&superuser_password)?;
         let result = client
             .execute("\
             INSERT INTO \"user\" (username, password, created_at, updated_at, deleted) \
@@ -229,14 +361,15 @@ pub async fn init_user(client: &Client, config: &InitConfig) -> Result<()> {
             ON CONFLICT (username) WHERE NOT deleted \
             DO UPDATE SET \
                 password = EXCLUDED.password, \
-                updated_at = EXCLUDED.updated_at\
+                updated_at = EXCLUDED.updated_at, \
+                change_xid = txid_current()\
             ", &[&superuser_username, &superuser_password])
             .await?;
         info!("modify {}/{} rows in user table", result, 1);
         let result = client
             .execute("\
-            INSERT INTO user_role (\"user\", role) \
-            SELECT \"user\".id, role.id FROM (SELECT UNNEST($1::TEXT[]) AS role) AS temp \
+            INSERT INTO user_role (\"user\", role, granted_at) \
+            SELECT \"user\".id, role.id, NOW() FROM (SELECT UNNEST($1::TEXT[]) AS role) AS temp \
                   JOIN role ON role.name = temp.role AND NOT role.deleted \
                   JOIN \"user\" ON \"user\".username = $2 \
             ON CONFLICT (\"user\", role) DO NOTHING\
@@ -278,6 +411,38 @@ pub async fn init_token(client: &Client) -> Result<()> {
     Ok(())
 }
 
+pub async fn drop_refresh_token(client: &Client) -> Result<()> {
+    // Drop refresh_token table
+    client
+        .query("DROP TABLE IF EXISTS refresh_token", &[])
+        .await?;
+    Ok(())
+}
+
+pub async fn init_refresh_token(client: &Client) -> Result<()> {
+    // Create refresh_token table. A chain is named after the access token it started with, and
+    // only the SHA-256 of a refresh token is stored
+    client
+        .query("\
+            CREATE TABLE IF NOT EXISTS refresh_token (\
+                id serial PRIMARY KEY,\
+                hash BYTEA UNIQUE NOT NULL,\
+                token INTEGER REFERENCES token(id) ON DELETE CASCADE NOT NULL,\
+                \"user\" INTEGER REFERENCES \"user\"(id) ON DELETE CASCADE NOT NULL,\
+                chain INTEGER NOT NULL,\
+                issued_at TIMESTAMP(0) WITH TIME ZONE NOT NULL,\
+                expires_at TIMESTAMP(0) WITH TIME ZONE NOT NULL,\
+                rotated BOOL NOT NULL,\
+                revoked BOOL NOT NULL\
+            )", &[])
+        .await?;
+    // Create index
+    client
+        .query("CREATE INDEX IF NOT EXISTS \"refresh_token_chain\" ON refresh_token (chain)", &[])
+        .await?;
+    Ok(())
+}
+
 pub async fn drop_global_settings(client: &Client) -> Result<()> {
     // Drop global_settings table
     client
@@ -325,15 +490,30 @@ pub async fn init_user_registration(client: &Client) -> Result<()> {
         .query("\
             CREATE TABLE IF NOT EXISTS user_registration(\
                 id CHAR(24) PRIMARY KEY,\
-                code CHAR(6) NOT NULL,\
+                code CHAR(6),\
                 username TEXT NOT NULL,\
                 password TEXT NOT NULL,\
                 email TEXT NOT NULL,\
                 created_at TIMESTAMP WITH TIME ZONE NOT NULL,\
                 expires_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                sent_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                attempts INTEGER NOT NULL,\
                 completed BOOL\
             )", &[])
         .await?;
+    // Added later, and pending registrations count as sent now
+    client
+        .query("\
+            ALTER TABLE user_registration \
+                ADD COLUMN IF NOT EXISTS sent_at TIMESTAMP WITH TIME ZONE NOT NULL \
+                    DEFAULT NOW()", &[])
+        .await?;
+    // Added later, and pending registrations have not been tried yet
+    client
+        .query("\
+            ALTER TABLE user_registration \
+                ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0", &[])
+        .await?;
     Ok(())
 }
 
@@ -349,14 +529,93 @@ pub async fn init_user_email_updating(client: &Client) -> Result<()> {
         .query("\
             CREATE TABLE IF NOT EXISTS user_email_updating(\
                 id CHAR(24) PRIMARY KEY,\
-                code CHAR(6) NOT NULL,\
+                code CHAR(6),\
                 \"user\" INTEGER REFERENCES \"user\"(id) ON DELETE CASCADE NOT NULL,\
                 new_email TEXT NOT NULL,\
                 created_at TIMESTAMP WITH TIME ZONE NOT NULL,\
                 expires_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                sent_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                attempts INTEGER NOT NULL,\
                 completed BOOL\
             )", &[])
         .await?;
+    // Added later, and pending updates count as sent now
+    client
+        .query("\
+            ALTER TABLE user_email_updating \
+                ADD COLUMN IF NOT EXISTS sent_at TIMESTAMP WITH TIME ZONE NOT NULL \
+                    DEFAULT NOW()", &[])
+        .await?;
+    // Added later, and pending updates have not been tried yet
+    client
+        .query("\
+            ALTER TABLE user_email_updating \
+                ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0", &[])
+        .await?;
+    Ok(())
+}
+
+pub async fn drop_user_password_reset(client: &Client) -> Result<()> {
+    client
+        .query("DROP TABLE IF EXISTS user_password_reset", &[])
+        .await?;
+    Ok(())
+}
+
+pub async fn init_user_password_reset(client: &Client) -> Result<()> {
+    client
+        .query("\
+            CREATE TABLE IF NOT EXISTS user_password_reset(\
+                id CHAR(24) PRIMARY KEY,\
+                code CHAR(6),\
+                \"user\" INTEGER REFERENCES \"user\"(id) ON DELETE CASCADE NOT NULL,\
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                expires_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                sent_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                attempts INTEGER NOT NULL,\
+                completed BOOL\
+            )", &[])
+        .await?;
+    Ok(())
+}
+
+pub async fn drop_audit_log(client: &Client) -> Result<()> {
+    client
+        .query("DROP TABLE IF EXISTS audit_log", &[])
+        .await?;
+    Ok(())
+}
+
+pub async fn init_audit_log(client: &Client) -> Result<()> {
+    // Create audit_log table. Actors and targets are not references, so that entries outlive
+    // them, and the rules keep entries from being changed or removed
+    client
+        .query("\
+            CREATE TABLE IF NOT EXISTS audit_log(\
+                id serial PRIMARY KEY,\
+                actor INTEGER NOT NULL,\
+                impersonator INTEGER,\
+                action TEXT NOT NULL,\
+                target INTEGER NOT NULL,\
+                detail JSONB NOT NULL,\
+                created_at TIMESTAMP WITH TIME ZONE NOT NULL\
+            )", &[])
+        .await?;
+    client
+        .query("CREATE OR REPLACE RULE audit_log_no_update AS ON UPDATE TO audit_log \
+                DO INSTEAD NOTHING", &[])
+        .await?;
+    client
+        .query("CREATE OR REPLACE RULE audit_log_no_delete AS ON DELETE TO audit_log \
+                DO INSTEAD NOTHING", &[])
+        .await?;
+    // Create index
+    client
+        .query("CREATE INDEX IF NOT EXISTS \"audit_log_actor\" ON audit_log (actor)", &[])
+        .await?;
+    client
+        .query("CREATE INDEX IF NOT EXISTS \"audit_log_target\" ON audit_log (target)", &[])
+        .await?;
     Ok(())
 }
 
@@ -369,9 +628,12 @@ pub async fn init(config: &InitConfig) -> Result<()> {
     });
     if config.reset {
         // in reverse order
+        drop_audit_log(&client).await?;
+        drop_user_password_reset(&client).await?;
         drop_user_email_updating(&client).await?;
         drop_user_registration(&client).await?;
         drop_global_settings(&client).await?;
+        drop_refresh_token(&client).await?;
         drop_token(&client).await?;
         drop_user(&client).await?;
         drop_role(&client).await?;
@@ -382,8 +644,103 @@ pub async fn init(config: &InitConfig) -> Result<()> {
     init_role(&client).await?;
     init_user(&client, config).await?;
     init_token(&client).await?;
+    init_refresh_token(&client).await?;
     init_global_settings(&client).await?;
     init_user_registration(&client).await?;
     init_user_email_updating(&client).await?;
+    init_user_password_reset(&client).await?;
+    init_audit_log(&client).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::testing::TestDatabase;
+    use serde_json::json;
+
+    // A user table as first released is brought up to date, and again without a change
+    #[actix_rt::test]
+    async fn init_user_migration_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client
+            .batch_execute("\
+                DROP TABLE user_role; \
+                DROP TABLE \"user\" CASCADE; \
+                CREATE TABLE \"user\" (\
+                    id serial PRIMARY KEY,\
+                    username TEXT NOT NULL,\
+                    password TEXT NOT NULL,\
+                    email TEXT,\
+                    nickname TEXT,\
+                    avatar TEXT,\
+                    avatar128 TEXT,\
+                    blocked BOOL,\
+                    created_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                    updated_at TIMESTAMP WITH TIME ZONE NOT NULL,\
+                    deleted BOOL NOT NULL\
+                ); \
+                INSERT INTO \"user\" (username, password, avatar, avatar128, \
+                                      created_at, updated_at, deleted) \
+                VALUES ('alice', 'password', 'alice.png', 'alice-128.png', NOW(), NOW(), FALSE), \
+                       ('bob', 'password', NULL, NULL, NOW(), NOW(), FALSE), \
+                       ('carol', 'password', NULL, NULL, NOW(), \
+                        NOW() - INTERVAL '1 day', TRUE)")
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            init_user(&db.client, &db.init_config).await.unwrap();
+            let rows = db.client
+                .query("SELECT username, avatar_thumbnails, avatar_webp, avatar_thumbnails_webp, \
+                        change_xid, totp_enabled, updated_at, deleted_at \
+                    FROM \"user\" ORDER BY id", &[])
+                .await
+                .unwrap();
+            assert_eq!(rows.iter().map(|row| row.get("avatar_thumbnails"))
+                           .collect::<Vec<serde_json::Value>>(),
+                       vec![json!({ "128": "alice-128.png" }), json!({}), json!({})]);
+            assert!(rows.iter().all(|row| row.get::<_, Option<String>>("avatar_webp").is_none()
+                && row.get::<_, serde_json::Value>("avatar_thumbnails_webp") == json!({})));
+            assert!(rows.iter().all(|row| row.get::<_, i64>("change_xid") > 0));
+            assert!(rows.iter().all(|row| !row.get::<_, bool>("totp_enabled")));
+            assert_eq!(rows.iter().map(|row| row.get("deleted_at"))
+                           .collect::<Vec<Option<chrono::DateTime<chrono::Utc>>>>(),
+                       vec![None, None, Some(rows[2].get("updated_at"))]);
+        }
+    }
+
+    // Users differing only in case stop the migration before the indexes on LOWER, with all of
+    // them listed
+    #[actix_rt::test]
+    async fn init_user_case_collision_test() {
+        let db = match TestDatabase::new(&[]).await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client
+            .batch_execute("\
+                DROP INDEX user_username_lower; \
+                DROP INDEX user_email_lower; \
+                INSERT INTO \"user\" (username, password, email, created_at, updated_at, deleted) \
+                VALUES ('alice', 'password', NULL, NOW(), NOW(), FALSE), \
+                       ('Alice', 'password', NULL, NOW(), NOW(), FALSE), \
+                       ('ALICE', 'password', NULL, NOW(), NOW(), TRUE)")
+            .await
+            .unwrap();
+        match init_user(&db.client, &db.init_config).await {
+            Err(InitError::CaseCollision { column, values }) => {
+                assert_eq!(column, "username");
+                assert_eq!(values, "alice and Alice");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        db.client
+            .execute("UPDATE \"user\" SET username = 'alice2' WHERE username = 'Alice'", &[])
+            .await
+            .unwrap();
+        init_user(&db.client, &db.init_config).await.unwrap();
+    }
+}
@@ -22,6 +22,7 @@ pub const PREDEFINED_PERMISSIONS: &[PredefinedPermission] = &[
     PredefinedPermission("permission", "read", "Read Permission", "Read the information of a permission via GET /api/permissions/:id"),
     PredefinedPermission("permission", "list", "List Permission", "List all the permissions matching criteria via GET /api/permissions"),
     PredefinedPermission("permission", "update", "Update Permission", "Update the information of a permission via PATCH /api/permissions/:id"),
+    PredefinedPermission("permission", "read-self", "Read Self Permission", "List user's own effective permissions and subscribable subjects via GET /api/users/me/permissions or GET /api/users/me/subscriptions"),
     // CRUD for roles
     PredefinedPermission("role", "create", "Create Role", "Create a new role via POST /api/roles"),
     PredefinedPermission("role", "read", "Read Role", "Read the information of a role via GET /api/roles/:id"),
@@ -30,30 +31,39 @@ pub const PREDEFINED_PERMISSIONS: &[PredefinedPermission] = &[
     PredefinedPermission("role", "delete", "Delete Role", "Delete a role via DELETE /api/roles/:id"),
     // CRUD for role's permissions
     PredefinedPermission("role-permission", "update", "Update Role's Permission", "Update role's permission via POST /api/roles/:id/permissions"),
+    // Bootstrapping for roles and permissions
+    PredefinedPermission("seed", "create", "Seed Roles and Permissions", "Upsert roles, permissions and their links via POST /api/admin/seed"),
     // Subjects for role's permissions
     PredefinedPermission("role-permission-updated", "subscribe", "Subscribe Role-Permission-Updated", "Subscribe to role's permission updated message"),
     // CRUD for users
     PredefinedPermission("user", "create", "Create User", "Create a new user via POST /api/users"),
     PredefinedPermission("user", "read", "Read User", "Read the information of a user via GET /api/users/:id"),
+    PredefinedPermission("user", "read-email", "Read User Email", "Read the unmasked email of a user via GET /api/users/:id when emails are redacted"),
     PredefinedPermission("user", "read-self", "Read Self User", "Read user's own information via GET /api/users/me"),
     PredefinedPermission("user", "list", "List User", "List all the users matching criteria via GET /api/users"),
     PredefinedPermission("user", "update", "Update User", "Update the information of a user via PATCH /api/users/:id"),
     PredefinedPermission("user", "update-self", "Update Self User", "Update user's own information via PATCH /api/users/me"),
     PredefinedPermission("user", "delete", "Delete User", "Delete a user via DELETE /api/users/:id"),
     PredefinedPermission("user", "delete-self", "Delete Self User", "Delete user's own account via DELETE /api/users/me"),
+    PredefinedPermission("user", "impersonate", "Impersonate User", "Acquire a short-lived token acting as a user with no more roles than the caller via POST /api/users/:id/impersonate"),
     // CRUD for user's public information
-    PredefinedPermission("user-public", "read", "Read User Public", "Read the public information of a user via GET /api/users/:id?populate=public"),
-    PredefinedPermission("user-public", "list", "List User Public", "List all the users matching criteria with public information via GET /api/users?populate=public"),
+    PredefinedPermission("user-public", "read", "Read User Public", "Read the public information of a user via GET /api/users/:id?populate=public, and search users by name via GET /api/users/search"),
+    PredefinedPermission("user-public", "list", "List User Public", "List all the users matching criteria with public information via GET /api/users?populate=public, and follow their changes via GET /api/users/changes"),
     // CRUD for user's password
     PredefinedPermission("user-password", "update", "Update Self User Password", "Update user's password via POST /api/users/:id/password"),
     PredefinedPermission("user-password", "update-self", "Update Self User Password", "Update user's password via POST /api/users/me/password"),
+    // Password reset for users who forgot their passwords
+    PredefinedPermission("password-reset", "create", "Request Password Reset", "Request a password reset e-mail via POST /api/password-resets"),
+    PredefinedPermission("password-reset", "confirm", "Confirm Password Reset", "Set a new password with the e-mailed code via POST /api/password-resets/{reset_id}/confirm"),
+    // TOTP two-factor authentication
+    PredefinedPermission("user-totp", "update-self", "Update Self TOTP", "Enroll, confirm or disable user's own TOTP via POST /api/users/me/totp, POST /api/users/me/totp/confirm or DELETE /api/users/me/totp"),
     // CRUD for user's avatar
-    PredefinedPermission("user-avatar", "update", "Update Self User Avatar", "Update user's avatar via POST /api/users/:id/avatar"),
+    PredefinedPermission("user-avatar", "update", "Update Self User Avatar", "Update user's avatar via POST /api/users/:id/avatar and /api/users/:id/avatar/from-url"),
     PredefinedPermission("user-avatar", "delete", "Delete Self User Avatar", "Delete user's avatar via DELETE /api/users/:id/avatar"),
     PredefinedPermission("user-avatar", "update-self", "Update Self User Avatar", "Update user's avatar via POST /api/users/me/avatar"),
     PredefinedPermission("user-avatar", "delete-self", "Delete Self User Avatar", "Delete user's avatar via DELETE /api/users/me/avatar"),
     // CRUD for user's role
-    PredefinedPermission("user-role", "update", "Update User's Role", "Update user's roles via POST /api/users/:id/roles"),
+    PredefinedPermission("user-role", "update", "Update User's Role", "Add or remove user's roles via POST or DELETE /api/users/:id/roles"),
     // Subjects for user's roles
     PredefinedPermission("user-role-updated", "subscribe", "Subscribe User-Role-Updated", "Subscribe to user's role updated message"),
     // Subjects for token
@@ -63,13 +73,15 @@ pub const PREDEFINED_PERMISSIONS: &[PredefinedPermission] = &[
     PredefinedPermission("user-updated-self", "subscribe", "Subscribe Self User-Updated", "Subscribe to self user updated message"),
     PredefinedPermission("user-deleted-self", "subscribe", "Subscribe Self User-Deleted", "Subscribe to self user deleted message"),
     // CRUD for token
-    PredefinedPermission("token", "acquire-by-username", "Acquire Token By Username", "Acquire token by username via POST /api/tokens/acquire-by-username"),
-    PredefinedPermission("token", "acquire-by-email", "Acquire Token By Email", "Acquire token by email via POST /api/tokens/acquire-by-email"),
+    PredefinedPermission("token", "acquire-by-username", "Acquire Token By Username", "Acquire token by username via POST /api/tokens/acquire-by-username or POST /api/tokens/acquire"),
+    PredefinedPermission("token", "acquire-by-email", "Acquire Token By Email", "Acquire token by email via POST /api/tokens/acquire-by-email or POST /api/tokens/acquire"),
+    PredefinedPermission("token", "refresh", "Refresh Token", "Trade a refresh token for a new access token and refresh token via POST /api/tokens/refresh"),
     PredefinedPermission("token", "resume", "Resume Token", "Resume a token by providing a valid token via POST /api/tokens/resume"),
     PredefinedPermission("token", "revoke", "Revoke Token", "Revoke all the tokens belong to a user via DELETE /api/tokens/users/:uid"),
     PredefinedPermission("token", "revoke-self", "Revoke Self Token", "Revoke all user's own tokens via DELETE /api/tokens/users/me"),
     PredefinedPermission("token", "list", "List Token", "List all the tokens belong to a user via GET /api/tokens/users/:uid"),
-    PredefinedPermission("token", "list-self", "List Self Token", "List all user's own tokens tokens via GET /api/tokens/users/me"),
+    PredefinedPermission("token", "list-self", "List Self Token", "List user's own active sessions via GET /api/users/me/tokens"),
+    PredefinedPermission("token", "read", "Read Token", "List the active sessions of a user via GET /api/users/:id/tokens"),
     PredefinedPermission("token", "read-single", "Read Single Token", "Read the information of a token via GET /api/tokens/jwt/:jti"),
     PredefinedPermission("token", "revoke-single", "Revoke Single Token", "Revoke one token belong to a user via DELETE /api/tokens/jwt/:jti"),
     PredefinedPermission("token", "read-single-self", "Read Single Self Token", "Read the information of a token via GET /api/tokens/my-jwt/:jti"),
@@ -84,7 +96,9 @@ pub const PREDEFINED_PERMISSIONS: &[PredefinedPermission] = &[
     PredefinedPermission("registration", "read", "Query User Registration", "Query a user registration status via GET /api/registrations/{reg_id}"),
     PredefinedPermission("registration", "confirm", "Confirm User Registration", "Confirm a user registration via POST /api/registrations/{reg_id}/confirm"),
     PredefinedPermission("registration", "resend", "Resend User Registration E-mail", "Resend user registration email via POST /api/registrations/{reg_id}/resend"),
-    PredefinedPermission("user-username", "check-existence", "Check Username Existence", "Check whether username is occupied via GET /api/users/check-username-existence"),
+    PredefinedPermission("registration", "cancel", "Cancel User Registration", "Cancel a pending user registration via DELETE /api/registrations/{reg_id}"),
+    PredefinedPermission("registration", "list", "List User Registrations", "List user registrations via GET /api/registrations"),
+    PredefinedPermission("user-username", "check-existence", "Check Username Existence", "Check whether usernames are occupied via GET /api/users/check-username-existence or POST /api/users/check-usernames-existence"),
     PredefinedPermission("user-email", "check-existence", "Check E-mail Existence", "Check whether E-mail is occupied via POST /api/users/check-email-existence"),
     // User email updating
     PredefinedPermission("user-email-updating", "create-self", "Update Self Email", "Update self's email via POST /api/email-updating/"),
@@ -92,8 +106,16 @@ pub const PREDEFINED_PERMISSIONS: &[PredefinedPermission] = &[
     PredefinedPermission("user-email-updating", "read-self", "Read Self Updating Email", "Query self's email updating via POST /api/email-updating/{update_id}"),
     PredefinedPermission("user-email-updating", "confirm", "Confirm User Updating Email", "Confirm user's email updating via POST /api/email-updating/{update_id}/confirm"),
     PredefinedPermission("user-email-updating", "confirm-self", "Confirm Self Updating Email", "Confirm self's email updating via POST /api/email-updating/{update_id}/confirm"),
+    PredefinedPermission("user-email-updating", "confirm-link", "Confirm Updating Email by Link", "Confirm email updating from the emailed link via GET /api/email-updating/{update_id}/confirm"),
     PredefinedPermission("user-email-updating", "resend", "Resend User Updating Email", "Resend user's email updating via POST /api/email-updating/{update_id}/resend"),
     PredefinedPermission("user-email-updating", "resend-self", "Resend Self Updating Email", "Resend self's email updating via POST /api/email-updating/{update_id}/resend"),
+    PredefinedPermission("user-email-updating", "cancel", "Cancel User Updating Email", "Cancel user's pending email updating via DELETE /api/email-updating/{update_id}"),
+    PredefinedPermission("user-email-updating", "cancel-self", "Cancel Self Updating Email", "Cancel self's pending email updating via DELETE /api/email-updating/{update_id}"),
+    PredefinedPermission("user-email-updating", "list", "List User Updating Emails", "List users' email updating via GET /api/email-updating"),
+    PredefinedPermission("pending-email", "resend", "Resend Pending E-mails", "Resend all pending confirmation e-mails via POST /api/admin/resend-pending"),
+    PredefinedPermission("pending-email", "read", "Read Pending E-mails Resending", "Query the progress of resending pending e-mails via GET /api/admin/resend-pending"),
+    // Audit log of security-sensitive actions
+    PredefinedPermission("audit", "read", "Read Audit Log", "List the audit log entries matching criteria via GET /api/audit-log"),
 ];
 
 pub const PREDEFINED_ROLES: &[PredefinedRole] = &[
@@ -106,6 +128,8 @@ pub const PREDEFINED_ROLES: &[PredefinedRole] = &[
         ("role", "list"),
         ("role", "update"),
         ("role", "delete"),
+        ("seed", "create"),
+        ("audit", "read"),
     ], "Administrator for Permissions", "Manage permissions and roles", false),
     PredefinedRole("user-admin", &[
         ("permission", "read"),
@@ -114,12 +138,15 @@ pub const PREDEFINED_ROLES: &[PredefinedRole] = &[
         ("role", "list"),
         ("user", "create"),
         ("user", "read"),
+        ("user", "read-email"),
         ("user", "list"),
         ("user", "update"),
         ("user", "delete"),
+        ("user", "impersonate"),
         ("user-password", "update"),
         ("user-avatar", "update"),
         ("user-avatar", "delete"),
+        ("token", "read"),
         ("token", "revoke"),
         ("token", "revoke-single"),
         ("token-acquired", "subscribe"),
@@ -130,15 +157,22 @@ pub const PREDEFINED_ROLES: &[PredefinedRole] = &[
         ("user-email-updating", "read"),
         ("user-email-updating", "confirm"),
         ("user-email-updating", "resend"),
+        ("user-email-updating", "cancel"),
+        ("user-email-updating", "list"),
+        ("registration", "list"),
+        ("pending-email", "resend"),
+        ("pending-email", "read"),
     ], "Administrator for Users", "Manage users", false),
     PredefinedRole("normal-user", &[
         ("user", "read-self"),
         ("user", "update-self"),
+        ("permission", "read-self"),
         ("user-public", "read"),
         ("user-public", "list"),
         ("user-password", "update-self"),
         ("user-avatar", "update-self"),
         ("user-avatar", "delete-self"),
+        ("user-totp", "update-self"),
         ("token", "resume"),
         ("token", "revoke-self"),
         ("token", "revoke-single-self"),
@@ -152,14 +186,20 @@ pub const PREDEFINED_ROLES: &[PredefinedRole] = &[
         ("user-email-updating", "read-self"),
         ("user-email-updating", "confirm-self"),
         ("user-email-updating", "resend-self"),
+        ("user-email-updating", "cancel-self"),
     ], "Normal User", "Manage users's own information", true),
     PredefinedRole("default", &[
         ("registration", "create"),
         ("registration", "confirm"),
         ("registration", "read"),
         ("registration", "resend"),
+        ("registration", "cancel"),
+        ("user-email-updating", "confirm-link"),
+        ("password-reset", "create"),
+        ("password-reset", "confirm"),
         ("token", "acquire-by-username"),
         ("token", "acquire-by-email"),
+        ("token", "refresh"),
         ("user-username", "check-existence"),
         ("user-email", "check-existence"),
     ], "Default", "Every user including not logged-in ones implicitly has this role", false),
@@ -1,17 +1,23 @@
 use crate::{
     api::{
         api_v1,
-        app_state::AppState,
+        handlers::{health::health_api, metrics::metrics_api},
+        app_state::{AppState, Mailer},
+        avatar_store::new_avatar_store,
+        login_limiter::LoginLimiter,
+        metrics::Metrics,
+        permission_cache::PermissionCache,
     },
-    config::StartConfig,
-    queries::Query,
+    config::{StartConfig, SmtpMode},
+    constants::{AVATAR_CACHE_MAX_AGE, AVATAR_PROCESSING_CAPACITY, EMAIL_QUEUE_CAPACITY},
+    queries::{email::EmailQueue, errors::Error as QueryError, pool::Pool},
     websocket::main_subscriber::MainSubscriber,
 };
 use actix::Actor;
 use actix_files as fs;
 use actix_web::{
     web, App, HttpServer,
-    middleware::Logger,
+    middleware::{DefaultHeaders, Logger},
 };
 use err_derive::Error;
 use lettre::{
@@ -19,14 +25,19 @@ use lettre::{
     Tls, TlsParameters,
     transport::smtp::authentication::Credentials,
 };
-use log::error;
+use log::{info, warn};
 use redis::RedisError;
 use rustls::ClientConfig;
-use tokio::sync::RwLock;
-use tokio_postgres::{
-    Error as PostgresError,
-    NoTls,
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Semaphore,
+};
+use tokio_postgres::Error as PostgresError;
 use webpki_roots::TLS_SERVER_ROOTS;
 
 #[derive(Debug, Error)]
@@ -37,64 +48,133 @@ pub enum StartError {
     Io(#[error(source)] #[error(from)] std::io::Error),
     #[error(display = "{}", _0)]
     Redis(#[error(source)] #[error(from)] RedisError),
+    #[error(display = "{}", _0)]
+    Query(#[error(source)] #[error(from)] QueryError),
 }
 
 pub type Result<T> = std::result::Result<T, StartError>;
 
 pub async fn start(config: &StartConfig) -> Result<()> {
-    let (client, connection) = tokio_postgres::connect(&config.db, NoTls).await?;
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            error!("connection error: {}", e);
-        }
-    });
-    let query = Query::new(&client).await;
+    let pool = Pool::new(config, None).await?;
+    let media_secret = {
+        let db = pool.get().await?;
+        db.query.token.get_secret(&db.client).await?
+    };
     let redis_client = redis::Client::open(&config.redis[..])?;
     let redis_connection = redis_client.get_async_connection().await?;
     let subscriber = MainSubscriber::new(
         redis_client.get_async_connection().await?,
         redis_connection.into_pubsub()
     ).start();
-    let mut tls_config = ClientConfig::new();
-    tls_config.root_store.add_server_trust_anchors(&TLS_SERVER_ROOTS);
+    let mailer = match (config.smtp.mode, &config.smtp.server) {
+        (SmtpMode::Send, Some(server)) => {
+            let mut tls_config = ClientConfig::new();
+            tls_config.root_store.add_server_trust_anchors(&TLS_SERVER_ROOTS);
 
-    let mut smtp_builder = SmtpTransport::builder(config.smtp.server.clone());
-    if let Some(ref username) = config.smtp.username {
-        if let Some(ref password) = config.smtp.password {
-            smtp_builder = smtp_builder.credentials(Credentials::new(
-                username.clone(),
-                password.clone(),
-            ));
-        }
-    }
+            let mut smtp_builder = SmtpTransport::builder(server.clone())
+                .port(config.smtp.port);
+            if let Some(ref username) = config.smtp.username {
+                if let Some(ref password) = config.smtp.password {
+                    smtp_builder = smtp_builder.credentials(Credentials::new(
+                        username.clone(),
+                        password.clone(),
+                    ));
+                }
+            }
 
-    let smtp = smtp_builder.tls(Tls::Required(TlsParameters::new(
-        config.smtp.server.clone(),
-        tls_config,
-    )))
-        .build();
+            Mailer::Queue(EmailQueue::start(smtp_builder.tls(Tls::Required(TlsParameters::new(
+                server.clone(),
+                tls_config,
+            )))
+                .build(), EMAIL_QUEUE_CAPACITY, config.smtp.max_attempts))
+        }
+        (SmtpMode::Log, _) => {
+            warn!("SMTP is in log mode, verification codes are logged instead of sent");
+            Mailer::log()
+        }
+        _ => {
+            warn!("SMTP is disabled, registration and e-mail updating are unavailable");
+            Mailer::Disabled
+        }
+    };
 
     let app_data = web::Data::new(AppState {
         config: config.clone(),
-        db: RwLock::from(client),
-        query,
+        pool,
         subscriber,
-        smtp,
+        mailer,
+        media_secret,
+        avatar_store: new_avatar_store(&config.media),
+        avatar_processing: Mutex::new(HashMap::new()),
+        avatar_jobs: Arc::new(Semaphore::new(AVATAR_PROCESSING_CAPACITY)),
+        resend_progress: Mutex::new(None),
+        login_limiter: LoginLimiter::new(&config.login_limit),
+        permission_cache: PermissionCache::new(&config.permission_cache),
+        metrics: Metrics::default(),
+        websocket_sessions: Mutex::new(HashSet::new()),
     });
-    let media_serve = config.media.serve;
+    // Avatars are the only media, and they must not be reachable without a signature
+    let media_serve = config.media.serve && config.media.avatar_url_ttl.is_none();
+    if config.media.serve && !media_serve {
+        warn!("media is not served publicly since avatar URLs are signed");
+    }
     let media_url = config.media.url.clone();
     let media_root = config.media.root.clone();
-    HttpServer::new(move || {
+    let metrics_server = match &config.metrics_bind {
+        Some(metrics_bind) => {
+            let app_data = app_data.clone();
+            Some(HttpServer::new(move || App::new()
+                .configure(metrics_api(&app_data)))
+                .disable_signals()
+                .bind(metrics_bind)?
+                .run())
+        }
+        None => None,
+    };
+    let server_app_data = app_data.clone();
+    // Signals are handled below, so that websockets are closed before the requests are drained
+    let server = HttpServer::new(move || {
+        let app_data = &server_app_data;
         let mut app = App::new()
             .wrap(Logger::default())
-            .configure(api_v1(&app_data));
+            .configure(api_v1(app_data))
+            .configure(health_api(app_data));
         if media_serve {
-            app = app.service(fs::Files::new(&media_url, &media_root))
+            // A new avatar gets a new random filename, so a served file never changes. The files
+            // carry ETag and Last-Modified for revalidation anyway
+            app = app.service(web::scope(&media_url)
+                .wrap(DefaultHeaders::new().header("Cache-Control", format!(
+                    "public, max-age={}, immutable", AVATAR_CACHE_MAX_AGE)))
+                .service(fs::Files::new("/", &media_root)))
         }
         app
     })
+        .disable_signals()
+        .shutdown_timeout(config.drain_timeout)
         .bind(&config.bind)?
-        .run()
-        .await?;
+        .run();
+    shutdown_signal().await;
+    info!("shutting down, closing {} websocket sessions", app_data.close_websocket_sessions());
+    // No new connections from here on, in-flight requests are waited for up to drain_timeout
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.stop(true).await;
+    }
+    server.stop(true).await;
+    if let Mailer::Queue(queue) = &app_data.mailer {
+        // Nothing else runs by now, so blocking is fine
+        let (drained, left) = queue.drain(Duration::from_secs(config.drain_timeout));
+        info!("sent {} queued e-mails", drained);
+        if left > 0 {
+            warn!("dropped {} queued e-mails after {} seconds", left, config.drain_timeout);
+        }
+    }
     Ok(())
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
 }
\ No newline at end of file
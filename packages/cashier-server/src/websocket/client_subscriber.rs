@@ -9,13 +9,9 @@ use crate::{
         },
     },
     api::app_state::AppState,
-    constants::{
-        WEBSOCKET_HEARTBEAT_INTERVAL,
-        WEBSOCKET_CLIENT_TIMEOUT,
-        WEBSOCKET_PERMISSION_REFRESH_INTERVAL,
-    },
+    constants::WEBSOCKET_PERMISSION_REFRESH_INTERVAL,
     queries::{
-        users::{PermissionTree, PermissionSubjectAction},
+        users::PermissionTree,
         errors::Error as QueryError,
     },
 };
@@ -25,7 +21,7 @@ use actix::{
 };
 use actix_web::web;
 use actix_web_actors::ws;
-use chrono::{DateTime, Utc, NaiveDateTime};
+use chrono::{DateTime, Duration, Utc, NaiveDateTime};
 use derive_more::From;
 use log::{info, error, warn};
 use serde::{Serialize, Deserialize};
@@ -34,7 +30,6 @@ use std::{
     collections::HashSet,
     result::Result,
     convert::{Infallible, identity},
-    borrow::Cow,
 };
 
 const MUST_INCLUDE_SUBJECT: &[&str] = &[
@@ -43,6 +38,11 @@ const MUST_INCLUDE_SUBJECT: &[&str] = &[
     "token-revoked",
     "user-role-updated",
     "role-permission-updated",
+    // Delivered to holders of the role or permission only, whatever they subscribe
+    "role-updated",
+    "role-deleted",
+    "role-parent-updated",
+    "permission-updated",
 ];
 
 const REMOVED_SUFFIX: &str = "-self";
@@ -183,6 +183,11 @@ pub struct ReloadSubjects {
     force: bool,
 }
 
+// Sent to every session as the server shuts down, see AppState::close_websocket_sessions
+#[derive(Message)]
+#[rtype(result = "Result<(), Infallible>")]
+pub struct CloseSession;
+
 pub struct ClientSubscriber {
     app_data: web::Data<AppState>,
     claims: Option<Claims>,
@@ -218,7 +223,7 @@ impl ClientSubscriber {
         self.subjects.contains(subject)
     }
     pub fn is_user(&self, uid: i32) -> bool {
-        self.claims.as_ref().map(|x| x.user_id == uid).contains(&true)
+        self.claims.as_ref().map(|x| x.user_id == uid) == Some(true)
     }
 }
 
@@ -226,10 +231,13 @@ impl Actor for ClientSubscriber {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let heartbeat_interval = WEBSOCKET_HEARTBEAT_INTERVAL.to_std().unwrap();
+        self.app_data.websocket_sessions.lock().unwrap().insert(ctx.address().recipient());
+        let websocket = &self.app_data.config.websocket;
+        let heartbeat_interval = std::time::Duration::from_secs(websocket.heartbeat_interval);
         let permission_refresh_interval = WEBSOCKET_PERMISSION_REFRESH_INTERVAL.to_std().unwrap();
-        let client_timeout = *WEBSOCKET_CLIENT_TIMEOUT;
+        let client_timeout = Duration::seconds(websocket.client_timeout as i64);
         ctx.run_interval(heartbeat_interval, move |act, ctx| {
+            // Subscriptions are dropped as the actor stops
             if Utc::now() - act.last_heartbeat > client_timeout {
                 info!("Websocket heartbeat timeout, disconnecting");
                 ctx.stop();
@@ -266,6 +274,7 @@ impl Actor for ClientSubscriber {
     }
 
     fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        self.app_data.websocket_sessions.lock().unwrap().remove(&ctx.address().recipient());
         ctx.spawn(self.app_data.subscriber.send(UpdateSubscribe {
             client: ctx.address().recipient(),
             subjects: HashSet::new(),
@@ -282,11 +291,23 @@ impl Actor for ClientSubscriber {
     }
 }
 
+impl Handler<CloseSession> for ClientSubscriber {
+    type Result = Result<(), Infallible>;
+
+    fn handle(&mut self, _msg: CloseSession, ctx: &mut Self::Context) -> Self::Result {
+        ctx.close(Some(ws::CloseCode::Away.into()));
+        ctx.stop();
+        Ok(())
+    }
+}
+
 impl Handler<InternalMessage> for ClientSubscriber {
     type Result = ResponseActFuture<Self, Result<(), Infallible>>;
 
     fn handle(&mut self, msg: InternalMessage, ctx: &mut Self::Context) -> Self::Result {
-        let mut new_permissions: Cow<PermissionTree> = Cow::Borrowed(&self.permissions);
+        // The permission tree is fetched again whenever a change may affect it, as roles inherit
+        // from ancestors the changes only tell about
+        let mut reload_permissions = false;
         let (push_message, shutdown_connection):
             (Vec<Option<InnerPublicMessage>>, Vec<bool>) = msg.messages.into_iter()
             .map(|msg| {
@@ -311,7 +332,7 @@ impl Handler<InternalMessage> for ClientSubscriber {
                          false),
                     InnerInternalMessage::UserUpdated(msg) => {
                         let uid = msg.id;
-                        let blocked = msg.blocked.flatten().contains(&true);
+                        let blocked = msg.blocked.flatten() == Some(true);
                         (
                             if self.has_subject("user-updated") ||
                                 (self.has_subject("user-updated-self") && self.is_user(uid)) {
@@ -331,55 +352,56 @@ impl Handler<InternalMessage> for ClientSubscriber {
                         )
                     }
                     InnerInternalMessage::UserRoleCreated(msg) => {
-                        if self.is_user(msg.user) {
-                            new_permissions.to_mut().add_role(msg.role, msg.role_permissions
-                                .into_iter()
-                                .map(|x| (x.id, PermissionSubjectAction {
-                                    subject: x.subject,
-                                    action: x.action,
-                                }))
-                                .collect()
-                            );
-                        }
+                        reload_permissions |= self.is_user(msg.user);
                         (if self.has_subject("user-role-updated") { Some(UserRoleCreated {
                             user: msg.user,
                             role: msg.role,
                         }.into()) } else { None }, false)
                     }
                     InnerInternalMessage::UserRoleDeleted(msg) => {
-                        if self.is_user(msg.user) {
-                            new_permissions.to_mut().remove_role(msg.role);
-                        }
+                        reload_permissions |= self.is_user(msg.user);
                         (if self.has_subject("user-role-updated") {
                             Some(msg.into())
                         } else { None }, false)
                     }
                     InnerInternalMessage::RolePermissionCreated(msg) => {
-                        new_permissions.to_mut().add_permission(msg.role, msg.permission,
-                                                                msg.subject, msg.action);
+                        reload_permissions |= self.permissions.has_role(msg.role);
                         (if self.has_subject("role-permission-updated") { Some(RolePermissionCreated {
                             role: msg.role,
                             permission: msg.permission,
                         }.into()) } else { None }, false)
                     }
                     InnerInternalMessage::RolePermissionDeleted(msg) => {
-                        new_permissions.to_mut().remove_permission(msg.role, msg.permission);
+                        reload_permissions |= self.permissions.has_role(msg.role);
                         (if self.has_subject("role-permission-updated") {
                             Some(msg.into())
                         } else { None }, false)
                     }
+                    InnerInternalMessage::RoleUpdated(msg) =>
+                        (if self.permissions.has_role(msg.id) { Some(msg.into()) } else { None },
+                         false),
+                    InnerInternalMessage::RoleDeleted(msg) => {
+                        if self.permissions.has_role(msg.id) {
+                            reload_permissions = true;
+                            (Some(msg.into()), false)
+                        } else { (None, false) }
+                    }
+                    InnerInternalMessage::RoleParentUpdated(msg) => {
+                        reload_permissions |= self.permissions.has_role(msg.role);
+                        (None, false)
+                    }
+                    InnerInternalMessage::PermissionUpdated(msg) =>
+                        (if self.permissions.has_permission(msg.id) {
+                            Some(msg.into())
+                        } else { None }, false),
                 }
             })
             .unzip();
         let push_message: Vec<_> = push_message.into_iter()
-            .filter_map(identity)
+            .flatten()
             .collect();
         let shutdown_connection = shutdown_connection.into_iter()
             .any(identity);
-        let new_permissions = match new_permissions {
-            Cow::Owned(new_permissions) => Some(new_permissions),
-            Cow::Borrowed(_) => None
-        };
         Box::new(if !push_message.is_empty() {
             fut::Either::Left(ctx.address().send::<ClientPushMessage>(PublicMessage {
                 sender_uid: msg.sender_uid,
@@ -394,17 +416,16 @@ impl Handler<InternalMessage> for ClientSubscriber {
                 if let Err(e) = result {
                     error!("send ClientPushMessage::PublicMessage to self error: {}", e)
                 }
-                match new_permissions {
-                    Some(new_permissions) =>
-                        fut::Either::Left(ctx.address().send(ReloadPermissions {
-                            new_permissions,
-                            force: false,
-                        }).into_actor(act)),
-                    None => fut::Either::Right(fut::ok(Ok(()))),
+                if reload_permissions {
+                    fut::Either::Left(ctx.address().send(ReloadPermissionsFromDatabase {
+                        force: false,
+                    }).into_actor(act))
+                } else {
+                    fut::Either::Right(fut::ok(Ok(())))
                 }
                     .then(move |result, _act, ctx| {
                         if let Err(e) = result {
-                            error!("send ReloadPermissions to self error: {}", e);
+                            error!("send ReloadPermissionsFromDatabase to self error: {}", e);
                             ctx.stop();
                         } else if shutdown_connection {
                             ctx.stop();
@@ -435,9 +456,11 @@ impl Handler<ReloadPermissionsFromDatabase> for ClientSubscriber {
         let app_data = self.app_data.clone();
         let user_id = self.claims.as_ref().map(|x| x.user_id);
         Box::new(async move {
-            app_data.query.user
-                .fetch_permission_tree(&*app_data.db.read().await, user_id)
-                .await
+            let db = app_data.pool.get().await?;
+            let permissions = db.query.user
+                .fetch_permission_tree(&db.client, user_id)
+                .await?;
+            Ok::<_, QueryError>(permissions)
         }
             .into_actor(self)
             .then(move |permissions, act, ctx| {
@@ -662,14 +685,15 @@ impl Handler<UpdateTokenRequest> for ClientSubscriber {
         Box::new(async move {
             match msg.jwt {
                 Some(token) => {
-                    let claims = app_data.query.token
-                        .verify_token(&*app_data.db.read().await, &token)
+                    let db = app_data.pool.get().await?;
+                    let claims = db.query.token
+                        .verify_token(&db.client, &token)
                         .await?;
-                    app_data.query.token
-                        .check_token_revoked(&*app_data.db.read().await, claims.jti)
+                    db.query.token
+                        .check_token_revoked(&db.client, claims.jti)
                         .await?;
-                    app_data.query.user
-                        .check_user_valid_by_id(&*app_data.db.read().await, claims.uid)
+                    db.query.user
+                        .check_user_valid_by_id(&db.client, claims.uid)
                         .await?;
                     Ok(Some(Claims {
                         user_id: claims.uid,
@@ -775,4 +799,115 @@ impl Handler<UpdateSubjectRequest> for ClientSubscriber {
             })
         )
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::api::{api_v1, testing::TestApp};
+    use crate::queries::audit::Actor;
+    use actix_web::{App, http::StatusCode, test};
+    use actix_web_actors::ws;
+    use futures::{SinkExt, Stream, StreamExt};
+    use std::time::Duration;
+
+    const ACTOR: Actor = Actor { uid: 0, impersonator: None };
+
+    // Skips the messages of other types, and the frames other than text
+    async fn next_message<S>(framed: &mut S, message_type: &str) -> serde_json::Value
+        where S: Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin {
+        actix_rt::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let ws::Frame::Text(text) = framed.next().await.unwrap().unwrap() {
+                    let message: serde_json::Value = serde_json::from_slice(&text).unwrap();
+                    if message["type"] == message_type {
+                        return message;
+                    }
+                }
+            }
+        })
+            .await
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn inherited_permission_revoked_test() {
+        let app = match TestApp::new(&[]).await {
+            Some(app) => app,
+            None => return,
+        };
+        let (_, admin) = app.user("admin", &["permission-admin"]).await;
+        let (viewer, editor, permission) = {
+            let mut db = app.app_data.db().await.unwrap();
+            let db = &mut *db;
+            let roles = &db.query.role;
+            let viewer = roles.create_role(&mut db.client, &ACTOR, "viewer", "Viewer", "")
+                .await
+                .unwrap()
+                .id;
+            let editor = roles.create_role(&mut db.client, &ACTOR, "editor", "Editor", "")
+                .await
+                .unwrap()
+                .id;
+            let permission = roles.resolve_permission(&db.client, "role", "read").await.unwrap();
+            roles.add_permissions_to_role(&mut db.client, &ACTOR, viewer, &[permission])
+                .await
+                .unwrap();
+            assert!(roles.add_parent_to_role(&mut db.client, &ACTOR, editor, viewer)
+                .await
+                .unwrap());
+            (viewer, editor, permission)
+        };
+        let (_, alice) = app.user("alice", &["editor"]).await;
+        let app_data = app.app_data.clone();
+        let mut server = test::start(move || App::new().configure(api_v1(&app_data)));
+        let mut framed = server.ws_at("/api/v1/ws").await.unwrap();
+        // Subscribed to the messages every client receives from then on
+        next_message(&mut framed, "subject-updated").await;
+        framed.send(ws::Message::Text(serde_json::json!({
+            "requestId": 1,
+            "message": { "type": "update-token", "jwt": &alice["Bearer ".len()..] },
+        }).to_string())).await.unwrap();
+        let permissions = |message: serde_json::Value| message["permissions"].as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["id"].as_i64().unwrap() as i32)
+            .collect::<Vec<_>>();
+        let updated = next_message(&mut framed, "permission-updated").await;
+        assert!(permissions(updated).contains(&permission));
+        let mut service = test::init_service(App::new().configure(api_v1(&app.app_data))).await;
+        // Told about the role only inherited from
+        let response = test::call_service(&mut service, test::TestRequest::patch()
+            .uri(&format!("/api/v1/roles/{}", viewer))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "displayName": "Reader" }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let push = next_message(&mut framed, "push").await;
+        assert_eq!(push["messages"][0]["type"], "role-updated");
+        assert_eq!(push["messages"][0]["id"], viewer);
+        // Revoked from the parent while the socket is open
+        let response = test::call_service(&mut service, test::TestRequest::delete()
+            .uri(&format!("/api/v1/roles/{}/permissions", viewer))
+            .header("Authorization", admin.clone())
+            .set_json(&serde_json::json!({ "permissions": [permission] }))
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let updated = next_message(&mut framed, "permission-updated").await;
+        assert!(!permissions(updated).contains(&permission));
+        // Inherited again through a new parent
+        let response = test::call_service(&mut service, test::TestRequest::put()
+            .uri(&format!("/api/v1/roles/{}/parents/{}",
+                          editor, app.db.client
+                              .query_one("SELECT id FROM role WHERE name = 'permission-admin'",
+                                         &[])
+                              .await
+                              .unwrap()
+                              .get::<_, i32>(0)))
+            .header("Authorization", admin.clone())
+            .to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let updated = next_message(&mut framed, "permission-updated").await;
+        assert!(permissions(updated).contains(&permission));
+        drop(framed);
+        server.stop().await;
+    }
+}
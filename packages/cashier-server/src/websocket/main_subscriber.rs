@@ -33,6 +33,11 @@ pub struct UpdateSubscribe {
 #[rtype(result = "Result<(), Infallible>")]
 pub struct RedisMessage(Msg);
 
+// Clients subscribed to any subject, for the metrics
+#[derive(Message)]
+#[rtype(result = "Result<usize, Infallible>")]
+pub struct CountClients;
+
 pub struct MainSubscriber {
     publisher: Arc<RwLock<Connection>>,
     subscriber: Arc<RwLock<PubSub>>,
@@ -41,6 +46,8 @@ pub struct MainSubscriber {
 }
 
 impl MainSubscriber {
+    // The actor never leaves its arbiter, so the redis connections need not be Sync
+    #[allow(clippy::arc_with_non_send_sync)]
     pub fn new(publisher: Connection, subscriber: PubSub) -> Self {
         Self {
             publisher: Arc::new(RwLock::new(publisher)),
@@ -142,7 +149,7 @@ impl Handler<UpdateSubscribe> for MainSubscriber {
         let to_add = &msg.subjects - old_subjects;
         for subject in to_add.iter() {
             let clients = self.subject2client.entry(subject.clone())
-                .or_insert_with(HashSet::new);
+                .or_default();
             clients.insert(msg.client.clone());
         }
         if msg.subjects.is_empty() {
@@ -187,4 +194,12 @@ impl Handler<InternalMessage> for MainSubscriber {
             Ok(())
         }.into_actor(self))
     }
-}
\ No newline at end of file
+}
+
+impl Handler<CountClients> for MainSubscriber {
+    type Result = Result<usize, Infallible>;
+
+    fn handle(&mut self, _msg: CountClients, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.client2subject.len())
+    }
+}
@@ -1,11 +1,12 @@
 use crate::{
-    queries::tokens::Token,
+    queries::{tokens::Token, users::AvatarThumbnails},
 };
 use actix::Message;
 use chrono::{DateTime, Utc};
 use derive_more::From;
-use serde::{Serialize, Deserialize, Deserializer};
+use serde::{Serialize, Deserialize, Deserializer, de::Error as _};
 use std::{
+    collections::BTreeMap,
     convert::Infallible,
     result::Result,
 };
@@ -26,6 +27,20 @@ pub fn double_option<'de, T, D>(de: D) -> Result<Option<Option<T>>, D::Error>
     Deserialize::deserialize(de).map(Some)
 }
 
+// Messages are internally tagged, and their buffered content keeps the sizes of the thumbnails as
+// the strings of the JSON keys, which cannot be read as numbers directly
+pub fn optional_thumbnails<'de, D>(de: D) -> Result<Option<AvatarThumbnails>, D::Error>
+    where
+        D: Deserializer<'de> {
+    Option::<BTreeMap<String, String>>::deserialize(de)?
+        .map(|thumbnails| thumbnails.into_iter()
+            .map(|(size, filename)| size.parse()
+                .map(|size| (size, filename))
+                .map_err(D::Error::custom))
+            .collect())
+        .transpose()
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 #[serde(transparent)]
@@ -71,9 +86,16 @@ pub struct UserUpdated {
     #[serde(deserialize_with = "double_option")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub avatar: Option<Option<String>>,
+    // Empty when the avatar is deleted
+    #[serde(deserialize_with = "optional_thumbnails")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_thumbnails: Option<AvatarThumbnails>,
     #[serde(deserialize_with = "double_option")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub avatar128: Option<Option<String>>,
+    pub avatar_webp: Option<Option<String>>,
+    #[serde(deserialize_with = "optional_thumbnails")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_thumbnails_webp: Option<AvatarThumbnails>,
     #[serde(deserialize_with = "double_option")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub blocked: Option<Option<bool>>,
@@ -126,6 +148,48 @@ pub struct RolePermissionDeleted {
     pub permission: i32,
 }
 
+// Only fields that changed are present
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleUpdated {
+    pub id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleDeleted {
+    pub id: i32,
+}
+
+// Not pushed to clients, only tells the holders of the role to fetch their permissions again
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InternalRoleParentUpdated {
+    pub role: i32,
+    pub parent: i32,
+}
+
+// Only fields that changed are present
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionUpdated {
+    pub id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Delivered to the clients subscribing to the kebab case of the name AsRefStr gives, so that
+// creations and deletions of links share the subject of their updates
 #[derive(Debug, Serialize, Deserialize, From, Clone, AsRefStr)]
 #[serde(tag = "type")]
 #[serde(rename_all = "kebab-case")]
@@ -135,10 +199,18 @@ pub enum InnerInternalMessage {
     UserCreated(UserCreated),
     UserUpdated(UserUpdated),
     UserDeleted(UserDeleted),
+    #[strum(serialize = "UserRoleUpdated")]
     UserRoleCreated(InternalUserRoleCreated),
+    #[strum(serialize = "UserRoleUpdated")]
     UserRoleDeleted(UserRoleDeleted),
+    #[strum(serialize = "RolePermissionUpdated")]
     RolePermissionCreated(InternalRolePermissionCreated),
+    #[strum(serialize = "RolePermissionUpdated")]
     RolePermissionDeleted(RolePermissionDeleted),
+    RoleUpdated(RoleUpdated),
+    RoleDeleted(RoleDeleted),
+    RoleParentUpdated(InternalRoleParentUpdated),
+    PermissionUpdated(PermissionUpdated),
 }
 
 #[derive(Debug, Serialize, Deserialize, Message, Clone)]
@@ -164,6 +236,9 @@ pub enum InnerPublicMessage {
     UserRoleDeleted(UserRoleDeleted),
     RolePermissionCreated(RolePermissionCreated),
     RolePermissionDeleted(RolePermissionDeleted),
+    RoleUpdated(RoleUpdated),
+    RoleDeleted(RoleDeleted),
+    PermissionUpdated(PermissionUpdated),
 }
 
 #[derive(Debug, Serialize, Deserialize, Message, Clone)]
@@ -61,6 +61,12 @@ pub struct FileStorageBuilder {
     make_dirs: bool,
 }
 
+impl Default for FileStorageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileStorageBuilder {
     pub fn new() -> FileStorageBuilder {
         Self {
@@ -103,7 +109,7 @@ impl FileStorageBuilder {
     pub fn origin_filename(mut self) -> Self {
         self.filename = Some(Box::new(|info, content_disposition| async move {
             let content_disposition = content_disposition
-                .ok_or_else(|| MulterError::MissingContentDisposition)?;
+                .ok_or(MulterError::MissingContentDisposition)?;
             let filename = content_disposition.get_filename()
                 .ok_or_else(|| MulterError::ExpectedFileField {
                     field: info.name.clone().into(),
@@ -115,7 +121,7 @@ impl FileStorageBuilder {
     pub fn random_filename(mut self, length: usize) -> Self {
         self.filename = Some(Box::new(move |info, content_disposition| async move {
             let content_disposition = content_disposition
-                .ok_or_else(|| MulterError::MissingContentDisposition)?;
+                .ok_or(MulterError::MissingContentDisposition)?;
             let extension = Path::new(content_disposition.get_filename()
                 .ok_or_else(|| MulterError::ExpectedFileField {
                     field: info.name.clone().into(),
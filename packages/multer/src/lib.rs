@@ -1,3 +1,6 @@
+// err-derive puts its impls in named consts, which newer compilers warn about
+#![allow(non_local_definitions)]
+
 pub mod memory_storage;
 pub mod file_storage;
 
@@ -115,6 +118,12 @@ pub struct FieldConfig {
     handler: Option<Handler>,
 }
 
+impl Default for FieldConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FieldConfig {
     pub fn new() -> Self {
         Self {
@@ -130,7 +139,7 @@ impl FieldConfig {
         self
     }
     pub fn accept_content_type(mut self, types: Vec<Mime>) -> Self {
-        self.accept_content_type = Some(HashSet::from_iter(types.into_iter()));
+        self.accept_content_type = Some(HashSet::from_iter(types));
         self
     }
     pub fn accept_file(mut self, value: bool) -> Self {
@@ -208,8 +217,8 @@ impl FieldResult {
     pub fn filename(&self) -> &Option<String> {
         &self.filename
     }
-    pub fn extra(&self) -> &Box<dyn FieldResultExtra + std::marker::Send> {
-        &self.extra
+    pub fn extra(&self) -> &(dyn FieldResultExtra + std::marker::Send) {
+        &*self.extra
     }
     pub fn extra_mut(&mut self) -> &mut Box<dyn FieldResultExtra + std::marker::Send> {
         &mut self.extra
@@ -231,7 +240,7 @@ impl MulterResults {
         self.fields.get(&None)
     }
     pub fn get_single(&self, index: &str) -> &FieldResult {
-        self.get(index).unwrap().get(0).unwrap()
+        self.get(index).unwrap().first().unwrap()
     }
     pub fn get_mut(&mut self, index: &str) -> Option<&mut Vec<FieldResult>> {
         self.fields.get_mut(&Some(index.into()))
@@ -275,7 +284,7 @@ pub async fn multer(mut payload: Multipart, config: &MulterConfig) -> Result<Mul
                 .ok_or_else(|| MulterError::UnexpectedField {
                     field: name.clone().into(),
                 })?;
-            let matched_result = results.fields.entry(matched_index).or_insert_with(Vec::new);
+            let matched_result = results.fields.entry(matched_index).or_default();
             // Check accept_content_type
             if let Some(accept_content_type) = matched_config.accept_content_type.as_ref() {
                 if !accept_content_type.contains(field.content_type()) {
@@ -311,7 +320,7 @@ pub async fn multer(mut payload: Multipart, config: &MulterConfig) -> Result<Mul
                 extra: (matched_config.handler.as_ref().unwrap())(&FieldInfo {
                     index: matched_result.len(),
                     name: &name,
-                    config: &matched_config,
+                    config: matched_config,
                 }, &mut field).await?,
             });
         } else {
@@ -28,18 +28,11 @@ impl FieldResultExtra for MemoryResultInfo {
     }
 }
 
+#[derive(Default)]
 pub struct MemoryStorageBuilder {
     max_size: Option<usize>,
 }
 
-impl Default for MemoryStorageBuilder {
-    fn default() -> Self {
-        Self {
-            max_size: None
-        }
-    }
-}
-
 impl MemoryStorageBuilder {
     pub fn new() -> Self {
         Self::default()